@@ -0,0 +1,404 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+//! Cache hierarchy reported to the guest through CPUID leaves 4 and 2.
+//!
+//! Leaf 4 describes the parameters of each cache, while leaf 2 encodes the
+//! same caches as descriptor bytes, picked from a table of well known
+//! configurations. Old guests only read leaf 2, so both leaves are
+//! generated from the same `CacheTopology` for them to agree. Leaf 2 also
+//! holds TLB and prefetch descriptors, which are kept from the host.
+//!
+//! The logical processors sharing each cache are only known once the CPU
+//! topology is, they are set per vCPU by `update_leaf_4_sharing()`.
+
+use hypervisor::arch::x86::{CPUID_FLAG_VALID_INDEX, CpuIdEntry};
+
+use super::CpuTopologyV2;
+
+// Leaf 4 EAX[4:0] cache types
+const CACHE_TYPE_NULL: u32 = 0;
+const CACHE_TYPE_DATA: u32 = 1;
+const CACHE_TYPE_INSTRUCTION: u32 = 2;
+const CACHE_TYPE_UNIFIED: u32 = 3;
+// Leaf 4 EAX bit 8
+const CACHE_SELF_INITIALIZING: u32 = 1 << 8;
+// Leaf 4 EAX[25:14], logical processors sharing the cache minus 1
+const CACHE_SHARING_SHIFT: u32 = 14;
+const CACHE_SHARING_MASK: u32 = 0xfff << CACHE_SHARING_SHIFT;
+// Leaf 4 EAX[31:26], cores in the package minus 1
+const CACHE_CORES_SHIFT: u32 = 26;
+const CACHE_CORES_MASK: u32 = 0x3f << CACHE_CORES_SHIFT;
+
+// Leaf 2 descriptor telling the guest to use leaf 4 instead
+const DESCRIPTOR_USE_LEAF_4: u8 = 0xff;
+// Leaf 2 AL, the number of times leaf 2 must be queried
+const LEAF_2_QUERIES: u8 = 1;
+// Register (EAX to EDX) and byte of the leaf 2 descriptors. The most
+// significant byte of each register is left null, as a register whose bit
+// 31 is set doesn't hold descriptors, and AL holds the query count.
+const LEAF_2_SLOTS: [(usize, u32); 11] = [
+    (0, 1),
+    (0, 2),
+    (1, 0),
+    (1, 1),
+    (1, 2),
+    (2, 0),
+    (2, 1),
+    (2, 2),
+    (3, 0),
+    (3, 1),
+    (3, 2),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// A cache reported in leaf 4. Its sharing fields, EAX[25:14] and
+/// EAX[31:26], are left to `update_leaf_4_sharing()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cache {
+    pub level: u8,
+    pub cache_type: CacheType,
+    /// Size in bytes
+    pub size: u32,
+    pub ways: u16,
+    /// Line size in bytes
+    pub line_size: u16,
+}
+
+// Leaf 2 descriptors of the non-sectored caches, see the Intel SDM Vol. 2A,
+// "Encoding of CPUID Leaf 2 Descriptors"
+const KIB: u32 = 1 << 10;
+const MIB: u32 = 1 << 20;
+const DESCRIPTORS: [(u8, u8, CacheType, u32, u16, u16); 30] = [
+    (0x0e, 1, CacheType::Data, 24 * KIB, 6, 64),
+    (0x2c, 1, CacheType::Data, 32 * KIB, 8, 64),
+    (0x30, 1, CacheType::Instruction, 32 * KIB, 8, 64),
+    (0x60, 1, CacheType::Data, 16 * KIB, 8, 64),
+    (0x21, 2, CacheType::Unified, 256 * KIB, 8, 64),
+    (0x24, 2, CacheType::Unified, MIB, 16, 64),
+    (0x48, 2, CacheType::Unified, 3 * MIB, 12, 64),
+    (0x4e, 2, CacheType::Unified, 6 * MIB, 24, 64),
+    (0x78, 2, CacheType::Unified, MIB, 4, 64),
+    (0x7d, 2, CacheType::Unified, 2 * MIB, 8, 64),
+    (0x80, 2, CacheType::Unified, 512 * KIB, 8, 64),
+    (0x86, 2, CacheType::Unified, 512 * KIB, 4, 64),
+    (0x87, 2, CacheType::Unified, MIB, 8, 64),
+    (0x4a, 3, CacheType::Unified, 6 * MIB, 12, 64),
+    (0x4b, 3, CacheType::Unified, 8 * MIB, 16, 64),
+    (0x4c, 3, CacheType::Unified, 12 * MIB, 12, 64),
+    (0x4d, 3, CacheType::Unified, 16 * MIB, 16, 64),
+    (0xd0, 3, CacheType::Unified, 512 * KIB, 4, 64),
+    (0xd1, 3, CacheType::Unified, MIB, 4, 64),
+    (0xd2, 3, CacheType::Unified, 2 * MIB, 4, 64),
+    (0xd6, 3, CacheType::Unified, MIB, 8, 64),
+    (0xd7, 3, CacheType::Unified, 2 * MIB, 8, 64),
+    (0xd8, 3, CacheType::Unified, 4 * MIB, 8, 64),
+    (0xdc, 3, CacheType::Unified, 3 * MIB / 2, 12, 64),
+    (0xdd, 3, CacheType::Unified, 3 * MIB, 12, 64),
+    (0xde, 3, CacheType::Unified, 6 * MIB, 12, 64),
+    (0xe2, 3, CacheType::Unified, 2 * MIB, 16, 64),
+    (0xe3, 3, CacheType::Unified, 4 * MIB, 16, 64),
+    (0xe4, 3, CacheType::Unified, 8 * MIB, 16, 64),
+    (0xea, 3, CacheType::Unified, 12 * MIB, 24, 64),
+];
+
+// Whether a leaf 2 descriptor describes a TLB or the prefetching rather than
+// a cache, see the same table of the Intel SDM
+fn is_non_cache_descriptor(descriptor: u8) -> bool {
+    matches!(
+        descriptor,
+        0x01..=0x05
+            | 0x0b
+            | 0x4f..=0x52
+            | 0x55..=0x57
+            | 0x59..=0x5d
+            | 0x61
+            | 0x63
+            | 0x64
+            | 0x6a..=0x6d
+            | 0x76
+            | 0xa0
+            | 0xb0..=0xb6
+            | 0xba
+            | 0xc0..=0xc4
+            | 0xca
+            | 0xf0
+            | 0xf1
+    )
+}
+
+// Descriptors of a leaf 2 entry, in the order of LEAF_2_SLOTS, skipping the
+// null ones and the registers whose bit 31 is set
+fn leaf_2_descriptors(entry: &CpuIdEntry) -> Vec<u8> {
+    let regs = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+    LEAF_2_SLOTS
+        .iter()
+        .filter(|&&(reg, _)| regs[reg] & (1 << 31) == 0)
+        .map(|&(reg, byte)| (regs[reg] >> (byte * 8)) as u8)
+        .filter(|&descriptor| descriptor != 0)
+        .collect()
+}
+
+impl Cache {
+    fn is_valid(&self) -> bool {
+        let set_size = u32::from(self.ways) * u32::from(self.line_size);
+        (1..=7).contains(&self.level)
+            && set_size != 0
+            && self.size != 0
+            && self.size.is_multiple_of(set_size)
+            && self.ways <= 1024
+            && self.line_size <= 4096
+    }
+
+    /// Leaf 2 descriptor of the cache, if there is one for its parameters.
+    fn descriptor(&self) -> Option<u8> {
+        DESCRIPTORS
+            .iter()
+            .find(|&&(_, level, cache_type, size, ways, line_size)| {
+                (level, cache_type, size, ways, line_size)
+                    == (
+                        self.level,
+                        self.cache_type,
+                        self.size,
+                        self.ways,
+                        self.line_size,
+                    )
+            })
+            .map(|d| d.0)
+    }
+
+    fn leaf_4(&self, index: u32) -> CpuIdEntry {
+        let cache_type = match self.cache_type {
+            CacheType::Data => CACHE_TYPE_DATA,
+            CacheType::Instruction => CACHE_TYPE_INSTRUCTION,
+            CacheType::Unified => CACHE_TYPE_UNIFIED,
+        };
+        let sets = self.size / (u32::from(self.ways) * u32::from(self.line_size));
+        CpuIdEntry {
+            function: 4,
+            index,
+            flags: CPUID_FLAG_VALID_INDEX,
+            eax: cache_type | (u32::from(self.level) << 5) | CACHE_SELF_INITIALIZING,
+            ebx: ((u32::from(self.ways) - 1) << 22) | (u32::from(self.line_size) - 1),
+            ecx: sets - 1,
+            edx: 0,
+        }
+    }
+}
+
+/// Caches reported to the guest, ordered as enumerated by leaf 4.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheTopology(pub Vec<Cache>);
+
+impl Default for CacheTopology {
+    /// Generic hierarchy with a descriptor for each cache: 32 KiB L1 data
+    /// and instruction caches, a 1 MiB L2 cache and an 8 MiB L3 cache.
+    fn default() -> Self {
+        let cache = |level, cache_type, size, ways| Cache {
+            level,
+            cache_type,
+            size,
+            ways,
+            line_size: 64,
+        };
+        CacheTopology(vec![
+            cache(1, CacheType::Data, 32 * KIB, 8),
+            cache(1, CacheType::Instruction, 32 * KIB, 8),
+            cache(2, CacheType::Unified, MIB, 16),
+            cache(3, CacheType::Unified, 8 * MIB, 16),
+        ])
+    }
+}
+
+impl CacheTopology {
+    /// Decode the caches enumerated by leaf 4, e.g. the host ones.
+    pub fn from_leaf_4(cpuid: &[CpuIdEntry]) -> Self {
+        let mut leaves: Vec<_> = cpuid.iter().filter(|e| e.function == 4).collect();
+        leaves.sort_by_key(|e| e.index);
+
+        let caches = leaves
+            .into_iter()
+            .map_while(|e| {
+                let cache_type = match e.eax & 0x1f {
+                    CACHE_TYPE_DATA => CacheType::Data,
+                    CACHE_TYPE_INSTRUCTION => CacheType::Instruction,
+                    CACHE_TYPE_UNIFIED => CacheType::Unified,
+                    _ => return None,
+                };
+                let ways = (e.ebx >> 22) + 1;
+                let partitions = ((e.ebx >> 12) & 0x3ff) + 1;
+                let line_size = (e.ebx & 0xfff) + 1;
+                Some(Cache {
+                    level: ((e.eax >> 5) & 0x7) as u8,
+                    cache_type,
+                    size: ways
+                        .saturating_mul(partitions)
+                        .saturating_mul(line_size)
+                        .saturating_mul(e.ecx.saturating_add(1)),
+                    ways: ways as u16,
+                    line_size: line_size as u16,
+                })
+            })
+            .collect();
+
+        CacheTopology(caches)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0.iter().all(Cache::is_valid)
+    }
+
+    /// Leaf 4 subleaves, terminated by a null cache subleaf.
+    pub fn leaf_4(&self) -> Vec<CpuIdEntry> {
+        let mut leaves: Vec<CpuIdEntry> =
+            self.0.iter().zip(0..).map(|(c, i)| c.leaf_4(i)).collect();
+        leaves.push(CpuIdEntry {
+            function: 4,
+            index: self.0.len() as u32,
+            flags: CPUID_FLAG_VALID_INDEX,
+            eax: CACHE_TYPE_NULL,
+            ..Default::default()
+        });
+        leaves
+    }
+
+    /// Leaf 2 matching `leaf_4()`. The caches are reported with their
+    /// descriptors when they all have one, otherwise the guest is told to
+    /// read leaf 4, so that both leaves never disagree. The TLB and prefetch
+    /// descriptors of `host`, the leaf 2 being replaced, follow them.
+    pub fn leaf_2(&self, host: Option<&CpuIdEntry>) -> CpuIdEntry {
+        let non_cache: Vec<u8> = host
+            .map(leaf_2_descriptors)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|&descriptor| is_non_cache_descriptor(descriptor))
+            .collect();
+        let descriptors: Option<Vec<u8>> = self.0.iter().map(Cache::descriptor).collect();
+
+        let mut descriptors = match descriptors {
+            Some(descriptors) if descriptors.len() + non_cache.len() <= LEAF_2_SLOTS.len() => {
+                descriptors
+            }
+            _ => vec![DESCRIPTOR_USE_LEAF_4],
+        };
+        descriptors.extend(non_cache);
+        let mut regs = [u32::from(LEAF_2_QUERIES), 0, 0, 0];
+        for (descriptor, (reg, byte)) in descriptors.into_iter().zip(LEAF_2_SLOTS) {
+            regs[reg] |= u32::from(descriptor) << (byte * 8);
+        }
+
+        CpuIdEntry {
+            function: 2,
+            eax: regs[0],
+            ebx: regs[1],
+            ecx: regs[2],
+            edx: regs[3],
+            ..Default::default()
+        }
+    }
+}
+
+/// Set the sharing fields of the leaf 4 subleaves from the CPU topology,
+/// for them to agree with leaves 0xb and 0x1f: the L1 and L2 caches are
+/// shared by the threads of a core, the other levels by the cores of a die.
+pub fn update_leaf_4_sharing(cpuid: &mut [CpuIdEntry], topology: &CpuTopologyV2) {
+    let threads_per_core = u32::from(topology.threads_per_core);
+    let threads_per_die = threads_per_core * u32::from(topology.cores_per_die);
+    let cores_per_package =
+        u32::from(topology.cores_per_die) * u32::from(topology.dies_per_package);
+
+    for entry in cpuid
+        .iter_mut()
+        .filter(|e| e.function == 4 && e.eax & 0x1f != CACHE_TYPE_NULL)
+    {
+        let sharing = if (entry.eax >> 5) & 0x7 <= 2 {
+            threads_per_core
+        } else {
+            threads_per_die
+        };
+        entry.eax &= !(CACHE_SHARING_MASK | CACHE_CORES_MASK);
+        entry.eax |= ((sharing - 1).min(0xfff) << CACHE_SHARING_SHIFT)
+            | ((cores_per_package - 1).min(0x3f) << CACHE_CORES_SHIFT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_leaves() {
+        let topology = CacheTopology::default();
+        assert!(topology.is_valid());
+
+        let leaf_2 = topology.leaf_2(None);
+        // L1d, L1i, L2 and L3 descriptors after the query count
+        assert_eq!(leaf_2.eax, 0x0030_2c01);
+        assert_eq!(leaf_2.ebx, 0x0000_4b24);
+        assert_eq!((leaf_2.ecx, leaf_2.edx), (0, 0));
+
+        let leaf_4 = topology.leaf_4();
+        assert_eq!(leaf_4.len(), 5);
+        // 1 MiB L2: 16 ways of 64 bytes lines, 1024 sets
+        assert_eq!(leaf_4[2].eax, 0x143);
+        assert_eq!(leaf_4[2].ebx, 0x03c0_003f);
+        assert_eq!(leaf_4[2].ecx, 1023);
+        assert_eq!(leaf_4[4].eax & 0x1f, CACHE_TYPE_NULL);
+
+        // Both leaves describe the same caches
+        assert_eq!(CacheTopology::from_leaf_4(&leaf_4), topology);
+
+        // A cache without descriptor makes leaf 2 point to leaf 4
+        let mut topology = topology;
+        topology.0[3].size = 30 * MIB;
+        topology.0[3].ways = 15;
+        assert!(topology.is_valid());
+        let leaf_2 = topology.leaf_2(None);
+        assert_eq!(leaf_2.eax, 0x0000_ff01);
+        assert_eq!((leaf_2.ebx, leaf_2.ecx, leaf_2.edx), (0, 0, 0));
+
+        // The host TLB (0x63, 0xb5, 0xc3) and prefetch (0xf0) descriptors
+        // are kept, unlike its caches (0x2c, 0x30, 0x7d) and the invalid EDX
+        let host = CpuIdEntry {
+            function: 2,
+            eax: 0x0063_2c01,
+            ebx: 0x00b5_7d30,
+            ecx: 0x00f0_00c3,
+            edx: 0x8000_0049,
+            ..Default::default()
+        };
+        let leaf_2 = topology.leaf_2(Some(&host));
+        assert_eq!(leaf_2.eax, 0x0063_ff01);
+        assert_eq!((leaf_2.ebx, leaf_2.ecx, leaf_2.edx), (0x00f0_c3b5, 0, 0));
+        let leaf_2 = CacheTopology::default().leaf_2(Some(&host));
+        assert_eq!(leaf_2.eax, 0x0030_2c01);
+        assert_eq!(leaf_2.ebx, 0x0063_4b24);
+        assert_eq!(leaf_2.ecx, 0x00f0_c3b5);
+
+        topology.0[3].size = 30 * MIB + 1;
+        assert!(!topology.is_valid());
+    }
+
+    #[test]
+    fn test_leaf_4_sharing() {
+        let mut leaf_4 = CacheTopology::default().leaf_4();
+        update_leaf_4_sharing(&mut leaf_4, &CpuTopologyV2::from((2, 4, 2, 1)));
+
+        // The L1 and L2 caches are shared by the 2 threads of a core
+        for entry in &leaf_4[..3] {
+            assert_eq!((entry.eax >> 14) & 0xfff, 1);
+        }
+        // The L3 cache by the 8 threads of a die
+        assert_eq!((leaf_4[3].eax >> 14) & 0xfff, 7);
+        // 8 cores in the package
+        assert!(leaf_4[..4].iter().all(|e| e.eax >> 26 == 7));
+        // The other fields and the null subleaf are untouched
+        assert_eq!(leaf_4[2].eax & 0x3fff, 0x143);
+        assert_eq!(leaf_4[4].eax, CACHE_TYPE_NULL);
+    }
+}
@@ -115,6 +115,9 @@ pub const TPM_SIZE: u64 = 0x1000;
 // IOAPIC
 pub const IOAPIC_START: GuestAddress = GuestAddress(0xfec0_0000);
 pub const IOAPIC_SIZE: u64 = 0x20;
+// Additional IOAPICs follow the first one, one page apart
+pub const IOAPIC_STRIDE: u64 = 0x1000;
+pub const MAX_IOAPICS: usize = 8;
 
 // APIC
 pub const APIC_START: GuestAddress = GuestAddress(0xfee0_0000);
@@ -6,25 +6,33 @@
 // Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
+pub mod cache;
 pub mod interrupts;
 pub mod layout;
 mod mpspec;
 mod mptable;
 pub mod regs;
+use std::collections::BTreeMap;
 use std::mem;
+use std::str::FromStr;
 
-use hypervisor::arch::x86::{CPUID_FLAG_VALID_INDEX, CpuIdEntry};
+use anyhow::anyhow;
+use hypervisor::arch::x86::features::{CpuFeature, MSR_IA32_ARCH_CAPABILITIES};
+use hypervisor::arch::x86::{CPUID_FLAG_VALID_INDEX, CpuIdEntry, MsrEntry};
 use hypervisor::{CpuVendor, HypervisorCpuError, HypervisorError};
 use linux_loader::loader::bootparam::{boot_params, setup_header};
 use linux_loader::loader::elf::start_info::{
     hvm_memmap_table_entry, hvm_modlist_entry, hvm_start_info,
 };
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use vm_memory::{
     Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryRegion,
 };
 
+use self::cache::CacheTopology;
+pub use self::regs::BootMode;
 use crate::{GuestMemoryMmap, InitramfsConfig, RegionType};
 mod smbios;
 use std::arch::x86_64;
@@ -35,29 +43,49 @@ pub mod tdx;
 // legacy devices such as mptable support at most 254 CPUs.
 pub const MAX_SUPPORTED_CPUS_LEGACY: u32 = 254;
 
+// Longest APIC bus cycle period, for the bus frequency reported by the KVM
+// timing leaf to stay at 1 kHz or more.
+pub const MAX_APIC_BUS_CYCLE_NS: u32 = KVM_APIC_BUS_KHZ;
+
 // CPUID feature bits
 #[cfg(feature = "kvm")]
 const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
+// Leaves reserved for the hypervisor, 0x4000_0000 reporting the highest one
+const HYPERVISOR_LEAVES: std::ops::RangeInclusive<u32> = 0x4000_0000..=0x4000_00ff;
 const MTRR_EDX_BIT: u8 = 12; // Hypervisor ecx bit.
+const MWAIT_ECX_BIT: u8 = 3; // MONITOR/MWAIT ecx bit.
+const X2APIC_ECX_BIT: u8 = 21; // x2APIC ecx bit.
 const INVARIANT_TSC_EDX_BIT: u8 = 8; // Invariant TSC bit on 0x8000_0007 EDX
+// LAPIC resolution of 1ns (freq: 1GHz) is KVM's default APIC_BUS_CYCLE_NS,
+// which KVM_CAP_X86_APIC_BUS_CYCLES_NS can make longer
+const KVM_APIC_BUS_KHZ: u32 = 1_000_000;
+const ARAT_EAX_BIT: u8 = 2; // Always running APIC timer on leaf 6 EAX
 const AMX_BF16: u8 = 22; // AMX tile computation on bfloat16 numbers
 const AMX_TILE: u8 = 24; // AMX tile load/store instructions
 const AMX_INT8: u8 = 25; // AMX tile computation on 8-bit integers
+const CET_SS_ECX_BIT: u8 = 7; // CET shadow stacks on leaf 7 ECX
+const CET_IBT_EDX_BIT: u8 = 20; // CET indirect branch tracking on leaf 7 EDX
+const ARCH_CAPABILITIES_EDX_BIT: u8 = 29; // IA32_ARCH_CAPABILITIES on leaf 7 EDX
+const RDPID_ECX_BIT: u8 = 22; // RDPID on leaf 7 ECX
+const RDTSCP_EDX_BIT: u8 = 27; // RDTSCP on leaf 0x8000_0001 EDX
+const LA57_ECX_BIT: u8 = 16; // 5-level paging on leaf 7 ECX
+const XSS_CET_U_BIT: u8 = 11; // CET user state on leaf 0xd subleaf 1 ECX
+const XSS_CET_S_BIT: u8 = 12; // CET supervisor state on leaf 0xd subleaf 1 ECX
+const INTEL_PT_EBX_BIT: u8 = 25; // Intel Processor Trace on leaf 7 EBX
+const XSS_PT_BIT: u8 = 8; // Processor Trace state on leaf 0xd subleaf 1 ECX
 
 // KVM feature bits
-#[cfg(feature = "tdx")]
 const KVM_FEATURE_CLOCKSOURCE_BIT: u8 = 0;
-#[cfg(feature = "tdx")]
 const KVM_FEATURE_CLOCKSOURCE2_BIT: u8 = 3;
-#[cfg(feature = "tdx")]
 const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u8 = 24;
-#[cfg(feature = "tdx")]
 const KVM_FEATURE_ASYNC_PF_BIT: u8 = 4;
-#[cfg(feature = "tdx")]
 const KVM_FEATURE_ASYNC_PF_VMEXIT_BIT: u8 = 10;
-#[cfg(feature = "tdx")]
+const KVM_FEATURE_ASYNC_PF_INT_BIT: u8 = 14;
 const KVM_FEATURE_STEAL_TIME_BIT: u8 = 5;
+const KVM_FEATURE_PV_EOI_BIT: u8 = 6;
+const KVM_FEATURE_POLL_CONTROL_BIT: u8 = 12;
+const KVM_FEATURE_MIGRATION_CONTROL_BIT: u8 = 17;
 
 const KVM_FEATURE_MSI_EXT_DEST_ID: u8 = 15;
 
@@ -77,12 +105,457 @@ pub struct EntryPoint {
 const E820_RAM: u32 = 1;
 const E820_RESERVED: u32 = 2;
 
+// CPUID leaf 5 ECX bits
+const MWAIT_EXTENSIONS_ECX_BIT: u8 = 0; // Enumeration of MONITOR/MWAIT extensions
+const MWAIT_INTERRUPT_BREAK_ECX_BIT: u8 = 1; // Interrupts as break-event for MWAIT
+
+// Number of C-states described by CPUID leaf 5 EDX, 4 bits each
+const MWAIT_CSTATES: usize = 8;
+const MWAIT_MAX_SUBSTATES: u8 = 0xf;
+
+/// Number of MWAIT sub-states advertised for each C-state, C0 first.
+///
+/// The guest idle driver picks its MWAIT hints from these counts, so only
+/// C-states actually implemented by the host should be advertised: a guest
+/// entering one which isn't may never wake up. Only C1, with a single
+/// sub-state, is advertised by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MwaitSubstates(pub [u8; MWAIT_CSTATES]);
+
+impl Default for MwaitSubstates {
+    fn default() -> Self {
+        let mut substates = [0; MWAIT_CSTATES];
+        substates[1] = 1;
+        MwaitSubstates(substates)
+    }
+}
+
+impl MwaitSubstates {
+    /// Encoding of the counts in leaf 5 EDX, C0 in bits 3:0.
+    fn edx(&self) -> u32 {
+        self.0.iter().enumerate().fold(0, |edx, (cstate, &count)| {
+            edx | (u32::from(count & MWAIT_MAX_SUBSTATES) << (cstate * 4))
+        })
+    }
+
+    fn from_edx(edx: u32) -> Self {
+        let mut substates = [0; MWAIT_CSTATES];
+        for (cstate, count) in substates.iter_mut().enumerate() {
+            *count = (edx >> (cstate * 4)) as u8 & MWAIT_MAX_SUBSTATES;
+        }
+        MwaitSubstates(substates)
+    }
+}
+
+/// MONITOR/MWAIT parameters advertised to the guest through CPUID leaf 5,
+/// written `<min_monitor_line_size>:<max_monitor_line_size>[:<sub_cstates>]`
+/// with the sub-state counts separated by `/` from C0, e.g. `64:64:0/2/1`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MwaitConfig {
+    /// Smallest monitor line size in bytes.
+    pub min_monitor_line_size: u16,
+    /// Largest monitor line size in bytes.
+    pub max_monitor_line_size: u16,
+    /// Number of MWAIT sub-states of each C-state.
+    pub sub_cstates: MwaitSubstates,
+}
+
+impl MwaitConfig {
+    /// Whether the monitor line sizes are ordered and not null, and the
+    /// sub-state counts fit in leaf 5 EDX.
+    pub fn is_valid(&self) -> bool {
+        self.min_monitor_line_size != 0
+            && self.min_monitor_line_size <= self.max_monitor_line_size
+            && self
+                .sub_cstates
+                .0
+                .iter()
+                .all(|&count| count <= MWAIT_MAX_SUBSTATES)
+    }
+
+    /// MONITOR/MWAIT parameters advertised by `cpuid`, `None` when MWAIT
+    /// is hidden or leaf 5 is missing.
+    pub fn from_cpuid(cpuid: &[CpuIdEntry]) -> Option<Self> {
+        let mwait = cpuid
+            .iter()
+            .any(|c| c.function == 1 && c.index == 0 && c.ecx & (1 << MWAIT_ECX_BIT) != 0);
+        if !mwait {
+            return None;
+        }
+
+        cpuid
+            .iter()
+            .find(|c| c.function == 5)
+            .map(|leaf_5| MwaitConfig {
+                min_monitor_line_size: leaf_5.eax as u16,
+                max_monitor_line_size: leaf_5.ebx as u16,
+                sub_cstates: MwaitSubstates::from_edx(leaf_5.edx),
+            })
+    }
+
+    fn validate(&self) -> super::Result<()> {
+        if !self.is_valid() {
+            return Err(Error::InvalidMwaitConfig(*self).into());
+        }
+
+        Ok(())
+    }
+
+    fn cpuid_entry(&self) -> CpuIdEntry {
+        CpuIdEntry {
+            function: 5,
+            eax: self.min_monitor_line_size as u32,
+            ebx: self.max_monitor_line_size as u32,
+            ecx: (1 << MWAIT_EXTENSIONS_ECX_BIT) | (1 << MWAIT_INTERRUPT_BREAK_ECX_BIT),
+            edx: self.sub_cstates.edx(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseMwaitConfigError {
+    #[error("Invalid MONITOR/MWAIT configuration: {0}")]
+    InvalidValue(String),
+}
+
+impl FromStr for MwaitConfig {
+    type Err = ParseMwaitConfigError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || ParseMwaitConfigError::InvalidValue(s.to_owned());
+        let mut fields = s.split(':');
+        let mut line_size = || {
+            fields
+                .next()
+                .and_then(|v| v.parse::<u16>().ok())
+                .ok_or_else(invalid)
+        };
+        let min_monitor_line_size = line_size()?;
+        let max_monitor_line_size = line_size()?;
+
+        let sub_cstates = match fields.next() {
+            None => MwaitSubstates::default(),
+            Some(counts) => {
+                let mut substates = [0; MWAIT_CSTATES];
+                let counts: Vec<&str> = counts.split('/').collect();
+                if counts.len() > MWAIT_CSTATES {
+                    return Err(invalid());
+                }
+                for (substate, count) in substates.iter_mut().zip(counts) {
+                    *substate = count.parse().map_err(|_| invalid())?;
+                }
+                MwaitSubstates(substates)
+            }
+        };
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+
+        let config = MwaitConfig {
+            min_monitor_line_size,
+            max_monitor_line_size,
+            sub_cstates,
+        };
+        if !config.is_valid() {
+            return Err(invalid());
+        }
+        Ok(config)
+    }
+}
+
+/// KVM paravirtual MSRs which can be hidden from the guest, along with the
+/// CPUID leaf 0x4000_0001 EAX feature bits advertising them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PvMsr {
+    /// kvmclock: `MSR_KVM_WALL_CLOCK`, `MSR_KVM_SYSTEM_TIME` and their
+    /// `_NEW` variants.
+    KvmClock,
+    /// Asynchronous page faults: `MSR_KVM_ASYNC_PF_EN`, `MSR_KVM_ASYNC_PF_INT`
+    /// and `MSR_KVM_ASYNC_PF_ACK`.
+    AsyncPf,
+    /// Steal time accounting: `MSR_KVM_STEAL_TIME`.
+    StealTime,
+    /// Paravirtual end of interrupt: `MSR_KVM_PV_EOI_EN`.
+    PvEoi,
+    /// Guest side halt polling control: `MSR_KVM_POLL_CONTROL`.
+    PollControl,
+    /// Migration status of encrypted guests: `MSR_KVM_MIGRATION_CONTROL`.
+    MigrationControl,
+}
+
+impl PvMsr {
+    /// Returns the indices of the MSRs.
+    pub fn msr_indices(&self) -> &'static [u32] {
+        match self {
+            PvMsr::KvmClock => &[0x11, 0x12, 0x4b56_4d00, 0x4b56_4d01],
+            PvMsr::AsyncPf => &[0x4b56_4d02, 0x4b56_4d06, 0x4b56_4d07],
+            PvMsr::StealTime => &[0x4b56_4d03],
+            PvMsr::PvEoi => &[0x4b56_4d04],
+            PvMsr::PollControl => &[0x4b56_4d05],
+            PvMsr::MigrationControl => &[0x4b56_4d08],
+        }
+    }
+
+    /// Returns the leaf 0x4000_0001 EAX bits advertising the MSRs, including
+    /// the features depending on them.
+    fn feature_mask(&self) -> u32 {
+        match self {
+            PvMsr::KvmClock => {
+                (1 << KVM_FEATURE_CLOCKSOURCE_BIT)
+                    | (1 << KVM_FEATURE_CLOCKSOURCE2_BIT)
+                    | (1 << KVM_FEATURE_CLOCKSOURCE_STABLE_BIT)
+            }
+            PvMsr::AsyncPf => {
+                (1 << KVM_FEATURE_ASYNC_PF_BIT)
+                    | (1 << KVM_FEATURE_ASYNC_PF_VMEXIT_BIT)
+                    | (1 << KVM_FEATURE_ASYNC_PF_INT_BIT)
+            }
+            PvMsr::StealTime => 1 << KVM_FEATURE_STEAL_TIME_BIT,
+            PvMsr::PvEoi => 1 << KVM_FEATURE_PV_EOI_BIT,
+            PvMsr::PollControl => 1 << KVM_FEATURE_POLL_CONTROL_BIT,
+            PvMsr::MigrationControl => 1 << KVM_FEATURE_MIGRATION_CONTROL_BIT,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParsePvMsrError {
+    #[error("Invalid paravirtual MSR: {0}")]
+    InvalidValue(String),
+}
+
+impl FromStr for PvMsr {
+    type Err = ParsePvMsrError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "kvmclock" => Ok(PvMsr::KvmClock),
+            "async_pf" => Ok(PvMsr::AsyncPf),
+            "steal_time" => Ok(PvMsr::StealTime),
+            "pv_eoi" => Ok(PvMsr::PvEoi),
+            "poll_control" => Ok(PvMsr::PollControl),
+            "migration_control" => Ok(PvMsr::MigrationControl),
+            _ => Err(ParsePvMsrError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Architectural performance monitoring events, along with their bit in the
+/// CPUID leaf 0xA EBX event-not-available bitmap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PmuEvent {
+    /// UnHalted Core Cycles
+    CoreCycles,
+    /// Instructions Retired
+    InstructionsRetired,
+    /// UnHalted Reference Cycles
+    ReferenceCycles,
+    /// Last Level Cache References
+    LlcReferences,
+    /// Last Level Cache Misses
+    LlcMisses,
+    /// Branch Instructions Retired
+    BranchInstructionsRetired,
+    /// Branch Mispredicts Retired
+    BranchMispredictsRetired,
+    /// Topdown Slots
+    TopdownSlots,
+}
+
+impl PmuEvent {
+    fn ebx_bit(&self) -> u32 {
+        match self {
+            PmuEvent::CoreCycles => 0,
+            PmuEvent::InstructionsRetired => 1,
+            PmuEvent::ReferenceCycles => 2,
+            PmuEvent::LlcReferences => 3,
+            PmuEvent::LlcMisses => 4,
+            PmuEvent::BranchInstructionsRetired => 5,
+            PmuEvent::BranchMispredictsRetired => 6,
+            PmuEvent::TopdownSlots => 7,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParsePmuEventError {
+    #[error("Invalid PMU event: {0}")]
+    InvalidValue(String),
+}
+
+impl FromStr for PmuEvent {
+    type Err = ParsePmuEventError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "core_cycles" => Ok(PmuEvent::CoreCycles),
+            "instructions_retired" => Ok(PmuEvent::InstructionsRetired),
+            "reference_cycles" => Ok(PmuEvent::ReferenceCycles),
+            "llc_references" => Ok(PmuEvent::LlcReferences),
+            "llc_misses" => Ok(PmuEvent::LlcMisses),
+            "branch_instructions_retired" => Ok(PmuEvent::BranchInstructionsRetired),
+            "branch_mispredicts_retired" => Ok(PmuEvent::BranchMispredictsRetired),
+            "topdown_slots" => Ok(PmuEvent::TopdownSlots),
+            _ => Err(ParsePmuEventError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+// Highest leaf 7 subleaf handled, subleaf 0 EAX reporting the highest one
+const LEAF_7_MAX_SUBLEAF: u32 = 2;
+
+/// Feature bits of a leaf 7 subleaf register to expose to the guest or to
+/// hide from it, written `<subleaf>.<register>(+|-)<bits>`, e.g. `1.eax+0x10`
+/// requires AVX-VNNI and `1.eax-0x20` hides AVX512_BF16. The supported
+/// features are exposed by default, exposing one makes sure the guest gets
+/// it or fails the VM creation, and re-exposes it after it was hidden.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Leaf7Override {
+    pub subleaf: u32,
+    pub register: CpuidReg,
+    pub bits: u32,
+    /// Whether the bits are exposed, in which case they must be supported
+    /// by the hypervisor, or hidden.
+    pub expose: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum ParseLeaf7OverrideError {
+    #[error("Invalid leaf 7 override: {0}")]
+    InvalidValue(String),
+}
+
+impl FromStr for Leaf7Override {
+    type Err = ParseLeaf7OverrideError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || ParseLeaf7OverrideError::InvalidValue(s.to_owned());
+        let (subleaf, rest) = s.split_once('.').ok_or_else(invalid)?;
+        let (register, bits, expose) = if let Some((register, bits)) = rest.split_once('+') {
+            (register, bits, true)
+        } else if let Some((register, bits)) = rest.split_once('-') {
+            (register, bits, false)
+        } else {
+            return Err(invalid());
+        };
+
+        let subleaf = subleaf.parse::<u32>().map_err(|_| invalid())?;
+        let register = match register.to_lowercase().as_str() {
+            "eax" => CpuidReg::EAX,
+            "ebx" => CpuidReg::EBX,
+            "ecx" => CpuidReg::ECX,
+            "edx" => CpuidReg::EDX,
+            _ => return Err(invalid()),
+        };
+        let bits = bits
+            .strip_prefix("0x")
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(invalid)?;
+
+        let o = Leaf7Override {
+            subleaf,
+            register,
+            bits,
+            expose,
+        };
+        if !o.is_feature_register() {
+            return Err(invalid());
+        }
+        Ok(o)
+    }
+}
+
+impl Leaf7Override {
+    /// Subleaf 0 EAX is the highest subleaf, not a feature register.
+    fn is_feature_register(&self) -> bool {
+        self.subleaf <= LEAF_7_MAX_SUBLEAF && (self.subleaf, self.register) != (0, CpuidReg::EAX)
+    }
+
+    /// Bits managed by dedicated options, which can only be hidden here:
+    /// AMX, CET, Processor Trace, IA32_ARCH_CAPABILITIES and LA57.
+    fn dedicated_bits(&self) -> u32 {
+        match (self.subleaf, self.register) {
+            (0, CpuidReg::EBX) => 1 << INTEL_PT_EBX_BIT,
+            (0, CpuidReg::ECX) => (1 << CET_SS_ECX_BIT) | (1 << LA57_ECX_BIT),
+            (0, CpuidReg::EDX) => {
+                (1 << AMX_BF16)
+                    | (1 << AMX_TILE)
+                    | (1 << AMX_INT8)
+                    | (1 << CET_IBT_EDX_BIT)
+                    | (1 << ARCH_CAPABILITIES_EDX_BIT)
+            }
+            _ => 0,
+        }
+    }
+}
+
 pub struct CpuidConfig {
     pub phys_bits: u8,
     pub kvm_hyperv: bool,
     #[cfg(feature = "tdx")]
     pub tdx: bool,
     pub amx: bool,
+    /// Expose the CET shadow stack and indirect branch tracking features
+    /// supported by the hypervisor, they are hidden otherwise.
+    pub cet: bool,
+    /// Expose Intel Processor Trace, leaf 0x14 and the `IA32_RTIT_*` MSRs,
+    /// which must be supported by the hypervisor. It is hidden otherwise.
+    pub processor_trace: bool,
+    /// Expose MONITOR/MWAIT to the guest with the given leaf 5 parameters.
+    /// MWAIT is hidden from the guest when `None`.
+    pub mwait: Option<MwaitConfig>,
+    /// Force the x2APIC feature bit on or off, the supported CPUID value is
+    /// kept when `None`.
+    pub x2apic: Option<bool>,
+    /// Force the invariant TSC bit on or off, the supported CPUID value is
+    /// kept when `None`.
+    pub invariant_tsc: Option<bool>,
+    /// Vendor string reported in leaf 0. Only this leaf is rewritten, the
+    /// other leaves keep the layout of the host vendor.
+    pub cpu_vendor_override: Option<[u8; 12]>,
+    /// Paravirtual MSRs whose feature bits are cleared from leaf 0x4000_0001.
+    pub disabled_pv_msrs: Vec<PvMsr>,
+    /// Advertise the always running APIC timer (ARAT) in leaf 6. The
+    /// other thermal and power management features are always hidden.
+    pub arat: bool,
+    /// Architectural PMU events reported as not available in leaf 0xA.
+    pub disabled_pmu_events: Vec<PmuEvent>,
+    /// Linear address bits reported in leaf 0x8000_0008, the supported
+    /// CPUID value is kept when `None`.
+    pub linear_bits: Option<u8>,
+    /// Caches reported in leaves 4 and 2, the supported CPUID values are
+    /// kept when `None`. Not configurable from the VMM, this is only set by
+    /// users of this crate.
+    pub cache_topology: Option<CacheTopology>,
+    /// Leaf 7 feature bits exposed or hidden on top of the supported ones,
+    /// applied in order.
+    pub leaf_7_overrides: Vec<Leaf7Override>,
+}
+
+// ARAT is advertised by default, as `CpusConfig` does
+impl Default for CpuidConfig {
+    fn default() -> Self {
+        CpuidConfig {
+            phys_bits: 0,
+            kvm_hyperv: false,
+            #[cfg(feature = "tdx")]
+            tdx: false,
+            amx: false,
+            cet: false,
+            processor_trace: false,
+            mwait: None,
+            x2apic: None,
+            invariant_tsc: None,
+            cpu_vendor_override: None,
+            disabled_pv_msrs: Vec::new(),
+            arat: true,
+            disabled_pmu_events: Vec::new(),
+            linear_bits: None,
+            cache_topology: None,
+            leaf_7_overrides: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -143,6 +616,22 @@ pub enum Error {
     #[error("Error getting CPU TSC frequency")]
     GetTscFrequency(#[source] HypervisorCpuError),
 
+    /// Error setting the TSC frequency
+    #[error("Error setting the TSC frequency")]
+    SetTscFrequency(#[source] HypervisorCpuError),
+
+    /// TSC frequency not applied by the hypervisor
+    #[error("TSC frequency of {0} kHz not applied, the TSC runs at {1:?} kHz")]
+    TscFrequencyMismatch(u32, Option<u32>),
+
+    /// Error setting IA32_ARCH_CAPABILITIES
+    #[error("Error setting IA32_ARCH_CAPABILITIES")]
+    SetArchCapabilities(#[source] anyhow::Error),
+
+    /// Error setting IA32_TSC_AUX
+    #[error("Error setting IA32_TSC_AUX")]
+    SetTscAux(#[source] HypervisorCpuError),
+
     /// Error retrieving TDX capabilities through the hypervisor (kvm/mshv) API
     #[cfg(feature = "tdx")]
     #[error("Error retrieving TDX capabilities through the hypervisor API")]
@@ -151,26 +640,50 @@ pub enum Error {
     /// Failed to configure E820 map for bzImage
     #[error("Failed to configure E820 map for bzImage")]
     E820Configuration,
-}
 
-pub fn get_x2apic_id(cpu_id: u32, topology: Option<(u16, u16, u16, u16)>) -> u32 {
-    if let Some(t) = topology {
-        let thread_mask_width = u16::BITS - (t.0 - 1).leading_zeros();
-        let core_mask_width = u16::BITS - (t.1 - 1).leading_zeros();
-        let die_mask_width = u16::BITS - (t.2 - 1).leading_zeros();
+    /// Invalid MONITOR/MWAIT configuration
+    #[error("Invalid MONITOR/MWAIT configuration: {0:?}")]
+    InvalidMwaitConfig(MwaitConfig),
 
-        let thread_id = cpu_id % (t.0 as u32);
-        let core_id = cpu_id / (t.0 as u32) % (t.1 as u32);
-        let die_id = cpu_id / ((t.0 * t.1) as u32) % (t.2 as u32);
-        let socket_id = cpu_id / ((t.0 * t.1 * t.2) as u32);
+    /// Invalid cache topology
+    #[error("Invalid cache topology: {0:?}")]
+    InvalidCacheTopology(CacheTopology),
 
-        return thread_id
-            | (core_id << thread_mask_width)
-            | (die_id << (thread_mask_width + core_mask_width))
-            | (socket_id << (thread_mask_width + core_mask_width + die_mask_width));
-    }
+    /// MWAIT advertised without a valid CPUID leaf 5
+    #[error("MWAIT advertised without a valid CPUID leaf 5")]
+    CpuidMwaitInconsistent,
+
+    /// CET requested while not supported by the hypervisor
+    #[error("CET is not supported by the hypervisor")]
+    CetUnsupported,
 
-    cpu_id
+    /// Processor Trace requested while not supported by the hypervisor
+    #[error("Intel Processor Trace is not supported by the hypervisor")]
+    ProcessorTraceUnsupported,
+
+    /// Linear address width not matching a paging mode
+    #[error("Invalid number of linear address bits {0}, 48 or 57 is expected")]
+    InvalidLinearAddressBits(u8),
+
+    /// Linear address width larger than the host one
+    #[error("{0} linear address bits requested, the host supports {1}")]
+    LinearAddressBitsUnsupported(u8, u8),
+
+    /// 5-level paging requested while not supported by the hypervisor
+    #[error("5-level paging (LA57) is not supported by the hypervisor")]
+    La57Unsupported,
+
+    /// Leaf 7 override not applicable
+    #[error("Invalid leaf 7 override {0:x?}: {1}")]
+    InvalidLeaf7Override(Leaf7Override, &'static str),
+
+    /// Hypervisor leaf populated beyond the maximum advertised one
+    #[error("CPUID leaf {0:#x} is beyond the maximum hypervisor leaf {1:#x}")]
+    HypervisorLeafOutOfRange(u32, u32),
+}
+
+pub fn get_x2apic_id(cpu_id: u32, topology: Option<(u16, u16, u16, u16)>) -> u32 {
+    hypervisor::arch::x86::x2apic_id(cpu_id, topology)
 }
 
 pub fn get_max_x2apic_id(topology: (u16, u16, u16, u16)) -> u32 {
@@ -180,7 +693,110 @@ pub fn get_max_x2apic_id(topology: (u16, u16, u16, u16)) -> u32 {
     )
 }
 
-#[derive(Copy, Clone, Debug)]
+/// Returns the x2APIC IDs of the vCPUs `cpus` of a NUMA node, matching the
+/// ones reported by their CPUID leaves 0xb and 0x1f.
+pub fn numa_node_x2apic_ids(cpus: &[u32], topology: Option<(u16, u16, u16, u16)>) -> Vec<u32> {
+    cpus.iter()
+        .map(|cpu| get_x2apic_id(*cpu, topology))
+        .collect()
+}
+
+/// Returns the first pair of vCPUs that the CPUID topology leaves report as
+/// threads of the same core, while `cpu_nodes` assigns them to different
+/// NUMA nodes. Guests expect SMT siblings to share a node, Linux ignores the
+/// topology of such a core.
+pub fn numa_split_core(
+    topology: (u16, u16, u16, u16),
+    cpu_nodes: &BTreeMap<u32, u32>,
+) -> Option<(u32, u32)> {
+    let thread_width = u16::BITS - (topology.0 - 1).leading_zeros();
+    let mut core_nodes = BTreeMap::new();
+
+    for (&cpu, &node) in cpu_nodes {
+        let core = get_x2apic_id(cpu, Some(topology)) >> thread_width;
+        let (sibling, sibling_node) = *core_nodes.entry(core).or_insert((cpu, node));
+        if sibling_node != node {
+            return Some((sibling, cpu));
+        }
+    }
+
+    None
+}
+
+// Level types of CPUID leaf 0x1f ECX[15:8]
+const TOPOLOGY_LEVEL_INVALID: u32 = 0;
+const TOPOLOGY_LEVEL_SMT: u32 = 1;
+const TOPOLOGY_LEVEL_CORE: u32 = 2;
+const TOPOLOGY_LEVEL_DIE: u32 = 5;
+
+/// Guest CPU topology enumerated through the V2 extended topology leaf
+/// (CPUID leaf 0x1f), which unlike leaf 0xb describes the dies of a socket.
+///
+/// The socket is not a level of its own: the x2APIC ID bits above the die
+/// level identify it. Leaf 0xb is still generated with the threads and the
+/// cores of a socket, for guests which don't know about leaf 0x1f or when
+/// the maximum basic leaf is below 0x1f (e.g. on AMD hosts).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuTopologyV2 {
+    pub threads_per_core: u16,
+    pub cores_per_die: u16,
+    pub dies_per_package: u16,
+    pub packages: u16,
+}
+
+impl From<(u16, u16, u16, u16)> for CpuTopologyV2 {
+    fn from(t: (u16, u16, u16, u16)) -> Self {
+        CpuTopologyV2 {
+            threads_per_core: t.0,
+            cores_per_die: t.1,
+            dies_per_package: t.2,
+            packages: t.3,
+        }
+    }
+}
+
+impl CpuTopologyV2 {
+    /// Returns the leaf 0x1f subleaves of the vCPU with the x2APIC ID
+    /// `x2apic_id`: the thread, core and die levels, followed by the invalid
+    /// level terminating the enumeration.
+    pub fn leaf_0x1f(&self, x2apic_id: u32) -> Vec<CpuIdEntry> {
+        let thread_width = u16::BITS - (self.threads_per_core - 1).leading_zeros();
+        let core_width = u16::BITS - (self.cores_per_die - 1).leading_zeros() + thread_width;
+        let die_width = u16::BITS - (self.dies_per_package - 1).leading_zeros() + core_width;
+
+        let threads = u32::from(self.threads_per_core);
+        let levels = [
+            (TOPOLOGY_LEVEL_SMT, thread_width, threads),
+            (
+                TOPOLOGY_LEVEL_CORE,
+                core_width,
+                threads * u32::from(self.cores_per_die),
+            ),
+            (
+                TOPOLOGY_LEVEL_DIE,
+                die_width,
+                threads * u32::from(self.cores_per_die) * u32::from(self.dies_per_package),
+            ),
+            (TOPOLOGY_LEVEL_INVALID, 0, 0),
+        ];
+
+        levels
+            .iter()
+            .zip(0u32..)
+            .map(|(&(level_type, shift, count), index)| CpuIdEntry {
+                function: 0x1f,
+                index,
+                flags: CPUID_FLAG_VALID_INDEX,
+                eax: shift,
+                ebx: count,
+                ecx: (level_type << 8) | index,
+                edx: x2apic_id,
+            })
+            .collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum CpuidReg {
     EAX,
     EBX,
@@ -385,13 +1001,38 @@ impl CpuidFeatureEntry {
                 feature_reg: CpuidReg::EDX,
                 compatible_check: CpuidCompatibleCheck::BitwiseSubset,
             },
-            // Leaf 0x7 subleaf 0x1, EAX, extended features
+            // Leaf 0x7 subleaf 0x1, EAX/EBX/ECX/EDX, extended features
             CpuidFeatureEntry {
                 function: 7,
                 index: 1,
                 feature_reg: CpuidReg::EAX,
                 compatible_check: CpuidCompatibleCheck::BitwiseSubset,
             },
+            CpuidFeatureEntry {
+                function: 7,
+                index: 1,
+                feature_reg: CpuidReg::EBX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
+            CpuidFeatureEntry {
+                function: 7,
+                index: 1,
+                feature_reg: CpuidReg::ECX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
+            CpuidFeatureEntry {
+                function: 7,
+                index: 1,
+                feature_reg: CpuidReg::EDX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
+            // Leaf 0x7 subleaf 0x2, EDX, extended features
+            CpuidFeatureEntry {
+                function: 7,
+                index: 2,
+                feature_reg: CpuidReg::EDX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
             // Leaf 0x8000_0001, ECX/EDX, CPUID features bits
             CpuidFeatureEntry {
                 function: 0x8000_0001,
@@ -456,6 +1097,34 @@ impl CpuidFeatureEntry {
                 feature_reg: CpuidReg::EDX,
                 compatible_check: CpuidCompatibleCheck::BitwiseSubset,
             },
+            // Hyper-V enlightenments, only present with kvm_hyperv, see the
+            // "Hypervisor Top Level Functional Specification"
+            // Leaf 0x4000_0003, EAX/EBX/EDX, Hyper-V features
+            CpuidFeatureEntry {
+                function: 0x4000_0003,
+                index: 0,
+                feature_reg: CpuidReg::EAX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
+            CpuidFeatureEntry {
+                function: 0x4000_0003,
+                index: 0,
+                feature_reg: CpuidReg::EBX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
+            CpuidFeatureEntry {
+                function: 0x4000_0003,
+                index: 0,
+                feature_reg: CpuidReg::EDX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
+            // Leaf 0x4000_0004, EAX, Hyper-V implementation recommendations
+            CpuidFeatureEntry {
+                function: 0x4000_0004,
+                index: 0,
+                feature_reg: CpuidReg::EAX,
+                compatible_check: CpuidCompatibleCheck::BitwiseSubset,
+            },
         ]
     }
 
@@ -549,19 +1218,10 @@ pub fn generate_common_cpuid(
     hypervisor: &dyn hypervisor::Hypervisor,
     config: &CpuidConfig,
 ) -> super::Result<Vec<CpuIdEntry>> {
-    // SAFETY: cpuid called with valid leaves
-    if unsafe { x86_64::__cpuid(1) }.ecx & (1 << HYPERVISOR_ECX_BIT) == 1 << HYPERVISOR_ECX_BIT {
-        // SAFETY: cpuid called with valid leaves
-        let hypervisor_cpuid = unsafe { x86_64::__cpuid(0x4000_0000) };
-
-        let mut identifier: [u8; 12] = [0; 12];
-        identifier[0..4].copy_from_slice(&hypervisor_cpuid.ebx.to_le_bytes()[..]);
-        identifier[4..8].copy_from_slice(&hypervisor_cpuid.ecx.to_le_bytes()[..]);
-        identifier[8..12].copy_from_slice(&hypervisor_cpuid.edx.to_le_bytes()[..]);
-
+    if hypervisor.running_nested() {
         info!(
             "Running under nested virtualisation. Hypervisor string: {}",
-            String::from_utf8_lossy(&identifier)
+            hypervisor::host_hypervisor_signature().unwrap_or_default()
         );
     }
 
@@ -617,6 +1277,19 @@ pub fn generate_common_cpuid(
 
     CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
 
+    if config.cet
+        && !hypervisor.feature_effectively_available(CpuFeature::ShadowStack)
+        && !hypervisor.feature_effectively_available(CpuFeature::Ibt)
+    {
+        return Err(Error::CetUnsupported.into());
+    }
+
+    if config.processor_trace
+        && !hypervisor.feature_effectively_available(CpuFeature::ProcessorTrace)
+    {
+        return Err(Error::ProcessorTraceUnsupported.into());
+    }
+
     #[cfg(feature = "tdx")]
     let tdx_capabilities = if config.tdx {
         let caps = hypervisor
@@ -636,9 +1309,19 @@ pub fn generate_common_cpuid(
                 if !config.amx && entry.index == 0 {
                     entry.edx &= !((1 << AMX_BF16) | (1 << AMX_TILE) | (1 << AMX_INT8))
                 }
+                // Clear CET related bits if the CET feature is not enabled
+                if !config.cet && entry.index == 0 {
+                    entry.ecx &= !(1 << CET_SS_ECX_BIT);
+                    entry.edx &= !(1 << CET_IBT_EDX_BIT);
+                }
             }
-            0xd =>
-            {
+            0xd => {
+                // The CET state components can only be enabled through
+                // IA32_XSS along with CET itself.
+                if !config.cet && entry.index == 1 {
+                    entry.ecx &= !((1 << XSS_CET_U_BIT) | (1 << XSS_CET_S_BIT));
+                }
+
                 #[cfg(feature = "tdx")]
                 if let Some(caps) = &tdx_capabilities {
                     let xcr0_mask: u64 = 0x82ff;
@@ -693,6 +1376,10 @@ pub fn generate_common_cpuid(
                 // device interrupts to cpus with APIC IDs > 254 without interrupt remapping.
                 entry.eax |= 1 << KVM_FEATURE_MSI_EXT_DEST_ID;
 
+                for pv_msr in &config.disabled_pv_msrs {
+                    entry.eax &= !pv_msr.feature_mask();
+                }
+
                 // These features are not supported by TDX
                 #[cfg(feature = "tdx")]
                 if config.tdx {
@@ -708,69 +1395,477 @@ pub fn generate_common_cpuid(
         }
     }
 
-    // Copy CPU identification string
-    for i in 0x8000_0002..=0x8000_0004 {
-        cpuid.retain(|c| c.function != i);
-        // SAFETY: call cpuid with valid leaves
-        let leaf = unsafe { std::arch::x86_64::__cpuid(i) };
-        cpuid.push(CpuIdEntry {
-            function: i,
-            eax: leaf.eax,
-            ebx: leaf.ebx,
-            ecx: leaf.ecx,
-            edx: leaf.edx,
-            ..Default::default()
-        });
+    // Copy CPU identification string
+    for i in 0x8000_0002..=0x8000_0004 {
+        cpuid.retain(|c| c.function != i);
+        // SAFETY: call cpuid with valid leaves
+        let leaf = unsafe { std::arch::x86_64::__cpuid(i) };
+        cpuid.push(CpuIdEntry {
+            function: i,
+            eax: leaf.eax,
+            ebx: leaf.ebx,
+            ecx: leaf.ecx,
+            edx: leaf.edx,
+            ..Default::default()
+        });
+    }
+
+    // Only advertise MWAIT along with a matching leaf 5, otherwise the guest
+    // idle driver ends up using bogus monitor line sizes.
+    if let Some(mwait) = &config.mwait {
+        mwait.validate()?;
+        cpuid.retain(|c| c.function != 5);
+        cpuid.push(mwait.cpuid_entry());
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function == 1 && entry.index == 0 {
+                entry.ecx |= 1 << MWAIT_ECX_BIT;
+            }
+        }
+    } else {
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function == 1 && entry.index == 0 {
+                entry.ecx &= !(1 << MWAIT_ECX_BIT);
+            }
+        }
+    }
+    check_mwait_consistency(&cpuid)?;
+
+    // Leaf 2 is generated along with leaf 4 for old guests reading the
+    // caches from the former to agree with the latter.
+    if let Some(topology) = &config.cache_topology {
+        if !topology.is_valid() {
+            return Err(Error::InvalidCacheTopology(topology.clone()).into());
+        }
+        let host_leaf_2 = cpuid.iter().find(|c| c.function == 2).copied();
+        cpuid.retain(|c| c.function != 2 && c.function != 4);
+        cpuid.push(topology.leaf_2(host_leaf_2.as_ref()));
+        cpuid.extend(topology.leaf_4());
+    }
+
+    if let Some(x2apic) = config.x2apic {
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function == 1 && entry.index == 0 {
+                if x2apic {
+                    entry.ecx |= 1 << X2APIC_ECX_BIT;
+                } else {
+                    entry.ecx &= !(1 << X2APIC_ECX_BIT);
+                }
+            }
+        }
+    }
+
+    if let Some(invariant_tsc) = config.invariant_tsc {
+        // SAFETY: cpuid called with valid leaves
+        let host_invariant_tsc =
+            unsafe { x86_64::__cpuid(0x8000_0007) }.edx & (1u32 << INVARIANT_TSC_EDX_BIT) != 0;
+        if invariant_tsc && !host_invariant_tsc {
+            warn!(
+                "Exposing an invariant TSC while the host TSC is not invariant, the guest \
+                 clock will drift unless the host CPU frequency is pinned"
+            );
+        }
+        set_invariant_tsc(&mut cpuid, invariant_tsc);
+    }
+
+    if let Some(linear_bits) = config.linear_bits {
+        // A host reporting 57 linear address bits may still be unable to
+        // run guests with 5-level paging enabled
+        if linear_bits == 57 && !hypervisor.la57_supported() {
+            return Err(Error::La57Unsupported.into());
+        }
+        set_linear_address_bits(&mut cpuid, linear_bits)?;
+    }
+
+    if !config.processor_trace {
+        hide_processor_trace(&mut cpuid);
+    }
+
+    set_thermal_power_leaf(&mut cpuid, config.arat);
+    disable_pmu_events(&mut cpuid, &config.disabled_pmu_events);
+    apply_leaf_7_overrides(&mut cpuid, &config.leaf_7_overrides)?;
+
+    if let Some(vendor) = &config.cpu_vendor_override {
+        let host_vendor = set_cpu_vendor(&mut cpuid, vendor);
+        if host_vendor != *vendor {
+            warn!(
+                "Exposing CPU vendor {:?} on a {:?} host: the feature leaves keep the layout \
+                 of the host vendor, which may confuse a guest relying on the vendor to \
+                 interpret them",
+                String::from_utf8_lossy(vendor),
+                String::from_utf8_lossy(&host_vendor)
+            );
+        }
+    }
+
+    if config.kvm_hyperv {
+        // Remove conflicting entries
+        cpuid.retain(|c| c.function != 0x4000_0000);
+        cpuid.retain(|c| c.function != 0x4000_0001);
+        // See "Hypervisor Top Level Functional Specification" for details
+        // Compliance with "Hv#1" requires leaves up to 0x4000_000a, the
+        // maximum cpuid leaf is set by set_hypervisor_leaf_range()
+        cpuid.push(CpuIdEntry {
+            function: 0x40000000,
+            ebx: 0x756e694c, // "Linu"
+            ecx: 0x564b2078, // "x KV"
+            edx: 0x7648204d, // "M Hv"
+            ..Default::default()
+        });
+        cpuid.push(CpuIdEntry {
+            function: 0x40000001,
+            eax: 0x31237648, // "Hv#1"
+            ..Default::default()
+        });
+        cpuid.push(CpuIdEntry {
+            function: 0x40000002,
+            eax: 0x3839,  // "Build number"
+            ebx: 0xa0000, // "Version"
+            ..Default::default()
+        });
+        // The TLB flush hypercalls target vCPUs by their VP index
+        let tlb_flush = hypervisor.hyperv_tlb_flush_supported();
+        let mut eax = (1 << 1) // AccessPartitionReferenceCounter
+            | (1 << 2) // AccessSynicRegs
+            | (1 << 3) // AccessSyntheticTimerRegs
+            | (1 << 9); // AccessPartitionReferenceTsc
+        if tlb_flush {
+            eax |= (1 << 5) // AccessHypercallMsrs
+                | (1 << 6); // AccessVpIndex
+        }
+        cpuid.push(CpuIdEntry {
+            function: 0x4000_0003,
+            eax,
+            edx: (1 << 3) // CPU dynamic partitioning
+                   | (1 << 10), // GuestCrashMsrsAvailable
+            ..Default::default()
+        });
+        let mut eax = 1 << 5; // Recommend relaxed timing
+        if tlb_flush {
+            eax |= (1 << 1) // Recommend hypercall for local TLB flush
+                | (1 << 2); // Recommend hypercall for remote TLB flush
+        }
+        cpuid.push(CpuIdEntry {
+            function: 0x4000_0004,
+            eax,
+            ..Default::default()
+        });
+        for i in 0x4000_0005..=0x4000_000a {
+            cpuid.push(CpuIdEntry {
+                function: i,
+                ..Default::default()
+            });
+        }
+    }
+
+    set_hypervisor_leaf_range(&mut cpuid);
+
+    Ok(cpuid)
+}
+
+/// Returns the highest leaf populated in the hypervisor range, leaf
+/// 0x4000_0000 included.
+fn max_hypervisor_leaf(cpuid: &[CpuIdEntry]) -> Option<u32> {
+    cpuid
+        .iter()
+        .map(|c| c.function)
+        .filter(|f| HYPERVISOR_LEAVES.contains(f))
+        .max()
+}
+
+/// Advertise the highest populated hypervisor leaf, whether it comes from
+/// KVM or from the Hyper-V enlightenments, in leaf 0x4000_0000 EAX.
+fn set_hypervisor_leaf_range(cpuid: &mut Vec<CpuIdEntry>) {
+    if let Some(max_leaf) = max_hypervisor_leaf(cpuid) {
+        CpuidPatch::set_cpuid_reg(cpuid, 0x4000_0000, None, CpuidReg::EAX, max_leaf);
+    }
+}
+
+/// Checks that no hypervisor leaf is populated beyond the one advertised in
+/// leaf 0x4000_0000 EAX, as the guest would never read it.
+fn check_hypervisor_leaf_range(cpuid: &[CpuIdEntry]) -> super::Result<()> {
+    let Some(max_leaf) = max_hypervisor_leaf(cpuid) else {
+        return Ok(());
+    };
+    let advertised = cpuid
+        .iter()
+        .find(|c| c.function == 0x4000_0000)
+        .map(|c| c.eax)
+        .unwrap_or(0);
+    if max_leaf > advertised {
+        return Err(Error::HypervisorLeafOutOfRange(max_leaf, advertised).into());
+    }
+
+    Ok(())
+}
+
+/// Checks that the MWAIT feature bit is only set when CPUID leaf 5 reports
+/// usable monitor line sizes.
+fn check_mwait_consistency(cpuid: &[CpuIdEntry]) -> super::Result<()> {
+    let mwait = cpuid
+        .iter()
+        .any(|c| c.function == 1 && c.index == 0 && c.ecx & (1 << MWAIT_ECX_BIT) != 0);
+    if !mwait {
+        return Ok(());
+    }
+
+    let leaf_5_valid = cpuid
+        .iter()
+        .any(|c| c.function == 5 && c.eax & 0xffff != 0 && c.eax & 0xffff <= c.ebx & 0xffff);
+    if !leaf_5_valid {
+        return Err(Error::CpuidMwaitInconsistent.into());
+    }
+
+    Ok(())
+}
+
+/// Report `linear_bits` in leaf 0x8000_0008 EAX[15:8]. Only the widths of
+/// 4-level and 5-level paging are accepted, and they can't exceed the host
+/// one. 5-level paging is hidden when reporting 48 bits, as the guest could
+/// enable it otherwise.
+fn set_linear_address_bits(cpuid: &mut [CpuIdEntry], linear_bits: u8) -> super::Result<()> {
+    if linear_bits != 48 && linear_bits != 57 {
+        return Err(Error::InvalidLinearAddressBits(linear_bits).into());
+    }
+
+    let Some(entry) = cpuid.iter_mut().find(|c| c.function == 0x8000_0008) else {
+        return Err(Error::LinearAddressBitsUnsupported(linear_bits, 0).into());
+    };
+    let host_linear_bits = ((entry.eax >> 8) & 0xff) as u8;
+    if linear_bits > host_linear_bits {
+        return Err(Error::LinearAddressBitsUnsupported(linear_bits, host_linear_bits).into());
+    }
+    entry.eax = (entry.eax & 0xffff_00ff) | (u32::from(linear_bits) << 8);
+
+    if linear_bits < 57 {
+        for entry in cpuid.iter_mut() {
+            if entry.function == 7 && entry.index == 0 {
+                entry.ecx &= !(1 << LA57_ECX_BIT);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Make leaf 7 subleaf 0 EAX report the highest subleaf populated up to
+/// `LEAF_7_MAX_SUBLEAF`, adding the missing subleaves below it, so that the
+/// guest enumerates every subleaf, and `apply_leaf_7_overrides()` finds an
+/// entry for each of them.
+fn reconcile_leaf_7_subleaves(cpuid: &mut Vec<CpuIdEntry>) {
+    let Some(max_subleaf) = cpuid
+        .iter()
+        .find(|c| c.function == 7 && c.index == 0)
+        .map(|c| c.eax)
+    else {
+        return;
+    };
+    // Subleaves beyond the highest one are reported as all zeros anyway
+    let max_subleaf = cpuid
+        .iter()
+        .filter(|c| c.function == 7 && c.index <= LEAF_7_MAX_SUBLEAF)
+        .filter(|c| c.index == 0 || c.eax | c.ebx | c.ecx | c.edx != 0)
+        .map(|c| c.index)
+        .fold(max_subleaf.min(LEAF_7_MAX_SUBLEAF), u32::max);
+
+    for index in 1..=max_subleaf {
+        if !cpuid.iter().any(|c| c.function == 7 && c.index == index) {
+            cpuid.push(CpuIdEntry {
+                function: 7,
+                index,
+                flags: CPUID_FLAG_VALID_INDEX,
+                ..Default::default()
+            });
+        }
+    }
+    for entry in cpuid.iter_mut() {
+        if entry.function == 7 && entry.index == 0 {
+            entry.eax = entry.eax.max(max_subleaf);
+        }
+    }
+}
+
+/// Reconcile the leaf 7 subleaves, then apply `overrides` in order. The
+/// exposed bits must be supported, i.e. present in `cpuid`, which starts
+/// from the hypervisor supported CPUID, and not managed by another option.
+fn apply_leaf_7_overrides(
+    cpuid: &mut Vec<CpuIdEntry>,
+    overrides: &[Leaf7Override],
+) -> super::Result<()> {
+    reconcile_leaf_7_subleaves(cpuid);
+
+    let supported = cpuid.clone();
+    for o in overrides {
+        let invalid = |reason| Error::InvalidLeaf7Override(*o, reason).into();
+        if !o.is_feature_register() {
+            return Err(invalid("not a feature register"));
+        }
+        if o.expose {
+            if o.bits & o.dedicated_bits() != 0 {
+                return Err(invalid("bits managed by a dedicated option"));
+            }
+            let supported =
+                CpuidPatch::get_cpuid_reg(&supported, 7, Some(o.subleaf), o.register).unwrap_or(0);
+            if o.bits & !supported != 0 {
+                return Err(invalid("bits not supported by the hypervisor"));
+            }
+        }
+
+        // Nothing to hide from a missing subleaf
+        if let Some(value) = CpuidPatch::get_cpuid_reg(cpuid, 7, Some(o.subleaf), o.register) {
+            let value = if o.expose {
+                value | o.bits
+            } else {
+                value & !o.bits
+            };
+            CpuidPatch::set_cpuid_reg(cpuid, 7, Some(o.subleaf), o.register, value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace the vendor string of leaf 0, held in EBX, EDX and ECX in that
+/// order, and return the previous one.
+fn set_cpu_vendor(cpuid: &mut [CpuIdEntry], vendor: &[u8; 12]) -> [u8; 12] {
+    let mut previous = [0u8; 12];
+    let reg = |i: usize| u32::from_le_bytes(vendor[i..i + 4].try_into().unwrap());
+    for entry in cpuid.iter_mut().filter(|c| c.function == 0) {
+        previous[0..4].copy_from_slice(&entry.ebx.to_le_bytes());
+        previous[4..8].copy_from_slice(&entry.edx.to_le_bytes());
+        previous[8..12].copy_from_slice(&entry.ecx.to_le_bytes());
+        entry.ebx = reg(0);
+        entry.edx = reg(4);
+        entry.ecx = reg(8);
+    }
+    previous
+}
+
+/// Returns the core crystal clock frequency in Hz reported by leaf 0x15 ECX,
+/// `None` when the leaf is missing or the frequency is not enumerated.
+pub fn core_crystal_clock_hz(cpuid: &[CpuIdEntry]) -> Option<u32> {
+    cpuid
+        .iter()
+        .find(|c| c.function == 0x15)
+        .map(|c| c.ecx)
+        .filter(|hz| *hz != 0)
+}
+
+/// Fill the core crystal clock frequency of leaf 0x15 ECX with
+/// `core_crystal_hz`. Without an override, a frequency missing while the
+/// TSC/crystal ratio (EBX/EAX) is reported is derived from `tsc_khz`, as
+/// guests calibrating the TSC from this leaf would otherwise fail.
+fn set_core_crystal_clock(
+    cpuid: &mut [CpuIdEntry],
+    core_crystal_hz: Option<u32>,
+    tsc_khz: Option<u32>,
+) {
+    let Some(entry) = cpuid.iter_mut().find(|c| c.function == 0x15) else {
+        return;
+    };
+
+    if let Some(hz) = core_crystal_hz {
+        entry.ecx = hz;
+    } else if entry.ecx == 0
+        && entry.eax != 0
+        && entry.ebx != 0
+        && let Some(tsc_khz) = tsc_khz
+    {
+        let hz = u64::from(tsc_khz) * 1000 * u64::from(entry.eax) / u64::from(entry.ebx);
+        entry.ecx = u32::try_from(hz).unwrap_or(0);
+    }
+}
+
+/// APIC bus frequency for a bus cycle period of `apic_bus_cycle_ns`, KVM's
+/// default one if not set.
+fn apic_bus_khz(apic_bus_cycle_ns: Option<u32>) -> u32 {
+    KVM_APIC_BUS_KHZ / apic_bus_cycle_ns.unwrap_or(1).max(1)
+}
+
+/// Publish the TSC and APIC bus frequencies, in kHz, through the KVM timing
+/// information leaf 0x4000_0010, which saves the guest from calibrating them.
+fn set_kvm_timing_leaf(cpuid: &mut Vec<CpuIdEntry>, tsc_khz: u32, apic_bus_khz: u32) {
+    cpuid.retain(|c| c.function != 0x4000_0010);
+    cpuid.push(CpuIdEntry {
+        function: 0x4000_0010,
+        eax: tsc_khz,
+        ebx: apic_bus_khz,
+        ..Default::default()
+    });
+    set_hypervisor_leaf_range(cpuid);
+}
+
+/// Returns the frequency the TSC runs at once `requested` kHz has been set,
+/// which must match: the guest timekeeping would drift if the timing leaf
+/// reported another frequency than the actual one.
+fn check_tsc_frequency(requested: u32, actual: Option<u32>) -> Result<u32, Error> {
+    match actual {
+        Some(khz) if khz == requested => Ok(khz),
+        _ => Err(Error::TscFrequencyMismatch(requested, actual)),
+    }
+}
+
+/// Replace leaf 6 with one only reporting ARAT, if enabled. The guest can't
+/// make use of the host thermal sensors and power management controls.
+fn set_thermal_power_leaf(cpuid: &mut Vec<CpuIdEntry>, arat: bool) {
+    cpuid.retain(|c| c.function != 6);
+    cpuid.push(CpuIdEntry {
+        function: 6,
+        eax: if arat { 1 << ARAT_EAX_BIT } else { 0 },
+        ..Default::default()
+    });
+}
+
+/// Hide Intel Processor Trace, removing its leaf 0x14 and XSAVE component.
+fn hide_processor_trace(cpuid: &mut Vec<CpuIdEntry>) {
+    cpuid.retain(|c| c.function != 0x14);
+    for entry in cpuid.iter_mut() {
+        if entry.function == 7 && entry.index == 0 {
+            entry.ebx &= !(1 << INTEL_PT_EBX_BIT);
+        } else if entry.function == 0xd && entry.index == 1 {
+            entry.ecx &= !(1 << XSS_PT_BIT);
+        }
+    }
+}
+
+/// Mark architectural PMU events as not available in leaf 0xA EBX. KVM
+/// refuses to program the events masked there, so the guest can't count
+/// them even when ignoring CPUID. Nothing is done without a PMU.
+fn disable_pmu_events(cpuid: &mut [CpuIdEntry], events: &[PmuEvent]) {
+    for entry in cpuid.iter_mut() {
+        // EAX[7:0] is the architectural PMU version
+        if entry.function == 0xa && entry.eax & 0xff != 0 {
+            for event in events {
+                entry.ebx |= 1 << event.ebx_bit();
+            }
+        }
     }
+}
 
-    if config.kvm_hyperv {
-        // Remove conflicting entries
-        cpuid.retain(|c| c.function != 0x4000_0000);
-        cpuid.retain(|c| c.function != 0x4000_0001);
-        // See "Hypervisor Top Level Functional Specification" for details
-        // Compliance with "Hv#1" requires leaves up to 0x4000_000a
-        cpuid.push(CpuIdEntry {
-            function: 0x40000000,
-            eax: 0x4000000a, // Maximum cpuid leaf
-            ebx: 0x756e694c, // "Linu"
-            ecx: 0x564b2078, // "x KV"
-            edx: 0x7648204d, // "M Hv"
-            ..Default::default()
-        });
-        cpuid.push(CpuIdEntry {
-            function: 0x40000001,
-            eax: 0x31237648, // "Hv#1"
-            ..Default::default()
-        });
-        cpuid.push(CpuIdEntry {
-            function: 0x40000002,
-            eax: 0x3839,  // "Build number"
-            ebx: 0xa0000, // "Version"
-            ..Default::default()
-        });
-        cpuid.push(CpuIdEntry {
-            function: 0x4000_0003,
-            eax: (1 << 1) // AccessPartitionReferenceCounter
-                   | (1 << 2) // AccessSynicRegs
-                   | (1 << 3) // AccessSyntheticTimerRegs
-                   | (1 << 9), // AccessPartitionReferenceTsc
-            edx: 1 << 3, // CPU dynamic partitioning
-            ..Default::default()
-        });
-        cpuid.push(CpuIdEntry {
-            function: 0x4000_0004,
-            eax: 1 << 5, // Recommend relaxed timing
-            ..Default::default()
-        });
-        for i in 0x4000_0005..=0x4000_000a {
+/// Set or clear the invariant TSC bit, adding leaf 0x8000_0007 if needed.
+fn set_invariant_tsc(cpuid: &mut Vec<CpuIdEntry>, invariant_tsc: bool) {
+    match cpuid.iter_mut().find(|c| c.function == 0x8000_0007) {
+        Some(entry) => {
+            if invariant_tsc {
+                entry.edx |= 1 << INVARIANT_TSC_EDX_BIT;
+            } else {
+                entry.edx &= !(1 << INVARIANT_TSC_EDX_BIT);
+            }
+        }
+        None if invariant_tsc => {
             cpuid.push(CpuIdEntry {
-                function: i,
+                function: 0x8000_0007,
+                edx: 1 << INVARIANT_TSC_EDX_BIT,
                 ..Default::default()
             });
+            // The leaf is only visible within the extended range
+            for entry in cpuid.iter_mut() {
+                if entry.function == 0x8000_0000 && entry.eax < 0x8000_0007 {
+                    entry.eax = 0x8000_0007;
+                }
+            }
         }
+        None => {}
     }
-
-    Ok(cpuid)
 }
 
 pub fn configure_vcpu(
@@ -781,6 +1876,12 @@ pub fn configure_vcpu(
     kvm_hyperv: bool,
     cpu_vendor: CpuVendor,
     topology: (u16, u16, u16, u16),
+    x2apic: Option<bool>,
+    boot_mode: BootMode,
+    core_crystal_hz: Option<u32>,
+    arch_capabilities: Option<u64>,
+    tsc_khz: Option<u32>,
+    apic_bus_cycle_ns: Option<u32>,
 ) -> super::Result<()> {
     let x2apic_id = get_x2apic_id(id, Some(topology));
 
@@ -808,25 +1909,47 @@ pub fn configure_vcpu(
         &mut cpuid, topology.0, topology.1, topology.2, topology.3, cpu_vendor, id,
     );
 
+    if let Some(khz) = tsc_khz {
+        vcpu.set_tsc_khz(khz).map_err(Error::SetTscFrequency)?;
+    }
+    let actual_tsc_khz = vcpu.tsc_khz().map_err(Error::GetTscFrequency)?;
+    let tsc_khz = match tsc_khz {
+        Some(khz) => Some(check_tsc_frequency(khz, actual_tsc_khz)?),
+        None => actual_tsc_khz,
+    };
+    set_core_crystal_clock(&mut cpuid, core_crystal_hz, tsc_khz);
+
     // The TSC frequency CPUID leaf should not be included when running with HyperV emulation
-    if !kvm_hyperv && let Some(tsc_khz) = vcpu.tsc_khz().map_err(Error::GetTscFrequency)? {
+    if !kvm_hyperv && let Some(tsc_khz) = tsc_khz {
         // Need to check that the TSC doesn't vary with dynamic frequency
         // SAFETY: cpuid called with valid leaves
         if unsafe { std::arch::x86_64::__cpuid(0x8000_0007) }.edx & (1u32 << INVARIANT_TSC_EDX_BIT)
             > 0
         {
-            CpuidPatch::set_cpuid_reg(&mut cpuid, 0x4000_0000, None, CpuidReg::EAX, 0x4000_0010);
-            cpuid.retain(|c| c.function != 0x4000_0010);
-            cpuid.push(CpuIdEntry {
-                function: 0x4000_0010,
-                eax: tsc_khz,
-                ebx: 1000000, /* LAPIC resolution of 1ns (freq: 1GHz) is hardcoded in KVM's
-                               * APIC_BUS_CYCLE_NS */
-                ..Default::default()
-            });
-        };
+            set_kvm_timing_leaf(&mut cpuid, tsc_khz, apic_bus_khz(apic_bus_cycle_ns));
+        } else {
+            debug!("Host TSC not invariant, not publishing the TSC frequency");
+        }
+    }
+
+    // The MSR is only readable by the guest when enumerated
+    if arch_capabilities.is_some() {
+        CpuidPatch::patch_cpuid(
+            &mut cpuid,
+            vec![CpuidPatch {
+                function: 7,
+                index: 0,
+                flags_bit: None,
+                eax_bit: None,
+                ebx_bit: None,
+                ecx_bit: None,
+                edx_bit: Some(ARCH_CAPABILITIES_EDX_BIT),
+            }],
+        );
     }
 
+    check_hypervisor_leaf_range(&cpuid)?;
+
     for c in &cpuid {
         debug!("{c}");
     }
@@ -836,11 +1959,42 @@ pub fn configure_vcpu(
 
     if kvm_hyperv {
         vcpu.enable_hyperv_synic().unwrap();
+        // Best effort, the guest then sees no crash action in
+        // HV_X64_MSR_CRASH_CTL and doesn't notify its crashes
+        if let Err(e) = vcpu.enable_hyperv_crash_msrs() {
+            warn!("Hyper-V crash notifications unavailable: {e}");
+        }
     }
 
     regs::setup_msrs(vcpu).map_err(Error::MsrsConfiguration)?;
+    if let Some(value) = arch_capabilities {
+        let written = vcpu
+            .set_msrs(&[MsrEntry {
+                index: MSR_IA32_ARCH_CAPABILITIES,
+                data: value,
+            }])
+            .map_err(|e| Error::SetArchCapabilities(e.into()))?;
+        if written != 1 {
+            return Err(Error::SetArchCapabilities(anyhow!(
+                "Value 0x{value:x} rejected by the hypervisor"
+            ))
+            .into());
+        }
+    }
+    // Identify the vCPU to RDTSCP and RDPID until the guest sets its own
+    // value, with the same id as the one used for the topology.
+    if CpuidPatch::is_feature_enabled(
+        &cpuid,
+        0x8000_0001,
+        0,
+        CpuidReg::EDX,
+        RDTSCP_EDX_BIT as usize,
+    ) || CpuidPatch::is_feature_enabled(&cpuid, 7, 0, CpuidReg::ECX, RDPID_ECX_BIT as usize)
+    {
+        vcpu.set_tsc_aux(id.into()).map_err(Error::SetTscAux)?;
+    }
     if let Some((kernel_entry_point, guest_memory)) = boot_setup {
-        regs::setup_regs(vcpu, kernel_entry_point).map_err(Error::RegsConfiguration)?;
+        regs::setup_regs(vcpu, kernel_entry_point, boot_mode).map_err(Error::RegsConfiguration)?;
         regs::setup_fpu(vcpu).map_err(Error::FpuConfiguration)?;
 
         // CPUs are required (by Intel sdm spec) to boot in x2apic mode if any
@@ -848,8 +2002,11 @@ pub fn configure_vcpu(
         // does not recognize the last vCPU if x2apic is not enabled when
         // there are 256 vCPUs in a flat hierarchy (i.e. max x2apic ID is 255),
         // so we need to enable x2apic in this case as well.
-        let enable_x2_apic_mode = get_max_x2apic_id(topology) > MAX_SUPPORTED_CPUS_LEGACY;
-        regs::setup_sregs(&guest_memory.memory(), vcpu, enable_x2_apic_mode)
+        // An explicit APIC mode takes precedence, it has been validated
+        // against the topology already.
+        let enable_x2_apic_mode =
+            x2apic.unwrap_or_else(|| get_max_x2apic_id(topology) > MAX_SUPPORTED_CPUS_LEGACY);
+        regs::setup_sregs(&guest_memory.memory(), vcpu, enable_x2_apic_mode, boot_mode)
             .map_err(Error::SregsConfiguration)?;
     }
     interrupts::set_lint(vcpu).map_err(|e| Error::LocalIntConfiguration(e.into()))?;
@@ -1337,35 +2494,17 @@ fn update_cpuid_topology(
 
     // The modern way: many-level hierarchy (but we here only support four levels).
     // CPU Topology leaf 0x1f
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(0), CpuidReg::EAX, thread_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0x1f,
-        Some(0),
-        CpuidReg::EBX,
-        u32::from(threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(0), CpuidReg::ECX, 1 << 8);
-
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(1), CpuidReg::EAX, core_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0x1f,
-        Some(1),
-        CpuidReg::EBX,
-        u32::from(cores_per_die * threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(1), CpuidReg::ECX, 2 << 8);
+    let topology = CpuTopologyV2 {
+        threads_per_core,
+        cores_per_die,
+        dies_per_package,
+        packages,
+    };
+    cpuid.retain(|c| c.function != 0x1f);
+    cpuid.extend(topology.leaf_0x1f(x2apic_id));
 
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::EAX, die_width);
-    CpuidPatch::set_cpuid_reg(
-        cpuid,
-        0x1f,
-        Some(2),
-        CpuidReg::EBX,
-        u32::from(dies_per_package * cores_per_die * threads_per_core),
-    );
-    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::ECX, 5 << 8);
+    // The caches shared by the logical processors enumerated above
+    cache::update_leaf_4_sharing(cpuid, &topology);
 
     if matches!(cpu_vendor, CpuVendor::AMD) {
         CpuidPatch::set_cpuid_reg(
@@ -1618,4 +2757,571 @@ mod tests {
 
         assert_eq!(255, get_max_x2apic_id((1, 256, 1, 1)));
     }
+
+    #[test]
+    fn test_get_x2apic_id_non_contiguous() {
+        // 2 threads per core, 3 cores per die and 2 dies: the core ID takes
+        // 2 bits and the die ID starts at bit 3, so IDs 6-7 and 14-15 are
+        // never used.
+        let topology = Some((2, 3, 2, 1));
+        let ids: Vec<u32> = (0..12).map(|i| get_x2apic_id(i, topology)).collect();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13]);
+        assert_eq!(13, get_max_x2apic_id((2, 3, 2, 1)));
+    }
+
+    #[test]
+    fn test_mwait_cpuid() {
+        let leaf_1 = CpuIdEntry {
+            function: 1,
+            ecx: 1 << MWAIT_ECX_BIT,
+            ..Default::default()
+        };
+        let mwait = MwaitConfig {
+            min_monitor_line_size: 64,
+            max_monitor_line_size: 64,
+            sub_cstates: MwaitSubstates([0, 2, 2, 2, 0, 0, 0, 0]),
+        };
+        let leaf_5 = mwait.cpuid_entry();
+        assert_eq!(leaf_5.eax, 64);
+        assert_eq!(leaf_5.ebx, 64);
+        assert_eq!(leaf_5.ecx, 0x3);
+        assert_eq!(leaf_5.edx, 0x2220);
+        assert_eq!(MwaitSubstates([1, 2, 3, 4, 5, 6, 7, 15]).edx(), 0xf765_4321);
+        // Only C1 is advertised by default
+        assert_eq!(MwaitSubstates::default().edx(), 0x10);
+
+        check_mwait_consistency(&[leaf_1, leaf_5]).unwrap();
+        // MWAIT without leaf 5
+        check_mwait_consistency(&[leaf_1]).unwrap_err();
+        // MWAIT with an empty leaf 5
+        check_mwait_consistency(&[
+            leaf_1,
+            CpuIdEntry {
+                function: 5,
+                ..Default::default()
+            },
+        ])
+        .unwrap_err();
+        // Leaf 5 without MWAIT is harmless
+        check_mwait_consistency(&[leaf_5]).unwrap();
+
+        assert_eq!(MwaitConfig::from_cpuid(&[leaf_1, leaf_5]), Some(mwait));
+        assert_eq!(MwaitConfig::from_cpuid(&[leaf_5]), None);
+        assert_eq!(MwaitConfig::from_cpuid(&[leaf_1]), None);
+
+        mwait.validate().unwrap();
+        MwaitConfig {
+            min_monitor_line_size: 128,
+            max_monitor_line_size: 64,
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        // Sub-state counts only have 4 bits
+        MwaitConfig {
+            sub_cstates: MwaitSubstates([0, 16, 0, 0, 0, 0, 0, 0]),
+            ..mwait
+        }
+        .validate()
+        .unwrap_err();
+        MwaitConfig::default().validate().unwrap_err();
+
+        assert_eq!("64:64:0/2/2/2".parse::<MwaitConfig>().unwrap(), mwait);
+        assert_eq!(
+            "32:64".parse::<MwaitConfig>().unwrap(),
+            MwaitConfig {
+                min_monitor_line_size: 32,
+                max_monitor_line_size: 64,
+                sub_cstates: MwaitSubstates::default(),
+            }
+        );
+        "64".parse::<MwaitConfig>().unwrap_err();
+        "128:64".parse::<MwaitConfig>().unwrap_err();
+        "64:64:0/16".parse::<MwaitConfig>().unwrap_err();
+        "64:64:0/1/1/1/1/1/1/1/1"
+            .parse::<MwaitConfig>()
+            .unwrap_err();
+        "64:64:0/1:0".parse::<MwaitConfig>().unwrap_err();
+    }
+
+    #[test]
+    fn test_hyperv_cpuid_compatibility() {
+        let hyperv_leaves = |recommendations: u32| {
+            [
+                CpuIdEntry {
+                    function: 0x4000_0003,
+                    eax: (1 << 5) | (1 << 6),
+                    ..Default::default()
+                },
+                CpuIdEntry {
+                    function: 0x4000_0004,
+                    eax: recommendations,
+                    ..Default::default()
+                },
+            ]
+        };
+        let tlb_flush = hyperv_leaves((1 << 1) | (1 << 2) | (1 << 5));
+        let no_tlb_flush = hyperv_leaves(1 << 5);
+
+        CpuidFeatureEntry::check_cpuid_compatibility(&no_tlb_flush, &tlb_flush).unwrap();
+        CpuidFeatureEntry::check_cpuid_compatibility(&tlb_flush, &tlb_flush).unwrap();
+        // The TLB flush enlightenment can't go away across a migration
+        CpuidFeatureEntry::check_cpuid_compatibility(&tlb_flush, &no_tlb_flush).unwrap_err();
+        // Nor can Hyper-V altogether
+        CpuidFeatureEntry::check_cpuid_compatibility(&tlb_flush, &[]).unwrap_err();
+    }
+
+    #[test]
+    fn test_invariant_tsc_cpuid() {
+        let invariant_tsc = |cpuid: &[CpuIdEntry]| {
+            cpuid
+                .iter()
+                .find(|c| c.function == 0x8000_0007)
+                .map(|c| c.edx & (1 << INVARIANT_TSC_EDX_BIT) != 0)
+        };
+        let mut cpuid = vec![
+            CpuIdEntry {
+                function: 0x8000_0000,
+                eax: 0x8000_0008,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x8000_0007,
+                edx: 1 << INVARIANT_TSC_EDX_BIT,
+                ..Default::default()
+            },
+        ];
+
+        set_invariant_tsc(&mut cpuid, false);
+        assert_eq!(invariant_tsc(&cpuid), Some(false));
+        set_invariant_tsc(&mut cpuid, true);
+        assert_eq!(invariant_tsc(&cpuid), Some(true));
+
+        // The leaf is added when missing, and made reachable
+        let mut cpuid = vec![CpuIdEntry {
+            function: 0x8000_0000,
+            eax: 0x8000_0004,
+            ..Default::default()
+        }];
+        set_invariant_tsc(&mut cpuid, false);
+        assert_eq!(invariant_tsc(&cpuid), None);
+        set_invariant_tsc(&mut cpuid, true);
+        assert_eq!(invariant_tsc(&cpuid), Some(true));
+        assert_eq!(cpuid[0].eax, 0x8000_0007);
+    }
+
+    #[test]
+    fn test_kvm_timing_leaf() {
+        let mut cpuid = vec![
+            CpuIdEntry {
+                function: 0x4000_0000,
+                eax: 0x4000_0001,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x4000_0010,
+                eax: 1,
+                ebx: 1,
+                ..Default::default()
+            },
+        ];
+        set_kvm_timing_leaf(&mut cpuid, 2_400_000, apic_bus_khz(None));
+        assert_eq!(cpuid[0].eax, 0x4000_0010);
+        let leaves: Vec<_> = cpuid.iter().filter(|c| c.function == 0x4000_0010).collect();
+        assert_eq!(leaves.len(), 1);
+        assert_eq!((leaves[0].eax, leaves[0].ebx), (2_400_000, 1_000_000));
+
+        // 10 ns bus cycles, i.e. a 100 MHz APIC bus
+        set_kvm_timing_leaf(&mut cpuid, 2_400_000, apic_bus_khz(Some(10)));
+        let leaves: Vec<_> = cpuid.iter().filter(|c| c.function == 0x4000_0010).collect();
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0].ebx, 100_000);
+        assert_eq!(apic_bus_khz(Some(MAX_APIC_BUS_CYCLE_NS)), 1);
+
+        assert_eq!(
+            check_tsc_frequency(2_400_000, Some(2_400_000)).unwrap(),
+            2_400_000
+        );
+        // Rejected or silently ignored by the hypervisor
+        assert!(matches!(
+            check_tsc_frequency(2_400_000, Some(2_399_999)),
+            Err(Error::TscFrequencyMismatch(2_400_000, Some(2_399_999)))
+        ));
+        assert!(matches!(
+            check_tsc_frequency(2_400_000, None),
+            Err(Error::TscFrequencyMismatch(2_400_000, None))
+        ));
+    }
+
+    #[test]
+    fn test_hypervisor_leaf_range() {
+        let leaf = |function: u32, eax: u32| CpuIdEntry {
+            function,
+            eax,
+            ..Default::default()
+        };
+
+        // KVM features and Hyper-V leaves, plus a leaf past the range
+        let mut cpuid = vec![
+            leaf(0x4000_0000, 0x4000_0001),
+            leaf(0x4000_0001, 0),
+            leaf(0x4000_000a, 0),
+            leaf(0x4000_0100, 0),
+        ];
+        assert!(matches!(
+            check_hypervisor_leaf_range(&cpuid),
+            Err(crate::Error::PlatformSpecific(
+                Error::HypervisorLeafOutOfRange(0x4000_000a, 0x4000_0001)
+            ))
+        ));
+        set_hypervisor_leaf_range(&mut cpuid);
+        assert_eq!(cpuid[0].eax, 0x4000_000a);
+        check_hypervisor_leaf_range(&cpuid).unwrap();
+
+        // Missing leaf 0x4000_0000
+        let cpuid = vec![leaf(0x4000_0001, 0)];
+        assert!(check_hypervisor_leaf_range(&cpuid).is_err());
+        check_hypervisor_leaf_range(&[leaf(0, 0xd)]).unwrap();
+    }
+
+    #[test]
+    fn test_cpu_vendor_override() {
+        // "GenuineIntel"
+        let mut cpuid = vec![CpuIdEntry {
+            function: 0,
+            eax: 0x20,
+            ebx: 0x756e_6547,
+            ecx: 0x6c65_746e,
+            edx: 0x4965_6e69,
+            ..Default::default()
+        }];
+
+        assert_eq!(
+            &set_cpu_vendor(&mut cpuid, b"AuthenticAMD"),
+            b"GenuineIntel"
+        );
+        assert_eq!(cpuid[0].eax, 0x20);
+        assert_eq!(cpuid[0].ebx, 0x6874_7541);
+        assert_eq!(cpuid[0].edx, 0x6974_6e65);
+        assert_eq!(cpuid[0].ecx, 0x444d_4163);
+    }
+
+    #[test]
+    fn test_core_crystal_clock() {
+        let leaf15 = |eax, ebx, ecx| {
+            vec![CpuIdEntry {
+                function: 0x15,
+                eax,
+                ebx,
+                ecx,
+                ..Default::default()
+            }]
+        };
+
+        // The host reports a ratio but no crystal frequency: 2.4GHz TSC with
+        // a 200/2 ratio gives a 24MHz crystal.
+        let mut cpuid = leaf15(2, 200, 0);
+        assert_eq!(core_crystal_clock_hz(&cpuid), None);
+        set_core_crystal_clock(&mut cpuid, None, Some(2_400_000));
+        assert_eq!(core_crystal_clock_hz(&cpuid), Some(24_000_000));
+
+        // Nothing can be derived without a ratio or a TSC frequency
+        let mut cpuid = leaf15(0, 0, 0);
+        set_core_crystal_clock(&mut cpuid, None, Some(2_400_000));
+        assert_eq!(core_crystal_clock_hz(&cpuid), None);
+        let mut cpuid = leaf15(2, 200, 0);
+        set_core_crystal_clock(&mut cpuid, None, None);
+        assert_eq!(core_crystal_clock_hz(&cpuid), None);
+
+        // The host reports a crystal frequency, kept unless overridden
+        let mut cpuid = leaf15(2, 176, 38_400_000);
+        set_core_crystal_clock(&mut cpuid, None, Some(2_400_000));
+        assert_eq!(core_crystal_clock_hz(&cpuid), Some(38_400_000));
+        set_core_crystal_clock(&mut cpuid, Some(25_000_000), Some(2_400_000));
+        assert_eq!(core_crystal_clock_hz(&cpuid), Some(25_000_000));
+        assert_eq!((cpuid[0].eax, cpuid[0].ebx), (2, 176));
+
+        // The leaf is not added when missing
+        let mut cpuid = Vec::new();
+        set_core_crystal_clock(&mut cpuid, Some(25_000_000), None);
+        assert!(cpuid.is_empty());
+    }
+
+    #[test]
+    fn test_linear_address_bits() {
+        let host_cpuid = || {
+            vec![
+                CpuIdEntry {
+                    function: 7,
+                    ecx: 1 << LA57_ECX_BIT,
+                    ..Default::default()
+                },
+                CpuIdEntry {
+                    function: 0x8000_0008,
+                    // 57 linear bits, 52 physical bits
+                    eax: 0x3934,
+                    ..Default::default()
+                },
+            ]
+        };
+
+        let mut cpuid = host_cpuid();
+        set_linear_address_bits(&mut cpuid, 57).unwrap();
+        assert_eq!(cpuid, host_cpuid());
+
+        // Lowering the width hides 5-level paging, physical bits are kept
+        set_linear_address_bits(&mut cpuid, 48).unwrap();
+        assert_eq!(cpuid[0].ecx, 0);
+        assert_eq!(cpuid[1].eax, 0x3034);
+
+        // The width can't go beyond the host one
+        let mut cpuid = host_cpuid();
+        cpuid[1].eax = 0x3034;
+        assert!(matches!(
+            set_linear_address_bits(&mut cpuid, 57).unwrap_err(),
+            crate::Error::PlatformSpecific(Error::LinearAddressBitsUnsupported(57, 48))
+        ));
+        assert_eq!(cpuid[1].eax, 0x3034);
+
+        set_linear_address_bits(&mut host_cpuid(), 52).unwrap_err();
+        set_linear_address_bits(&mut [], 48).unwrap_err();
+    }
+
+    #[test]
+    fn test_leaf_7_overrides() {
+        let entry = |index, eax, ebx, ecx, edx| CpuIdEntry {
+            function: 7,
+            index,
+            flags: CPUID_FLAG_VALID_INDEX,
+            eax,
+            ebx,
+            ecx,
+            edx,
+        };
+        // Subleaf 0 only reports subleaf 1 while subleaf 2 is populated
+        let host_cpuid = || {
+            vec![
+                entry(0, 1, 1 << 16, 1 << 11, 1 << AMX_TILE),
+                // AVX-VNNI and AVX512_BF16
+                entry(1, 0x30, 0, 0, 1 << 4),
+                // PSFD
+                entry(2, 0, 0, 0, 1),
+            ]
+        };
+        let parse = |s: &str| s.parse::<Leaf7Override>().unwrap();
+
+        let mut cpuid = host_cpuid();
+        apply_leaf_7_overrides(
+            &mut cpuid,
+            &[
+                parse("1.eax-0x20"),
+                parse("1.eax+0x10"),
+                parse("2.edx-0x1"),
+                parse("0.ebx-0x10000"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            cpuid,
+            vec![
+                entry(0, 2, 0, 1 << 11, 1 << AMX_TILE),
+                entry(1, 0x10, 0, 0, 1 << 4),
+                entry(2, 0, 0, 0, 0),
+            ]
+        );
+
+        // A hidden feature can be exposed again
+        let mut cpuid = host_cpuid();
+        apply_leaf_7_overrides(&mut cpuid, &[parse("1.eax-0x30"), parse("1.eax+0x20")]).unwrap();
+        assert_eq!(cpuid[1].eax, 0x20);
+
+        // Missing subleaves are added
+        let mut cpuid = vec![entry(0, 2, 0, 0, 0)];
+        apply_leaf_7_overrides(&mut cpuid, &[]).unwrap();
+        assert_eq!(
+            cpuid,
+            vec![
+                entry(0, 2, 0, 0, 0),
+                entry(1, 0, 0, 0, 0),
+                entry(2, 0, 0, 0, 0)
+            ]
+        );
+
+        // Unsupported or dedicated features can't be exposed
+        for o in ["1.eax+0x1", "2.edx+0x2", "0.edx+0x1000000"] {
+            assert!(matches!(
+                apply_leaf_7_overrides(&mut host_cpuid(), &[parse(o)]).unwrap_err(),
+                crate::Error::PlatformSpecific(Error::InvalidLeaf7Override(..))
+            ));
+        }
+        // While they can be hidden
+        let mut cpuid = host_cpuid();
+        apply_leaf_7_overrides(&mut cpuid, &[parse("0.edx-0x1000000")]).unwrap();
+        assert_eq!(cpuid[0].edx, 0);
+
+        for s in [
+            "0.eax+0x1",
+            "3.ebx+0x1",
+            "1.esi+0x1",
+            "1.eax+1",
+            "1.eax=0x1",
+            "eax+0x1",
+        ] {
+            s.parse::<Leaf7Override>().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_hide_processor_trace() {
+        let mut cpuid = vec![
+            CpuIdEntry {
+                function: 7,
+                ebx: (1 << INTEL_PT_EBX_BIT) | 1,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0xd,
+                index: 1,
+                ecx: (1 << XSS_PT_BIT) | (1 << XSS_CET_U_BIT),
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x14,
+                eax: 1,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x14,
+                index: 1,
+                eax: 0x2,
+                ..Default::default()
+            },
+        ];
+
+        hide_processor_trace(&mut cpuid);
+        assert_eq!(cpuid.len(), 2);
+        assert_eq!(cpuid[0].ebx, 1);
+        assert_eq!(cpuid[1].ecx, 1 << XSS_CET_U_BIT);
+    }
+
+    #[test]
+    fn test_disable_pmu_events() {
+        let mut cpuid = vec![CpuIdEntry {
+            function: 0xa,
+            // Version 2, 8 events in the EBX bitmap
+            eax: 0x0800_0002,
+            // Top-down slots not available
+            ebx: 1 << 7,
+            ..Default::default()
+        }];
+
+        disable_pmu_events(
+            &mut cpuid,
+            &[PmuEvent::LlcMisses, PmuEvent::BranchMispredictsRetired],
+        );
+        assert_eq!(cpuid[0].ebx, (1 << 7) | (1 << 6) | (1 << 4));
+        assert_eq!(cpuid[0].eax, 0x0800_0002);
+
+        // No PMU
+        let mut cpuid = vec![CpuIdEntry {
+            function: 0xa,
+            ..Default::default()
+        }];
+        disable_pmu_events(&mut cpuid, &[PmuEvent::CoreCycles]);
+        assert_eq!(cpuid[0].ebx, 0);
+
+        assert_eq!(
+            "llc_misses".parse::<PmuEvent>().unwrap(),
+            PmuEvent::LlcMisses
+        );
+        "cache_misses".parse::<PmuEvent>().unwrap_err();
+    }
+
+    #[test]
+    fn test_thermal_power_leaf() {
+        let mut cpuid = vec![CpuIdEntry {
+            function: 6,
+            // Digital thermal sensor, turbo boost and HWP
+            eax: 0x83,
+            ecx: 0x9,
+            ..Default::default()
+        }];
+
+        set_thermal_power_leaf(&mut cpuid, true);
+        assert_eq!(cpuid.len(), 1);
+        assert_eq!(cpuid[0].eax, 1 << ARAT_EAX_BIT);
+        assert_eq!((cpuid[0].ebx, cpuid[0].ecx, cpuid[0].edx), (0, 0, 0));
+
+        set_thermal_power_leaf(&mut cpuid, false);
+        assert_eq!(cpuid.len(), 1);
+        assert_eq!(cpuid[0].eax, 0);
+    }
+
+    #[test]
+    fn test_numa_topology() {
+        // 2 threads per core, 2 cores per die and 2 sockets, one per node
+        let topology = (2, 2, 1, 2);
+        let nodes = [(0, vec![0, 1, 2, 3]), (1, vec![4, 5, 6, 7])];
+        let cpu_nodes: BTreeMap<u32, u32> = nodes
+            .iter()
+            .flat_map(|(node, cpus)| cpus.iter().map(move |cpu| (*cpu, *node)))
+            .collect();
+        assert_eq!(numa_split_core(topology, &cpu_nodes), None);
+
+        for (_, cpus) in &nodes {
+            let x2apic_ids = numa_node_x2apic_ids(cpus, Some(topology));
+            for (cpu, x2apic_id) in cpus.iter().zip(x2apic_ids) {
+                let mut cpuid = vec![CpuIdEntry {
+                    function: 1,
+                    ..Default::default()
+                }];
+                update_cpuid_topology(&mut cpuid, 2, 2, 1, 2, CpuVendor::Intel, *cpu);
+                let leaf_0xb = cpuid
+                    .iter()
+                    .find(|c| c.function == 0xb && c.index == 1)
+                    .unwrap();
+                assert_eq!(leaf_0xb.edx, x2apic_id);
+                assert!(
+                    cpuid
+                        .iter()
+                        .filter(|c| c.function == 0x1f)
+                        .all(|c| c.edx == x2apic_id)
+                );
+            }
+        }
+        assert_eq!(
+            numa_node_x2apic_ids(&nodes[1].1, Some(topology)),
+            [4, 5, 6, 7]
+        );
+
+        // vCPUs 2 and 3 are threads of the same core
+        let mut cpu_nodes = cpu_nodes;
+        cpu_nodes.insert(3, 1);
+        assert_eq!(numa_split_core(topology, &cpu_nodes), Some((2, 3)));
+    }
+
+    #[test]
+    fn test_cpu_topology_v2_two_dies() {
+        // 2 threads per core, 4 cores per die and 2 dies per socket
+        let topology = CpuTopologyV2::from((2, 4, 2, 1));
+        let x2apic_id = get_x2apic_id(13, Some((2, 4, 2, 1)));
+        // Thread 1 of core 2 of die 1
+        assert_eq!(x2apic_id, (1 << 3) | (2 << 1) | 1);
+
+        let leaf = topology.leaf_0x1f(x2apic_id);
+        let levels: Vec<_> = leaf
+            .iter()
+            .map(|e| (e.index, e.eax, e.ebx, e.ecx, e.edx))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![
+                (0, 1, 2, TOPOLOGY_LEVEL_SMT << 8, x2apic_id),
+                (1, 3, 8, (TOPOLOGY_LEVEL_CORE << 8) | 1, x2apic_id),
+                (2, 4, 16, (TOPOLOGY_LEVEL_DIE << 8) | 2, x2apic_id),
+                (3, 0, 0, 3, x2apic_id),
+            ]
+        );
+        assert!(leaf.iter().all(|e| e.flags == CPUID_FLAG_VALID_INDEX));
+    }
 }
@@ -6,19 +6,60 @@
 // Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
+use std::str::FromStr;
 use std::{mem, result};
 
 use hypervisor::arch::x86::gdt::{gdt_entry, segment_from_gdt};
-use hypervisor::arch::x86::regs::CR0_PE;
+use hypervisor::arch::x86::regs::{CR0_PE, CR0_PG, CR4_PAE, EFER_LMA, EFER_LME};
 use hypervisor::arch::x86::{FpuState, SpecialRegisters};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use vm_memory::{Address, Bytes, GuestMemory, GuestMemoryError};
 
 use crate::layout::{
-    BOOT_GDT_START, BOOT_IDT_START, BOOT_STACK_POINTER, PVH_INFO_START, ZERO_PAGE_START,
+    BOOT_GDT_START, BOOT_IDT_START, BOOT_STACK_POINTER, PDE_START, PDPTE_START, PML4_START,
+    PVH_INFO_START, ZERO_PAGE_START,
 };
 use crate::{EntryPoint, GuestMemoryMmap};
 
+/// Execution mode of the boot vCPU when it starts running.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BootMode {
+    /// 16-bit real mode at 0xffff:0000, as expected by a legacy BIOS. The
+    /// entry point of the payload is ignored.
+    RealMode,
+    /// 32-bit protected mode without paging, as defined by the PVH boot
+    /// protocol.
+    #[default]
+    ProtectedMode,
+    /// 64-bit long mode, with the first GiB of memory identity mapped.
+    LongMode,
+}
+
+#[derive(Debug, Error)]
+pub enum ParseBootModeError {
+    #[error("Invalid boot mode: {0}")]
+    InvalidValue(String),
+}
+
+impl FromStr for BootMode {
+    type Err = ParseBootModeError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "real" => Ok(BootMode::RealMode),
+            "protected" => Ok(BootMode::ProtectedMode),
+            "long" => Ok(BootMode::LongMode),
+            _ => Err(ParseBootModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+// Real mode segment of the reset vector used by `BootMode::RealMode`
+const REAL_MODE_CS_SELECTOR: u16 = 0xffff;
+// CR0.ET is hardwired to 1 on all the CPUs supporting long mode
+const CR0_ET: u64 = 0x10;
+
 #[derive(Debug, Error)]
 pub enum Error {
     /// Failed to get SREGs for this CPU.
@@ -94,8 +135,20 @@ pub fn setup_msrs(vcpu: &dyn hypervisor::Vcpu) -> Result<()> {
 ///
 /// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
 /// * `entry_point` - Description of the boot entry to set up.
-pub fn setup_regs(vcpu: &dyn hypervisor::Vcpu, entry_point: EntryPoint) -> Result<()> {
+/// * `boot_mode` - Execution mode the vCPU starts in.
+pub fn setup_regs(
+    vcpu: &dyn hypervisor::Vcpu,
+    entry_point: EntryPoint,
+    boot_mode: BootMode,
+) -> Result<()> {
     let mut regs = vcpu.create_standard_regs();
+    if boot_mode == BootMode::RealMode {
+        // Execution starts at the beginning of the CS segment
+        regs.set_rflags(0x0000000000000002u64);
+        regs.set_rip(0);
+        return vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters);
+    }
+
     match entry_point.setup_header {
         None => {
             regs.set_rflags(0x0000000000000002u64);
@@ -118,16 +171,95 @@ pub fn setup_regs(vcpu: &dyn hypervisor::Vcpu, entry_point: EntryPoint) -> Resul
 ///
 /// * `mem` - The memory that will be passed to the guest.
 /// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `boot_mode` - Execution mode the vCPU starts in.
 pub fn setup_sregs(
     mem: &GuestMemoryMmap,
     vcpu: &dyn hypervisor::Vcpu,
     enable_x2_apic_mode: bool,
+    boot_mode: BootMode,
 ) -> Result<()> {
     let mut sregs: SpecialRegisters = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
-    configure_segments_and_sregs(mem, &mut sregs, enable_x2_apic_mode)?;
+    match boot_mode {
+        BootMode::RealMode => configure_real_mode_sregs(&mut sregs),
+        BootMode::ProtectedMode => {
+            configure_segments_and_sregs(mem, &mut sregs, enable_x2_apic_mode)?
+        }
+        BootMode::LongMode => {
+            configure_long_mode_sregs(mem, &mut sregs)?;
+            if enable_x2_apic_mode {
+                const X2APIC_ENABLE_BIT: u64 = 1 << 10;
+                sregs.apic_base |= X2APIC_ENABLE_BIT;
+            }
+        }
+    }
     vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
 }
 
+/// Start from the reset state of the special registers, only moving CS to
+/// the 0xffff:0000 real mode reset vector.
+///
+/// The vCPU can't run in x2APIC mode in real mode, the guest firmware is
+/// expected to enable it if needed.
+pub fn configure_real_mode_sregs(sregs: &mut SpecialRegisters) {
+    sregs.cs.selector = REAL_MODE_CS_SELECTOR;
+    sregs.cs.base = (REAL_MODE_CS_SELECTOR as u64) << 4;
+    sregs.cs.limit = 0xffff;
+    sregs.cr0 &= !(CR0_PE | CR0_PG);
+    sregs.cr4 = 0;
+    sregs.efer &= !(EFER_LME | EFER_LMA);
+}
+
+/// Configure a flat 64-bit code segment and identity map the first GiB of
+/// memory with 2 MiB pages.
+pub fn configure_long_mode_sregs(
+    mem: &GuestMemoryMmap,
+    sregs: &mut SpecialRegisters,
+) -> Result<()> {
+    let gdt_table: [u64; BOOT_GDT_MAX] = [
+        gdt_entry(0, 0, 0),            // NULL
+        gdt_entry(0xa09b, 0, 0xfffff), // CODE
+        gdt_entry(0xc093, 0, 0xfffff), // DATA
+        gdt_entry(0x008b, 0, 0x67),    // TSS
+    ];
+
+    let code_seg = segment_from_gdt(gdt_table[1], 1);
+    let data_seg = segment_from_gdt(gdt_table[2], 2);
+    let tss_seg = segment_from_gdt(gdt_table[3], 3);
+
+    write_gdt_table(&gdt_table[..], mem)?;
+    sregs.gdt.base = BOOT_GDT_START.raw_value();
+    sregs.gdt.limit = mem::size_of_val(&gdt_table) as u16 - 1;
+
+    write_idt_value(0, mem)?;
+    sregs.idt.base = BOOT_IDT_START.raw_value();
+    sregs.idt.limit = mem::size_of::<u64>() as u16 - 1;
+
+    sregs.cs = code_seg;
+    sregs.ds = data_seg;
+    sregs.es = data_seg;
+    sregs.fs = data_seg;
+    sregs.gs = data_seg;
+    sregs.ss = data_seg;
+    sregs.tr = tss_seg;
+
+    // PML4 -> PDPTE -> PDE with 512 2 MiB pages
+    mem.write_obj(PDPTE_START.raw_value() | 0x03, PML4_START)
+        .map_err(Error::WritePml4Address)?;
+    mem.write_obj(PDE_START.raw_value() | 0x03, PDPTE_START)
+        .map_err(Error::WritePdpteAddress)?;
+    for i in 0..512u64 {
+        mem.write_obj((i << 21) | 0x83, PDE_START.unchecked_add(i * 8))
+            .map_err(Error::WritePdeAddress)?;
+    }
+
+    sregs.cr3 = PML4_START.raw_value();
+    sregs.cr4 = CR4_PAE;
+    sregs.cr0 = CR0_PE | CR0_PG | CR0_ET;
+    sregs.efer |= EFER_LME | EFER_LMA;
+
+    Ok(())
+}
+
 const BOOT_GDT_MAX: usize = 4;
 
 fn write_gdt_table(table: &[u64], guest_mem: &GuestMemoryMmap) -> Result<()> {
@@ -243,4 +375,41 @@ mod tests {
         assert_eq!(CR0_PE, sregs.cr0);
         assert_eq!(0, sregs.cr4);
     }
+
+    #[test]
+    fn real_mode_sregs() {
+        let mut sregs: SpecialRegisters = Default::default();
+        configure_real_mode_sregs(&mut sregs);
+
+        assert_eq!(0xffff, sregs.cs.selector);
+        assert_eq!(0xffff0, sregs.cs.base);
+        assert_eq!(0, sregs.cr0 & CR0_PE);
+        assert_eq!(0, sregs.efer);
+    }
+
+    #[test]
+    fn long_mode_sregs() {
+        let mut sregs: SpecialRegisters = Default::default();
+        let gm = create_guest_mem();
+        configure_long_mode_sregs(&gm, &mut sregs).unwrap();
+
+        assert_eq!(
+            0xaf9b000000ffff,
+            read_u64(&gm, BOOT_GDT_START.unchecked_add(8))
+        );
+        assert_eq!(1, sregs.cs.l);
+        assert_eq!(0xb003, read_u64(&gm, PML4_START));
+        assert_eq!(0xc003, read_u64(&gm, PDPTE_START));
+        for i in 0..512 {
+            assert_eq!(
+                (i << 21) + 0x83u64,
+                read_u64(&gm, PDE_START.unchecked_add(i * 8))
+            );
+        }
+
+        assert_eq!(PML4_START.raw_value(), sregs.cr3);
+        assert_eq!(CR4_PAE, sregs.cr4);
+        assert_eq!(CR0_PE | CR0_PG, sregs.cr0 & (CR0_PE | CR0_PG));
+        assert_eq!(EFER_LME | EFER_LMA, sregs.efer);
+    }
 }
@@ -22,6 +22,8 @@ pub const GED_DEVICE_ACPI_SIZE: usize = 0x1;
 pub struct AcpiShutdownDevice {
     exit_evt: EventFd,
     reset_evt: EventFd,
+    // Hypervisor eventfd also notified on shutdown, see Vm::shutdown_eventfd()
+    shutdown_evt: Option<EventFd>,
     vcpus_kill_signalled: Arc<AtomicBool>,
 }
 
@@ -30,11 +32,13 @@ impl AcpiShutdownDevice {
     pub fn new(
         exit_evt: EventFd,
         reset_evt: EventFd,
+        shutdown_evt: Option<EventFd>,
         vcpus_kill_signalled: Arc<AtomicBool>,
     ) -> AcpiShutdownDevice {
         AcpiShutdownDevice {
             exit_evt,
             reset_evt,
+            shutdown_evt,
             vcpus_kill_signalled,
         }
     }
@@ -67,6 +71,11 @@ impl BusDevice for AcpiShutdownDevice {
         const SLEEP_VALUE_BIT: u8 = 2;
         if data[0] == (S5_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
             info!("ACPI Shutdown signalled");
+            if let Some(shutdown_evt) = &self.shutdown_evt
+                && let Err(e) = shutdown_evt.write(1)
+            {
+                warn!("Failed to signal the guest shutdown: {e}");
+            }
             if let Err(e) = self.exit_evt.write(1) {
                 error!("Error triggering ACPI shutdown event: {e}");
             }
@@ -34,6 +34,14 @@ pub enum Error {
     /// Failed enabling the interrupt.
     #[error("Failed enabling the interrupt")]
     EnableInterrupt(#[source] io::Error),
+    #[cfg(target_arch = "x86_64")]
+    /// Invalid number of IOAPIC pins.
+    #[error("Invalid number of IOAPIC pins: {0}")]
+    InvalidIoapicPins(usize),
+    #[cfg(target_arch = "x86_64")]
+    /// IRQ beyond the pins of the interrupt controller.
+    #[error("Invalid IRQ {0}")]
+    InvalidIrq(usize),
     #[cfg(target_arch = "aarch64")]
     /// Failed creating GIC device.
     #[error("Failed creating GIC device")]
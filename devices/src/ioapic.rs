@@ -10,7 +10,7 @@
 // See https://pdos.csail.mit.edu/6.828/2016/readings/ia32/ioapic.pdf for a specification.
 
 use std::result;
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Mutex};
 
 use byteorder::{ByteOrder, LittleEndian};
 use serde::{Deserialize, Serialize};
@@ -29,9 +29,9 @@ type Result<T> = result::Result<T, Error>;
 
 // I/O REDIRECTION TABLE REGISTER
 //
-// There are 24 I/O Redirection Table entry registers. Each register is a
-// dedicated entry for each interrupt input signal. Each register is 64 bits
-// split between two 32 bits registers as follow:
+// There is one I/O Redirection Table entry register per pin, 24 by default.
+// Each register is a dedicated entry for each interrupt input signal. Each
+// register is 64 bits split between two 32 bits registers as follow:
 //
 // 63-56: Destination Field - R/W
 // 55-17: Reserved
@@ -85,7 +85,10 @@ fn set_remote_irr(entry: &mut RedirectionTableEntry, val: u8) {
 }
 
 pub const NUM_IOAPIC_PINS: usize = 24;
-const IOAPIC_VERSION_ID: u32 = 0x0017_0011;
+// The redirection table registers must fit in the 8 bits IOREGSEL
+pub const MAX_IOAPIC_PINS: usize = 120;
+// Maximum Redirection Entry (bits 23-16) is filled from the number of pins
+const IOAPIC_VERSION: u32 = 0x11;
 
 // Constants for IOAPIC direct register offset
 const IOAPIC_REG_ID: u8 = 0x00;
@@ -96,7 +99,6 @@ const IOAPIC_REG_ARBITRATION_ID: u8 = 0x02;
 const IOREGSEL_OFF: u8 = 0x0;
 const IOWIN_OFF: u8 = 0x10;
 const IOWIN_SCALE: u8 = 0x2;
-const REG_MAX_OFFSET: u8 = IOWIN_OFF + (NUM_IOAPIC_PINS as u8 * 2) - 1;
 
 #[repr(u8)]
 enum TriggerMode {
@@ -129,8 +131,8 @@ pub struct Ioapic {
     id: String,
     id_reg: u32,
     reg_sel: u32,
-    reg_entries: [RedirectionTableEntry; NUM_IOAPIC_PINS],
-    used_entries: [bool; NUM_IOAPIC_PINS],
+    reg_entries: Vec<RedirectionTableEntry>,
+    used_entries: Vec<bool>,
     apic_address: GuestAddress,
     interrupt_source_group: Arc<dyn InterruptSourceGroup>,
 }
@@ -139,8 +141,8 @@ pub struct Ioapic {
 pub struct IoapicState {
     id_reg: u32,
     reg_sel: u32,
-    reg_entries: [RedirectionTableEntry; NUM_IOAPIC_PINS],
-    used_entries: [bool; NUM_IOAPIC_PINS],
+    reg_entries: Vec<RedirectionTableEntry>,
+    used_entries: Vec<bool>,
     apic_address: u64,
 }
 
@@ -187,16 +189,31 @@ impl BusDevice for Ioapic {
 }
 
 impl Ioapic {
+    /// Create an IOAPIC with `num_pins` pins, between 1 and
+    /// `MAX_IOAPIC_PINS`, and the 4 bits `ioapic_id` in its ID register.
+    /// When restoring, `state` must come from an IOAPIC with the same number
+    /// of pins.
     pub fn new(
         id: String,
         apic_address: GuestAddress,
+        ioapic_id: u8,
+        num_pins: usize,
         interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
         state: Option<IoapicState>,
     ) -> Result<Ioapic> {
+        if num_pins == 0 || num_pins > MAX_IOAPIC_PINS {
+            return Err(Error::InvalidIoapicPins(num_pins));
+        }
+        if let Some(state) = &state
+            && (state.reg_entries.len() != num_pins || state.used_entries.len() != num_pins)
+        {
+            return Err(Error::InvalidIoapicPins(state.reg_entries.len()));
+        }
+
         let interrupt_source_group = interrupt_manager
             .create_group(MsiIrqGroupConfig {
                 base: 0,
-                count: NUM_IOAPIC_PINS as InterruptIndex,
+                count: num_pins as InterruptIndex,
             })
             .map_err(Error::CreateInterruptSourceGroup)?;
 
@@ -205,16 +222,16 @@ impl Ioapic {
             (
                 state.id_reg,
                 state.reg_sel,
-                state.reg_entries,
-                state.used_entries,
+                state.reg_entries.clone(),
+                state.used_entries.clone(),
                 GuestAddress(state.apic_address),
             )
         } else {
             (
+                u32::from(ioapic_id & 0xf),
                 0,
-                0,
-                [0x10000; NUM_IOAPIC_PINS],
-                [false; NUM_IOAPIC_PINS],
+                vec![0x10000; num_pins],
+                vec![false; num_pins],
                 apic_address,
             )
         };
@@ -263,9 +280,9 @@ impl Ioapic {
                 }
             }
             IOAPIC_REG_ID => self.id_reg = (val >> 24) & 0xf,
-            IOWIN_OFF..=REG_MAX_OFFSET => {
+            reg if (IOWIN_OFF..=self.reg_max_offset()).contains(&reg) => {
                 let (index, is_high_bits) = decode_irq_from_selector(self.reg_sel as u8);
-                if index > NUM_IOAPIC_PINS {
+                if index >= self.reg_entries.len() {
                     warn!("IOAPIC index out of range: {index}");
                     return;
                 }
@@ -298,11 +315,11 @@ impl Ioapic {
         debug!("IOAPIC_R reg 0x{:x}", self.reg_sel);
 
         match self.reg_sel as u8 {
-            IOAPIC_REG_VERSION => IOAPIC_VERSION_ID,
+            IOAPIC_REG_VERSION => ((self.reg_entries.len() as u32 - 1) << 16) | IOAPIC_VERSION,
             IOAPIC_REG_ID | IOAPIC_REG_ARBITRATION_ID => (self.id_reg & 0xf) << 24,
-            IOWIN_OFF..=REG_MAX_OFFSET => {
+            reg if (IOWIN_OFF..=self.reg_max_offset()).contains(&reg) => {
                 let (index, is_high_bits) = decode_irq_from_selector(self.reg_sel as u8);
-                if index > NUM_IOAPIC_PINS {
+                if index >= self.reg_entries.len() {
                     warn!("IOAPIC index out of range: {index}");
                     return 0;
                 }
@@ -322,12 +339,22 @@ impl Ioapic {
         }
     }
 
+    /// Number of pins, hence of redirection table entries.
+    pub fn num_pins(&self) -> usize {
+        self.reg_entries.len()
+    }
+
+    fn reg_max_offset(&self) -> u8 {
+        // Computed so that MAX_IOAPIC_PINS doesn't overflow
+        IOWIN_OFF - 1 + (self.reg_entries.len() as u8 * IOWIN_SCALE)
+    }
+
     fn state(&self) -> IoapicState {
         IoapicState {
             id_reg: self.id_reg,
             reg_sel: self.reg_sel,
-            reg_entries: self.reg_entries,
-            used_entries: self.used_entries,
+            reg_entries: self.reg_entries.clone(),
+            used_entries: self.used_entries.clone(),
             apic_address: self.apic_address.0,
         }
     }
@@ -401,8 +428,7 @@ impl InterruptController for Ioapic {
     // The ioapic must be informed about EOIs in order to deassert interrupts
     // already sent.
     fn end_of_interrupt(&mut self, vec: u8) {
-        for i in 0..NUM_IOAPIC_PINS {
-            let entry = &mut self.reg_entries[i];
+        for entry in self.reg_entries.iter_mut() {
             // Clear Remote IRR bit
             if vector(*entry) == vec && trigger_mode(*entry) == 1 {
                 set_remote_irr(entry, 0);
@@ -413,7 +439,10 @@ impl InterruptController for Ioapic {
     // This should be called anytime an interrupt needs to be injected into the
     // running guest.
     fn service_irq(&mut self, irq: usize) -> Result<()> {
-        let entry = &mut self.reg_entries[irq];
+        let entry = self
+            .reg_entries
+            .get_mut(irq)
+            .ok_or(Error::InvalidIrq(irq))?;
 
         self.interrupt_source_group
             .trigger(irq as InterruptIndex)
@@ -436,6 +465,46 @@ impl InterruptController for Ioapic {
     }
 }
 
+/// IOAPICs sharing the legacy IRQs, each one handling the IRQs from its GSI
+/// base up to its number of pins.
+pub struct IoapicSet {
+    ioapics: Vec<(usize, Arc<Mutex<Ioapic>>)>,
+}
+
+impl IoapicSet {
+    /// Create a set from `(gsi_base, ioapic)` pairs, which must not overlap.
+    pub fn new(ioapics: Vec<(usize, Arc<Mutex<Ioapic>>)>) -> Self {
+        IoapicSet { ioapics }
+    }
+
+    /// Returns the IOAPIC handling `irq`, along with the matching pin.
+    fn route(&self, irq: usize) -> Option<(&Arc<Mutex<Ioapic>>, usize)> {
+        self.ioapics.iter().find_map(|(gsi_base, ioapic)| {
+            let pin = irq.checked_sub(*gsi_base)?;
+            (pin < ioapic.lock().unwrap().num_pins()).then_some((ioapic, pin))
+        })
+    }
+}
+
+impl InterruptController for IoapicSet {
+    // The vector may be used by any of the IOAPICs
+    fn end_of_interrupt(&mut self, vec: u8) {
+        for (_, ioapic) in self.ioapics.iter() {
+            ioapic.lock().unwrap().end_of_interrupt(vec);
+        }
+    }
+
+    fn service_irq(&mut self, irq: usize) -> Result<()> {
+        let (ioapic, pin) = self.route(irq).ok_or(Error::InvalidIrq(irq))?;
+        ioapic.lock().unwrap().service_irq(pin)
+    }
+
+    fn notifier(&self, irq: usize) -> Option<EventFd> {
+        let (ioapic, pin) = self.route(irq)?;
+        ioapic.lock().unwrap().notifier(pin)
+    }
+}
+
 impl Snapshottable for Ioapic {
     fn id(&self) -> String {
         self.id.clone()
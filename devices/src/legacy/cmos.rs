@@ -13,7 +13,9 @@ use std::{mem, thread};
 #[cfg_attr(target_env = "musl", allow(deprecated))]
 use libc::time_t;
 use libc::{CLOCK_REALTIME, clock_gettime, gmtime_r, timespec, tm};
+use serde::{Deserialize, Serialize};
 use vm_device::BusDevice;
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
 use vmm_sys_util::eventfd::EventFd;
 
 const INDEX_MASK: u8 = 0x7f;
@@ -23,21 +25,41 @@ const DATA_LEN: usize = 128;
 
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
+    id: String,
     index: u8,
     data: [u8; DATA_LEN],
+    // Seconds added to the host wall clock to get the RTC time.
+    time_offset: i64,
     reset_evt: EventFd,
     vcpus_kill_signalled: Option<Arc<AtomicBool>>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct CmosState {
+    time_offset: i64,
+}
+
+fn host_time() -> timespec {
+    // SAFETY: It is safe to zero initialize the timespec struct because it
+    // contains only plain data, and clock_gettime only writes to it.
+    unsafe {
+        let mut timespec: timespec = mem::zeroed();
+        clock_gettime(CLOCK_REALTIME, &mut timespec as *mut _);
+        timespec
+    }
+}
+
 impl Cmos {
     /// Constructs a CMOS/RTC device with initial data.
     /// `mem_below_4g` is the size of memory in bytes below the 32-bit gap.
     /// `mem_above_4g` is the size of memory in bytes above the 32-bit gap.
     pub fn new(
+        id: String,
         mem_below_4g: u64,
         mem_above_4g: u64,
         reset_evt: EventFd,
         vcpus_kill_signalled: Option<Arc<AtomicBool>>,
+        state: Option<CmosState>,
     ) -> Cmos {
         let mut data = [0u8; DATA_LEN];
 
@@ -56,12 +78,26 @@ impl Cmos {
         data[0x5d] = (high_mem >> 16) as u8;
 
         Cmos {
+            id,
             index: 0,
             data,
+            time_offset: state.map_or(0, |s| s.time_offset),
             reset_evt,
             vcpus_kill_signalled,
         }
     }
+
+    /// Set the time read by the guest from the RTC, as seconds since the
+    /// Unix epoch. The RTC then keeps running along with the host clock.
+    pub fn set_time(&mut self, unix_secs: u64) {
+        self.time_offset = (unix_secs as i64).saturating_sub(host_time().tv_sec);
+    }
+
+    fn state(&self) -> CmosState {
+        CmosState {
+            time_offset: self.time_offset,
+        }
+    }
 }
 
 impl BusDevice for Cmos {
@@ -116,16 +152,14 @@ impl BusDevice for Cmos {
                 let day;
                 let month;
                 let year;
-                // SAFETY: The clock_gettime and gmtime_r calls are safe as long as the structs they are
-                // given are large enough, and neither of them fail. It is safe to zero initialize
-                // the tm and timespec struct because it contains only plain data.
+                let timespec = host_time();
+                // SAFETY: The gmtime_r call is safe as long as the struct it is given is large
+                // enough, and it doesn't fail. It is safe to zero initialize the tm struct because
+                // it contains only plain data.
                 let update_in_progress = unsafe {
-                    let mut timespec: timespec = mem::zeroed();
-                    clock_gettime(CLOCK_REALTIME, &mut timespec as *mut _);
-
                     // https://github.com/rust-lang/libc/issues/1848
                     #[cfg_attr(target_env = "musl", allow(deprecated))]
-                    let now: time_t = timespec.tv_sec;
+                    let now: time_t = timespec.tv_sec.saturating_add(self.time_offset);
                     let mut tm: tm = mem::zeroed();
                     gmtime_r(&now, &mut tm as *mut _);
 
@@ -170,3 +204,17 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+impl Snapshottable for Cmos {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.state())
+    }
+}
+
+impl Pausable for Cmos {}
+impl Transportable for Cmos {}
+impl Migratable for Cmos {}
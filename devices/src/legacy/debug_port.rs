@@ -3,8 +3,10 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::collections::VecDeque;
 use std::fmt;
-use std::time::Instant;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::time::{Duration, Instant};
 
 use vm_device::BusDevice;
 
@@ -50,13 +52,88 @@ impl fmt::Display for DebugIoPortRange {
     }
 }
 
+// POST codes queued for a subscriber not keeping up, on top of the history.
+const LISTENER_QUEUE_SIZE: usize = 1024;
+
+/// POST code written by the guest to the debug port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PostCode {
+    pub code: u8,
+    /// Time elapsed since the VM was created.
+    pub timestamp: Duration,
+}
+
 pub struct DebugPort {
     timestamp: Instant,
+    history: VecDeque<PostCode>,
+    history_size: usize,
+    listeners: Vec<SyncSender<PostCode>>,
+    dropped: u64,
 }
 
 impl DebugPort {
     pub fn new(timestamp: Instant) -> Self {
-        Self { timestamp }
+        Self::with_history(timestamp, 0)
+    }
+
+    /// Create a debug port remembering the last `history_size` POST codes.
+    pub fn with_history(timestamp: Instant, history_size: usize) -> Self {
+        Self {
+            timestamp,
+            history: VecDeque::with_capacity(history_size),
+            history_size,
+            listeners: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Number of POST codes not delivered to a subscriber because it didn't
+    /// keep up with the guest.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Last POST codes written by the guest, oldest first.
+    pub fn history(&self) -> Vec<PostCode> {
+        self.history.iter().copied().collect()
+    }
+
+    /// Get a stream of the POST codes written by the guest.
+    ///
+    /// The codes from the history are delivered first, so that a late
+    /// subscriber still sees what happened before it attached. The stream
+    /// is dropped from the device when the receiver is. The guest is never
+    /// blocked by a slow subscriber: once `LISTENER_QUEUE_SIZE` codes are
+    /// pending, the new ones are dropped and accounted in `dropped()`.
+    pub fn subscribe(&mut self) -> Receiver<PostCode> {
+        let (sender, receiver) = sync_channel(self.history_size + LISTENER_QUEUE_SIZE);
+        for post_code in self.history.iter() {
+            // Can't fail, the receiver is alive and the queue can hold the
+            // whole history.
+            let _ = sender.try_send(*post_code);
+        }
+        self.listeners.push(sender);
+        receiver
+    }
+
+    fn record(&mut self, post_code: PostCode) {
+        if self.history_size > 0 {
+            if self.history.len() == self.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(post_code);
+        }
+
+        let dropped = &mut self.dropped;
+        self.listeners
+            .retain(|listener| match listener.try_send(post_code) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    *dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
     }
 }
 
@@ -82,6 +159,60 @@ impl BusDevice for DebugPort {
             elapsed.as_micros()
         );
 
+        self.record(PostCode {
+            code,
+            timestamp: elapsed,
+        });
+
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_code_history() {
+        let mut debug_port = DebugPort::with_history(Instant::now(), 2);
+        for code in [0x10, 0x11, 0x12] {
+            debug_port.write(0x80, 0, &[code]);
+        }
+
+        let codes: Vec<u8> = debug_port.history().iter().map(|p| p.code).collect();
+        assert_eq!(codes, vec![0x11, 0x12]);
+
+        // A late subscriber gets the history, then the new codes.
+        let receiver = debug_port.subscribe();
+        debug_port.write(0x80, 0, &[0x13]);
+        let codes: Vec<u8> = receiver.try_iter().map(|p| p.code).collect();
+        assert_eq!(codes, vec![0x11, 0x12, 0x13]);
+
+        drop(receiver);
+        debug_port.write(0x80, 0, &[0x14]);
+        assert!(debug_port.listeners.is_empty());
+    }
+
+    #[test]
+    fn test_post_code_slow_subscriber() {
+        let mut debug_port = DebugPort::new(Instant::now());
+        let receiver = debug_port.subscribe();
+        for _ in 0..LISTENER_QUEUE_SIZE + 2 {
+            debug_port.write(0x80, 0, &[0x30]);
+        }
+
+        assert_eq!(debug_port.dropped(), 2);
+        assert_eq!(receiver.try_iter().count(), LISTENER_QUEUE_SIZE);
+        assert_eq!(debug_port.listeners.len(), 1);
+    }
+
+    #[test]
+    fn test_post_code_no_history() {
+        let mut debug_port = DebugPort::new(Instant::now());
+        let receiver = debug_port.subscribe();
+        debug_port.write(0x80, 0, &[0x20]);
+
+        assert!(debug_port.history().is_empty());
+        assert_eq!(receiver.try_recv().unwrap().code, 0x20);
+    }
+}
@@ -23,7 +23,7 @@ mod uart_pl011;
 
 pub use self::cmos::Cmos;
 #[cfg(target_arch = "x86_64")]
-pub use self::debug_port::DebugPort;
+pub use self::debug_port::{DebugPort, PostCode};
 #[cfg(feature = "fw_cfg")]
 pub use self::fw_cfg::FwCfg;
 #[cfg(target_arch = "x86_64")]
@@ -22,10 +22,12 @@ fuzz_target!(|bytes: &[u8]| -> Corpus {
     above_4g.copy_from_slice(&bytes[8..16]);
 
     let mut cmos = Cmos::new(
+        String::from("cmos"),
         u64::from_le_bytes(below_4g),
         u64::from_le_bytes(above_4g),
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
+        None,
     );
 
     let mut i = 16;
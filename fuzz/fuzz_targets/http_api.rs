@@ -132,6 +132,39 @@ impl RequestHandler for StubApiRequestHandler {
                     max_phys_bits: 46,
                     affinity: None,
                     features: CpuFeatures::default(),
+                    apic_mode: ApicMode::Auto,
+                    #[cfg(target_arch = "x86_64")]
+                    boot_mode: Default::default(),
+                    #[cfg(target_arch = "x86_64")]
+                    invariant_tsc: None,
+                    #[cfg(target_arch = "x86_64")]
+                    cpu_vendor_override: None,
+                    #[cfg(target_arch = "x86_64")]
+                    core_crystal_hz: None,
+                    #[cfg(target_arch = "x86_64")]
+                    disabled_pv_msrs: Vec::new(),
+                    #[cfg(target_arch = "x86_64")]
+                    arat: true,
+                    #[cfg(target_arch = "x86_64")]
+                    async_pf: false,
+                    #[cfg(target_arch = "x86_64")]
+                    arch_capabilities: None,
+                    #[cfg(target_arch = "x86_64")]
+                    disabled_pmu_events: Vec::new(),
+                    #[cfg(target_arch = "x86_64")]
+                    linear_bits: None,
+                    #[cfg(target_arch = "x86_64")]
+                    tsc_khz: None,
+                    #[cfg(target_arch = "x86_64")]
+                    rapl: None,
+                    #[cfg(target_arch = "x86_64")]
+                    apic_bus_cycle_ns: None,
+                    #[cfg(target_arch = "x86_64")]
+                    trap_ud: false,
+                    #[cfg(target_arch = "x86_64")]
+                    leaf_7_overrides: Vec::new(),
+                    #[cfg(target_arch = "x86_64")]
+                    mwait: None,
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
@@ -145,6 +178,7 @@ impl RequestHandler for StubApiRequestHandler {
                     prefault: false,
                     zones: None,
                     thp: true,
+                    thp_collapse: true,
                 },
                 payload: Some(PayloadConfig {
                     kernel: Some(PathBuf::from("/path/to/kernel")),
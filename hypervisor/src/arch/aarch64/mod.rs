@@ -4,3 +4,6 @@
 
 pub mod gic;
 pub mod regs;
+
+/// Number of bytes of memory sharing the same MTE allocation tag.
+pub const MTE_GRANULE_SIZE: u64 = 16;
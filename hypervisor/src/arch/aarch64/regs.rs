@@ -170,7 +170,7 @@ pub enum ExceptionClass {
 // Taken from arch/arm64/include/uapi/asm/ptrace.h.
 const PSR_MODE_EL1h: u64 = 0x0000_0005;
 const PSR_F_BIT: u64 = 0x0000_0040;
-const PSR_I_BIT: u64 = 0x0000_0080;
+pub const PSR_I_BIT: u64 = 0x0000_0080;
 const PSR_A_BIT: u64 = 0x0000_0100;
 const PSR_D_BIT: u64 = 0x0000_0200;
 // Taken from arch/arm64/kvm/inject_fault.c.
@@ -216,6 +216,78 @@ arm64_sys_reg!(MPIDR_EL1, 3, 0, 0, 0, 5);
 arm64_sys_reg!(ID_AA64MMFR0_EL1, 3, 0, 0, 7, 0);
 arm64_sys_reg!(TTBR1_EL1, 3, 0, 2, 0, 1);
 arm64_sys_reg!(TCR_EL1, 3, 0, 2, 0, 2);
+arm64_sys_reg!(SCTLR_EL1, 3, 0, 1, 0, 0);
+
+// SCTLR_EL1 bits
+const SCTLR_EL1_M: u64 = 1 << 0;
+
+// TCR_EL1 fields
+const TCR_EL1_T0SZ_SHIFT: u32 = 0;
+const TCR_EL1_EPD0: u64 = 1 << 7;
+const TCR_EL1_TG0_SHIFT: u32 = 14;
+const TCR_EL1_T1SZ_SHIFT: u32 = 16;
+const TCR_EL1_EPD1: u64 = 1 << 23;
+const TCR_EL1_TG1_SHIFT: u32 = 30;
+const TCR_EL1_TXSZ_MASK: u64 = 0x3f;
+const TCR_EL1_TG_MASK: u64 = 0x3;
+
+/// Stage 1 translation of one of the two EL1&0 VA ranges.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TranslationRange {
+    /// Whether table walks are enabled (TCR_EL1.EPDn clear), a TLB miss
+    /// otherwise faults.
+    pub walks: bool,
+    /// Translation granule in bytes, 4KiB, 16KiB or 64KiB.
+    pub granule_size: u64,
+    /// Size of the range in bits, 64 - TCR_EL1.TnSZ.
+    pub va_bits: u32,
+}
+
+/// EL1&0 stage 1 translation regime, decoded from SCTLR_EL1 and TCR_EL1.
+///
+/// TTBR0_EL1 translates the low VA range and TTBR1_EL1 the high one, which
+/// starts at `!0 << ttbr1.va_bits`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PagingMode {
+    /// Whether the stage 1 MMU is enabled (SCTLR_EL1.M), VAs are IPAs
+    /// otherwise.
+    pub mmu_enabled: bool,
+    pub ttbr0: TranslationRange,
+    pub ttbr1: TranslationRange,
+}
+
+impl PagingMode {
+    pub fn new(sctlr_el1: u64, tcr_el1: u64) -> Self {
+        let field = |shift: u32, mask: u64| (tcr_el1 >> shift) & mask;
+        let va_bits = |shift: u32| 64 - field(shift, TCR_EL1_TXSZ_MASK) as u32;
+
+        // TG0 and TG1 don't share the same encoding
+        let granule0 = match field(TCR_EL1_TG0_SHIFT, TCR_EL1_TG_MASK) {
+            0b01 => 0x10000,
+            0b10 => 0x4000,
+            _ => 0x1000,
+        };
+        let granule1 = match field(TCR_EL1_TG1_SHIFT, TCR_EL1_TG_MASK) {
+            0b01 => 0x4000,
+            0b11 => 0x10000,
+            _ => 0x1000,
+        };
+
+        PagingMode {
+            mmu_enabled: sctlr_el1 & SCTLR_EL1_M != 0,
+            ttbr0: TranslationRange {
+                walks: tcr_el1 & TCR_EL1_EPD0 == 0,
+                granule_size: granule0,
+                va_bits: va_bits(TCR_EL1_T0SZ_SHIFT),
+            },
+            ttbr1: TranslationRange {
+                walks: tcr_el1 & TCR_EL1_EPD1 == 0,
+                granule_size: granule1,
+                va_bits: va_bits(TCR_EL1_T1SZ_SHIFT),
+            },
+        }
+    }
+}
 
 pub const AARCH64_ARCH_TIMER_PHYS_SECURE_IRQ: u32 = 13;
 pub const AARCH64_ARCH_TIMER_PHYS_NONSECURE_IRQ: u32 = 14;
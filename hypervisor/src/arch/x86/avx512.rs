@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Enumeration of the AVX-512 subsets reported by CPUID leaf 0x7.
+//!
+//! Every subset other than the foundation (`AVX512F`) depends on it, the
+//! other bits must be ignored when `AVX512F` is clear. The subsets are
+//! spread over:
+//!
+//! * CPUID.(EAX=7,ECX=0):EBX, ECX and EDX.
+//! * CPUID.(EAX=7,ECX=1):EAX, for `AVX512_BF16`.
+
+use super::CpuIdEntry;
+
+// CPUID.(EAX=7,ECX=0):EBX
+const AVX512F_EBX_BIT: u32 = 16;
+const AVX512DQ_EBX_BIT: u32 = 17;
+const AVX512IFMA_EBX_BIT: u32 = 21;
+const AVX512PF_EBX_BIT: u32 = 26;
+const AVX512ER_EBX_BIT: u32 = 27;
+const AVX512CD_EBX_BIT: u32 = 28;
+const AVX512BW_EBX_BIT: u32 = 30;
+const AVX512VL_EBX_BIT: u32 = 31;
+// CPUID.(EAX=7,ECX=0):ECX
+const AVX512VBMI_ECX_BIT: u32 = 1;
+const AVX512VBMI2_ECX_BIT: u32 = 6;
+const AVX512VNNI_ECX_BIT: u32 = 11;
+const AVX512BITALG_ECX_BIT: u32 = 12;
+const AVX512VPOPCNTDQ_ECX_BIT: u32 = 14;
+// CPUID.(EAX=7,ECX=0):EDX
+const AVX512_4VNNIW_EDX_BIT: u32 = 2;
+const AVX512_4FMAPS_EDX_BIT: u32 = 3;
+const AVX512_VP2INTERSECT_EDX_BIT: u32 = 8;
+const AVX512_FP16_EDX_BIT: u32 = 23;
+// CPUID.(EAX=7,ECX=1):EAX
+const AVX512_BF16_EAX_BIT: u32 = 5;
+
+/// AVX-512 subsets, all `false` when `f` is `false`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Avx512Subsets {
+    pub f: bool,
+    pub dq: bool,
+    pub ifma: bool,
+    pub pf: bool,
+    pub er: bool,
+    pub cd: bool,
+    pub bw: bool,
+    pub vl: bool,
+    pub vbmi: bool,
+    pub vbmi2: bool,
+    pub vnni: bool,
+    pub bitalg: bool,
+    pub vpopcntdq: bool,
+    pub vnniw_4: bool,
+    pub fmaps_4: bool,
+    pub vp2intersect: bool,
+    pub fp16: bool,
+    pub bf16: bool,
+}
+
+impl Avx512Subsets {
+    /// Decode the subsets enumerated by the leaf 0x7 entries of `cpuid`.
+    pub fn from_cpuid(cpuid: &[CpuIdEntry]) -> Self {
+        let leaf = |index: u32| {
+            cpuid
+                .iter()
+                .find(|e| e.function == 7 && e.index == index)
+                .copied()
+                .unwrap_or_default()
+        };
+        let subleaf0 = leaf(0);
+        let subleaf1 = leaf(1);
+        let bit = |reg: u32, bit: u32| reg & (1 << bit) != 0;
+
+        if !bit(subleaf0.ebx, AVX512F_EBX_BIT) {
+            return Self::default();
+        }
+
+        Avx512Subsets {
+            f: true,
+            dq: bit(subleaf0.ebx, AVX512DQ_EBX_BIT),
+            ifma: bit(subleaf0.ebx, AVX512IFMA_EBX_BIT),
+            pf: bit(subleaf0.ebx, AVX512PF_EBX_BIT),
+            er: bit(subleaf0.ebx, AVX512ER_EBX_BIT),
+            cd: bit(subleaf0.ebx, AVX512CD_EBX_BIT),
+            bw: bit(subleaf0.ebx, AVX512BW_EBX_BIT),
+            vl: bit(subleaf0.ebx, AVX512VL_EBX_BIT),
+            vbmi: bit(subleaf0.ecx, AVX512VBMI_ECX_BIT),
+            vbmi2: bit(subleaf0.ecx, AVX512VBMI2_ECX_BIT),
+            vnni: bit(subleaf0.ecx, AVX512VNNI_ECX_BIT),
+            bitalg: bit(subleaf0.ecx, AVX512BITALG_ECX_BIT),
+            vpopcntdq: bit(subleaf0.ecx, AVX512VPOPCNTDQ_ECX_BIT),
+            vnniw_4: bit(subleaf0.edx, AVX512_4VNNIW_EDX_BIT),
+            fmaps_4: bit(subleaf0.edx, AVX512_4FMAPS_EDX_BIT),
+            vp2intersect: bit(subleaf0.edx, AVX512_VP2INTERSECT_EDX_BIT),
+            fp16: bit(subleaf0.edx, AVX512_FP16_EDX_BIT),
+            // Subleaf 1 only exists when subleaf 0 reports it in EAX
+            bf16: subleaf0.eax >= 1 && bit(subleaf1.eax, AVX512_BF16_EAX_BIT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf7(subleaf0: (u32, u32, u32, u32), subleaf1_eax: u32) -> Vec<CpuIdEntry> {
+        vec![
+            CpuIdEntry {
+                function: 7,
+                index: 0,
+                eax: subleaf0.0,
+                ebx: subleaf0.1,
+                ecx: subleaf0.2,
+                edx: subleaf0.3,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 7,
+                index: 1,
+                eax: subleaf1_eax,
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_avx512_subsets() {
+        // Sapphire Rapids-like: F, DQ, CD, BW, VL, IFMA, VBMI, VBMI2, VNNI,
+        // BITALG, VPOPCNTDQ, FP16 and BF16.
+        let ebx = (1 << AVX512F_EBX_BIT)
+            | (1 << AVX512DQ_EBX_BIT)
+            | (1 << AVX512IFMA_EBX_BIT)
+            | (1 << AVX512CD_EBX_BIT)
+            | (1 << AVX512BW_EBX_BIT)
+            | (1 << AVX512VL_EBX_BIT);
+        let ecx = (1 << AVX512VBMI_ECX_BIT)
+            | (1 << AVX512VBMI2_ECX_BIT)
+            | (1 << AVX512VNNI_ECX_BIT)
+            | (1 << AVX512BITALG_ECX_BIT)
+            | (1 << AVX512VPOPCNTDQ_ECX_BIT);
+        let edx = 1 << AVX512_FP16_EDX_BIT;
+        let cpuid = leaf7((1, ebx, ecx, edx), 1 << AVX512_BF16_EAX_BIT);
+
+        assert_eq!(
+            Avx512Subsets::from_cpuid(&cpuid),
+            Avx512Subsets {
+                f: true,
+                dq: true,
+                ifma: true,
+                cd: true,
+                bw: true,
+                vl: true,
+                vbmi: true,
+                vbmi2: true,
+                vnni: true,
+                bitalg: true,
+                vpopcntdq: true,
+                fp16: true,
+                bf16: true,
+                ..Default::default()
+            }
+        );
+
+        // Knights Landing-like: F, CD, PF, ER, 4VNNIW and 4FMAPS, and no
+        // subleaf 1 even though its bits are set.
+        let ebx = (1 << AVX512F_EBX_BIT)
+            | (1 << AVX512PF_EBX_BIT)
+            | (1 << AVX512ER_EBX_BIT)
+            | (1 << AVX512CD_EBX_BIT);
+        let edx = (1 << AVX512_4VNNIW_EDX_BIT) | (1 << AVX512_4FMAPS_EDX_BIT);
+        let cpuid = leaf7((0, ebx, 0, edx), 1 << AVX512_BF16_EAX_BIT);
+
+        assert_eq!(
+            Avx512Subsets::from_cpuid(&cpuid),
+            Avx512Subsets {
+                f: true,
+                pf: true,
+                er: true,
+                cd: true,
+                vnniw_4: true,
+                fmaps_4: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_avx512_without_foundation() {
+        let cpuid = leaf7(
+            (1, 1 << AVX512DQ_EBX_BIT, 1 << AVX512VNNI_ECX_BIT, 0),
+            1 << AVX512_BF16_EAX_BIT,
+        );
+        assert_eq!(Avx512Subsets::from_cpuid(&cpuid), Avx512Subsets::default());
+        assert_eq!(Avx512Subsets::from_cpuid(&[]), Avx512Subsets::default());
+    }
+}
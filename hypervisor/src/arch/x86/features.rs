@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Availability of CPU features once microcode mitigations are accounted for.
+//!
+//! Some features are still enumerated by CPUID while a microcode update made
+//! them unusable, or only usable after the host kernel flipped a control MSR.
+//! The following MSRs are consulted:
+//!
+//! * `IA32_ARCH_CAPABILITIES` (0x10a): bit 7 (`TSX_CTRL`) tells whether
+//!   `IA32_TSX_CTRL` is implemented.
+//! * `IA32_TSX_CTRL` (0x122): bit 0 (`RTM_DISABLE`) forces all RTM
+//!   transactions to abort and makes HLE prefixes ignored, bit 1
+//!   (`TSX_CPUID_CLEAR`) hides RTM and HLE from CPUID.
+//! * `IA32_TSX_FORCE_ABORT` (0x10f): bit 0 (`RTM_FORCE_ABORT`) forces RTM
+//!   transactions to abort, only implemented when CPUID.(EAX=7,ECX=0):EDX[13]
+//!   is set.
+//!
+//! CPUID.(EAX=7,ECX=0):EDX[11] (`RTM_ALWAYS_ABORT`) is also taken into
+//! account, as it reports that RTM transactions always abort even though
+//! CPUID.(EAX=7,ECX=0):EBX[11] may still be set.
+//!
+//! No MSR affects the CET and Processor Trace features, which only depend on
+//! CPUID.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use super::CpuIdEntry;
+
+/// `IA32_ARCH_CAPABILITIES` MSR index.
+pub const MSR_IA32_ARCH_CAPABILITIES: u32 = 0x10a;
+/// `IA32_TSX_FORCE_ABORT` MSR index.
+pub const MSR_IA32_TSX_FORCE_ABORT: u32 = 0x10f;
+/// `IA32_TSX_CTRL` MSR index.
+pub const MSR_IA32_TSX_CTRL: u32 = 0x122;
+
+const ARCH_CAP_TSX_CTRL_MSR: u64 = 1 << 7;
+const TSX_CTRL_RTM_DISABLE: u64 = 1 << 0;
+const TSX_CTRL_CPUID_CLEAR: u64 = 1 << 1;
+const TSX_FORCE_ABORT_RTM_FORCE_ABORT: u64 = 1 << 0;
+
+const LEAF7_EBX_HLE_BIT: u32 = 4;
+const LEAF7_EBX_RTM_BIT: u32 = 11;
+const LEAF7_EBX_INTEL_PT_BIT: u32 = 25;
+const LEAF7_ECX_CET_SS_BIT: u32 = 7;
+const LEAF7_EDX_CET_IBT_BIT: u32 = 20;
+const LEAF7_EDX_RTM_ALWAYS_ABORT_BIT: u32 = 11;
+const LEAF7_EDX_TSX_FORCE_ABORT_BIT: u32 = 13;
+
+/// CPU features whose availability may be affected by microcode mitigations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuFeature {
+    /// Hardware Lock Elision, CPUID.(EAX=7,ECX=0):EBX[4].
+    Hle,
+    /// Restricted Transactional Memory, CPUID.(EAX=7,ECX=0):EBX[11].
+    Rtm,
+    /// CET shadow stacks, CPUID.(EAX=7,ECX=0):ECX[7].
+    ShadowStack,
+    /// CET indirect branch tracking, CPUID.(EAX=7,ECX=0):EDX[20].
+    Ibt,
+    /// Intel Processor Trace, CPUID.(EAX=7,ECX=0):EBX[25].
+    ProcessorTrace,
+}
+
+/// Host values of the MSRs listed in the module documentation.
+///
+/// `None` means the MSR could not be read, either because it is not
+/// implemented or because the caller lacks access to it. In that case only
+/// CPUID is trusted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MitigationMsrs {
+    pub arch_capabilities: Option<u64>,
+    pub tsx_ctrl: Option<u64>,
+    pub tsx_force_abort: Option<u64>,
+}
+
+impl MitigationMsrs {
+    /// Reads the MSRs of the first host CPU through `/dev/cpu/0/msr`.
+    ///
+    /// This requires the `msr` kernel module and `CAP_SYS_RAWIO`, every MSR
+    /// is reported as `None` otherwise.
+    pub fn from_host() -> Self {
+        let Ok(file) = File::open("/dev/cpu/0/msr") else {
+            return Self::default();
+        };
+
+        let read = |index: u32| {
+            let mut buf = [0u8; 8];
+            file.read_exact_at(&mut buf, index as u64)
+                .ok()
+                .map(|_| u64::from_le_bytes(buf))
+        };
+
+        MitigationMsrs {
+            arch_capabilities: read(MSR_IA32_ARCH_CAPABILITIES),
+            tsx_ctrl: read(MSR_IA32_TSX_CTRL),
+            tsx_force_abort: read(MSR_IA32_TSX_FORCE_ABORT),
+        }
+    }
+}
+
+fn leaf7_bit(cpuid: &[CpuIdEntry], reg: fn(&CpuIdEntry) -> u32, bit: u32) -> bool {
+    cpuid
+        .iter()
+        .any(|e| e.function == 7 && e.index == 0 && reg(e) & (1 << bit) != 0)
+}
+
+fn tsx_disabled(msrs: &MitigationMsrs) -> bool {
+    // IA32_TSX_CTRL is only meaningful when IA32_ARCH_CAPABILITIES says it
+    // exists. Without IA32_ARCH_CAPABILITIES, rely on the read succeeding.
+    let tsx_ctrl_implemented = msrs
+        .arch_capabilities
+        .map(|v| v & ARCH_CAP_TSX_CTRL_MSR != 0)
+        .unwrap_or(true);
+
+    tsx_ctrl_implemented
+        && msrs
+            .tsx_ctrl
+            .map(|v| v & (TSX_CTRL_RTM_DISABLE | TSX_CTRL_CPUID_CLEAR) != 0)
+            .unwrap_or(false)
+}
+
+/// Returns whether `feature` is enumerated by `cpuid` and not disabled by
+/// any of the mitigation MSRs.
+pub fn feature_effectively_available(
+    feature: CpuFeature,
+    cpuid: &[CpuIdEntry],
+    msrs: &MitigationMsrs,
+) -> bool {
+    match feature {
+        CpuFeature::Hle => leaf7_bit(cpuid, |e| e.ebx, LEAF7_EBX_HLE_BIT) && !tsx_disabled(msrs),
+        CpuFeature::Rtm => {
+            let force_abort = leaf7_bit(cpuid, |e| e.edx, LEAF7_EDX_TSX_FORCE_ABORT_BIT)
+                && msrs
+                    .tsx_force_abort
+                    .map(|v| v & TSX_FORCE_ABORT_RTM_FORCE_ABORT != 0)
+                    .unwrap_or(false);
+
+            leaf7_bit(cpuid, |e| e.ebx, LEAF7_EBX_RTM_BIT)
+                && !leaf7_bit(cpuid, |e| e.edx, LEAF7_EDX_RTM_ALWAYS_ABORT_BIT)
+                && !force_abort
+                && !tsx_disabled(msrs)
+        }
+        CpuFeature::ShadowStack => leaf7_bit(cpuid, |e| e.ecx, LEAF7_ECX_CET_SS_BIT),
+        CpuFeature::Ibt => leaf7_bit(cpuid, |e| e.edx, LEAF7_EDX_CET_IBT_BIT),
+        CpuFeature::ProcessorTrace => leaf7_bit(cpuid, |e| e.ebx, LEAF7_EBX_INTEL_PT_BIT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf7(ebx: u32, edx: u32) -> Vec<CpuIdEntry> {
+        vec![CpuIdEntry {
+            function: 7,
+            ebx,
+            edx,
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn test_cpuid_only() {
+        let cpuid = leaf7((1 << LEAF7_EBX_RTM_BIT) | (1 << LEAF7_EBX_HLE_BIT), 0);
+        let msrs = MitigationMsrs::default();
+        assert!(feature_effectively_available(
+            CpuFeature::Rtm,
+            &cpuid,
+            &msrs
+        ));
+        assert!(feature_effectively_available(
+            CpuFeature::Hle,
+            &cpuid,
+            &msrs
+        ));
+
+        assert!(!feature_effectively_available(
+            CpuFeature::Rtm,
+            &leaf7(0, 0),
+            &msrs
+        ));
+
+        assert!(feature_effectively_available(
+            CpuFeature::ProcessorTrace,
+            &leaf7(1 << LEAF7_EBX_INTEL_PT_BIT, 0),
+            &msrs
+        ));
+    }
+
+    #[test]
+    fn test_tsx_ctrl() {
+        let cpuid = leaf7((1 << LEAF7_EBX_RTM_BIT) | (1 << LEAF7_EBX_HLE_BIT), 0);
+        let mut msrs = MitigationMsrs {
+            arch_capabilities: Some(ARCH_CAP_TSX_CTRL_MSR),
+            tsx_ctrl: Some(TSX_CTRL_RTM_DISABLE),
+            tsx_force_abort: None,
+        };
+        assert!(!feature_effectively_available(
+            CpuFeature::Rtm,
+            &cpuid,
+            &msrs
+        ));
+        assert!(!feature_effectively_available(
+            CpuFeature::Hle,
+            &cpuid,
+            &msrs
+        ));
+
+        // The value is ignored when IA32_TSX_CTRL is not implemented.
+        msrs.arch_capabilities = Some(0);
+        assert!(feature_effectively_available(
+            CpuFeature::Rtm,
+            &cpuid,
+            &msrs
+        ));
+    }
+
+    #[test]
+    fn test_rtm_always_abort() {
+        let msrs = MitigationMsrs::default();
+        let cpuid = leaf7(1 << LEAF7_EBX_RTM_BIT, 1 << LEAF7_EDX_RTM_ALWAYS_ABORT_BIT);
+        assert!(!feature_effectively_available(
+            CpuFeature::Rtm,
+            &cpuid,
+            &msrs
+        ));
+
+        let cpuid = leaf7(1 << LEAF7_EBX_RTM_BIT, 1 << LEAF7_EDX_TSX_FORCE_ABORT_BIT);
+        let msrs = MitigationMsrs {
+            tsx_force_abort: Some(TSX_FORCE_ABORT_RTM_FORCE_ABORT),
+            ..Default::default()
+        };
+        assert!(!feature_effectively_available(
+            CpuFeature::Rtm,
+            &cpuid,
+            &msrs
+        ));
+    }
+}
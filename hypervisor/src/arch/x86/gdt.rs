@@ -8,7 +8,10 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 // For GDT details see arch/x86/include/asm/segment.h
-use crate::arch::x86::SegmentRegister;
+use core::fmt;
+
+use crate::arch::x86::regs::EFER_LMA;
+use crate::arch::x86::{DescriptorTable, SegmentRegister, SpecialRegisters};
 
 /// Constructor for a conventional segment GDT (or LDT) entry. Derived from the kernel's segment.h.
 pub fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
@@ -113,6 +116,403 @@ pub fn segment_from_gdt(entry: u64, table_index: u8) -> SegmentRegister {
     }
 }
 
+// System descriptor types, see the Intel SDM Vol. 3, "System Descriptor Types".
+const SYSTEM_TYPE_LDT: u8 = 0x2;
+const SYSTEM_TYPE_TSS_AVAILABLE: u8 = 0x9;
+const SYSTEM_TYPE_TSS_BUSY: u8 = 0xb;
+const SYSTEM_TYPE_CALL_GATE: u8 = 0xc;
+const SYSTEM_TYPE_INTERRUPT_GATE: u8 = 0xe;
+const SYSTEM_TYPE_TRAP_GATE: u8 = 0xf;
+// 16-bit TSS and gates, not valid in long mode
+const SYSTEM_TYPE_TSS16_AVAILABLE: u8 = 0x1;
+const SYSTEM_TYPE_TSS16_BUSY: u8 = 0x3;
+const SYSTEM_TYPE_TASK_GATE: u8 = 0x5;
+const SYSTEM_TYPE_INTERRUPT_GATE16: u8 = 0x6;
+const SYSTEM_TYPE_TRAP_GATE16: u8 = 0x7;
+
+/// Location of the vCPU descriptor tables, as found in the special registers.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DescriptorTables {
+    pub gdt: DescriptorTable,
+    pub idt: DescriptorTable,
+    pub ldt: SegmentRegister,
+    pub tr: SegmentRegister,
+    /// Whether the vCPU runs in long mode, in which case system descriptors
+    /// take 16 bytes.
+    pub long_mode: bool,
+}
+
+impl From<&SpecialRegisters> for DescriptorTables {
+    fn from(sregs: &SpecialRegisters) -> Self {
+        DescriptorTables {
+            gdt: sregs.gdt,
+            idt: sregs.idt,
+            ldt: sregs.ldt,
+            tr: sregs.tr,
+            long_mode: sregs.efer & EFER_LMA != 0,
+        }
+    }
+}
+
+/// Segment register of the vCPU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Cs,
+    Ds,
+    Es,
+    Fs,
+    Gs,
+    Ss,
+    Tr,
+    Ldt,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Segment::Cs => "cs",
+            Segment::Ds => "ds",
+            Segment::Es => "es",
+            Segment::Fs => "fs",
+            Segment::Gs => "gs",
+            Segment::Ss => "ss",
+            Segment::Tr => "tr",
+            Segment::Ldt => "ldt",
+        };
+        f.pad(name)
+    }
+}
+
+/// Hidden part of a segment register, i.e. the descriptor cached by the
+/// vCPU when the selector was loaded.
+///
+/// The base and limit are the ones the vCPU enforces: in 64-bit mode, the
+/// CS, DS, ES and SS bases are treated as 0 and none of the code and data
+/// segment limits are checked, whatever the cache holds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SegmentCache {
+    pub segment: Segment,
+    pub selector: u16,
+    pub base: u64,
+    /// Byte granular limit, `None` if it isn't checked.
+    pub limit: Option<u32>,
+    pub type_: u8,
+    /// System (LDT, TSS) rather than code or data segment
+    pub system: bool,
+    pub dpl: u8,
+    pub present: bool,
+    /// Whether a usable selector was loaded, a null selector makes the
+    /// segment unusable.
+    pub usable: bool,
+    /// 64-bit code segment (L flag)
+    pub long: bool,
+    /// 32-bit rather than 16-bit default operand size or stack pointer
+    /// (D/B flag)
+    pub default_big: bool,
+}
+
+impl SegmentCache {
+    fn new(segment: Segment, reg: &SegmentRegister, mode_64bit: bool) -> Self {
+        let (base, limit) = match segment {
+            Segment::Cs | Segment::Ds | Segment::Es | Segment::Ss if mode_64bit => (0, None),
+            Segment::Fs | Segment::Gs if mode_64bit => (reg.base, None),
+            _ => (reg.base, Some(reg.limit)),
+        };
+
+        SegmentCache {
+            segment,
+            selector: reg.selector,
+            base,
+            limit,
+            type_: reg.type_,
+            system: reg.s == 0,
+            dpl: reg.dpl,
+            present: reg.present != 0,
+            usable: reg.unusable == 0,
+            long: reg.l != 0,
+            default_big: reg.db != 0,
+        }
+    }
+
+    /// Human readable description of the segment type.
+    pub fn type_name(&self) -> &'static str {
+        if self.system {
+            return match self.type_ {
+                SYSTEM_TYPE_LDT => "ldt",
+                SYSTEM_TYPE_TSS16_AVAILABLE => "tss16 available",
+                SYSTEM_TYPE_TSS16_BUSY => "tss16 busy",
+                SYSTEM_TYPE_TSS_AVAILABLE => "tss available",
+                SYSTEM_TYPE_TSS_BUSY => "tss busy",
+                _ => "invalid system",
+            };
+        }
+
+        // Bit 3 tells code segments apart, bit 0 is the accessed flag
+        match self.type_ & 0xe {
+            0x0 => "data read-only",
+            0x2 => "data read/write",
+            0x4 => "data read-only expand-down",
+            0x6 => "data read/write expand-down",
+            0x8 => "code execute-only",
+            0xa => "code execute/read",
+            0xc => "code execute-only conforming",
+            _ => "code execute/read conforming",
+        }
+    }
+}
+
+impl fmt::Display for SegmentCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<3} selector = 0x{:04x} ", self.segment, self.selector)?;
+        if !self.usable {
+            return f.write_str("unusable");
+        }
+        write!(f, "base = 0x{:016x} limit = ", self.base)?;
+        match self.limit {
+            Some(limit) => write!(f, "0x{limit:08x}")?,
+            None => f.write_str("unchecked")?,
+        }
+        write!(
+            f,
+            " type = 0x{:x} ({}) dpl = {} present = {} l = {} db = {}",
+            self.type_,
+            self.type_name(),
+            self.dpl,
+            self.present as u8,
+            self.long as u8,
+            self.default_big as u8
+        )
+    }
+}
+
+/// Decode the segment register caches of the vCPU, in the CS, DS, ES, FS,
+/// GS, SS, TR and LDT order.
+///
+/// 64-bit mode is enabled by EFER.LMA along with the L flag of CS, while
+/// a code segment without the L flag runs in compatibility mode, where
+/// segmentation behaves as in protected mode.
+pub fn segment_caches(sregs: &SpecialRegisters) -> Vec<SegmentCache> {
+    let mode_64bit = sregs.efer & EFER_LMA != 0 && sregs.cs.l != 0;
+    [
+        (Segment::Cs, &sregs.cs),
+        (Segment::Ds, &sregs.ds),
+        (Segment::Es, &sregs.es),
+        (Segment::Fs, &sregs.fs),
+        (Segment::Gs, &sregs.gs),
+        (Segment::Ss, &sregs.ss),
+        (Segment::Tr, &sregs.tr),
+        (Segment::Ldt, &sregs.ldt),
+    ]
+    .into_iter()
+    .map(|(segment, reg)| SegmentCache::new(segment, reg, mode_64bit))
+    .collect()
+}
+
+/// Kind of gate found in the IDT (or the GDT for call gates).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GateKind {
+    Call,
+    Interrupt,
+    Trap,
+    Task,
+}
+
+/// A decoded GDT, LDT or IDT entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Descriptor {
+    /// Null descriptor
+    Null,
+    /// Code segment
+    Code(SegmentRegister),
+    /// Data segment
+    Data(SegmentRegister),
+    /// LDT descriptor
+    Ldt {
+        base: u64,
+        limit: u32,
+        present: bool,
+    },
+    /// TSS descriptor
+    Tss {
+        base: u64,
+        limit: u32,
+        busy: bool,
+        dpl: u8,
+        present: bool,
+    },
+    /// Call, interrupt, trap or task gate
+    Gate {
+        kind: GateKind,
+        selector: u16,
+        offset: u64,
+        ist: u8,
+        dpl: u8,
+        present: bool,
+    },
+    /// Reserved or unsupported system descriptor type
+    Invalid(u64),
+}
+
+impl Descriptor {
+    /// Size in bytes of the table entry holding the descriptor.
+    fn system_size(type_: u8, long_mode: bool) -> usize {
+        match type_ {
+            SYSTEM_TYPE_LDT
+            | SYSTEM_TYPE_TSS_AVAILABLE
+            | SYSTEM_TYPE_TSS_BUSY
+            | SYSTEM_TYPE_CALL_GATE
+            | SYSTEM_TYPE_INTERRUPT_GATE
+            | SYSTEM_TYPE_TRAP_GATE
+                if long_mode =>
+            {
+                16
+            }
+            _ => 8,
+        }
+    }
+
+    fn decode(low: u64, high: Option<u64>, index: u16, long_mode: bool) -> Self {
+        if low == 0 {
+            return Descriptor::Null;
+        }
+
+        if get_s(low) == 1 {
+            let segment = segment_from_gdt(low, 0);
+            let segment = SegmentRegister {
+                selector: index * 8,
+                ..segment
+            };
+            // Bit 3 of the type tells code segments apart
+            return if segment.type_ & 0x8 != 0 {
+                Descriptor::Code(segment)
+            } else {
+                Descriptor::Data(segment)
+            };
+        }
+
+        let type_ = get_type(low);
+        let present = get_p(low) == 1;
+        // Upper 32 bits of the base or offset of 16-byte descriptors
+        let upper = high.map_or(0, |high| (high & 0xffff_ffff) << 32);
+        match type_ {
+            SYSTEM_TYPE_LDT => Descriptor::Ldt {
+                base: get_base(low) | upper,
+                limit: get_limit(low),
+                present,
+            },
+            SYSTEM_TYPE_TSS_AVAILABLE | SYSTEM_TYPE_TSS_BUSY => Descriptor::Tss {
+                base: get_base(low) | upper,
+                limit: get_limit(low),
+                busy: type_ == SYSTEM_TYPE_TSS_BUSY,
+                dpl: get_dpl(low),
+                present,
+            },
+            SYSTEM_TYPE_TSS16_AVAILABLE | SYSTEM_TYPE_TSS16_BUSY if !long_mode => Descriptor::Tss {
+                base: get_base(low),
+                limit: get_limit(low),
+                busy: type_ == SYSTEM_TYPE_TSS16_BUSY,
+                dpl: get_dpl(low),
+                present,
+            },
+            SYSTEM_TYPE_CALL_GATE
+            | SYSTEM_TYPE_INTERRUPT_GATE
+            | SYSTEM_TYPE_TRAP_GATE
+            | SYSTEM_TYPE_INTERRUPT_GATE16
+            | SYSTEM_TYPE_TRAP_GATE16
+            | SYSTEM_TYPE_TASK_GATE => {
+                let kind = match type_ {
+                    SYSTEM_TYPE_CALL_GATE => GateKind::Call,
+                    SYSTEM_TYPE_INTERRUPT_GATE | SYSTEM_TYPE_INTERRUPT_GATE16 => {
+                        GateKind::Interrupt
+                    }
+                    SYSTEM_TYPE_TRAP_GATE | SYSTEM_TYPE_TRAP_GATE16 => GateKind::Trap,
+                    _ => GateKind::Task,
+                };
+                if long_mode && (kind == GateKind::Task || type_ < SYSTEM_TYPE_CALL_GATE) {
+                    return Descriptor::Invalid(low);
+                }
+                let offset = if kind == GateKind::Task {
+                    0
+                } else {
+                    (low & 0xffff) | ((low >> 32) & 0xffff_0000) | upper
+                };
+                Descriptor::Gate {
+                    kind,
+                    selector: ((low >> 16) & 0xffff) as u16,
+                    offset,
+                    ist: if long_mode {
+                        ((low >> 32) & 0x7) as u8
+                    } else {
+                        0
+                    },
+                    dpl: get_dpl(low),
+                    present,
+                }
+            }
+            _ => Descriptor::Invalid(low),
+        }
+    }
+}
+
+fn decode_table(
+    table: &[u8],
+    long_mode: bool,
+    entry_size: Option<usize>,
+) -> Vec<(u16, Descriptor)> {
+    let read = |offset: usize| -> Option<u64> {
+        table
+            .get(offset..offset + 8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    };
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some(low) = read(offset) {
+        let size = entry_size.unwrap_or_else(|| {
+            if low != 0 && get_s(low) == 0 {
+                Descriptor::system_size(get_type(low), long_mode)
+            } else {
+                8
+            }
+        });
+        let high = if size == 16 {
+            match read(offset + 8) {
+                Some(high) => Some(high),
+                // Truncated 16-byte descriptor
+                None => break,
+            }
+        } else {
+            None
+        };
+
+        let index = (offset / 8) as u16;
+        entries.push((index, Descriptor::decode(low, high, index, long_mode)));
+        offset += size;
+    }
+
+    entries
+}
+
+/// Decode the raw content of a GDT or LDT.
+///
+/// Returns the decoded descriptors along with their index in the table, in
+/// 8-byte units (i.e. the selector shifted right by 3). In long mode, LDT,
+/// TSS and call gate descriptors span 16 bytes, and therefore two indexes.
+pub fn decode_gdt(table: &[u8], long_mode: bool) -> Vec<(u16, Descriptor)> {
+    decode_table(table, long_mode, None)
+}
+
+/// Decode the raw content of an IDT, returning one descriptor per vector.
+///
+/// IDT entries are 16 bytes wide in long mode and 8 bytes otherwise.
+pub fn decode_idt(table: &[u8], long_mode: bool) -> Vec<Descriptor> {
+    let entry_size = if long_mode { 16 } else { 8 };
+    decode_table(table, long_mode, Some(entry_size))
+        .into_iter()
+        .map(|(_, d)| d)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -138,4 +538,139 @@ mod tests {
         assert_eq!(0xffffffff, seg.limit);
         assert_eq!(0x0, seg.unusable);
     }
+
+    #[test]
+    fn segment_caches_flat_model() {
+        let mut sregs = SpecialRegisters {
+            cs: segment_from_gdt(gdt_entry(0xa09b, 0x1000, 0xfffff), 1),
+            ds: segment_from_gdt(gdt_entry(0xc093, 0x2000, 0xfffff), 2),
+            fs: segment_from_gdt(gdt_entry(0xc093, 0x3000, 0xfffff), 2),
+            tr: segment_from_gdt(gdt_entry(0x008b, 0x4000, 0x67), 3),
+            efer: EFER_LMA,
+            ..Default::default()
+        };
+        sregs.es.unusable = 1;
+
+        let caches = segment_caches(&sregs);
+        assert_eq!(caches.len(), 8);
+        let cs = caches[0];
+        assert_eq!(cs.segment, Segment::Cs);
+        assert_eq!(cs.selector, 0x8);
+        assert_eq!((cs.base, cs.limit), (0, None));
+        assert!(cs.long && !cs.system);
+        assert_eq!(cs.type_name(), "code execute/read");
+        assert_eq!((caches[1].base, caches[1].limit), (0, None));
+        assert!(!caches[2].usable);
+        // FS and GS keep their base in 64-bit mode
+        assert_eq!((caches[3].base, caches[3].limit), (0x3000, None));
+        // System segment limits are still checked
+        let tr = caches[6];
+        assert_eq!((tr.base, tr.limit), (0x4000, Some(0x67)));
+        assert!(tr.system);
+        assert_eq!(tr.type_name(), "tss busy");
+        assert_eq!(
+            tr.to_string(),
+            "tr  selector = 0x0018 base = 0x0000000000004000 limit = 0x00000067 \
+             type = 0xb (tss busy) dpl = 0 present = 1 l = 0 db = 0"
+        );
+
+        // Compatibility mode uses the cached bases and limits
+        sregs.cs = segment_from_gdt(gdt_entry(0xc09b, 0x1000, 0xfffff), 1);
+        let caches = segment_caches(&sregs);
+        assert_eq!(
+            (caches[0].base, caches[0].limit),
+            (0x1000, Some(0xffff_ffff))
+        );
+        assert_eq!(
+            (caches[1].base, caches[1].limit),
+            (0x2000, Some(0xffff_ffff))
+        );
+    }
+
+    #[test]
+    fn decode_gdt_long_mode() {
+        let mut table = Vec::new();
+        // Null, 64-bit code, data
+        for entry in [
+            0,
+            gdt_entry(0xa09b, 0, 0xfffff),
+            gdt_entry(0xc093, 0, 0xfffff),
+        ] {
+            table.extend(entry.to_le_bytes());
+        }
+        // 64-bit available TSS at 0xffff_8880_1234_5000, spanning 16 bytes
+        table.extend(gdt_entry(0x0089, 0x1234_5000, 0x67).to_le_bytes());
+        table.extend(0xffff_8880u64.to_le_bytes());
+        table.extend(gdt_entry(0xc093, 0, 0xfffff).to_le_bytes());
+
+        let entries = decode_gdt(&table, true);
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0], (0, Descriptor::Null));
+        match entries[1] {
+            (1, Descriptor::Code(seg)) => {
+                assert_eq!(seg.selector, 0x8);
+                assert_eq!(seg.l, 1);
+            }
+            e => panic!("Unexpected entry {e:?}"),
+        }
+        assert!(matches!(entries[2], (2, Descriptor::Data(_))));
+        assert_eq!(
+            entries[3],
+            (
+                3,
+                Descriptor::Tss {
+                    base: 0xffff_8880_1234_5000,
+                    limit: 0x67,
+                    busy: false,
+                    dpl: 0,
+                    present: true,
+                }
+            )
+        );
+        // The TSS takes indexes 3 and 4
+        assert!(matches!(entries[4], (5, Descriptor::Data(_))));
+
+        // Outside of long mode, the TSS only takes 8 bytes
+        let entries = decode_gdt(&table, false);
+        assert_eq!(entries.len(), 6);
+        assert!(matches!(
+            entries[3],
+            (
+                3,
+                Descriptor::Tss {
+                    base: 0x1234_5000,
+                    ..
+                }
+            )
+        ));
+    }
+
+    #[test]
+    fn decode_idt_long_mode() {
+        // Interrupt gate to 0xffff_ffff_8100_1234 through selector 0x10, IST 1
+        let low: u64 = 0x1234 | (0x10 << 16) | (1 << 32) | (0x8e << 40) | (0x8100u64 << 48);
+        let high: u64 = 0xffff_ffff;
+        let mut table = Vec::new();
+        table.extend(low.to_le_bytes());
+        table.extend(high.to_le_bytes());
+        table.extend([0; 16]);
+        // Truncated entry is ignored
+        table.extend([0; 8]);
+
+        let entries = decode_idt(&table, true);
+        assert_eq!(
+            entries,
+            vec![
+                Descriptor::Gate {
+                    kind: GateKind::Interrupt,
+                    selector: 0x10,
+                    offset: 0xffff_ffff_8100_1234,
+                    ist: 1,
+                    dpl: 0,
+                    present: true,
+                },
+                Descriptor::Null,
+            ]
+        );
+    }
 }
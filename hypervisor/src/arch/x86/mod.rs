@@ -13,13 +13,17 @@
 
 use core::fmt;
 
+pub mod avx512;
 #[cfg(all(feature = "mshv_emulator", target_arch = "x86_64"))]
 pub mod emulator;
+pub mod features;
 pub mod gdt;
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 #[allow(non_upper_case_globals)]
 pub mod msr_index;
+pub mod pci;
+pub mod rapl;
 
 // MTRR constants
 pub const MTRR_ENABLE: u64 = 0x800; // IA32_MTRR_DEF_TYPE MSR: E (MTRRs enabled) flag, bit 11
@@ -28,6 +32,33 @@ pub const MTRR_MEM_TYPE_WB: u64 = 0x6;
 // IOAPIC pins
 pub const NUM_IOAPIC_PINS: usize = 24;
 
+// Extended control register index of XCR0, as used by XGETBV/XSETBV
+pub const XCR0_INDEX: u32 = 0;
+
+/// Returns the x2APIC ID of the vCPU `index`, given the threads per core,
+/// cores per die, dies per package and packages of the topology. Each level
+/// takes a power of two of the ID space, the IDs are therefore not
+/// contiguous when a level isn't a power of two.
+pub fn x2apic_id(index: u32, topology: Option<(u16, u16, u16, u16)>) -> u32 {
+    if let Some(t) = topology {
+        let thread_mask_width = u16::BITS - (t.0 - 1).leading_zeros();
+        let core_mask_width = u16::BITS - (t.1 - 1).leading_zeros();
+        let die_mask_width = u16::BITS - (t.2 - 1).leading_zeros();
+
+        let thread_id = index % (t.0 as u32);
+        let core_id = index / (t.0 as u32) % (t.1 as u32);
+        let die_id = index / ((t.0 * t.1) as u32) % (t.2 as u32);
+        let socket_id = index / ((t.0 * t.1 * t.2) as u32);
+
+        return thread_id
+            | (core_id << thread_mask_width)
+            | (die_id << (thread_mask_width + core_mask_width))
+            | (socket_id << (thread_mask_width + core_mask_width + die_mask_width));
+    }
+
+    index
+}
+
 // X86 Exceptions
 #[derive(Clone, Debug)]
 pub enum Exception {
@@ -53,6 +84,7 @@ pub enum Exception {
 }
 
 pub mod regs;
+pub mod xsave;
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct SegmentRegister {
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Decoding of the legacy PCI configuration access mechanism #1, where the
+//! configuration address is written to port 0xCF8 and the data is accessed
+//! through ports 0xCFC-0xCFF.
+//!
+//! PIO exits are delivered through `VmOps::pio_read()`/`VmOps::pio_write()`,
+//! handlers for these ports can use [`decode_pci_config_access`] to find out
+//! which configuration register is targeted.
+
+/// Port of the CONFIG_ADDRESS register.
+pub const PCI_CONFIG_ADDRESS_PORT: u64 = 0xcf8;
+/// First port of the CONFIG_DATA register.
+pub const PCI_CONFIG_DATA_PORT: u64 = 0xcfc;
+
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+const BUS_NUMBER_OFFSET: u32 = 16;
+const BUS_NUMBER_MASK: u32 = 0xff;
+const DEVICE_NUMBER_OFFSET: u32 = 11;
+const DEVICE_NUMBER_MASK: u32 = 0x1f;
+const FUNCTION_NUMBER_OFFSET: u32 = 8;
+const FUNCTION_NUMBER_MASK: u32 = 0x07;
+const REGISTER_OFFSET_MASK: u32 = 0xfc;
+
+/// Type of the configuration cycle generated by the host bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciConfigCycle {
+    /// Access to a device on the bus directly behind the host bridge.
+    Type0,
+    /// Access to a device behind a PCI-to-PCI bridge.
+    Type1,
+}
+
+/// Decoded configuration space access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciConfigAccess {
+    pub cycle: PciConfigCycle,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    /// Byte offset in the configuration space of the function.
+    pub offset: u8,
+}
+
+/// Decode an access to the CONFIG_DATA ports.
+///
+/// # Arguments
+///
+/// * `config_address` - Current value of the CONFIG_ADDRESS register.
+/// * `port` - Port targeted by the PIO exit.
+///
+/// Returns `None` if `port` is not one of the CONFIG_DATA ports or if the
+/// enable bit of `config_address` is clear, in which case the access must
+/// not reach the configuration space.
+pub fn decode_pci_config_access(config_address: u32, port: u64) -> Option<PciConfigAccess> {
+    if !(PCI_CONFIG_DATA_PORT..PCI_CONFIG_DATA_PORT + 4).contains(&port) {
+        return None;
+    }
+
+    if config_address & CONFIG_ADDRESS_ENABLE == 0 {
+        return None;
+    }
+
+    let bus = ((config_address >> BUS_NUMBER_OFFSET) & BUS_NUMBER_MASK) as u8;
+    let cycle = if bus == 0 {
+        PciConfigCycle::Type0
+    } else {
+        PciConfigCycle::Type1
+    };
+
+    Some(PciConfigAccess {
+        cycle,
+        bus,
+        device: ((config_address >> DEVICE_NUMBER_OFFSET) & DEVICE_NUMBER_MASK) as u8,
+        function: ((config_address >> FUNCTION_NUMBER_OFFSET) & FUNCTION_NUMBER_MASK) as u8,
+        offset: (config_address & REGISTER_OFFSET_MASK) as u8 + (port - PCI_CONFIG_DATA_PORT) as u8,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_address(bus: u32, device: u32, function: u32, register: u32) -> u32 {
+        CONFIG_ADDRESS_ENABLE | (bus << 16) | (device << 11) | (function << 8) | register
+    }
+
+    #[test]
+    fn test_type0_access() {
+        let access =
+            decode_pci_config_access(config_address(0, 3, 1, 0x10), PCI_CONFIG_DATA_PORT).unwrap();
+        assert_eq!(
+            access,
+            PciConfigAccess {
+                cycle: PciConfigCycle::Type0,
+                bus: 0,
+                device: 3,
+                function: 1,
+                offset: 0x10,
+            }
+        );
+    }
+
+    #[test]
+    fn test_type1_access() {
+        let access =
+            decode_pci_config_access(config_address(2, 31, 7, 0xfc), PCI_CONFIG_DATA_PORT + 3)
+                .unwrap();
+        assert_eq!(
+            access,
+            PciConfigAccess {
+                cycle: PciConfigCycle::Type1,
+                bus: 2,
+                device: 31,
+                function: 7,
+                offset: 0xff,
+            }
+        );
+    }
+
+    #[test]
+    fn test_register_low_bits_ignored() {
+        // Bits 1:0 of CONFIG_ADDRESS are reserved, the byte offset comes
+        // from the data port.
+        let access =
+            decode_pci_config_access(config_address(0, 0, 0, 0x43), PCI_CONFIG_DATA_PORT + 2)
+                .unwrap();
+        assert_eq!(access.offset, 0x42);
+    }
+
+    #[test]
+    fn test_enable_bit() {
+        let address = config_address(0, 3, 0, 0x10);
+        assert!(decode_pci_config_access(address, PCI_CONFIG_DATA_PORT).is_some());
+        assert!(
+            decode_pci_config_access(address & !CONFIG_ADDRESS_ENABLE, PCI_CONFIG_DATA_PORT)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_non_data_port() {
+        let address = config_address(0, 3, 0, 0x10);
+        assert!(decode_pci_config_access(address, PCI_CONFIG_ADDRESS_PORT).is_none());
+        assert!(decode_pci_config_access(address, PCI_CONFIG_DATA_PORT + 4).is_none());
+    }
+}
@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+//! Guest visible RAPL (Running Average Power Limit) energy MSRs.
+//!
+//! KVM reads every RAPL MSR as 0 on its own, which power monitoring tools
+//! running in the guest can't make use of. The MSRs can instead be handled
+//! by the VMM, either:
+//!
+//! * emulated: the energy counters advance at a fixed synthetic power from
+//!   the creation of the VM, so nothing about the host is revealed.
+//! * passed through: the counters of the host package the vCPU runs on are
+//!   returned. They account for the energy consumed by every process and
+//!   VM on that package, which leaks information about their activity and
+//!   enables power side channels. This is only suitable for hosts dedicated
+//!   to a single guest.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// `MSR_RAPL_POWER_UNIT` MSR index.
+pub const MSR_RAPL_POWER_UNIT: u32 = 0x606;
+/// `MSR_PKG_ENERGY_STATUS` MSR index.
+pub const MSR_PKG_ENERGY_STATUS: u32 = 0x611;
+/// `MSR_DRAM_ENERGY_STATUS` MSR index.
+pub const MSR_DRAM_ENERGY_STATUS: u32 = 0x619;
+/// `MSR_PP0_ENERGY_STATUS` MSR index.
+pub const MSR_PP0_ENERGY_STATUS: u32 = 0x639;
+/// `MSR_PP1_ENERGY_STATUS` MSR index.
+pub const MSR_PP1_ENERGY_STATUS: u32 = 0x641;
+
+/// The RAPL MSRs handled by the VMM, all read-only.
+pub const RAPL_MSRS: [u32; 5] = [
+    MSR_RAPL_POWER_UNIT,
+    MSR_PKG_ENERGY_STATUS,
+    MSR_DRAM_ENERGY_STATUS,
+    MSR_PP0_ENERGY_STATUS,
+    MSR_PP1_ENERGY_STATUS,
+];
+
+// Emulated units: 1/8 W power, 2^-14 J energy and 2^-10 s time units
+const EMULATED_POWER_UNIT: u64 = 0x000a_0e03;
+const EMULATED_ENERGY_UNIT_BITS: u32 = 14;
+// Emulated power of the package, its cores (PP0), its uncore device (PP1)
+// and the DRAM, in W
+const EMULATED_PKG_WATTS: u64 = 10;
+const EMULATED_PP0_WATTS: u64 = 8;
+const EMULATED_PP1_WATTS: u64 = 0;
+const EMULATED_DRAM_WATTS: u64 = 2;
+
+/// How the RAPL MSRs are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RaplMode {
+    /// Synthetic counters, specific to the VM.
+    #[default]
+    Emulated,
+    /// Counters of the host, see the module documentation for the
+    /// information this leaks to the guest.
+    Passthrough,
+}
+
+#[derive(Debug, Error)]
+pub enum ParseRaplModeError {
+    #[error("Invalid RAPL mode: {0}")]
+    InvalidValue(String),
+}
+
+impl FromStr for RaplMode {
+    type Err = ParseRaplModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "emulated" => Ok(RaplMode::Emulated),
+            "passthrough" => Ok(RaplMode::Passthrough),
+            _ => Err(ParseRaplModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Value of the RAPL MSR `index` once the VM has been running for
+/// `elapsed`. The energy counters are 32 bits wide and wrap around.
+fn emulated_msr(index: u32, elapsed: Duration) -> Option<u64> {
+    let watts = match index {
+        MSR_RAPL_POWER_UNIT => return Some(EMULATED_POWER_UNIT),
+        MSR_PKG_ENERGY_STATUS => EMULATED_PKG_WATTS,
+        MSR_DRAM_ENERGY_STATUS => EMULATED_DRAM_WATTS,
+        MSR_PP0_ENERGY_STATUS => EMULATED_PP0_WATTS,
+        MSR_PP1_ENERGY_STATUS => EMULATED_PP1_WATTS,
+        _ => return None,
+    };
+
+    let energy =
+        ((elapsed.as_micros() * u128::from(watts)) << EMULATED_ENERGY_UNIT_BITS) / 1_000_000;
+    Some((energy & 0xffff_ffff) as u64)
+}
+
+/// Reads `index` on the host CPU the calling thread runs on, through
+/// `/dev/cpu/<cpu>/msr`.
+fn host_msr(index: u32) -> Option<u64> {
+    // SAFETY: FFI call without arguments
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        return None;
+    }
+
+    let file = File::open(format!("/dev/cpu/{cpu}/msr")).ok()?;
+    let mut buf = [0u8; 8];
+    file.read_exact_at(&mut buf, u64::from(index)).ok()?;
+    Some(u64::from_le_bytes(buf))
+}
+
+/// RAPL MSRs of a VM.
+#[derive(Debug)]
+pub struct RaplMsrs {
+    mode: RaplMode,
+    start: Instant,
+}
+
+impl RaplMsrs {
+    pub fn new(mode: RaplMode) -> Self {
+        RaplMsrs {
+            mode,
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns the value read by the guest from `index`, `None` if it is
+    /// not a RAPL MSR or if the host one can't be read.
+    pub fn read(&self, index: u32) -> Option<u64> {
+        if !RAPL_MSRS.contains(&index) {
+            return None;
+        }
+
+        match self.mode {
+            RaplMode::Emulated => emulated_msr(index, self.start.elapsed()),
+            RaplMode::Passthrough => host_msr(index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emulated_rapl_msrs() {
+        assert_eq!(
+            emulated_msr(MSR_RAPL_POWER_UNIT, Duration::from_secs(5)),
+            Some(EMULATED_POWER_UNIT)
+        );
+        assert_eq!(emulated_msr(MSR_PKG_ENERGY_STATUS, Duration::ZERO), Some(0));
+        // 10 W during 1.5 s is 15 J, i.e. 15 << 14 energy units
+        assert_eq!(
+            emulated_msr(MSR_PKG_ENERGY_STATUS, Duration::from_millis(1500)),
+            Some(15 << 14)
+        );
+        assert_eq!(
+            emulated_msr(MSR_DRAM_ENERGY_STATUS, Duration::from_secs(1)),
+            Some(2 << 14)
+        );
+        assert_eq!(
+            emulated_msr(MSR_PP1_ENERGY_STATUS, Duration::from_secs(1)),
+            Some(0)
+        );
+        // 2^32 energy units are consumed in 2^18 / 10 s
+        assert_eq!(
+            emulated_msr(MSR_PKG_ENERGY_STATUS, Duration::from_millis(26_214_500)),
+            Some(1 << 14)
+        );
+        assert_eq!(emulated_msr(0x610, Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn test_rapl_mode_parsing() {
+        assert_eq!("emulated".parse::<RaplMode>().unwrap(), RaplMode::Emulated);
+        assert_eq!(
+            "passthrough".parse::<RaplMode>().unwrap(),
+            RaplMode::Passthrough
+        );
+        "on".parse::<RaplMode>().unwrap_err();
+    }
+}
@@ -4,6 +4,8 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use super::{DescriptorTable, FpuState, SegmentRegister, SpecialRegisters};
+
 // EFER (technically not a register) bits
 pub const EFER_LMA: u64 = 0x400;
 pub const EFER_LME: u64 = 0x100;
@@ -22,13 +24,187 @@ pub const PF_SHIFT: usize = 2;
 pub const AF_SHIFT: usize = 4;
 pub const ZF_SHIFT: usize = 6;
 pub const SF_SHIFT: usize = 7;
+pub const IF_SHIFT: usize = 9;
 pub const DF_SHIFT: usize = 10;
 pub const OF_SHIFT: usize = 11;
+pub const VM_SHIFT: usize = 17;
 
 pub const CF: u64 = 1 << CF_SHIFT;
 pub const PF: u64 = 1 << PF_SHIFT;
 pub const AF: u64 = 1 << AF_SHIFT;
 pub const ZF: u64 = 1 << ZF_SHIFT;
 pub const SF: u64 = 1 << SF_SHIFT;
+pub const IF: u64 = 1 << IF_SHIFT;
 pub const DF: u64 = 1 << DF_SHIFT;
 pub const OF: u64 = 1 << OF_SHIFT;
+pub const VM: u64 = 1 << VM_SHIFT;
+
+// Power-on/RESET values, see the Intel SDM Vol. 3A, 10.1.1 "Processor State
+// After Reset" and the AMD APM Vol. 2, 14.1.3 "Processor Initialization State".
+pub const RESET_RIP: u64 = 0xfff0;
+pub const RESET_RFLAGS: u64 = 0x2;
+pub const RESET_CR0: u64 = 0x6000_0010;
+pub const RESET_CS_SELECTOR: u16 = 0xf000;
+pub const RESET_CS_BASE: u64 = 0xffff_0000;
+pub const RESET_FCW: u16 = 0x37f;
+pub const RESET_MXCSR: u32 = 0x1f80;
+
+// IA32_APIC_BASE bits
+pub const APIC_BASE_BSP: u64 = 1 << 8;
+pub const APIC_BASE_ENABLE: u64 = 1 << 11;
+pub const APIC_BASE_DEFAULT: u64 = 0xfee0_0000;
+
+/// Special registers of a vCPU after RESET.
+///
+/// The BSP flag of the APIC base is taken from `current`, the local APIC is
+/// otherwise back to its default address in xAPIC mode.
+pub fn reset_sregs(current: &SpecialRegisters) -> SpecialRegisters {
+    let segment = |selector: u16, base: u64, type_: u8| SegmentRegister {
+        base,
+        limit: 0xffff,
+        selector,
+        type_,
+        present: 1,
+        s: 1,
+        ..Default::default()
+    };
+    // Accessed read/write data, accessed execute/read code
+    let data = segment(0, 0, 0x3);
+
+    SpecialRegisters {
+        cs: segment(RESET_CS_SELECTOR, RESET_CS_BASE, 0xb),
+        ds: data,
+        es: data,
+        fs: data,
+        gs: data,
+        ss: data,
+        // Busy TSS
+        tr: SegmentRegister {
+            s: 0,
+            ..segment(0, 0, 0xb)
+        },
+        // LDT
+        ldt: SegmentRegister {
+            s: 0,
+            ..segment(0, 0, 0x2)
+        },
+        gdt: DescriptorTable {
+            base: 0,
+            limit: 0xffff,
+        },
+        idt: DescriptorTable {
+            base: 0,
+            limit: 0xffff,
+        },
+        cr0: RESET_CR0,
+        apic_base: APIC_BASE_DEFAULT | APIC_BASE_ENABLE | (current.apic_base & APIC_BASE_BSP),
+        ..Default::default()
+    }
+}
+
+/// Paging mode of a vCPU, which determines the format of its page tables.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PagingMode {
+    /// Paging disabled (CR0.PG clear), linear addresses are physical.
+    None,
+    /// 32-bit paging, two levels of 4-byte entries.
+    Bits32,
+    /// PAE paging, three levels of 8-byte entries.
+    Pae,
+    /// 4-level paging, 48-bit linear addresses.
+    Level4,
+    /// 5-level paging (CR4.LA57 set), 57-bit linear addresses.
+    Level5,
+}
+
+impl From<&SpecialRegisters> for PagingMode {
+    fn from(sregs: &SpecialRegisters) -> Self {
+        if sregs.cr0 & CR0_PG == 0 {
+            PagingMode::None
+        } else if sregs.cr4 & CR4_PAE == 0 {
+            PagingMode::Bits32
+        } else if sregs.efer & EFER_LME == 0 {
+            PagingMode::Pae
+        } else if sregs.cr4 & CR4_LA57 == 0 {
+            PagingMode::Level4
+        } else {
+            PagingMode::Level5
+        }
+    }
+}
+
+/// x87 and SSE state after RESET.
+pub fn reset_fpu() -> FpuState {
+    FpuState {
+        fcw: RESET_FCW,
+        mxcsr: RESET_MXCSR,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_sregs() {
+        let running = SpecialRegisters {
+            cr0: CR0_PE | CR0_PG,
+            cr4: CR4_PAE,
+            efer: EFER_LME | EFER_LMA,
+            // x2APIC enabled
+            apic_base: APIC_BASE_DEFAULT | APIC_BASE_ENABLE | (1 << 10) | APIC_BASE_BSP,
+            ..Default::default()
+        };
+
+        let sregs = reset_sregs(&running);
+        assert_eq!(sregs.cs.selector, RESET_CS_SELECTOR);
+        assert_eq!(sregs.cs.base + RESET_RIP, 0xffff_fff0);
+        assert_eq!(sregs.cr0, RESET_CR0);
+        assert_eq!(sregs.cr4, 0);
+        assert_eq!(sregs.efer, 0);
+        assert_eq!(sregs.apic_base, 0xfee0_0900);
+
+        let sregs = reset_sregs(&SpecialRegisters {
+            apic_base: running.apic_base & !APIC_BASE_BSP,
+            ..running
+        });
+        assert_eq!(sregs.apic_base, 0xfee0_0800);
+    }
+    #[test]
+    fn test_paging_mode() {
+        let sregs = |cr0: u64, cr4: u64, efer: u64| SpecialRegisters {
+            cr0,
+            cr4,
+            efer,
+            ..Default::default()
+        };
+
+        assert_eq!(PagingMode::from(&sregs(CR0_PE, 0, 0)), PagingMode::None);
+        // CR4 and EFER are ignored while paging is off
+        assert_eq!(
+            PagingMode::from(&sregs(CR0_PE, CR4_PAE | CR4_LA57, EFER_LME)),
+            PagingMode::None
+        );
+        assert_eq!(
+            PagingMode::from(&sregs(CR0_PE | CR0_PG, 0, 0)),
+            PagingMode::Bits32
+        );
+        assert_eq!(
+            PagingMode::from(&sregs(CR0_PE | CR0_PG, CR4_PAE, 0)),
+            PagingMode::Pae
+        );
+        assert_eq!(
+            PagingMode::from(&sregs(CR0_PE | CR0_PG, CR4_PAE, EFER_LME | EFER_LMA)),
+            PagingMode::Level4
+        );
+        assert_eq!(
+            PagingMode::from(&sregs(
+                CR0_PE | CR0_PG,
+                CR4_PAE | CR4_LA57,
+                EFER_LME | EFER_LMA
+            )),
+            PagingMode::Level5
+        );
+    }
+}
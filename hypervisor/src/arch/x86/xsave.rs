@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Layout of the XSAVE area, as enumerated by CPUID leaf 0xD.
+//!
+//! * CPUID.(EAX=0xD,ECX=0):EDX:EAX lists the user state components which
+//!   can be enabled in XCR0.
+//! * CPUID.(EAX=0xD,ECX=1):EDX:ECX lists the supervisor state components
+//!   which can be enabled in IA32_XSS.
+//! * CPUID.(EAX=0xD,ECX=i), for every component i from 2 up, reports its
+//!   size in EAX and its offset in the standard format in EBX. ECX bit 0 is
+//!   set for supervisor components, which have no standard format offset,
+//!   and bit 1 when the component is 64 bytes aligned in the compacted
+//!   format.
+//!
+//! Components 0 (x87) and 1 (SSE) live in the legacy region at the start of
+//! the area and are not enumerated through their own subleaf.
+
+use super::CpuIdEntry;
+
+const XSAVE_LEAF: u32 = 0xd;
+// Legacy region and XSAVE header
+const XSAVE_AREA_MIN_SIZE: u32 = 576;
+const FIRST_EXTENDED_COMPONENT: u32 = 2;
+const LAST_COMPONENT: u32 = 63;
+
+const SUPERVISOR_ECX_BIT: u32 = 0;
+const ALIGNED_ECX_BIT: u32 = 1;
+
+/// Extended XSAVE state component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XsaveComponent {
+    /// Bit of the component in XCR0 or IA32_XSS.
+    pub index: u32,
+    /// Size in bytes.
+    pub size: u32,
+    /// Offset in the standard format, `None` for supervisor components
+    /// which are only saved in the compacted format.
+    pub offset: Option<u32>,
+    /// Whether the component is enabled through IA32_XSS rather than XCR0.
+    pub supervisor: bool,
+    /// Whether the component is 64 bytes aligned in the compacted format.
+    pub aligned: bool,
+}
+
+/// Extended XSAVE state components, ordered by index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XsaveLayout {
+    pub components: Vec<XsaveComponent>,
+}
+
+impl XsaveLayout {
+    /// Decode the components enumerated by the leaf 0xD entries of `cpuid`.
+    pub fn from_cpuid(cpuid: &[CpuIdEntry]) -> Self {
+        let leaf = |index: u32| {
+            cpuid
+                .iter()
+                .find(|e| e.function == XSAVE_LEAF && e.index == index)
+        };
+
+        let mask = |lo: u32, hi: u32| (u64::from(hi) << 32) | u64::from(lo);
+        let user = leaf(0).map(|e| mask(e.eax, e.edx)).unwrap_or(0);
+        let supervisor = leaf(1).map(|e| mask(e.ecx, e.edx)).unwrap_or(0);
+
+        let components = (FIRST_EXTENDED_COMPONENT..=LAST_COMPONENT)
+            .filter(|i| (user | supervisor) & (1 << i) != 0)
+            .filter_map(|index| {
+                let entry = leaf(index).filter(|e| e.eax != 0)?;
+                let supervisor = entry.ecx & (1 << SUPERVISOR_ECX_BIT) != 0;
+
+                Some(XsaveComponent {
+                    index,
+                    size: entry.eax,
+                    offset: (!supervisor).then_some(entry.ebx),
+                    supervisor,
+                    aligned: entry.ecx & (1 << ALIGNED_ECX_BIT) != 0,
+                })
+            })
+            .collect();
+
+        XsaveLayout { components }
+    }
+
+    /// Returns the component with bit `index`, if enumerated.
+    pub fn component(&self, index: u32) -> Option<&XsaveComponent> {
+        self.components.iter().find(|c| c.index == index)
+    }
+
+    /// Size of a standard format XSAVE area holding every user component.
+    pub fn standard_size(&self) -> u32 {
+        self.components
+            .iter()
+            .filter_map(|c| c.offset.map(|offset| offset + c.size))
+            .fold(XSAVE_AREA_MIN_SIZE, u32::max)
+    }
+
+    /// Returns the index of the first component which `target` lacks or
+    /// lays out differently, meaning a state saved with this layout can't
+    /// be restored with `target`'s.
+    pub fn incompatible_component(&self, target: &XsaveLayout) -> Option<u32> {
+        self.components
+            .iter()
+            .find(|c| target.component(c.index) != Some(c))
+            .map(|c| c.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subleaf(index: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuIdEntry {
+        CpuIdEntry {
+            function: XSAVE_LEAF,
+            index,
+            eax,
+            ebx,
+            ecx,
+            edx,
+            ..Default::default()
+        }
+    }
+
+    // x87, SSE, AVX, the AVX-512 components (5 to 7) and CET_U (11). PKRU
+    // (9) has a subleaf but isn't supported.
+    fn skylake_like_cpuid() -> Vec<CpuIdEntry> {
+        vec![
+            subleaf(0, 0xe7, 2688, 2696, 0),
+            subleaf(1, 0xf, 0, 1 << 11, 0),
+            subleaf(2, 256, 576, 0, 0),
+            subleaf(5, 64, 1088, 0, 0),
+            subleaf(6, 512, 1152, 0, 0),
+            subleaf(7, 1024, 1664, 0, 0),
+            subleaf(9, 8, 2688, 0, 0),
+            subleaf(11, 16, 0, 1, 0),
+        ]
+    }
+
+    #[test]
+    fn test_xsave_layout() {
+        let layout = XsaveLayout::from_cpuid(&skylake_like_cpuid());
+
+        let indices: Vec<u32> = layout.components.iter().map(|c| c.index).collect();
+        assert_eq!(indices, vec![2, 5, 6, 7, 11]);
+        assert_eq!(
+            layout.component(7),
+            Some(&XsaveComponent {
+                index: 7,
+                size: 1024,
+                offset: Some(1664),
+                supervisor: false,
+                aligned: false,
+            })
+        );
+        assert_eq!(
+            layout.component(11),
+            Some(&XsaveComponent {
+                index: 11,
+                size: 16,
+                offset: None,
+                supervisor: true,
+                aligned: false,
+            })
+        );
+        assert_eq!(layout.component(9), None);
+        assert_eq!(layout.standard_size(), 2688);
+
+        let empty = XsaveLayout::from_cpuid(&[]);
+        assert_eq!(empty, XsaveLayout::default());
+        assert_eq!(empty.standard_size(), XSAVE_AREA_MIN_SIZE);
+    }
+
+    #[test]
+    fn test_xsave_layout_compatibility() {
+        let layout = XsaveLayout::from_cpuid(&skylake_like_cpuid());
+        assert_eq!(layout.incompatible_component(&layout), None);
+        assert_eq!(XsaveLayout::default().incompatible_component(&layout), None);
+
+        // Without AVX-512
+        let mut cpuid = skylake_like_cpuid();
+        cpuid[0].eax = 0x7;
+        let target = XsaveLayout::from_cpuid(&cpuid);
+        assert_eq!(layout.incompatible_component(&target), Some(5));
+        assert_eq!(target.incompatible_component(&layout), None);
+
+        // AVX at another offset
+        let mut cpuid = skylake_like_cpuid();
+        cpuid[2].ebx = 640;
+        let target = XsaveLayout::from_cpuid(&cpuid);
+        assert_eq!(layout.incompatible_component(&target), Some(2));
+    }
+}
@@ -10,14 +10,29 @@
 //
 //
 
+use std::cell::Cell;
+#[cfg(target_arch = "aarch64")]
+use std::fmt;
+use std::io;
+use std::ptr::{self, NonNull};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use thiserror::Error;
 #[cfg(not(target_arch = "riscv64"))]
 use vm_memory::GuestAddress;
+use vmm_sys_util::signal::register_signal_handler;
 
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 use crate::RegList;
 #[cfg(target_arch = "aarch64")]
 use crate::VcpuInit;
+#[cfg(target_arch = "aarch64")]
+use crate::arch::aarch64::regs::{PagingMode, SCTLR_EL1, TCR_EL1};
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::gdt::{DescriptorTables, SegmentCache, segment_caches};
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::regs::PagingMode;
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86::{CpuIdEntry, FpuState, LapicState, MsrEntry, SpecialRegisters};
 #[cfg(feature = "tdx")]
@@ -98,6 +113,26 @@ pub enum HypervisorCpuError {
     #[error("Failed to get Msr entries")]
     GetMsrEntries(#[source] anyhow::Error),
     ///
+    /// Reading a specific MSR failed
+    ///
+    #[error("Failed to read MSR 0x{0:x}")]
+    ReadMsr(u32 /* index */),
+    ///
+    /// Writing a specific MSR failed
+    ///
+    #[error("Failed to write MSR 0x{0:x} after writing {1} MSRs")]
+    WriteMsr(u32 /* index */, usize /* written */),
+    ///
+    /// Setting IA32_TSC_AUX error
+    ///
+    #[error("Failed to set TSC_AUX")]
+    SetTscAux(#[source] anyhow::Error),
+    ///
+    /// Setting IA32_MISC_ENABLE error
+    ///
+    #[error("Failed to set MISC_ENABLE")]
+    SetMiscEnable(#[source] anyhow::Error),
+    ///
     /// Setting multi-processing  state error
     ///
     #[error("Failed to set MP state")]
@@ -157,6 +192,12 @@ pub enum HypervisorCpuError {
     #[error("Failed to set Vcpu events")]
     SetVcpuEvents(#[source] anyhow::Error),
     ///
+    /// Injecting SError error
+    ///
+    #[cfg(target_arch = "aarch64")]
+    #[error("Failed to inject SError")]
+    InjectSError(#[source] anyhow::Error),
+    ///
     /// Vcpu Init error
     ///
     #[error("Failed to init vcpu")]
@@ -335,6 +376,193 @@ pub enum HypervisorCpuError {
     GetNestedState(#[source] anyhow::Error),
     #[error("Failed to set nested guest state")]
     SetNestedState(#[source] anyhow::Error),
+    ///
+    /// Getting vCPU statistics error
+    ///
+    #[error("Failed to get vCPU statistics")]
+    GetStats(#[source] anyhow::Error),
+    ///
+    /// Setting vCPU affinity error
+    ///
+    #[error("Failed to set vCPU affinity")]
+    SetVcpuAffinity(#[source] anyhow::Error),
+    ///
+    /// Enabling steal time error
+    ///
+    #[error("Failed to enable steal time")]
+    EnableStealTime(#[source] anyhow::Error),
+    ///
+    /// Enabling Hyper-V crash MSRs error
+    ///
+    #[error("Failed to enable Hyper-V crash MSRs")]
+    EnableHyperVCrashMsrs(#[source] anyhow::Error),
+    ///
+    /// Setting the exit observer error
+    ///
+    #[error("Failed to set the exit observer")]
+    SetExitObserver(#[source] anyhow::Error),
+    ///
+    /// Resetting the vCPU error
+    ///
+    #[error("Failed to reset vCPU")]
+    ResetVcpu(#[source] anyhow::Error),
+    ///
+    /// Enforcing the paravirtual CPUID features error
+    ///
+    #[error("Failed to enforce the paravirtual CPUID features")]
+    EnforcePvCpuid(#[source] anyhow::Error),
+    ///
+    /// Setting the APIC base error
+    ///
+    #[error("Failed to set the APIC base")]
+    SetApicBase(#[source] anyhow::Error),
+    ///
+    /// Invalid control register number error
+    ///
+    #[error("Invalid control register CR{0}")]
+    InvalidControlRegister(u8),
+    ///
+    /// Configuring the asynchronous page faults error
+    ///
+    #[error("Failed to configure the asynchronous page faults")]
+    ConfigureAsyncPf(#[source] anyhow::Error),
+    ///
+    /// Getting SMBASE error
+    ///
+    #[error("Failed to get SMBASE")]
+    GetSmbase(#[source] anyhow::Error),
+    ///
+    /// Setting SMBASE error
+    ///
+    #[error("Failed to set SMBASE")]
+    SetSmbase(#[source] anyhow::Error),
+}
+
+/// Halt-polling statistics of a vCPU, as accumulated since its creation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HaltPollStats {
+    /// Number of halts ended by a successful poll.
+    pub successful_polls: u64,
+    /// Number of halts where polling was attempted.
+    pub attempted_polls: u64,
+    /// Number of polls stopped because of an invalid wakeup.
+    pub invalid_polls: u64,
+    /// Number of wakeups after the vCPU was scheduled out.
+    pub wakeups: u64,
+    /// Time spent in successful polls, in nanoseconds.
+    pub success_ns: u64,
+    /// Time spent in failed polls, in nanoseconds.
+    pub fail_ns: u64,
+}
+
+/// Second-level (EPT/NPT) MMU statistics.
+///
+/// The page fault counters are accounted per vCPU while the shadow page table
+/// and huge page counters are accounted per VM, each source leaves the other
+/// fields to zero. Use `+=` to aggregate them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MmuStats {
+    /// Page faults handled by the hypervisor MMU.
+    pub pf_taken: u64,
+    /// Page faults fixed by installing a mapping.
+    pub pf_fixed: u64,
+    /// Page faults requiring instruction emulation.
+    pub pf_emulate: u64,
+    /// Page faults which turned out to be already fixed.
+    pub pf_spurious: u64,
+    /// Writes to guest page tables tracked by the MMU.
+    pub pte_writes: u64,
+    /// Shadow pages zapped.
+    pub shadow_zapped: u64,
+    /// Huge pages split because of the NX huge page mitigation.
+    pub nx_huge_page_splits: u64,
+    /// Guest memory currently mapped with 4 KiB pages.
+    pub pages_4k: u64,
+    /// Guest memory currently mapped with 2 MiB pages.
+    pub pages_2m: u64,
+    /// Guest memory currently mapped with 1 GiB pages.
+    pub pages_1g: u64,
+}
+
+impl std::ops::AddAssign for MmuStats {
+    fn add_assign(&mut self, other: Self) {
+        self.pf_taken += other.pf_taken;
+        self.pf_fixed += other.pf_fixed;
+        self.pf_emulate += other.pf_emulate;
+        self.pf_spurious += other.pf_spurious;
+        self.pte_writes += other.pte_writes;
+        self.shadow_zapped += other.shadow_zapped;
+        self.nx_huge_page_splits += other.nx_huge_page_splits;
+        self.pages_4k += other.pages_4k;
+        self.pages_2m += other.pages_2m;
+        self.pages_1g += other.pages_1g;
+    }
+}
+
+/// Kind of reset requested by the guest through PSCI (ARM DEN0022,
+/// "SYSTEM_RESET" and "SYSTEM_RESET2").
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    /// SYSTEM_RESET
+    Cold,
+    /// SYSTEM_RESET2 with the architectural SYSTEM_WARM_RESET type
+    Warm,
+    /// SYSTEM_RESET2 with a vendor specific type, bit 31 of the PSCI reset
+    /// type stripped from `reset_type`. The meaning of `cookie` is up to
+    /// the vendor.
+    Vendor { reset_type: u32, cookie: u64 },
+}
+
+#[cfg(target_arch = "aarch64")]
+impl ResetReason {
+    // Bit 31 of the SYSTEM_RESET2 reset type, set for vendor specific ones
+    const VENDOR_RESET_TYPE: u32 = 1 << 31;
+    const SYSTEM_WARM_RESET: u32 = 0;
+
+    /// Decode the `reset_type` and `cookie` arguments of a SYSTEM_RESET2
+    /// call, `None` for a reserved architectural reset type.
+    pub fn from_reset2(reset_type: u32, cookie: u64) -> Option<Self> {
+        if reset_type & Self::VENDOR_RESET_TYPE != 0 {
+            Some(ResetReason::Vendor {
+                reset_type: reset_type & !Self::VENDOR_RESET_TYPE,
+                cookie,
+            })
+        } else if reset_type == Self::SYSTEM_WARM_RESET {
+            Some(ResetReason::Warm)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetReason::Cold => write!(f, "cold"),
+            ResetReason::Warm => write!(f, "warm"),
+            ResetReason::Vendor { reset_type, cookie } => {
+                write!(f, "vendor {reset_type:#x}, cookie {cookie:#x}")
+            }
+        }
+    }
+}
+
+/// Reason of a hypervisor internal error, as reported by KVM through the
+/// KVM_INTERNAL_ERROR_* suberrors.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InternalErrorKind {
+    /// The instruction emulator couldn't decode or emulate an instruction.
+    Emulation,
+    /// An exception was raised while delivering another one.
+    SimultaneousException,
+    /// A VM exit occurred while delivering an event and couldn't be handled.
+    EventDelivery,
+    /// The hardware reported an exit reason the hypervisor doesn't handle.
+    UnexpectedExitReason,
+    Other(u32),
 }
 
 #[derive(Debug)]
@@ -349,6 +577,201 @@ pub enum VmExit {
     Tdx,
     #[cfg(feature = "kvm")]
     Debug,
+    Timeout,
+    Watchpoint {
+        gpa: u64,
+        size: usize,
+        write: bool,
+    },
+    /// The guest reported a crash through the Hyper-V crash MSRs: `code`
+    /// is HV_X64_MSR_CRASH_P0 (the bugcheck code for Windows) and `params`
+    /// are HV_X64_MSR_CRASH_P1..P4.
+    #[cfg(target_arch = "x86_64")]
+    GuestCrash {
+        code: u64,
+        params: [u64; 4],
+    },
+    /// The hypervisor failed to handle an exit of the guest. `data` holds
+    /// the raw suberror specific words and `insn` the bytes of the faulting
+    /// instruction, when reported.
+    ///
+    /// The vCPU state is left untouched, so the caller may try to recover,
+    /// e.g. by emulating the instruction itself and moving RIP past it,
+    /// before running the vCPU again. Otherwise the VM should be stopped.
+    #[cfg(target_arch = "x86_64")]
+    InternalError {
+        suberror: InternalErrorKind,
+        data: Vec<u64>,
+        insn: Option<Vec<u8>>,
+    },
+    /// The guest called PSCI SYSTEM_SUSPEND, only reported when
+    /// `HypervisorVmConfig::system_suspend` is set. `entry_point` and
+    /// `context_id` are the arguments of the call.
+    ///
+    /// The call is left pending and the VMM must complete it before running
+    /// the vCPU again, either by denying it (writing a PSCI error code such
+    /// as DENIED to X0) or by suspending the VM. In the latter case the state
+    /// of the VM can be saved, and when a wakeup event occurs the calling
+    /// vCPU resumes as if woken from a CPU_ON (ARM DEN0022, "SYSTEM_SUSPEND"):
+    /// reset it with `Vcpu::vcpu_init()`, then set PC to `entry_point`, X0 to
+    /// `context_id` and PSTATE to EL1h with D, A, I and F masked before
+    /// running it. The other vCPUs must be off, as the guest only calls
+    /// SYSTEM_SUSPEND from its last running vCPU.
+    #[cfg(target_arch = "aarch64")]
+    Suspend {
+        entry_point: u64,
+        context_id: u64,
+    },
+    /// The guest called PSCI SYSTEM_OFF, asking to be powered off.
+    #[cfg(target_arch = "aarch64")]
+    SystemOff,
+    /// The guest called PSCI SYSTEM_RESET or SYSTEM_RESET2, asking to be
+    /// rebooted the way given by the `ResetReason`.
+    #[cfg(target_arch = "aarch64")]
+    SystemReset(ResetReason),
+}
+
+/// Reason of a vCPU exit as reported by the hypervisor, before the exit is
+/// handled. Unlike [`VmExit`], the exits handled within [`Vcpu::run()`],
+/// such as the PIO and MMIO ones, are not folded into `VmExit::Ignore`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VcpuExitKind {
+    Io,
+    Mmio,
+    Msr,
+    Hlt,
+    Shutdown,
+    SystemEvent,
+    IoapicEoi,
+    Hyperv,
+    Debug,
+    InternalError,
+    FailEntry,
+    /// The run call was interrupted by a signal
+    Interrupted,
+    /// Hypervisor specific exit reason, e.g. `KVM_EXIT_*` for KVM
+    Other(u32),
+}
+
+/// Observer notified of every exit returned by [`Vcpu::run()`], e.g. to
+/// build exit-rate histograms.
+///
+/// It is called from the vCPU thread right after the exit has been handled,
+/// implementations must therefore be cheap and must not block.
+pub trait VcpuExitObserver: Send + Sync {
+    /// `duration_in_guest` is the time spent in the hypervisor run call,
+    /// excluding the handling of the exit by the VMM.
+    fn on_exit(&self, kind: VcpuExitKind, duration_in_guest: Duration);
+}
+
+// Interval between the signals sent once the deadline of `run_with_timeout()`
+// has passed, in case the first one was delivered before entering the guest
+// of a vCPU without "immediate_exit" state.
+const RUN_TIMEOUT_KICK_INTERVAL: Duration = Duration::from_millis(1);
+
+thread_local! {
+    // "immediate_exit" state of the vCPU run by `run_with_timeout()` on the
+    // calling thread, null otherwise
+    static RUN_TIMEOUT_IMMEDIATE_EXIT: Cell<*mut u8> = const { Cell::new(ptr::null_mut()) };
+    // Whether the timer signal set the "immediate_exit" state published above
+    static RUN_TIMEOUT_EXPIRED: Cell<bool> = const { Cell::new(false) };
+}
+
+extern "C" fn handle_run_timeout_signal(_: i32, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    let immediate_exit = RUN_TIMEOUT_IMMEDIATE_EXIT.with(Cell::get);
+    // SAFETY: the pointer is valid as long as it is published, see
+    // RunTimeoutExit
+    if !immediate_exit.is_null() && unsafe { immediate_exit.read_volatile() } == 0 {
+        // SAFETY: as above
+        unsafe { immediate_exit.write_volatile(1) };
+        RUN_TIMEOUT_EXPIRED.with(|e| e.set(true));
+    }
+}
+
+///
+/// Registers the handler of `signum` as the signal sent by the timer of
+/// `Vcpu::run_with_timeout()`. It must be called once before any vCPU is run
+/// with a timeout, with a signal the process doesn't use for anything else.
+///
+pub fn register_run_timeout_handler(signum: i32) -> io::Result<()> {
+    register_signal_handler(signum, handle_run_timeout_signal)
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+}
+
+/// Publishes the "immediate_exit" state of a vCPU to the timer signal handler
+/// for the duration of a `run_with_timeout()` call, so that a signal delivered
+/// right before entering the guest isn't lost.
+struct RunTimeoutExit(*mut u8);
+
+impl RunTimeoutExit {
+    fn new(immediate_exit: Option<NonNull<u8>>) -> Self {
+        let immediate_exit = immediate_exit.map_or(ptr::null_mut(), NonNull::as_ptr);
+        RUN_TIMEOUT_EXPIRED.with(|e| e.set(false));
+        RUN_TIMEOUT_IMMEDIATE_EXIT.with(|e| e.set(immediate_exit));
+        RunTimeoutExit(immediate_exit)
+    }
+}
+
+impl Drop for RunTimeoutExit {
+    fn drop(&mut self) {
+        RUN_TIMEOUT_IMMEDIATE_EXIT.with(|e| e.set(ptr::null_mut()));
+        // Only undo what the timer did, a kick set by another thread must
+        // still stop the next run
+        if RUN_TIMEOUT_EXPIRED.with(Cell::get) {
+            // SAFETY: the state outlives the run_with_timeout() call
+            unsafe { self.0.write_volatile(0) };
+        }
+    }
+}
+
+/// POSIX timer sending a signal to the thread which created it.
+struct RunTimer(libc::timer_t);
+
+impl RunTimer {
+    fn new(signum: i32) -> io::Result<Self> {
+        // SAFETY: all zeros is a valid pattern
+        let mut sev: libc::sigevent = unsafe { std::mem::zeroed() };
+        sev.sigev_notify = libc::SIGEV_THREAD_ID;
+        sev.sigev_signo = signum;
+        // SAFETY: FFI call, trivially safe
+        sev.sigev_notify_thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+
+        let mut timer: libc::timer_t = std::ptr::null_mut();
+        // SAFETY: FFI call with valid pointers
+        let ret = unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut sev, &mut timer) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(RunTimer(timer))
+    }
+
+    fn arm(&self, timeout: Duration) -> io::Result<()> {
+        let timespec = |d: Duration| libc::timespec {
+            tv_sec: d.as_secs() as libc::time_t,
+            tv_nsec: d.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec {
+            it_interval: timespec(RUN_TIMEOUT_KICK_INTERVAL),
+            // A zero value would disarm the timer
+            it_value: timespec(timeout.max(Duration::from_nanos(1))),
+        };
+
+        // SAFETY: FFI call with a valid timer and valid pointers
+        let ret = unsafe { libc::timer_settime(self.0, 0, &spec, std::ptr::null_mut()) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for RunTimer {
+    fn drop(&mut self) {
+        // SAFETY: FFI call with a valid timer
+        unsafe { libc::timer_delete(self.0) };
+    }
 }
 
 ///
@@ -385,6 +808,87 @@ pub trait Vcpu: Send + Sync {
     fn set_sregs(&self, sregs: &SpecialRegisters) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     ///
+    /// Returns the control register `CRn`, `n` being one of 0, 2, 3, 4 or 8.
+    ///
+    /// CR2 holds the linear address of the last page fault and CR8 the task
+    /// priority.
+    ///
+    /// This is a convenience wrapper around `get_sregs()`, so each call
+    /// costs a full special registers read. Callers needing several control
+    /// registers, or running on a hot path such as exit handling, should
+    /// call `get_sregs()` once instead.
+    ///
+    fn get_cr(&self, n: u8) -> Result<u64> {
+        let sregs = self.get_sregs()?;
+        match n {
+            0 => Ok(sregs.cr0),
+            2 => Ok(sregs.cr2),
+            3 => Ok(sregs.cr3),
+            4 => Ok(sregs.cr4),
+            8 => Ok(sregs.cr8),
+            _ => Err(HypervisorCpuError::InvalidControlRegister(n)),
+        }
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets the control register `CRn` to `val`, `n` being one of 0, 2, 3, 4
+    /// or 8. The other special registers are left untouched.
+    ///
+    /// The value is not validated against the other registers: e.g. setting
+    /// CR0.PG without a valid CR3 makes the next guest instruction fault.
+    ///
+    /// This is a convenience wrapper doing a `get_sregs()` and `set_sregs()`
+    /// round trip. Callers updating several special registers should do that
+    /// round trip once themselves rather than calling this repeatedly.
+    ///
+    fn set_cr(&self, n: u8, val: u64) -> Result<()> {
+        if !matches!(n, 0 | 2 | 3 | 4 | 8) {
+            return Err(HypervisorCpuError::InvalidControlRegister(n));
+        }
+
+        let mut sregs = self.get_sregs()?;
+        match n {
+            0 => sregs.cr0 = val,
+            2 => sregs.cr2 = val,
+            3 => sregs.cr3 = val,
+            4 => sregs.cr4 = val,
+            _ => sregs.cr8 = val,
+        }
+        self.set_sregs(&sregs)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the location of the GDT, IDT, LDT and TSS of the vCPU.
+    ///
+    /// The tables live in guest memory, their content can be decoded with
+    /// `gdt::decode_gdt()` and `gdt::decode_idt()`.
+    ///
+    fn get_descriptor_tables(&self) -> Result<DescriptorTables> {
+        Ok(DescriptorTables::from(&self.get_sregs()?))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the hidden part of the CS, DS, ES, FS, GS, SS, TR and LDT
+    /// segment registers, as cached by the vCPU.
+    ///
+    /// The result is only valid while the vCPU is paused.
+    ///
+    fn get_segment_caches(&self) -> Result<Vec<SegmentCache>> {
+        Ok(segment_caches(&self.get_sregs()?))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the paging mode of the vCPU, derived from CR0.PG, CR4.PAE,
+    /// CR4.LA57 and EFER.LME, which tells how to walk its page tables.
+    ///
+    /// The guest can switch modes at any time, so the result is only valid
+    /// while the vCPU is paused.
+    ///
+    fn paging_mode(&self) -> Result<PagingMode> {
+        Ok(PagingMode::from(&self.get_sregs()?))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
     /// Returns the floating point state (FPU) from the vCPU.
     ///
     fn get_fpu(&self) -> Result<FpuState>;
@@ -405,6 +909,86 @@ pub trait Vcpu: Send + Sync {
     fn enable_hyperv_synic(&self) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     ///
+    /// Advertise crash notification support through HV_X64_MSR_CRASH_CTL,
+    /// a guest write to this MSR is then reported as `VmExit::GuestCrash`.
+    ///
+    fn enable_hyperv_crash_msrs(&self) -> Result<()> {
+        Err(HypervisorCpuError::EnableHyperVCrashMsrs(anyhow!(
+            "unsupported"
+        )))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Make guest accesses to the paravirtual MSRs whose feature bits are
+    /// cleared from CPUID leaf 0x4000_0001 fail with a #GP, instead of
+    /// being serviced anyway.
+    ///
+    fn enforce_pv_cpuid(&self) -> Result<()> {
+        Err(HypervisorCpuError::EnforcePvCpuid(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Relocate the LAPIC MMIO window to `base` through
+    /// `MSR_IA32_APIC_BASE`, keeping the enable, BSP and x2APIC bits.
+    ///
+    /// `base` must be 4KiB aligned and must not be backed by guest RAM. The
+    /// in-kernel LAPIC follows the MSR, while MSI addresses always target
+    /// the architectural 0xfee0_0000 window whatever the LAPIC base is, so
+    /// the GSI routes don't need to be updated.
+    ///
+    /// Moving any vCPU away from the default base makes KVM inhibit APICv
+    /// and AVIC for the whole VM, not only for this vCPU: all the interrupts
+    /// and APIC accesses are then emulated, which is much slower.
+    ///
+    fn set_apic_base(&self, _base: u64) -> Result<()> {
+        Err(HypervisorCpuError::SetApicBase(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Advertise or hide the KVM asynchronous page faults, which let the
+    /// guest schedule another task while the host faults a page in, instead
+    /// of stalling the vCPU. This helps with overcommitted memory, but the
+    /// extra guest interrupts hurt the latency of real-time guests.
+    ///
+    /// This must be called once the CPUID is set and before the vCPU runs.
+    /// Enabling fails if the CPUID doesn't already advertise the feature,
+    /// e.g. when the host doesn't support it. The guest sets the feature up
+    /// through `MSR_KVM_ASYNC_PF_EN` and `MSR_KVM_ASYNC_PF_INT`, which are
+    /// part of the saved MSRs.
+    ///
+    fn configure_async_pf(&self, _enabled: bool) -> Result<()> {
+        Err(HypervisorCpuError::ConfigureAsyncPf(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the SMBASE of the vCPU, where its SMRAM state save area
+    /// lives. It is 0x30000 after reset, and the value returned reflects any
+    /// relocation done by the SMM handler since, as RSM reloads SMBASE from
+    /// the state save area.
+    ///
+    /// SMBASE is part of the saved MSRs when SMM is supported, so a guest
+    /// which relocated it keeps taking SMIs at the right place once
+    /// restored.
+    ///
+    fn get_smbase(&self) -> Result<u64> {
+        Err(HypervisorCpuError::GetSmbase(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Set the SMBASE of the vCPU, e.g. to start with an already relocated
+    /// SMRAM.
+    ///
+    /// This must be done while the vCPU is out of SMM, i.e. the SMM flag of
+    /// its vCPU events is clear: in SMM, RSM reads the state save area at
+    /// the current SMBASE and reloads SMBASE from it. When restoring a
+    /// state, SMBASE is written along with the other MSRs, before the vCPU
+    /// events possibly set the SMM flag again.
+    ///
+    fn set_smbase(&self, _smbase: u64) -> Result<()> {
+        Err(HypervisorCpuError::SetSmbase(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
     /// X86 specific call to retrieve the CPUID registers.
     ///
     fn get_cpuid2(&self, num_entries: usize) -> Result<Vec<CpuIdEntry>>;
@@ -425,9 +1009,141 @@ pub trait Vcpu: Send + Sync {
     fn get_msrs(&self, msrs: &mut Vec<MsrEntry>) -> Result<usize>;
     #[cfg(target_arch = "x86_64")]
     ///
+    /// Read the MSRs listed in `indices` with a single `get_msrs()` call,
+    /// returning their `(index, value)` pairs in the same order.
+    ///
+    /// The hypervisor stops at the first MSR it can't read, whose index
+    /// is then reported through `HypervisorCpuError::ReadMsr`.
+    ///
+    fn read_msrs(&self, indices: &[u32]) -> Result<Vec<(u32, u64)>> {
+        let mut msrs: Vec<MsrEntry> = indices
+            .iter()
+            .map(|&index| MsrEntry {
+                index,
+                ..Default::default()
+            })
+            .collect();
+
+        let count = self.get_msrs(&mut msrs)?;
+        if count < msrs.len() {
+            return Err(HypervisorCpuError::ReadMsr(msrs[count].index));
+        }
+
+        Ok(msrs.iter().map(|m| (m.index, m.data)).collect())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the guest view of the TSC, read through `IA32_TSC`: the host
+    /// TSC at the time of the call, with the TSC scaling ratio and then the
+    /// TSC offset of the vCPU applied.
+    ///
+    /// The vCPU must not be running, i.e. it must be paused or this must be
+    /// called from its thread. The guest TSC keeps counting while the vCPU
+    /// is paused, so the value is the one at the time of the read, not the
+    /// one of the last pause, and the values of different vCPUs are only
+    /// comparable when read close together.
+    ///
+    fn read_guest_tsc(&self) -> Result<u64> {
+        use crate::arch::x86::msr_index::MSR_IA32_TSC;
+
+        Ok(self.read_msrs(&[MSR_IA32_TSC])?[0].1)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
     /// Setup the model-specific registers (MSR) for this vCPU.
     ///
     fn set_msrs(&self, msrs: &[MsrEntry]) -> Result<usize>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Write the `(index, value)` pairs of `entries` with a single
+    /// `set_msrs()` call, returning how many were written.
+    ///
+    /// The hypervisor writes them in order and stops at the first MSR it
+    /// can't write, whose index is then reported through
+    /// `HypervisorCpuError::WriteMsr` along with the number of MSRs written
+    /// before it. The caller is responsible for ordering MSRs depending on
+    /// each other, e.g. `IA32_EFER` must come before the MSRs it enables.
+    ///
+    fn write_msrs(&self, entries: &[(u32, u64)]) -> Result<usize> {
+        let msrs: Vec<MsrEntry> = entries
+            .iter()
+            .map(|&(index, data)| MsrEntry { index, data })
+            .collect();
+
+        let count = self.set_msrs(&msrs)?;
+        if count < msrs.len() {
+            return Err(HypervisorCpuError::WriteMsr(msrs[count].index, count));
+        }
+
+        Ok(count)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Set `IA32_TSC_AUX`, returned to the guest by RDTSCP and RDPID. Guests
+    /// rely on it to identify the CPU they run on, e.g. Linux stores the CPU
+    /// and node numbers in it, so it must be preserved across migration.
+    ///
+    fn set_tsc_aux(&self, val: u64) -> Result<()> {
+        use crate::arch::x86::msr_index::MSR_TSC_AUX;
+
+        // The upper 32 bits are reserved
+        if val > u64::from(u32::MAX) {
+            return Err(HypervisorCpuError::SetTscAux(anyhow!(
+                "Reserved bits set in 0x{val:x}"
+            )));
+        }
+
+        self.write_msrs(&[(MSR_TSC_AUX, val)])
+            .map_err(|e| HypervisorCpuError::SetTscAux(e.into()))?;
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns `IA32_MISC_ENABLE`, which holds processor feature controls
+    /// such as Enhanced SpeedStep or the XD Bit Disable.
+    ///
+    fn misc_enable(&self) -> Result<u64> {
+        use crate::arch::x86::msr_index::MSR_IA32_MISC_ENABLE;
+
+        Ok(self.read_msrs(&[MSR_IA32_MISC_ENABLE])?[0].1)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Set the `set` bits of `IA32_MISC_ENABLE` and clear the `clear` ones,
+    /// the other bits are left as is.
+    ///
+    /// On hardware, setting XD Bit Disable (bit 34) hides NX from CPUID
+    /// 0x8000_0001 EDX. The guest CPUID is not updated along with it, the bit
+    /// can then only be set when NX is not advertised, and cleared when it is.
+    ///
+    fn set_misc_enable_bits(&self, _set: u64, _clear: u64) -> Result<()> {
+        Err(HypervisorCpuError::SetMiscEnable(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the guest XCR0, the mask of the XSAVE state components it
+    /// enabled.
+    ///
+    fn get_xcr0(&self) -> Result<u64> {
+        Err(HypervisorCpuError::GetXcsr(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Set the guest XCR0. When restoring a vCPU, this must happen before the
+    /// XSAVE state is loaded, the components not enabled in XCR0 would be
+    /// rejected otherwise.
+    ///
+    fn set_xcr0(&self, _xcr0: u64) -> Result<()> {
+        Err(HypervisorCpuError::SetXcsr(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Make the hypervisor report the vCPU steal time into the guest
+    /// buffer at `gpa`, as the guest does through MSR_KVM_STEAL_TIME.
+    ///
+    fn enable_steal_time(&self, _gpa: u64) -> Result<()> {
+        Err(HypervisorCpuError::EnableStealTime(anyhow!("unsupported")))
+    }
     ///
     /// Returns the vcpu's current "multiprocessing state".
     ///
@@ -491,6 +1207,21 @@ pub trait Vcpu: Send + Sync {
     #[cfg(target_arch = "aarch64")]
     fn get_sys_reg(&self, sys_reg: u32) -> Result<u64>;
     ///
+    /// Returns the EL1&0 stage 1 translation regime of the vCPU, decoded
+    /// from SCTLR_EL1 and TCR_EL1: whether the MMU is on, and the granule
+    /// and VA size of each range.
+    ///
+    /// The guest can reprogram these registers at any time, so the result
+    /// is only valid while the vCPU is paused.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn paging_mode(&self) -> Result<PagingMode> {
+        Ok(PagingMode::new(
+            self.get_sys_reg(SCTLR_EL1)?,
+            self.get_sys_reg(TCR_EL1)?,
+        ))
+    }
+    ///
     /// Gets the value of a non-core register on RISC-V 64-bit
     ///
     #[cfg(target_arch = "riscv64")]
@@ -501,6 +1232,32 @@ pub trait Vcpu: Send + Sync {
     #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
     fn setup_regs(&self, cpu_id: u32, boot_ip: u64, fdt_start: u64) -> Result<()>;
     ///
+    /// Set the address the vCPU starts executing at.
+    ///
+    /// On the boot vCPU this is the entry point of the payload. Secondary
+    /// vCPUs are created powered off and only start once the guest brings
+    /// them up through PSCI CPU_ON, at which point the entry address passed
+    /// by the guest replaces the value set here.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_reset_vector(&self, _pc: u64) -> Result<()> {
+        Err(HypervisorCpuError::SetAarchCoreRegister(anyhow!(
+            "unsupported"
+        )))
+    }
+    ///
+    /// Make a virtual SError pending, with the syndrome `esr` (ISS field of
+    /// ESR_EL1). An `esr` of 0 lets the hypervisor pick an implementation
+    /// defined syndrome.
+    ///
+    /// The SError is only taken once the guest unmasks it through PSTATE.A,
+    /// until then it is held pending, as on hardware.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn inject_serror(&self, _esr: u64) -> Result<()> {
+        Err(HypervisorCpuError::InjectSError(anyhow!("unsupported")))
+    }
+    ///
     /// Check if the CPU supports PMU
     ///
     #[cfg(target_arch = "aarch64")]
@@ -524,6 +1281,45 @@ pub trait Vcpu: Send + Sync {
     /// Triggers the running of the current virtual CPU returning an exit reason.
     ///
     fn run(&mut self) -> std::result::Result<VmExit, HypervisorCpuError>;
+    ///
+    /// Runs the vCPU like `run()`, but returns `VmExit::Timeout` if the
+    /// guest did not exit on its own before `timeout` elapsed.
+    ///
+    /// The deadline is enforced by a POSIX timer sending `signum` to the
+    /// calling thread, whose handler, registered by the caller with
+    /// `register_run_timeout_handler()`, sets the "immediate_exit" state so
+    /// that the guest isn't entered again once it has passed. The state is
+    /// cleared on return only if the timer did set it. The resolution is
+    /// bounded by the signal delivery latency and the timer slack of the
+    /// thread: the guest can keep running for up to a few hundred
+    /// microseconds past the deadline. Without "immediate_exit" state, this
+    /// can extend by up to a millisecond if the first signal raced with
+    /// entering the guest.
+    ///
+    fn run_with_timeout(
+        &mut self,
+        timeout: Duration,
+        signum: i32,
+    ) -> std::result::Result<VmExit, HypervisorCpuError> {
+        let immediate_exit = RunTimeoutExit::new(self.immediate_exit_ptr());
+        let timer = RunTimer::new(signum).map_err(|e| HypervisorCpuError::RunVcpu(e.into()))?;
+        let start = Instant::now();
+        timer
+            .arm(timeout)
+            .map_err(|e| HypervisorCpuError::RunVcpu(e.into()))?;
+
+        let exit = self.run();
+        drop(timer);
+        drop(immediate_exit);
+        let exit = exit?;
+
+        // Being interrupted by the timer is reported as VmExit::Ignore
+        if matches!(exit, VmExit::Ignore) && start.elapsed() >= timeout {
+            return Ok(VmExit::Timeout);
+        }
+
+        Ok(exit)
+    }
     #[cfg(target_arch = "x86_64")]
     ///
     /// Translate guest virtual address to guest physical address
@@ -540,6 +1336,13 @@ pub trait Vcpu: Send + Sync {
     /// Set the "immediate_exit" state
     ///
     fn set_immediate_exit(&mut self, _exit: bool) {}
+    ///
+    /// Returns the location of the "immediate_exit" state, for it to be set
+    /// from a signal handler while the vCPU runs.
+    ///
+    fn immediate_exit_ptr(&mut self) -> Option<NonNull<u8>> {
+        None
+    }
     #[cfg(feature = "tdx")]
     ///
     /// Returns the details about TDX exit reason
@@ -603,4 +1406,152 @@ pub trait Vcpu: Send + Sync {
     /// Trigger NMI interrupt
     ///
     fn nmi(&self) -> Result<()>;
+    ///
+    /// Returns the halt-polling statistics of the vCPU
+    ///
+    fn halt_poll_stats(&self) -> Result<HaltPollStats> {
+        Err(HypervisorCpuError::GetStats(anyhow!("unsupported")))
+    }
+    ///
+    /// Returns the MMU page fault statistics of the vCPU
+    ///
+    fn get_mmu_stats(&self) -> Result<MmuStats> {
+        Err(HypervisorCpuError::GetStats(anyhow!("unsupported")))
+    }
+    ///
+    /// Set the observer called on each exit, replacing the previous one
+    ///
+    fn set_exit_observer(&self, _obs: Arc<dyn VcpuExitObserver>) -> Result<()> {
+        Err(HypervisorCpuError::SetExitObserver(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the bytes of the instruction behind the last IO or MMIO exit,
+    /// or behind the last emulation failure, `None` if not available.
+    ///
+    /// The hypervisor reports the exact instruction for some exits only.
+    /// Otherwise up to 15 bytes, the maximum instruction length, are read
+    /// from guest memory at RIP while it still points at the instruction,
+    /// and the instruction may be shorter. KVM moves RIP past MMIO writes
+    /// before exiting, `None` is returned for these.
+    ///
+    fn last_exit_instruction(&self) -> Option<Vec<u8>> {
+        None
+    }
+    ///
+    /// Reset the vCPU to its power-on state, without recreating it
+    ///
+    /// On x86_64 the BSP is left runnable at the reset vector while the APs
+    /// wait for INIT/SIPI, the BSP is identified by the APIC base. On aarch64
+    /// the vCPU is initialized again the same way it was first initialized,
+    /// the secondary vCPUs are therefore powered off.
+    ///
+    fn reset(&self) -> Result<()> {
+        Err(HypervisorCpuError::ResetVcpu(anyhow!("unsupported")))
+    }
+    ///
+    /// Returns the current privilege level: the RPL of CS on x86_64 (0 in
+    /// real mode and 3 in virtual-8086 mode), the exception level on aarch64.
+    ///
+    /// The value is read from the saved register state and is therefore
+    /// only meaningful while the vCPU is paused.
+    ///
+    fn current_privilege_level(&self) -> Result<u8> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            use crate::arch::x86::regs::{CR0_PE, VM};
+
+            let sregs = self.get_sregs()?;
+            if sregs.cr0 & CR0_PE == 0 {
+                return Ok(0);
+            }
+            if self.get_regs()?.get_rflags() & VM != 0 {
+                return Ok(3);
+            }
+            Ok((sregs.cs.selector & 0x3) as u8)
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            // PSTATE.M[4] is set in AArch32 state, where only EL0 (User
+            // mode) and EL1 can be running under KVM.
+            let pstate = self.get_regs()?.get_pstate();
+            if pstate & 0x10 != 0 {
+                return Ok(if pstate & 0xf == 0 { 0 } else { 1 });
+            }
+            Ok(((pstate >> 2) & 0x3) as u8)
+        }
+        #[cfg(target_arch = "riscv64")]
+        {
+            Err(HypervisorCpuError::GetStandardRegs(anyhow!("unsupported")))
+        }
+    }
+    ///
+    /// Whether the guest has maskable interrupts enabled: RFLAGS.IF is set
+    /// on x86_64, PSTATE.I (the IRQ mask) is cleared on aarch64.
+    ///
+    /// The value is read from the saved register state and is therefore
+    /// only meaningful while the vCPU is paused at an instruction boundary.
+    /// On x86_64, an interrupt can still be held off by the interrupt
+    /// shadow of STI or MOV SS.
+    ///
+    fn interrupts_enabled(&self) -> Result<bool> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            use crate::arch::x86::regs::IF;
+
+            Ok(self.get_regs()?.get_rflags() & IF != 0)
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            use crate::arch::aarch64::regs::PSR_I_BIT;
+
+            Ok(self.get_regs()?.get_pstate() & PSR_I_BIT == 0)
+        }
+        #[cfg(target_arch = "riscv64")]
+        {
+            Err(HypervisorCpuError::GetStandardRegs(anyhow!("unsupported")))
+        }
+    }
+    ///
+    /// Pin the calling thread to the given set of host CPUs.
+    ///
+    /// This must be called from the thread running the vCPU.
+    ///
+    fn set_vcpu_affinity(&self, cpu_set: &[usize]) -> Result<()> {
+        if cpu_set.is_empty() {
+            return Err(HypervisorCpuError::SetVcpuAffinity(anyhow!(
+                "Empty host CPU set"
+            )));
+        }
+
+        // SAFETY: FFI call, trivially safe
+        let host_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+        // SAFETY: all zeros is a valid pattern
+        let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in cpu_set {
+            if cpu as libc::c_long >= host_cpus || cpu >= libc::CPU_SETSIZE as usize {
+                return Err(HypervisorCpuError::SetVcpuAffinity(anyhow!(
+                    "Host CPU {cpu} does not exist"
+                )));
+            }
+            // SAFETY: FFI call, trivially safe
+            unsafe { libc::CPU_SET(cpu, &mut cpuset) };
+        }
+
+        // SAFETY: FFI call with correct arguments
+        let ret = unsafe {
+            libc::sched_setaffinity(
+                0,
+                std::mem::size_of::<libc::cpu_set_t>(),
+                &cpuset as *const libc::cpu_set_t,
+            )
+        };
+        if ret != 0 {
+            return Err(HypervisorCpuError::SetVcpuAffinity(
+                std::io::Error::last_os_error().into(),
+            ));
+        }
+
+        Ok(())
+    }
 }
@@ -9,13 +9,24 @@
 //
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
+#[cfg(target_arch = "x86_64")]
+use std::sync::OnceLock;
 
 use thiserror::Error;
 
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86::CpuIdEntry;
 #[cfg(target_arch = "x86_64")]
+use crate::arch::x86::avx512::Avx512Subsets;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::features::{self, CpuFeature, MitigationMsrs};
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::xsave::XsaveLayout;
+#[cfg(target_arch = "x86_64")]
 use crate::cpu::CpuVendor;
 #[cfg(feature = "tdx")]
 use crate::kvm::TdxCapabilities;
@@ -37,8 +48,12 @@ pub enum HypervisorError {
     ///
     /// Vm creation failure
     ///
-    #[error("Failed to create Vm")]
-    VmCreate(#[source] anyhow::Error),
+    #[error("Failed to create Vm: {context}")]
+    VmCreate {
+        context: VmCreateContext,
+        #[source]
+        source: anyhow::Error,
+    },
     ///
     /// Vm setup failure
     ///
@@ -60,6 +75,11 @@ pub enum HypervisorError {
     #[error("Failed to get the list of supported MSRs")]
     GetMsrList(#[source] anyhow::Error),
     ///
+    /// Failed to retrieve a feature MSR
+    ///
+    #[error("Failed to get feature MSR")]
+    GetFeatureMsr(#[source] anyhow::Error),
+    ///
     /// API version is not compatible
     ///
     #[error("Incompatible API version")]
@@ -89,6 +109,69 @@ pub enum HypervisorError {
     ///
     #[error("Unsupported VmType")]
     UnsupportedVmType(),
+    ///
+    /// Invalid number of vCPUs requested
+    ///
+    #[error("Invalid number of vCPUs")]
+    InvalidVcpuCount(#[source] anyhow::Error),
+    ///
+    /// Failed to retrieve the kernel module parameters
+    ///
+    #[error("Failed to get kernel module parameters")]
+    GetModuleParams(#[source] anyhow::Error),
+    ///
+    /// SEV not supported or not enabled on the host
+    ///
+    #[cfg(target_arch = "x86_64")]
+    #[error("SEV is not supported by the host")]
+    SevUnsupported,
+    ///
+    /// Failed to retrieve the SEV ASIDs
+    ///
+    #[cfg(target_arch = "x86_64")]
+    #[error("Failed to get the SEV ASIDs")]
+    GetSevAsidInfo(#[source] anyhow::Error),
+    ///
+    /// Handling MSR accesses from userspace not supported by the host
+    ///
+    #[cfg(target_arch = "x86_64")]
+    #[error("Handling the RAPL MSRs is not supported by the host")]
+    RaplUnsupported,
+    ///
+    /// Failed to enable the handling of the RAPL MSRs
+    ///
+    #[cfg(target_arch = "x86_64")]
+    #[error("Failed to enable the handling of the RAPL MSRs")]
+    EnableRapl(#[source] anyhow::Error),
+    ///
+    /// Memory Tagging Extension not supported by the host
+    ///
+    #[cfg(target_arch = "aarch64")]
+    #[error("Memory Tagging Extension is not supported by the host")]
+    MteUnsupported,
+    ///
+    /// Failed to enable the Memory Tagging Extension
+    ///
+    #[cfg(target_arch = "aarch64")]
+    #[error("Failed to enable the Memory Tagging Extension")]
+    EnableMte(#[source] anyhow::Error),
+    ///
+    /// Forwarding PSCI SYSTEM_SUSPEND not supported by the host
+    ///
+    #[cfg(target_arch = "aarch64")]
+    #[error("PSCI SYSTEM_SUSPEND forwarding is not supported by the host")]
+    SystemSuspendUnsupported,
+    ///
+    /// Failed to enable the forwarding of PSCI SYSTEM_SUSPEND
+    ///
+    #[cfg(target_arch = "aarch64")]
+    #[error("Failed to enable PSCI SYSTEM_SUSPEND forwarding")]
+    EnableSystemSuspend(#[source] anyhow::Error),
+    ///
+    /// Snapshot taken on an incompatible host
+    ///
+    #[error("Snapshot is incompatible with this host")]
+    IncompatibleSnapshot(#[source] anyhow::Error),
 }
 
 ///
@@ -96,6 +179,270 @@ pub enum HypervisorError {
 ///
 pub type Result<T> = std::result::Result<T, HypervisorError>;
 
+/// Types of VM a hypervisor may be able to create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmType {
+    /// Regular VM
+    Default,
+    /// VM with private memory, without hardware protection
+    SwProtected,
+    /// AMD SEV
+    Sev,
+    /// AMD SEV-ES
+    SevEs,
+    /// AMD SEV-SNP
+    SevSnp,
+    /// Intel TDX
+    Tdx,
+}
+
+/// Kernel context of a VM creation failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmCreateContext {
+    /// Operation that failed, e.g. `KVM_CREATE_VM`
+    pub operation: &'static str,
+    /// Type of the VM requested from the hypervisor
+    pub vm_type: u64,
+    /// Error number returned by the kernel, if any
+    pub errno: Option<i32>,
+}
+
+impl VmCreateContext {
+    /// Likely cause of the failure, derived from the error number.
+    pub fn reason(&self) -> &'static str {
+        match self.errno {
+            Some(libc::EACCES | libc::EPERM) => "no permission to access the hypervisor device",
+            Some(libc::ENOENT | libc::ENODEV | libc::ENXIO) => "hypervisor device not available",
+            Some(libc::EINVAL) => "unsupported VM type",
+            Some(libc::ENOMEM) => "out of memory",
+            Some(libc::EMFILE | libc::ENFILE) => "too many open files",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for VmCreateContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} failed for VM type {} ({})",
+            self.operation,
+            self.vm_type,
+            self.reason()
+        )
+    }
+}
+
+/// Effective parameters of the KVM kernel modules.
+///
+/// This is only meant for diagnostic purposes, the typed fields are `None`
+/// when the corresponding parameter is not exposed by the host kernel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KvmModuleParams {
+    /// Nested virtualization (`kvm_intel.nested` or `kvm_amd.nested`)
+    pub nested: Option<bool>,
+    /// APIC virtualization (`kvm_intel.enable_apicv` or `kvm_amd.avic`)
+    pub enable_apicv: Option<bool>,
+    /// TDP MMU (`kvm.tdp_mmu`)
+    pub tdp_mmu: Option<bool>,
+    /// Default halt-polling window (`kvm.halt_poll_ns`)
+    pub halt_poll_ns: Option<u64>,
+    /// All the readable parameters, indexed by `<module>.<parameter>`
+    pub raw: BTreeMap<String, String>,
+}
+
+/// MMU the hypervisor manages the guest memory mappings with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmuMode {
+    /// TDP MMU, dedicated to two-dimensional paging (EPT or NPT)
+    Tdp,
+    /// Legacy shadow MMU, used when the TDP MMU is disabled or when the
+    /// host runs without two-dimensional paging
+    Shadow,
+    /// The MMU could not be determined from the host kernel
+    Unknown,
+}
+
+/// Pause loop exiting parameters, in effect for all the VMs of the host.
+///
+/// A vCPU exits once it has been spinning for `window` with less than `gap`
+/// between two consecutive PAUSE instructions. The window is adjusted per
+/// vCPU at runtime, starting from `window` and growing up to `window_max`.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PleConfig {
+    /// `kvm_intel.ple_gap` (cycles), `kvm_amd.pause_filter_thresh` (cycles)
+    pub gap: u32,
+    /// `kvm_intel.ple_window` (cycles), `kvm_amd.pause_filter_count` (PAUSE
+    /// instructions)
+    pub window: u32,
+    /// `kvm_intel.ple_window_grow`, `kvm_amd.pause_filter_count_grow`
+    pub window_grow: u32,
+    /// `kvm_intel.ple_window_shrink`, `kvm_amd.pause_filter_count_shrink`
+    pub window_shrink: u32,
+    /// `kvm_intel.ple_window_max`, `kvm_amd.pause_filter_count_max`
+    pub window_max: u32,
+}
+
+/// SEV address space identifiers (ASIDs) of the host. Each encrypted guest
+/// runs with its own ASID, which selects its memory encryption key, so the
+/// number of ASIDs bounds the number of SEV guests the host can run.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SevAsidInfo {
+    /// Number of ASIDs, CPUID.(EAX=8000_001FH):ECX
+    pub asids: u32,
+    /// Number of the ASIDs only usable by SEV-ES and SEV-SNP guests, the
+    /// ones below CPUID.(EAX=8000_001FH):EDX
+    pub es_asids: u32,
+    /// Number of guests the SEV firmware currently holds a key for, for all
+    /// the VMMs of the host
+    pub guests: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SevAsidInfo {
+    /// Number of ASIDs left for new encrypted guests.
+    pub fn available(&self) -> u32 {
+        self.asids.saturating_sub(self.guests)
+    }
+}
+
+/// Host description stored in a snapshot, checked before restoring it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct SnapshotHeader {
+    /// Architecture the snapshot was taken on, as in `std::env::consts::ARCH`
+    pub arch: String,
+    /// Hypervisor the snapshot was taken with, e.g. `Kvm`
+    pub hypervisor_type: String,
+    /// Bitmap of the [`SNAPSHOT_FEATURES`] exposed to the guest
+    pub feature_fingerprint: u64,
+}
+
+/// CPU features whose state is part of the vCPU state, a snapshot using them
+/// cannot be restored on a host lacking them. The position of each entry
+/// is its bit in `SnapshotHeader::feature_fingerprint`, entries must only
+/// be appended.
+///
+/// Each entry is the feature name, followed by the CPUID leaf, subleaf,
+/// register (0 to 3 for EAX to EDX) and bit reporting it.
+#[cfg(target_arch = "x86_64")]
+pub const SNAPSHOT_FEATURES: [(&str, u32, u32, u8, u8); 6] = [
+    ("xsave", 0x1, 0, 2, 26),
+    ("avx", 0x1, 0, 2, 28),
+    ("avx512f", 0x7, 0, 1, 16),
+    ("pku", 0x7, 0, 2, 3),
+    ("la57", 0x7, 0, 2, 16),
+    ("amx-tile", 0x7, 0, 3, 24),
+];
+
+/// Returns the signature of the hypervisor the host itself runs under, read
+/// from CPUID leaf 0x4000_0000, or `None` when the host is not a guest
+/// (CPUID.(EAX=1):ECX[31] is clear). The signature is empty when the
+/// hypervisor does not report one.
+///
+/// The host CPUID is only probed on the first call.
+#[cfg(target_arch = "x86_64")]
+pub fn host_hypervisor_signature() -> Option<&'static str> {
+    static SIGNATURE: OnceLock<Option<String>> = OnceLock::new();
+
+    SIGNATURE
+        .get_or_init(|| {
+            // SAFETY: call cpuid with valid leaves
+            if unsafe { x86_64::__cpuid(1) }.ecx & (1 << 31) == 0 {
+                return None;
+            }
+
+            // SAFETY: call cpuid with valid leaves
+            let leaf = unsafe { x86_64::__cpuid(0x4000_0000) };
+            let mut signature = [0u8; 12];
+            signature[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+            signature[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+            signature[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+
+            Some(
+                String::from_utf8_lossy(&signature)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            )
+        })
+        .as_deref()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn snapshot_feature_fingerprint(cpuid: &[CpuIdEntry]) -> u64 {
+    SNAPSHOT_FEATURES
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, function, index, reg, bit))| {
+            cpuid
+                .iter()
+                .find(|e| e.function == *function && e.index == *index)
+                .map(|e| [e.eax, e.ebx, e.ecx, e.edx][*reg as usize] & (1 << bit) != 0)
+                .unwrap_or(false)
+        })
+        .fold(0, |fingerprint, (i, _)| fingerprint | (1 << i))
+}
+
+impl KvmModuleParams {
+    /// Build the parameters from the raw `<module>.<parameter>` values.
+    pub fn from_raw(raw: BTreeMap<String, String>) -> Self {
+        let value = |names: &[&str]| names.iter().find_map(|name| raw.get(*name));
+        let bool_value = |names: &[&str]| match value(names).map(|v| v.as_str()) {
+            Some("Y" | "y" | "1") => Some(true),
+            Some("N" | "n" | "0") => Some(false),
+            _ => None,
+        };
+
+        let nested = bool_value(&["kvm_intel.nested", "kvm_amd.nested"]);
+        let enable_apicv = bool_value(&["kvm_intel.enable_apicv", "kvm_amd.avic"]);
+        let tdp_mmu = bool_value(&["kvm.tdp_mmu"]);
+        let halt_poll_ns = value(&["kvm.halt_poll_ns"]).and_then(|v| v.parse().ok());
+
+        KvmModuleParams {
+            nested,
+            enable_apicv,
+            tdp_mmu,
+            halt_poll_ns,
+            raw,
+        }
+    }
+
+    /// Pause loop exiting parameters, `None` if it is disabled or if the
+    /// parameters are not exposed.
+    #[cfg(target_arch = "x86_64")]
+    pub fn ple_config(&self) -> Option<PleConfig> {
+        let value = |name: &str| -> Option<u32> { self.raw.get(name)?.parse().ok() };
+
+        let intel = self.raw.contains_key("kvm_intel.ple_gap");
+        let config = if intel {
+            PleConfig {
+                gap: value("kvm_intel.ple_gap")?,
+                window: value("kvm_intel.ple_window")?,
+                window_grow: value("kvm_intel.ple_window_grow").unwrap_or_default(),
+                window_shrink: value("kvm_intel.ple_window_shrink").unwrap_or_default(),
+                window_max: value("kvm_intel.ple_window_max").unwrap_or_default(),
+            }
+        } else {
+            PleConfig {
+                gap: value("kvm_amd.pause_filter_thresh")?,
+                window: value("kvm_amd.pause_filter_count")?,
+                window_grow: value("kvm_amd.pause_filter_count_grow").unwrap_or_default(),
+                window_shrink: value("kvm_amd.pause_filter_count_shrink").unwrap_or_default(),
+                window_max: value("kvm_amd.pause_filter_count_max").unwrap_or_default(),
+            }
+        };
+
+        // A zero gap disables PLE on Intel, a zero count disables the pause
+        // filter on AMD.
+        if (intel && config.gap == 0) || config.window == 0 {
+            return None;
+        }
+
+        Some(config)
+    }
+}
+
 ///
 /// Trait to represent a Hypervisor
 ///
@@ -111,11 +458,111 @@ pub trait Hypervisor: Send + Sync {
     /// Return a hypervisor-agnostic Vm trait object
     ///
     fn create_vm(&self, config: HypervisorVmConfig) -> Result<Arc<dyn Vm>>;
+    ///
+    /// Create a Vm from a VM file descriptor created by another process,
+    /// taking ownership of it
+    ///
+    fn create_vm_from_fd(&self, _fd: RawFd, _config: HypervisorVmConfig) -> Result<Arc<dyn Vm>> {
+        Err(HypervisorError::VmCreate {
+            context: VmCreateContext {
+                operation: "create_vm_from_fd",
+                vm_type: 0,
+                errno: None,
+            },
+            source: anyhow!("unsupported"),
+        })
+    }
     #[cfg(target_arch = "x86_64")]
     ///
     /// Get the supported CpuID
     ///
     fn get_supported_cpuid(&self) -> Result<Vec<CpuIdEntry>>;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Enable or disable caching the supported CpuID across `create_vm()`
+    /// calls, the cache is dropped either way
+    ///
+    fn set_cpuid_cache(&self, _enabled: bool) {}
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Drop the cached supported CpuID, it is queried again on next use
+    ///
+    fn invalidate_cpuid_cache(&self) {}
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the indices of the MSRs to save and restore when migrating a vCPU
+    ///
+    fn migration_msr_indices(&self) -> Result<Vec<u32>> {
+        Err(HypervisorError::GetMsrList(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the value of a feature MSR, such as `IA32_ARCH_CAPABILITIES`, as
+    /// the hypervisor supports exposing it to the guests
+    ///
+    fn get_feature_msr(&self, _index: u32) -> Result<u64> {
+        Err(HypervisorError::GetFeatureMsr(anyhow!("unsupported")))
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Check whether a CPU feature can actually be used by a guest
+    ///
+    /// On top of the supported CPUID this consults `IA32_ARCH_CAPABILITIES`,
+    /// `IA32_TSX_CTRL` and `IA32_TSX_FORCE_ABORT` on the host, see
+    /// [`crate::arch::x86::features`] for the bits that are checked. MSRs that
+    /// cannot be read are ignored.
+    ///
+    fn feature_effectively_available(&self, feature: CpuFeature) -> bool {
+        let Ok(cpuid) = self.get_supported_cpuid() else {
+            return false;
+        };
+
+        features::feature_effectively_available(feature, &cpuid, &MitigationMsrs::from_host())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the AVX-512 subsets which can be exposed to a guest, as enumerated
+    /// by the supported CPUID. None are reported if it can't be retrieved.
+    ///
+    fn avx512_subsets(&self) -> Avx512Subsets {
+        self.get_supported_cpuid()
+            .map(|cpuid| Avx512Subsets::from_cpuid(&cpuid))
+            .unwrap_or_default()
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the layout of the XSAVE area components which can be exposed to
+    /// a guest, as enumerated by the supported CPUID. It is empty if it
+    /// can't be retrieved.
+    ///
+    fn xsave_layout(&self) -> XsaveLayout {
+        self.get_supported_cpuid()
+            .map(|cpuid| XsaveLayout::from_cpuid(&cpuid))
+            .unwrap_or_default()
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Whether guests can enable 5-level paging, as enumerated by the
+    /// supported CPUID leaf 7 ECX bit 16 (LA57). This is only the case when
+    /// the host paging supports it as well.
+    ///
+    fn la57_supported(&self) -> bool {
+        self.get_supported_cpuid()
+            .map(|cpuid| {
+                cpuid
+                    .iter()
+                    .any(|e| e.function == 7 && e.index == 0 && e.ecx & (1 << 16) != 0)
+            })
+            .unwrap_or(false)
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Whether the Hyper-V TLB flush hypercalls are handled, which lets the
+    /// guests of the Hyper-V emulation be advised to use them.
+    ///
+    fn hyperv_tlb_flush_supported(&self) -> bool {
+        false
+    }
     ///
     /// Check particular extensions if any
     ///
@@ -143,6 +590,123 @@ pub trait Hypervisor: Send + Sync {
 
     /// Get maximum number of vCPUs
     fn get_max_vcpus(&self) -> u32;
+    ///
+    /// Get the types of VM that `create_vm()` can create
+    ///
+    fn supported_vm_types(&self) -> Vec<VmType> {
+        vec![VmType::Default]
+    }
+    ///
+    /// Retrieve the parameters of the KVM kernel modules
+    ///
+    fn kvm_module_params(&self) -> Result<KvmModuleParams> {
+        Err(HypervisorError::GetModuleParams(anyhow!("unsupported")))
+    }
+    ///
+    /// Report which MMU backs the guest memory mappings
+    ///
+    /// This is a cheap diagnostic, `MmuMode::Unknown` is returned when the
+    /// host doesn't tell.
+    ///
+    fn mmu_mode(&self) -> MmuMode {
+        MmuMode::Unknown
+    }
+    ///
+    /// Pause loop exiting parameters applied to the vCPUs, if enabled
+    ///
+    /// They can only be tuned for the whole host, through the parameters
+    /// of the KVM kernel modules.
+    ///
+    #[cfg(target_arch = "x86_64")]
+    fn ple_config(&self) -> Option<PleConfig> {
+        self.kvm_module_params().ok()?.ple_config()
+    }
+    ///
+    /// Retrieve the number of SEV ASIDs of the host and how many are in use
+    ///
+    /// Fails with `HypervisorError::SevUnsupported` when the host can't run
+    /// SEV guests.
+    ///
+    #[cfg(target_arch = "x86_64")]
+    fn sev_asid_info(&self) -> Result<SevAsidInfo> {
+        Err(HypervisorError::SevUnsupported)
+    }
+    ///
+    /// Describe the host, to be stored in a snapshot of a VM whose vCPUs
+    /// were given `cpuid`. The features the host supports but doesn't
+    /// expose to the guest don't prevent restoring elsewhere.
+    ///
+    fn snapshot_header(
+        &self,
+        #[cfg(target_arch = "x86_64")] cpuid: &[CpuIdEntry],
+    ) -> Result<SnapshotHeader> {
+        #[cfg(target_arch = "x86_64")]
+        let feature_fingerprint = snapshot_feature_fingerprint(cpuid);
+        #[cfg(not(target_arch = "x86_64"))]
+        let feature_fingerprint = 0;
+
+        Ok(SnapshotHeader {
+            arch: std::env::consts::ARCH.to_string(),
+            hypervisor_type: format!("{:?}", self.hypervisor_type()),
+            feature_fingerprint,
+        })
+    }
+    ///
+    /// Check that a snapshot taken on the host described by `header` can be
+    /// restored on this host, i.e. that this host supports all the features
+    /// exposed to the guest
+    ///
+    fn validate_snapshot_header(&self, header: &SnapshotHeader) -> Result<()> {
+        let host = self.snapshot_header(
+            #[cfg(target_arch = "x86_64")]
+            &self.get_supported_cpuid()?,
+        )?;
+
+        if header.arch != host.arch {
+            return Err(HypervisorError::IncompatibleSnapshot(anyhow!(
+                "Snapshot taken on {}, this host is {}",
+                header.arch,
+                host.arch
+            )));
+        }
+
+        if header.hypervisor_type != host.hypervisor_type {
+            return Err(HypervisorError::IncompatibleSnapshot(anyhow!(
+                "Snapshot taken with {}, this host uses {}",
+                header.hypervisor_type,
+                host.hypervisor_type
+            )));
+        }
+
+        let missing = header.feature_fingerprint & !host.feature_fingerprint;
+        if missing != 0 {
+            #[cfg(target_arch = "x86_64")]
+            let features = SNAPSHOT_FEATURES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| missing & (1 << i) != 0)
+                .map(|(_, (name, ..))| *name)
+                .collect::<Vec<_>>()
+                .join(", ");
+            #[cfg(not(target_arch = "x86_64"))]
+            let features = format!("0x{missing:x}");
+
+            return Err(HypervisorError::IncompatibleSnapshot(anyhow!(
+                "CPU features used by the snapshot are missing on this host: {features}"
+            )));
+        }
+
+        Ok(())
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Whether the host is itself a guest of another hypervisor, in which
+    /// case the VMs run nested: VM exits are much more expensive and some
+    /// features may be missing. See `host_hypervisor_signature()`.
+    ///
+    fn running_nested(&self) -> bool {
+        host_hypervisor_signature().is_some()
+    }
     #[cfg(target_arch = "x86_64")]
     ///
     /// Determine CPU vendor
@@ -167,3 +731,69 @@ pub trait Hypervisor: Send + Sync {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_arch = "x86_64")]
+    fn module_params(params: &[(&str, &str)]) -> KvmModuleParams {
+        KvmModuleParams::from_raw(
+            params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_ple_config() {
+        let intel = module_params(&[
+            ("kvm_intel.ple_gap", "128"),
+            ("kvm_intel.ple_window", "4096"),
+            ("kvm_intel.ple_window_grow", "2"),
+            ("kvm_intel.ple_window_shrink", "0"),
+            ("kvm_intel.ple_window_max", "4294967295"),
+        ]);
+        assert_eq!(
+            intel.ple_config(),
+            Some(PleConfig {
+                gap: 128,
+                window: 4096,
+                window_grow: 2,
+                window_shrink: 0,
+                window_max: u32::MAX,
+            })
+        );
+
+        // A zero gap disables PLE on Intel
+        let intel = module_params(&[("kvm_intel.ple_gap", "0"), ("kvm_intel.ple_window", "4096")]);
+        assert_eq!(intel.ple_config(), None);
+
+        // The grow, shrink and max parameters are optional
+        let amd = module_params(&[
+            ("kvm_amd.pause_filter_thresh", "0"),
+            ("kvm_amd.pause_filter_count", "3000"),
+        ]);
+        assert_eq!(
+            amd.ple_config(),
+            Some(PleConfig {
+                window: 3000,
+                ..Default::default()
+            })
+        );
+
+        // A zero count disables the pause filter on AMD
+        let amd = module_params(&[
+            ("kvm_amd.pause_filter_thresh", "128"),
+            ("kvm_amd.pause_filter_count", "0"),
+        ]);
+        assert_eq!(amd.ple_config(), None);
+
+        assert_eq!(
+            module_params(&[("kvm.halt_poll_ns", "200000")]).ple_config(),
+            None
+        );
+    }
+}
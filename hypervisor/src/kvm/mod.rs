@@ -11,19 +11,25 @@
 //
 
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs::File;
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 use std::mem::offset_of;
-#[cfg(feature = "tdx")]
-use std::os::unix::io::AsRawFd;
-#[cfg(feature = "tdx")]
-use std::os::unix::io::RawFd;
+#[cfg(target_arch = "x86_64")]
+use std::ops::{Range, RangeInclusive};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
 use std::result;
-#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
-use std::sync::Mutex;
 #[cfg(target_arch = "x86_64")]
+use std::sync::atomic::AtomicU32;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use kvm_ioctls::{NoDatamatch, VcpuFd, VmFd};
 use vmm_sys_util::eventfd::EventFd;
@@ -54,20 +60,24 @@ use crate::{HypervisorType, HypervisorVmConfig, cpu, hypervisor};
 pub mod x86_64;
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
-    KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP, KVM_CAP_X2APIC_API, KVM_GUESTDBG_USE_HW_BP,
-    KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK, KVM_X2APIC_API_USE_32BIT_IDS, MsrList, kvm_enable_cap,
-    kvm_msr_entry,
+    KVM_CAP_ENFORCE_PV_FEATURE_CPUID, KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP,
+    KVM_CAP_X2APIC_API, KVM_GUESTDBG_USE_HW_BP, KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK,
+    KVM_X2APIC_API_USE_32BIT_IDS, MsrList, kvm_enable_cap, kvm_msr_entry,
 };
 #[cfg(target_arch = "x86_64")]
 use x86_64::check_required_kvm_extensions;
 #[cfg(target_arch = "x86_64")]
-pub use x86_64::{CpuId, ExtendedControlRegisters, MsrEntries, VcpuKvmState};
+pub use x86_64::{CpuId, ExtendedControlRegisters, FpuStateFormat, MsrEntries, VcpuKvmState};
 
 #[cfg(target_arch = "x86_64")]
 use crate::ClockData;
 #[cfg(target_arch = "x86_64")]
+use crate::arch::x86::rapl::{RAPL_MSRS, RaplMsrs};
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::regs::{APIC_BASE_BSP, RESET_RFLAGS, RESET_RIP, reset_fpu, reset_sregs};
+#[cfg(target_arch = "x86_64")]
 use crate::arch::x86::{
-    CpuIdEntry, FpuState, LapicState, MsrEntry, NUM_IOAPIC_PINS, SpecialRegisters, XsaveState,
+    CpuIdEntry, FpuState, LapicState, MsrEntry, SpecialRegisters, XsaveState, msr_index, x2apic_id,
 };
 use crate::{
     CpuState, IoEventAddress, IrqRoutingEntry, MpState, StandardRegisters,
@@ -82,6 +92,8 @@ pub mod aarch64;
 pub mod riscv64;
 #[cfg(target_arch = "aarch64")]
 use std::mem;
+// Binary statistics
+mod stats;
 
 ///
 /// Export generically-named wrappers of kvm-bindings for Unix-based platforms
@@ -104,6 +116,10 @@ use kvm_bindings::{
     KVM_REG_ARM64_SYSREG_OP1_MASK, KVM_REG_ARM64_SYSREG_OP2_MASK, KVM_REG_SIZE_U32,
     KVM_REG_SIZE_U64, KVM_REG_SIZE_U128, kvm_regs, user_pt_regs,
 };
+use kvm_bindings::{
+    KVM_MEM_GUEST_MEMFD, KVM_MEMORY_ATTRIBUTE_PRIVATE, kvm_create_guest_memfd,
+    kvm_memory_attributes, kvm_userspace_memory_region2,
+};
 #[cfg(target_arch = "riscv64")]
 use kvm_bindings::{KVM_REG_RISCV_CORE, kvm_riscv_core};
 #[cfg(feature = "tdx")]
@@ -111,10 +127,20 @@ use kvm_bindings::{KVM_X86_DEFAULT_VM, KVM_X86_SW_PROTECTED_VM, KVMIO, kvm_run__
 pub use kvm_ioctls::{Cap, Kvm, VcpuExit};
 use thiserror::Error;
 use vfio_ioctls::VfioDeviceFd;
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
 #[cfg(target_arch = "x86_64")]
-use vmm_sys_util::ioctl_io_nr;
+use vmm_sys_util::ioctl::ioctl_with_ref;
 #[cfg(feature = "tdx")]
-use vmm_sys_util::{ioctl::ioctl_with_val, ioctl_iowr_nr};
+use vmm_sys_util::ioctl::ioctl_with_val;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl_io_nr;
+#[cfg(target_arch = "aarch64")]
+use vmm_sys_util::ioctl_ior_nr;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl_iow_nr;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl_iowr_nr;
 pub use {kvm_bindings, kvm_ioctls};
 
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
@@ -123,9 +149,183 @@ use crate::RegList;
 use crate::arch::aarch64::regs;
 #[cfg(target_arch = "x86_64")]
 ioctl_io_nr!(KVM_NMI, kvm_bindings::KVMIO, 0x9a);
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(
+    KVM_X86_SET_MSR_FILTER,
+    kvm_bindings::KVMIO,
+    0xc6,
+    kvm_bindings::kvm_msr_filter
+);
+
+// SEV firmware commands, see include/uapi/linux/psp-sev.h
+#[cfg(target_arch = "x86_64")]
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Default)]
+struct SevIssueCmd {
+    cmd: u32,
+    data: u64,
+    error: u32,
+}
+#[cfg(target_arch = "x86_64")]
+const _: () = assert!(std::mem::size_of::<SevIssueCmd>() == 16);
+#[cfg(target_arch = "x86_64")]
+ioctl_iowr_nr!(SEV_ISSUE_CMD, b'S' as u32, 0x0, SevIssueCmd);
+#[cfg(target_arch = "x86_64")]
+const SEV_PLATFORM_STATUS: u32 = 1;
+// struct sev_user_data_status
+#[cfg(target_arch = "x86_64")]
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Default)]
+struct SevPlatformStatus {
+    api_major: u8,
+    api_minor: u8,
+    state: u8,
+    flags: u32,
+    build: u8,
+    guest_count: u32,
+}
+
+// KVM paravirtual steal time, see Documentation/virt/kvm/x86/msr.rst
+#[cfg(target_arch = "x86_64")]
+const MSR_KVM_STEAL_TIME: u32 = 0x4b56_4d03;
+#[cfg(target_arch = "x86_64")]
+const KVM_MSR_ENABLED: u64 = 1;
+// Size and alignment of `struct kvm_steal_time`
+#[cfg(target_arch = "x86_64")]
+const KVM_STEAL_TIME_SIZE: u64 = 64;
+// KVM asynchronous page faults, see Documentation/virt/kvm/x86/msr.rst
+#[cfg(target_arch = "x86_64")]
+const MSR_KVM_ASYNC_PF_EN: u32 = 0x4b56_4d02;
+#[cfg(target_arch = "x86_64")]
+const MSR_KVM_ASYNC_PF_INT: u32 = 0x4b56_4d06;
+// KVM_FEATURE_ASYNC_PF, KVM_FEATURE_ASYNC_PF_VMEXIT and KVM_FEATURE_ASYNC_PF_INT
+// of the KVM features leaf, see Documentation/virt/kvm/x86/cpuid.rst
+#[cfg(target_arch = "x86_64")]
+const KVM_ASYNC_PF_FEATURES: u32 = (1 << 4) | (1 << 10) | (1 << 14);
+#[cfg(target_arch = "x86_64")]
+const KVM_CPUID_SIGNATURE: u32 = 0x4000_0000;
+#[cfg(target_arch = "x86_64")]
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+// Hyper-V crash MSRs, see the "Hypervisor Top Level Functional Specification"
+#[cfg(target_arch = "x86_64")]
+const HV_X64_MSR_CRASH_P0: u32 = 0x4000_0100;
+#[cfg(target_arch = "x86_64")]
+const HV_X64_MSR_CRASH_CTL: u32 = 0x4000_0105;
+#[cfg(target_arch = "x86_64")]
+const HV_CRASH_CTL_CRASH_NOTIFY: u64 = 1 << 63;
+// Hyper-V enlightenment MSRs whose content is set up by the guest
+#[cfg(target_arch = "x86_64")]
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+#[cfg(target_arch = "x86_64")]
+const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+#[cfg(target_arch = "x86_64")]
+const HV_X64_MSR_VP_INDEX: u32 = 0x4000_0002;
+#[cfg(target_arch = "x86_64")]
+const HV_X64_MSR_VP_ASSIST_PAGE: u32 = 0x4000_0073;
+// CPUID.01H:ECX.XSAVE
+#[cfg(target_arch = "x86_64")]
+const XSAVE_ECX_BIT: u8 = 26;
+// CPUID.80000001H:EDX.NX
+#[cfg(target_arch = "x86_64")]
+const NX_EDX_BIT: u8 = 20;
+// CPUID.80000001H:EDX.RDTSCP
+#[cfg(target_arch = "x86_64")]
+const RDTSCP_EDX_BIT: u8 = 27;
+// CPUID.(EAX=7,ECX=0):ECX.RDPID
+#[cfg(target_arch = "x86_64")]
+const RDPID_ECX_BIT: u8 = 22;
+// KVM_X86_*_VM values, also their bit in KVM_CAP_VM_TYPES
+#[cfg(target_arch = "x86_64")]
+const KVM_X86_VM_TYPES: [(u32, hypervisor::VmType); 6] = [
+    (0, hypervisor::VmType::Default),
+    (1, hypervisor::VmType::SwProtected),
+    (2, hypervisor::VmType::Sev),
+    (3, hypervisor::VmType::SevEs),
+    (4, hypervisor::VmType::SevSnp),
+    (5, hypervisor::VmType::Tdx),
+];
+// MSRs the guest state can't be restored without
+#[cfg(target_arch = "x86_64")]
+const REQUIRED_MIGRATION_MSRS: [u32; 11] = [
+    msr_index::MSR_IA32_SYSENTER_CS,
+    msr_index::MSR_IA32_SYSENTER_ESP,
+    msr_index::MSR_IA32_SYSENTER_EIP,
+    msr_index::MSR_STAR,
+    msr_index::MSR_CSTAR,
+    msr_index::MSR_LSTAR,
+    msr_index::MSR_KERNEL_GS_BASE,
+    msr_index::MSR_SYSCALL_MASK,
+    msr_index::MSR_IA32_TSC,
+    msr_index::MSR_IA32_MISC_ENABLE,
+    msr_index::MSR_IA32_CR_PAT,
+];
+// MSRs holding the CET state. Its XSAVE components (CET_U and CET_S) are
+// supervisor states enabled through IA32_XSS, which KVM_GET_XSAVE does not
+// return, so the state is only migrated through these MSRs.
+#[cfg(target_arch = "x86_64")]
+const CET_MIGRATION_MSRS: [u32; 8] = [
+    msr_index::MSR_IA32_XSS,
+    msr_index::MSR_IA32_U_CET,
+    msr_index::MSR_IA32_S_CET,
+    msr_index::MSR_IA32_PL0_SSP,
+    msr_index::MSR_IA32_PL1_SSP,
+    msr_index::MSR_IA32_PL2_SSP,
+    msr_index::MSR_IA32_PL3_SSP,
+    msr_index::MSR_IA32_INT_SSP_TAB,
+];
+// MSRs holding the Processor Trace configuration. KVM only reports them when
+// kvm_intel runs with pt_mode=1, the trace output itself lives in guest
+// memory. The address range MSRs are migrated when KVM reports them, their
+// number depending on CPUID.(EAX=0x14,ECX=1):EAX[2:0].
+#[cfg(target_arch = "x86_64")]
+const PT_MIGRATION_MSRS: [u32; 5] = [
+    msr_index::MSR_IA32_RTIT_OUTPUT_BASE,
+    msr_index::MSR_IA32_RTIT_OUTPUT_MASK,
+    msr_index::MSR_IA32_RTIT_CTL,
+    msr_index::MSR_IA32_RTIT_STATUS,
+    msr_index::MSR_IA32_RTIT_CR3_MATCH,
+];
+#[cfg(target_arch = "aarch64")]
+ioctl_ior_nr!(
+    KVM_ARM_MTE_COPY_TAGS,
+    kvm_bindings::KVMIO,
+    0xb4,
+    kvm_bindings::kvm_arm_copy_mte_tags
+);
+
+// Not part of the kvm-bindings generated from older kernel headers
+#[cfg(target_arch = "x86_64")]
+const KVM_CAP_X86_APIC_BUS_CYCLES_NS: u32 = 237;
+
+// IO ports of the in-kernel PICs, master and slave, and of their ELCRs
+#[cfg(target_arch = "x86_64")]
+const KVM_PIC_PIO_PORTS: [RangeInclusive<u16>; 3] = [0x20..=0x21, 0xa0..=0xa1, 0x4d0..=0x4d1];
 
 #[cfg(feature = "tdx")]
 const KVM_EXIT_TDX: u32 = 50;
+
+/// Kind of a vCPU exit, from the raw exit reason left by KVM_RUN.
+fn exit_kind(exit_reason: u32) -> cpu::VcpuExitKind {
+    match exit_reason {
+        kvm_bindings::KVM_EXIT_IO => cpu::VcpuExitKind::Io,
+        kvm_bindings::KVM_EXIT_MMIO => cpu::VcpuExitKind::Mmio,
+        kvm_bindings::KVM_EXIT_X86_RDMSR | kvm_bindings::KVM_EXIT_X86_WRMSR => {
+            cpu::VcpuExitKind::Msr
+        }
+        kvm_bindings::KVM_EXIT_HLT => cpu::VcpuExitKind::Hlt,
+        kvm_bindings::KVM_EXIT_SHUTDOWN => cpu::VcpuExitKind::Shutdown,
+        kvm_bindings::KVM_EXIT_SYSTEM_EVENT => cpu::VcpuExitKind::SystemEvent,
+        kvm_bindings::KVM_EXIT_IOAPIC_EOI => cpu::VcpuExitKind::IoapicEoi,
+        kvm_bindings::KVM_EXIT_HYPERV => cpu::VcpuExitKind::Hyperv,
+        kvm_bindings::KVM_EXIT_DEBUG => cpu::VcpuExitKind::Debug,
+        kvm_bindings::KVM_EXIT_INTERNAL_ERROR => cpu::VcpuExitKind::InternalError,
+        kvm_bindings::KVM_EXIT_FAIL_ENTRY => cpu::VcpuExitKind::FailEntry,
+        kvm_bindings::KVM_EXIT_INTR => cpu::VcpuExitKind::Interrupted,
+        r => cpu::VcpuExitKind::Other(r),
+    }
+}
 #[cfg(feature = "tdx")]
 const TDG_VP_VMCALL_GET_QUOTE: u64 = 0x10002;
 #[cfg(feature = "tdx")]
@@ -460,6 +660,324 @@ impl From<IrqRoutingEntry> for kvm_irq_routing_entry {
     }
 }
 
+// Size of the chunks guest memory is prefaulted by, progress is reported
+// after each of them.
+const PREFAULT_CHUNK_SIZE: u64 = 1 << 30;
+
+/// Host mapping backing a memory slot, as described by `/proc/self/smaps`.
+struct HostMapping {
+    page_size: u64,
+    // VM_IO or VM_PFNMAP mapping, e.g. a VFIO BAR, whose accesses reach a
+    // device instead of memory
+    device: bool,
+}
+
+///
+/// Returns the host mapping containing `addr`.
+///
+fn host_mapping(addr: u64) -> Option<HostMapping> {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").ok()?;
+    let mut in_mapping = false;
+    let mut page_size = None;
+
+    for line in smaps.lines() {
+        // Each mapping starts with a "<start>-<end> <perms> ..." line
+        if let Some((range, _)) = line.split_once(' ')
+            && let Some((start, end)) = range.split_once('-')
+            && let (Ok(start), Ok(end)) =
+                (u64::from_str_radix(start, 16), u64::from_str_radix(end, 16))
+        {
+            in_mapping = (start..end).contains(&addr);
+        } else if !in_mapping {
+            continue;
+        } else if let Some(size) = line.strip_prefix("KernelPageSize:") {
+            let size_kb = size.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+            page_size = Some(size_kb << 10);
+        } else if let Some(flags) = line.strip_prefix("VmFlags:") {
+            // The flags are the last field of a mapping
+            return Some(HostMapping {
+                page_size: page_size?,
+                device: flags.split_whitespace().any(|f| f == "io" || f == "pf"),
+            });
+        }
+    }
+
+    None
+}
+
+///
+/// Whether the host kernel supports MADV_POPULATE_{READ,WRITE}, added in
+/// Linux 5.14.
+///
+fn madv_populate_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        // The advice is checked before the range, unknown ones fail with
+        // EINVAL even for an empty range.
+        // SAFETY: FFI call on an empty range, no memory is affected
+        unsafe { libc::madvise(std::ptr::null_mut(), 0, libc::MADV_POPULATE_WRITE) == 0 }
+    })
+}
+
+///
+/// Populates the host pages backing the `[addr, addr + size)` range of guest
+/// RAM.
+///
+fn prefault_range(addr: u64, size: u64, readonly: bool, page_size: u64) -> std::io::Result<()> {
+    if madv_populate_supported() {
+        let advice = if readonly {
+            libc::MADV_POPULATE_READ
+        } else {
+            libc::MADV_POPULATE_WRITE
+        };
+        // SAFETY: the range is part of a guest RAM mapping, populating it
+        // leaves its content untouched.
+        let ret = unsafe { libc::madvise(addr as *mut libc::c_void, size as usize, advice) };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+
+    // Older kernels: read each page instead, which populates it without
+    // risking to lose a concurrent guest write. Private anonymous pages are
+    // then only mapped to the zero page until written. With huge pages,
+    // reading one byte populates the whole huge page, hence stepping by the
+    // size of the backing pages.
+    for offset in (0..size).step_by(page_size as usize) {
+        // SAFETY: the address is part of a guest RAM mapping, which is only
+        // read from.
+        unsafe { std::ptr::read_volatile((addr + offset) as *const u8) };
+    }
+
+    Ok(())
+}
+
+/// Guest page carved out of its memory slot to trap the accesses watched by a
+/// GPA watchpoint.
+struct KvmGpaWatchpoint {
+    gpa: u64,
+    len: u64,
+    access: vm::WatchpointAccess,
+    page: u64,
+    page_size: u64,
+    // Memory slot the page was carved out of
+    slot: kvm_userspace_memory_region,
+    // Slots replacing it, the first one keeping its id. The watched page is
+    // only mapped, read-only, when watching writes.
+    regions: Vec<kvm_userspace_memory_region>,
+}
+
+impl KvmGpaWatchpoint {
+    fn contains(&self, gpa: u64, size: usize) -> bool {
+        gpa >= self.page && gpa + size as u64 <= self.page + self.page_size
+    }
+
+    fn host_address(&self, gpa: u64) -> u64 {
+        self.slot.userspace_addr + (gpa - self.slot.guest_phys_addr)
+    }
+
+    fn exit(&self, gpa: u64, size: usize, write: bool) -> cpu::VmExit {
+        let watched = match self.access {
+            vm::WatchpointAccess::Read => !write,
+            vm::WatchpointAccess::Write => write,
+            vm::WatchpointAccess::ReadWrite => true,
+        };
+
+        if watched && gpa < self.gpa + self.len && self.gpa < gpa + size as u64 {
+            cpu::VmExit::Watchpoint { gpa, size, write }
+        } else {
+            cpu::VmExit::Ignore
+        }
+    }
+}
+
+/// MMIO exits to a guest physical region limited by `set_mmio_rate_limit()`.
+struct KvmMmioRateLimit {
+    region: Range<u64>,
+    max_exits_per_sec: u32,
+    counters: Mutex<MmioRateLimitCounters>,
+}
+
+struct MmioRateLimitCounters {
+    // Theoretical arrival time of the next exit, running ahead of the
+    // current time when exits come faster than the limit
+    next: Instant,
+    exits: u64,
+    throttled: u64,
+}
+
+impl KvmMmioRateLimit {
+    fn new(region: Range<u64>, max_exits_per_sec: u32) -> Self {
+        KvmMmioRateLimit {
+            region,
+            max_exits_per_sec,
+            counters: Mutex::new(MmioRateLimitCounters {
+                next: Instant::now(),
+                exits: 0,
+                throttled: 0,
+            }),
+        }
+    }
+
+    ///
+    /// Accounts for an exit at `now`, returning how long the vCPU must be
+    /// paused for to bring the region back under its limit.
+    ///
+    fn account(&self, now: Instant) -> Option<Duration> {
+        let interval = Duration::from_secs(1) / self.max_exits_per_sec;
+        let mut counters = self.counters.lock().unwrap();
+        counters.exits += 1;
+        counters.next = counters.next.max(now) + interval;
+
+        // One second worth of exits can run ahead of the current time
+        let delay = counters
+            .next
+            .saturating_duration_since(now + Duration::from_secs(1));
+        if delay.is_zero() {
+            return None;
+        }
+        counters.throttled += 1;
+        Some(delay)
+    }
+
+    fn stats(&self) -> vm::MmioExitStats {
+        let counters = self.counters.lock().unwrap();
+        vm::MmioExitStats {
+            region: self.region.clone(),
+            max_exits_per_sec: self.max_exits_per_sec,
+            exits: counters.exits,
+            throttled: counters.throttled,
+        }
+    }
+}
+
+///
+/// Pauses the calling vCPU if the MMIO exit to `gpa` exceeds the limit of
+/// its region. The pause is cut short by any signal kicking the vCPU out,
+/// so that the VMM pausing it doesn't wait for the delay to run out.
+///
+fn throttle_mmio_exit(limits: &RwLock<Vec<KvmMmioRateLimit>>, gpa: u64) {
+    let delay = limits
+        .read()
+        .unwrap()
+        .iter()
+        .find(|l| l.region.contains(&gpa))
+        .and_then(|l| l.account(Instant::now()));
+    if let Some(delay) = delay {
+        // Unlike std::thread::sleep(), nanosleep() isn't resumed once
+        // interrupted by a signal
+        let delay = libc::timespec {
+            tv_sec: delay.as_secs() as libc::time_t,
+            tv_nsec: delay.subsec_nanos() as libc::c_long,
+        };
+        // SAFETY: FFI call with a valid timespec, the remaining time is
+        // not needed
+        unsafe { libc::nanosleep(&delay, std::ptr::null_mut()) };
+    }
+}
+
+/// Where to find the instruction behind the last exit of a vCPU.
+#[cfg(target_arch = "x86_64")]
+enum LastExitInsn {
+    /// Not available, e.g. KVM already moved RIP past MMIO writes.
+    Unknown,
+    /// RIP still points at the instruction.
+    AtRip,
+    /// Copied by KVM in the exit struct, on emulation failures.
+    Bytes(Vec<u8>),
+}
+
+///
+/// Whether the operation behind `exit` is only complete, and the guest state
+/// consistent, once userspace re-entered KVM_RUN.
+///
+fn exit_completes_on_next_run(exit: &VcpuExit) -> bool {
+    match exit {
+        #[cfg(target_arch = "x86_64")]
+        VcpuExit::IoIn(..) | VcpuExit::IoOut(..) => true,
+        VcpuExit::MmioRead(..) | VcpuExit::MmioWrite(..) => true,
+        #[cfg(target_arch = "x86_64")]
+        VcpuExit::X86Rdmsr(..) | VcpuExit::X86Wrmsr(..) => true,
+        _ => false,
+    }
+}
+
+/// PIO handler registered with `register_pio_handler()`
+#[cfg(target_arch = "x86_64")]
+type KvmPioHandler = (RangeInclusive<u16>, Arc<dyn vm::PioHandler>);
+
+///
+/// Returns the PIO handler registered for `port`, with the base of its range.
+///
+#[cfg(target_arch = "x86_64")]
+fn find_pio_handler(
+    handlers: &RwLock<Vec<KvmPioHandler>>,
+    port: u16,
+) -> Option<(u16, Arc<dyn vm::PioHandler>)> {
+    handlers
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(range, _)| range.contains(&port))
+        .map(|(range, handler)| (*range.start(), handler.clone()))
+}
+
+///
+/// Completes a guest read from a page carved out by a GPA watchpoint.
+///
+fn watched_mmio_read(
+    watchpoints: &RwLock<Vec<KvmGpaWatchpoint>>,
+    gpa: u64,
+    data: &mut [u8],
+) -> Option<cpu::VmExit> {
+    let watchpoints = watchpoints.read().unwrap();
+    let watchpoint = watchpoints.iter().find(|w| w.contains(gpa, data.len()))?;
+
+    // SAFETY: the page is still mapped in the host, it has only been removed
+    // from the guest memory slots.
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            watchpoint.host_address(gpa) as *const u8,
+            data.as_mut_ptr(),
+            data.len(),
+        );
+    }
+
+    Some(watchpoint.exit(gpa, data.len(), false))
+}
+
+///
+/// Completes a guest write to a page carved out by a GPA watchpoint.
+///
+fn watched_mmio_write(
+    watchpoints: &RwLock<Vec<KvmGpaWatchpoint>>,
+    gpa: u64,
+    data: &[u8],
+) -> Option<cpu::VmExit> {
+    let watchpoints = watchpoints.read().unwrap();
+    let watchpoint = watchpoints.iter().find(|w| w.contains(gpa, data.len()))?;
+
+    // The writes to read-only memory exit as MMIO with or without the
+    // watchpoint, they are left to the VMM unless watched.
+    if (watchpoint.slot.flags & KVM_MEM_READONLY) != 0 {
+        let exit = watchpoint.exit(gpa, data.len(), true);
+        return matches!(exit, cpu::VmExit::Watchpoint { .. }).then_some(exit);
+    }
+
+    // SAFETY: the page is still mapped in the host, it has only been removed
+    // from the guest memory slots or made read-only for the guest.
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            data.as_ptr(),
+            watchpoint.host_address(gpa) as *mut u8,
+            data.len(),
+        );
+    }
+
+    Some(watchpoint.exit(gpa, data.len(), true))
+}
+
 struct KvmDirtyLogSlot {
     slot: u32,
     guest_phys_addr: u64,
@@ -467,15 +985,110 @@ struct KvmDirtyLogSlot {
     userspace_addr: u64,
 }
 
+fn set_page_dirty(bitmap: &mut [u64], page: u64) {
+    bitmap[(page / 64) as usize] |= 1 << (page % 64);
+}
+
 /// Wrapper over KVM VM ioctls.
 pub struct KvmVm {
     fd: Arc<VmFd>,
+    label: String,
     #[cfg(target_arch = "x86_64")]
     msrs: Vec<MsrEntry>,
     dirty_log_slots: Arc<RwLock<HashMap<u32, KvmDirtyLogSlot>>>,
+    // Whether dirty pages are logged, between start_dirty_log() and
+    // stop_dirty_log()
+    dirty_log_enabled: AtomicBool,
+    // Dirty pages bitmaps fetched before a GPA watchpoint split or merged
+    // back their slot, returned by the next get_dirty_log()
+    dirty_log_pending: Mutex<HashMap<u32, Vec<u64>>>,
+    memory_slots: RwLock<HashMap<u32, kvm_userspace_memory_region>>,
+    halt_poll_ns: RwLock<Option<u32>>,
+    watchpoints: Arc<RwLock<Vec<KvmGpaWatchpoint>>>,
+    mmio_rate_limits: Arc<RwLock<Vec<KvmMmioRateLimit>>>,
+    // Binary statistics of the vCPUs, kept open to make polling cheap
+    vcpu_stats: Mutex<Vec<File>>,
+    // Memory slots created by map_device_mmio()
+    device_mmio_slots: Mutex<Vec<u32>>,
+    // Signaled by the vCPUs on guest shutdown
+    shutdown_evt: Arc<EventFd>,
+    // Whether each vCPU has an IO operation to complete, indexed by vCPU id
+    vcpu_io_in_flight: Mutex<Vec<(u32, Arc<AtomicBool>)>>,
+    #[cfg(target_arch = "x86_64")]
+    pio_handlers: Arc<RwLock<Vec<KvmPioHandler>>>,
+    // GSIs reserved for the userspace IOAPIC pins by enable_split_irq()
+    #[cfg(target_arch = "x86_64")]
+    ioapic_pins: AtomicU32,
+    // Whether the PICs are emulated in kernel, set by create_irq_chip()
+    #[cfg(target_arch = "x86_64")]
+    in_kernel_pic: AtomicBool,
+    // Whether the VM type protects the guest memory from the host
+    #[cfg(target_arch = "x86_64")]
+    confidential: bool,
+    // RAPL MSRs handled by the vCPUs, if enabled
+    #[cfg(target_arch = "x86_64")]
+    rapl: Option<Arc<RaplMsrs>>,
+    // Topology and vCPU count the APIC IDs are derived from
+    #[cfg(target_arch = "x86_64")]
+    topology: Option<(u16, u16, u16, u16)>,
+    #[cfg(target_arch = "x86_64")]
+    max_vcpus: u32,
+    #[cfg(target_arch = "aarch64")]
+    mte: bool,
 }
 
 impl KvmVm {
+    ///
+    /// Attaches the VM label to an error.
+    ///
+    fn labelled(&self, e: vm::HypervisorVmError) -> vm::HypervisorVmError {
+        e.with_label(&self.label)
+    }
+
+    ///
+    /// Copies the MTE tags of `tags.len()` granules starting at `gpa`, in the
+    /// direction given by `flags` (KVM_ARM_TAGS_{TO,FROM}_GUEST).
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn copy_mte_tags(&self, gpa: u64, tags: *mut u8, count: usize, flags: u64) -> vm::Result<()> {
+        use crate::arch::aarch64::MTE_GRANULE_SIZE;
+
+        if !self.mte {
+            return Err(self.labelled(vm::HypervisorVmError::CopyMteTags(anyhow!(
+                "MTE is not enabled"
+            ))));
+        }
+
+        let length = count as u64 * MTE_GRANULE_SIZE;
+        let mut done = 0;
+        // The kernel may copy less than requested, e.g. when interrupted
+        while done < length {
+            let mut copy = kvm_bindings::kvm_arm_copy_mte_tags {
+                guest_ipa: gpa + done,
+                length: length - done,
+                // SAFETY: `done` is within the `count` granules the buffer
+                // has room for.
+                addr: unsafe { tags.add((done / MTE_GRANULE_SIZE) as usize) }.cast(),
+                flags,
+                reserved: [0; 2],
+            };
+            // SAFETY: the buffer holds one byte per granule of the range and
+            // we check the return value.
+            let ret = unsafe { ioctl_with_mut_ref(&*self.fd, KVM_ARM_MTE_COPY_TAGS(), &mut copy) };
+            if ret <= 0 {
+                let e = if ret < 0 {
+                    std::io::Error::last_os_error().into()
+                } else {
+                    anyhow!("No tags copied at 0x{:x}", gpa + done)
+                };
+                return Err(self.labelled(vm::HypervisorVmError::CopyMteTags(e)));
+            }
+            done += ret as u64;
+        }
+
+        Ok(())
+    }
+
     ///
     /// Creates an emulated device in the kernel.
     ///
@@ -492,6 +1105,155 @@ impl KvmVm {
         self.fd.check_extension(c)
     }
 
+    ///
+    /// Deletes the memory slots `old` and creates `new` in their place.
+    ///
+    fn replace_memory_slots(
+        &self,
+        old: &[u32],
+        new: &[kvm_userspace_memory_region],
+    ) -> result::Result<(), kvm_ioctls::Error> {
+        for slot in old {
+            let region = kvm_userspace_memory_region {
+                slot: *slot,
+                ..Default::default()
+            };
+            // SAFETY: Safe because the size is 0, which deletes the slot.
+            unsafe { self.fd.set_user_memory_region(region)? };
+        }
+
+        for region in new {
+            // SAFETY: Safe because the new regions map the same host memory
+            // and guest ranges as the deleted ones.
+            unsafe { self.fd.set_user_memory_region(*region)? };
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Flags set in KVM for a memory slot created with `flags`, dirty pages
+    /// being only logged between start_dirty_log() and stop_dirty_log().
+    ///
+    fn kvm_slot_flags(&self, flags: u32) -> u32 {
+        if (flags & KVM_MEM_LOG_DIRTY_PAGES) == 0 {
+            flags
+        } else if self.dirty_log_enabled.load(Ordering::Acquire) {
+            KVM_MEM_LOG_DIRTY_PAGES
+        } else {
+            0
+        }
+    }
+
+    ///
+    /// Sets or clears `KVM_MEM_LOG_DIRTY_PAGES` on the slots logging dirty
+    /// pages, including the ones a GPA watchpoint split them into.
+    ///
+    fn set_dirty_log(&self, enable: bool) -> result::Result<(), kvm_ioctls::Error> {
+        let watchpoints = self.watchpoints.read().unwrap();
+        let dirty_log_slots = self.dirty_log_slots.read().unwrap();
+        self.dirty_log_enabled.store(enable, Ordering::Release);
+        if enable {
+            self.dirty_log_pending.lock().unwrap().clear();
+        }
+
+        let flags = if enable { KVM_MEM_LOG_DIRTY_PAGES } else { 0 };
+        for s in dirty_log_slots.values() {
+            let regions = match watchpoints.iter().find(|w| w.slot.slot == s.slot) {
+                Some(w) => w
+                    .regions
+                    .iter()
+                    .filter(|r| (r.flags & KVM_MEM_LOG_DIRTY_PAGES) != 0)
+                    .map(|r| kvm_userspace_memory_region { flags, ..*r })
+                    .collect(),
+                None => vec![kvm_userspace_memory_region {
+                    slot: s.slot,
+                    guest_phys_addr: s.guest_phys_addr,
+                    memory_size: s.memory_size,
+                    userspace_addr: s.userspace_addr,
+                    flags,
+                }],
+            };
+            for region in regions {
+                // SAFETY: Safe because guest regions are guaranteed not to overlap.
+                unsafe { self.fd.set_user_memory_region(region)? };
+            }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Dirty pages bitmap of the memory slot `slot`, gathered from the slots
+    /// a GPA watchpoint split it into if any. The bitmap fetched before the
+    /// slot was last split or merged back is included.
+    ///
+    fn slot_dirty_log(
+        &self,
+        slot: u32,
+        memory_size: u64,
+        watchpoints: &[KvmGpaWatchpoint],
+    ) -> result::Result<Vec<u64>, kvm_ioctls::Error> {
+        let mut bitmap = match watchpoints.iter().find(|w| w.slot.slot == slot) {
+            None => self.fd.get_dirty_log(slot, memory_size as usize)?,
+            Some(w) => {
+                let mut bitmap = vec![0; (memory_size / w.page_size).div_ceil(64) as usize];
+                for r in w
+                    .regions
+                    .iter()
+                    .filter(|r| (r.flags & KVM_MEM_LOG_DIRTY_PAGES) != 0)
+                {
+                    let part = self.fd.get_dirty_log(r.slot, r.memory_size as usize)?;
+                    let first = (r.guest_phys_addr - w.slot.guest_phys_addr) / w.page_size;
+                    for page in 0..r.memory_size / w.page_size {
+                        if part[(page / 64) as usize] & (1 << (page % 64)) != 0 {
+                            set_page_dirty(&mut bitmap, first + page);
+                        }
+                    }
+                }
+                // The guest writes to the watched page are completed by the
+                // VMM, without KVM logging them
+                set_page_dirty(&mut bitmap, (w.page - w.slot.guest_phys_addr) / w.page_size);
+                bitmap
+            }
+        };
+
+        if let Some(pending) = self.dirty_log_pending.lock().unwrap().remove(&slot) {
+            for (b, p) in bitmap.iter_mut().zip(pending) {
+                *b |= p;
+            }
+        }
+
+        Ok(bitmap)
+    }
+
+    ///
+    /// Saves the dirty pages logged in `slot` before the slots backing it
+    /// are replaced, which discards their bitmaps.
+    ///
+    fn save_dirty_log(
+        &self,
+        slot: &kvm_userspace_memory_region,
+        watchpoints: &[KvmGpaWatchpoint],
+    ) -> result::Result<(), kvm_ioctls::Error> {
+        if !self.dirty_log_enabled.load(Ordering::Acquire)
+            || !self
+                .dirty_log_slots
+                .read()
+                .unwrap()
+                .contains_key(&slot.slot)
+        {
+            return Ok(());
+        }
+
+        let bitmap = self.slot_dirty_log(slot.slot, slot.memory_size, watchpoints)?;
+        self.dirty_log_pending
+            .lock()
+            .unwrap()
+            .insert(slot.slot, bitmap);
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Translates the MSI extended destination ID bits according to the logic
     /// found in the Linux kernel's KVM MSI handling in kvm_msi_to_lapic_irq()/x86_msi_msg_get_destid():
@@ -550,6 +1312,28 @@ impl KvmVm {
 /// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
 /// ```
 impl vm::Vm for KvmVm {
+    ///
+    /// Returns the label the VM has been created with.
+    ///
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the APIC ID the guest sees for the vCPU `index`.
+    ///
+    fn apic_id_for_vcpu(&self, index: u32) -> vm::Result<u32> {
+        if self.max_vcpus != 0 && index >= self.max_vcpus {
+            return Err(self.labelled(vm::HypervisorVmError::GetApicId(anyhow!(
+                "vCPU {index} is beyond the {} vCPUs of the VM",
+                self.max_vcpus
+            ))));
+        }
+
+        Ok(x2apic_id(index, self.topology))
+    }
+
     #[cfg(target_arch = "x86_64")]
     ///
     /// Sets the address of the one-page region in the VM's address space.
@@ -557,7 +1341,7 @@ impl vm::Vm for KvmVm {
     fn set_identity_map_address(&self, address: u64) -> vm::Result<()> {
         self.fd
             .set_identity_map_address(address)
-            .map_err(|e| vm::HypervisorVmError::SetIdentityMapAddress(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetIdentityMapAddress(e.into())))
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -567,7 +1351,7 @@ impl vm::Vm for KvmVm {
     fn set_tss_address(&self, offset: usize) -> vm::Result<()> {
         self.fd
             .set_tss_address(offset)
-            .map_err(|e| vm::HypervisorVmError::SetTssAddress(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetTssAddress(e.into())))
     }
 
     #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
@@ -577,7 +1361,10 @@ impl vm::Vm for KvmVm {
     fn create_irq_chip(&self) -> vm::Result<()> {
         self.fd
             .create_irq_chip()
-            .map_err(|e| vm::HypervisorVmError::CreateIrq(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CreateIrq(e.into())))?;
+        #[cfg(target_arch = "x86_64")]
+        self.in_kernel_pic.store(true, Ordering::Release);
+        Ok(())
     }
 
     ///
@@ -586,7 +1373,7 @@ impl vm::Vm for KvmVm {
     fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> vm::Result<()> {
         self.fd
             .register_irqfd(fd, gsi)
-            .map_err(|e| vm::HypervisorVmError::RegisterIrqFd(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIrqFd(e.into())))
     }
 
     ///
@@ -595,7 +1382,7 @@ impl vm::Vm for KvmVm {
     fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> vm::Result<()> {
         self.fd
             .unregister_irqfd(fd, gsi)
-            .map_err(|e| vm::HypervisorVmError::UnregisterIrqFd(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::UnregisterIrqFd(e.into())))
     }
 
     ///
@@ -609,7 +1396,16 @@ impl vm::Vm for KvmVm {
         let fd = self
             .fd
             .create_vcpu(id as u64)
-            .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CreateVcpu(e.into())))?;
+        match stats::open_stats_fd(&fd) {
+            Ok(stats) => self.vcpu_stats.lock().unwrap().push(stats),
+            Err(e) => debug!("No binary statistics for vCPU {id}: {e}"),
+        }
+        let io_in_flight = Arc::new(AtomicBool::new(false));
+        self.vcpu_io_in_flight
+            .lock()
+            .unwrap()
+            .push((id, io_in_flight.clone()));
         let vcpu = KvmVcpu {
             fd,
             #[cfg(target_arch = "x86_64")]
@@ -617,17 +1413,40 @@ impl vm::Vm for KvmVm {
             vm_ops,
             #[cfg(target_arch = "x86_64")]
             hyperv_synic: AtomicBool::new(false),
-        };
-        Ok(Box::new(vcpu))
-    }
+            watchpoints: self.watchpoints.clone(),
+            mmio_rate_limits: self.mmio_rate_limits.clone(),
+            #[cfg(target_arch = "aarch64")]
+            shutdown_evt: self.shutdown_evt.clone(),
+            io_in_flight,
+            #[cfg(target_arch = "x86_64")]
+            pio_handlers: self.pio_handlers.clone(),
+            #[cfg(target_arch = "x86_64")]
+            last_exit_insn: Mutex::new(LastExitInsn::Unknown),
+            #[cfg(target_arch = "x86_64")]
+            rapl: self.rapl.clone(),
+            #[cfg(target_arch = "x86_64")]
+            smm: self
+                .fd
+                .check_extension_raw(kvm_bindings::KVM_CAP_X86_SMM.into())
+                > 0,
+            exit_observer: Mutex::new(None),
+            #[cfg(target_arch = "aarch64")]
+            init: Mutex::new(None),
+        };
+        Ok(Box::new(vcpu))
+    }
 
     #[cfg(target_arch = "aarch64")]
     ///
     /// Creates a virtual GIC device.
     ///
     fn create_vgic(&self, config: VgicConfig) -> vm::Result<Arc<Mutex<dyn Vgic>>> {
-        let gic_device = KvmGicV3Its::new(self, config)
-            .map_err(|e| vm::HypervisorVmError::CreateVgic(anyhow!("Vgic error {:?}", e)))?;
+        let gic_device = KvmGicV3Its::new(self, config).map_err(|e| {
+            self.labelled(vm::HypervisorVmError::CreateVgic(anyhow!(
+                "Vgic error {:?}",
+                e
+            )))
+        })?;
         Ok(Arc::new(Mutex::new(gic_device)))
     }
 
@@ -636,8 +1455,11 @@ impl vm::Vm for KvmVm {
     /// Creates a virtual AIA device.
     ///
     fn create_vaia(&self, config: VaiaConfig) -> vm::Result<Arc<Mutex<dyn Vaia>>> {
-        let aia_device = KvmAiaImsics::new(self, config)
-            .map_err(|e| vm::HypervisorVmError::CreateVaia(anyhow!("Vaia error {e:?}")))?;
+        let aia_device = KvmAiaImsics::new(self, config).map_err(|e| {
+            self.labelled(vm::HypervisorVmError::CreateVaia(anyhow!(
+                "Vaia error {e:?}"
+            )))
+        })?;
         Ok(Arc::new(Mutex::new(aia_device)))
     }
 
@@ -656,16 +1478,16 @@ impl vm::Vm for KvmVm {
                 vm::DataMatch::DataMatch32(kvm_dm32) => self
                     .fd
                     .register_ioevent(fd, addr, kvm_dm32)
-                    .map_err(|e| vm::HypervisorVmError::RegisterIoEvent(e.into())),
+                    .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIoEvent(e.into()))),
                 vm::DataMatch::DataMatch64(kvm_dm64) => self
                     .fd
                     .register_ioevent(fd, addr, kvm_dm64)
-                    .map_err(|e| vm::HypervisorVmError::RegisterIoEvent(e.into())),
+                    .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIoEvent(e.into()))),
             }
         } else {
             self.fd
                 .register_ioevent(fd, addr, NoDatamatch)
-                .map_err(|e| vm::HypervisorVmError::RegisterIoEvent(e.into()))
+                .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIoEvent(e.into())))
         }
     }
 
@@ -676,7 +1498,7 @@ impl vm::Vm for KvmVm {
         let addr = &kvm_ioctls::IoEventAddress::from(*addr);
         self.fd
             .unregister_ioevent(fd, addr, NoDatamatch)
-            .map_err(|e| vm::HypervisorVmError::UnregisterIoEvent(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::UnregisterIoEvent(e.into())))
     }
 
     ///
@@ -750,12 +1572,29 @@ impl vm::Vm for KvmVm {
             })
             .collect();
 
+        // KVM considers the GSIs reserved for the IOAPIC pins as level
+        // triggered legacy IRQs, and tracks their EOIs. MSIs must be routed
+        // through the GSIs above them.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let ioapic_pins = self.ioapic_pins.load(Ordering::Acquire);
+            if let Some(entry) = entries
+                .iter()
+                .find(|e| e.type_ == KVM_IRQ_ROUTING_MSI && e.gsi < ioapic_pins)
+            {
+                return Err(self.labelled(vm::HypervisorVmError::SetGsiRouting(anyhow!(
+                    "MSI routed through GSI {} reserved for the {ioapic_pins} IOAPIC pins",
+                    entry.gsi
+                ))));
+            }
+        }
+
         let irq_routing =
             kvm_bindings::fam_wrappers::KvmIrqRouting::from_entries(&entries).unwrap();
 
         self.fd
             .set_gsi_routing(&irq_routing)
-            .map_err(|e| vm::HypervisorVmError::SetGsiRouting(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetGsiRouting(e.into())))
     }
 
     ///
@@ -790,6 +1629,7 @@ impl vm::Vm for KvmVm {
     ///
     fn create_user_memory_region(&self, user_memory_region: UserMemoryRegion) -> vm::Result<()> {
         let mut region: kvm_userspace_memory_region = user_memory_region.into();
+        let slot = region;
 
         if (region.flags & KVM_MEM_LOG_DIRTY_PAGES) != 0 {
             if (region.flags & KVM_MEM_READONLY) != 0 {
@@ -818,8 +1658,11 @@ impl vm::Vm for KvmVm {
         unsafe {
             self.fd
                 .set_user_memory_region(region)
-                .map_err(|e| vm::HypervisorVmError::CreateUserMemory(e.into()))
+                .map_err(|e| self.labelled(vm::HypervisorVmError::CreateUserMemory(e.into())))?;
         }
+
+        self.memory_slots.write().unwrap().insert(slot.slot, slot);
+        Ok(())
     }
 
     ///
@@ -829,7 +1672,23 @@ impl vm::Vm for KvmVm {
         let mut region: kvm_userspace_memory_region = user_memory_region.into();
 
         // Remove the corresponding entry from "self.dirty_log_slots" if needed
+        let mut watchpoints = self.watchpoints.write().unwrap();
         self.dirty_log_slots.write().unwrap().remove(&region.slot);
+        self.dirty_log_pending.lock().unwrap().remove(&region.slot);
+        self.memory_slots.write().unwrap().remove(&region.slot);
+
+        // Tear down the slots a GPA watchpoint split the region into
+        if let Some(index) = watchpoints.iter().position(|w| w.slot.slot == region.slot) {
+            let watchpoint = watchpoints.remove(index);
+            let slots: Vec<u32> = watchpoint.regions.iter().map(|r| r.slot).collect();
+            let mut memory_slots = self.memory_slots.write().unwrap();
+            for slot in &slots {
+                memory_slots.remove(slot);
+            }
+            return self
+                .replace_memory_slots(&slots, &[])
+                .map_err(|e| self.labelled(vm::HypervisorVmError::RemoveUserMemory(e.into())));
+        }
 
         // Setting the size to 0 means "remove"
         region.memory_size = 0;
@@ -837,8 +1696,180 @@ impl vm::Vm for KvmVm {
         unsafe {
             self.fd
                 .set_user_memory_region(region)
-                .map_err(|e| vm::HypervisorVmError::RemoveUserMemory(e.into()))
+                .map_err(|e| self.labelled(vm::HypervisorVmError::RemoveUserMemory(e.into())))
+        }
+    }
+
+    ///
+    /// Creates a guest_memfd for the private memory of the VM.
+    ///
+    fn create_guest_memfd(&self, size: u64, flags: u64) -> vm::Result<File> {
+        let gmem = kvm_create_guest_memfd {
+            size,
+            flags,
+            ..Default::default()
+        };
+        let fd = self
+            .fd
+            .create_guest_memfd(gmem)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CreateGuestMemfd(e.into())))?;
+        // SAFETY: fd is a valid file descriptor we own
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    ///
+    /// Creates a guest physical memory region with a private guest_memfd
+    /// backing.
+    ///
+    fn create_user_memory_region_with_guest_memfd(
+        &self,
+        user_memory_region: UserMemoryRegion,
+        guest_memfd: &File,
+        guest_memfd_offset: u64,
+    ) -> vm::Result<()> {
+        let region: kvm_userspace_memory_region = user_memory_region.into();
+        if (region.flags & KVM_MEM_LOG_DIRTY_PAGES) != 0 {
+            return Err(vm::HypervisorVmError::CreateUserMemory(anyhow!(
+                "Dirty pages log is not supported with guest_memfd"
+            )));
+        }
+
+        let region2 = kvm_userspace_memory_region2 {
+            slot: region.slot,
+            flags: region.flags | KVM_MEM_GUEST_MEMFD,
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            userspace_addr: region.userspace_addr,
+            guest_memfd_offset,
+            guest_memfd: guest_memfd.as_raw_fd() as u32,
+            ..Default::default()
+        };
+
+        // The slot is not tracked in `memory_slots`, as it can't be
+        // recreated through KVM_SET_USER_MEMORY_REGION.
+        // SAFETY: Safe because guest regions are guaranteed not to overlap.
+        unsafe {
+            self.fd
+                .set_user_memory_region2(region2)
+                .map_err(|e| self.labelled(vm::HypervisorVmError::CreateUserMemory(e.into())))
+        }
+    }
+
+    ///
+    /// Sets or clears the private attribute of a range of guest memory.
+    ///
+    fn set_memory_private(&self, guest_phys_addr: u64, size: u64, private: bool) -> vm::Result<()> {
+        let attributes = kvm_memory_attributes {
+            address: guest_phys_addr,
+            size,
+            attributes: if private {
+                KVM_MEMORY_ATTRIBUTE_PRIVATE as u64
+            } else {
+                0
+            },
+            flags: 0,
+        };
+        self.fd
+            .set_memory_attributes(attributes)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetMemoryAttributes(e.into())))
+    }
+
+    ///
+    /// Maps a range of a device into the guest through the given memory slot.
+    ///
+    fn map_device_mmio(
+        &self,
+        slot: u32,
+        gpa: vm_memory::GuestAddress,
+        host_fd: RawFd,
+        offset: u64,
+        size: u64,
+    ) -> vm::Result<()> {
+        let error = |msg: String| self.labelled(vm::HypervisorVmError::MapDeviceMmio(anyhow!(msg)));
+
+        // SAFETY: FFI call, trivially safe
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        if size == 0 {
+            return Err(error("Empty device MMIO range".to_string()));
+        }
+        for (name, value) in [
+            ("guest address", gpa.0),
+            ("device offset", offset),
+            ("size", size),
+        ] {
+            if value % page_size != 0 {
+                return Err(error(format!(
+                    "The {name} 0x{value:x} is not aligned on the 0x{page_size:x} page size"
+                )));
+            }
+        }
+
+        let mut device_slots = self.device_mmio_slots.lock().unwrap();
+        if self.memory_slots.read().unwrap().contains_key(&slot) {
+            return Err(error(format!("Memory slot {slot} is already in use")));
+        }
+
+        // SAFETY: FFI call with valid arguments, the result is checked below
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                host_fd,
+                offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(self.labelled(vm::HypervisorVmError::MapDeviceMmio(
+                std::io::Error::last_os_error().into(),
+            )));
+        }
+
+        let region = self.make_user_memory_region(slot, gpa.0, size, addr as u64, false, false);
+        if let Err(e) = self.create_user_memory_region(region) {
+            // SAFETY: addr and size come from the successful mmap() above
+            unsafe { libc::munmap(addr, size as usize) };
+            return Err(e);
+        }
+
+        device_slots.push(slot);
+        Ok(())
+    }
+
+    ///
+    /// Removes a device mapping from the guest and unmaps it.
+    ///
+    fn unmap_device_mmio(&self, slot: u32) -> vm::Result<()> {
+        let mut device_slots = self.device_mmio_slots.lock().unwrap();
+        let index = device_slots
+            .iter()
+            .position(|s| *s == slot)
+            .ok_or_else(|| {
+                self.labelled(vm::HypervisorVmError::UnmapDeviceMmio(anyhow!(
+                    "Memory slot {slot} does not map device MMIO"
+                )))
+            })?;
+        let region = self.memory_slots.read().unwrap()[&slot];
+
+        self.remove_user_memory_region(region.into())?;
+        device_slots.remove(index);
+
+        // SAFETY: the mapping was created by map_device_mmio() and is no
+        // longer accessible from the guest.
+        let ret = unsafe {
+            libc::munmap(
+                region.userspace_addr as *mut libc::c_void,
+                region.memory_size as usize,
+            )
+        };
+        if ret != 0 {
+            return Err(self.labelled(vm::HypervisorVmError::UnmapDeviceMmio(
+                std::io::Error::last_os_error().into(),
+            )));
         }
+
+        Ok(())
     }
 
     ///
@@ -849,13 +1880,22 @@ impl vm::Vm for KvmVm {
         let mut kvm_kvi: kvm_bindings::kvm_vcpu_init = (*kvi).into();
         self.fd
             .get_preferred_target(&mut kvm_kvi)
-            .map_err(|e| vm::HypervisorVmError::GetPreferredTarget(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetPreferredTarget(e.into())))?;
         *kvi = kvm_kvi.into();
         Ok(())
     }
 
     #[cfg(target_arch = "x86_64")]
-    fn enable_split_irq(&self) -> vm::Result<()> {
+    fn enable_split_irq(&self, ioapic_pins: u32) -> vm::Result<()> {
+        // The GSIs reserved for the IOAPIC pins are taken out of the routing
+        // table, at least one must be left for MSIs.
+        let max_routes = self.fd.check_extension_int(Cap::IrqRouting).max(0) as u32;
+        if ioapic_pins >= max_routes {
+            return Err(self.labelled(vm::HypervisorVmError::EnableSplitIrq(anyhow!(
+                "{ioapic_pins} IOAPIC pins exceed the {max_routes} GSIs supported"
+            ))));
+        }
+
         // Create split irqchip
         // Only the local APIC is emulated in kernel, both PICs and IOAPIC
         // are not.
@@ -863,10 +1903,11 @@ impl vm::Vm for KvmVm {
             cap: KVM_CAP_SPLIT_IRQCHIP,
             ..Default::default()
         };
-        cap.args[0] = NUM_IOAPIC_PINS as u64;
+        cap.args[0] = ioapic_pins as u64;
         self.fd
             .enable_cap(&cap)
-            .map_err(|e| vm::HypervisorVmError::EnableSplitIrq(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::EnableSplitIrq(e.into())))?;
+        self.ioapic_pins.store(ioapic_pins, Ordering::Release);
         Ok(())
     }
 
@@ -898,7 +1939,7 @@ impl vm::Vm for KvmVm {
         Ok(self
             .fd
             .get_clock()
-            .map_err(|e| vm::HypervisorVmError::GetClock(e.into()))?
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetClock(e.into())))?
             .into())
     }
 
@@ -908,7 +1949,7 @@ impl vm::Vm for KvmVm {
         let data = (*data).into();
         self.fd
             .set_clock(&data)
-            .map_err(|e| vm::HypervisorVmError::SetClock(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetClock(e.into())))
     }
 
     /// Create a device that is used for passthrough
@@ -920,64 +1961,32 @@ impl vm::Vm for KvmVm {
         };
 
         self.create_device(&mut vfio_dev)
-            .map_err(|e| vm::HypervisorVmError::CreatePassthroughDevice(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CreatePassthroughDevice(e.into())))
     }
 
     ///
     /// Start logging dirty pages
     ///
     fn start_dirty_log(&self) -> vm::Result<()> {
-        let dirty_log_slots = self.dirty_log_slots.read().unwrap();
-        for (_, s) in dirty_log_slots.iter() {
-            let region = kvm_userspace_memory_region {
-                slot: s.slot,
-                guest_phys_addr: s.guest_phys_addr,
-                memory_size: s.memory_size,
-                userspace_addr: s.userspace_addr,
-                flags: KVM_MEM_LOG_DIRTY_PAGES,
-            };
-            // SAFETY: Safe because guest regions are guaranteed not to overlap.
-            unsafe {
-                self.fd
-                    .set_user_memory_region(region)
-                    .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
-            }
-        }
-
-        Ok(())
+        self.set_dirty_log(true)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::StartDirtyLog(e.into())))
     }
 
     ///
     /// Stop logging dirty pages
     ///
     fn stop_dirty_log(&self) -> vm::Result<()> {
-        let dirty_log_slots = self.dirty_log_slots.read().unwrap();
-        for (_, s) in dirty_log_slots.iter() {
-            let region = kvm_userspace_memory_region {
-                slot: s.slot,
-                guest_phys_addr: s.guest_phys_addr,
-                memory_size: s.memory_size,
-                userspace_addr: s.userspace_addr,
-                flags: 0,
-            };
-            // SAFETY: Safe because guest regions are guaranteed not to overlap.
-            unsafe {
-                self.fd
-                    .set_user_memory_region(region)
-                    .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
-            }
-        }
-
-        Ok(())
+        self.set_dirty_log(false)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::StopDirtyLog(e.into())))
     }
 
     ///
     /// Get dirty pages bitmap (one bit per page)
     ///
     fn get_dirty_log(&self, slot: u32, _base_gpa: u64, memory_size: u64) -> vm::Result<Vec<u64>> {
-        self.fd
-            .get_dirty_log(slot, memory_size as usize)
-            .map_err(|e| vm::HypervisorVmError::GetDirtyLog(e.into()))
+        let watchpoints = self.watchpoints.read().unwrap();
+        self.slot_dirty_log(slot, memory_size, &watchpoints)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetDirtyLog(e.into())))
     }
 
     ///
@@ -1069,385 +2078,1439 @@ impl vm::Vm for KvmVm {
     fn as_any(&self) -> &dyn Any {
         self
     }
-}
 
-#[cfg(feature = "tdx")]
-fn tdx_command(
-    fd: &RawFd,
-    command: TdxCommand,
-    flags: u32,
-    data: u64,
-) -> std::result::Result<(), std::io::Error> {
-    #[repr(C)]
-    struct TdxIoctlCmd {
-        command: TdxCommand,
-        flags: u32,
-        data: u64,
-        error: u64,
-        unused: u64,
+    ///
+    /// Set the halt-polling window of the VM through KVM_CAP_HALT_POLL.
+    ///
+    fn set_halt_poll_ns(&self, ns: u32) -> vm::Result<()> {
+        let cap = kvm_bindings::kvm_enable_cap {
+            cap: kvm_bindings::KVM_CAP_HALT_POLL,
+            args: [ns as u64, 0, 0, 0],
+            ..Default::default()
+        };
+        self.fd
+            .enable_cap(&cap)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetHaltPollNs(e.into())))?;
+        *self.halt_poll_ns.write().unwrap() = Some(ns);
+        Ok(())
     }
-    let cmd = TdxIoctlCmd {
-        command,
-        flags,
-        data,
-        error: 0,
-        unused: 0,
-    };
-    // SAFETY: FFI call. All input parameters are valid.
-    let ret = unsafe {
-        ioctl_with_val(
-            fd,
-            KVM_MEMORY_ENCRYPT_OP(),
-            &cmd as *const TdxIoctlCmd as std::os::raw::c_ulong,
-        )
-    };
 
-    if ret < 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-    Ok(())
-}
+    ///
+    /// Populate the host memory backing all the memory slots.
+    ///
+    fn prefault_memory(&self, mut progress: Option<&mut dyn FnMut(u64, u64)>) -> vm::Result<()> {
+        // Only RAM is populated, accessing device memory could have side
+        // effects.
+        let device_slots = self.device_mmio_slots.lock().unwrap().clone();
+        let slots: Vec<(kvm_userspace_memory_region, Option<HostMapping>)> = self
+            .memory_slots
+            .read()
+            .unwrap()
+            .values()
+            .filter(|slot| !device_slots.contains(&slot.slot))
+            .map(|slot| (*slot, host_mapping(slot.userspace_addr)))
+            .filter(|(_, mapping)| !mapping.as_ref().is_some_and(|m| m.device))
+            .collect();
+        let total: u64 = slots.iter().map(|(s, _)| s.memory_size).sum();
+        // SAFETY: FFI call, trivially safe
+        let host_page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let mut done = 0;
+
+        for (slot, mapping) in slots {
+            let readonly = (slot.flags & KVM_MEM_READONLY) != 0;
+            // Huge pages can only be populated as a whole, which requires
+            // the chunks to be aligned on the size of the backing pages.
+            let page_size = mapping.map_or(host_page_size, |m| m.page_size);
+            let chunk_size = PREFAULT_CHUNK_SIZE.max(page_size);
+
+            let mut offset = 0;
+            while offset < slot.memory_size {
+                let size = chunk_size.min(slot.memory_size - offset);
+                prefault_range(slot.userspace_addr + offset, size, readonly, page_size).map_err(
+                    |e| {
+                        self.labelled(vm::HypervisorVmError::PrefaultMemory(anyhow!(
+                            "Failed to populate slot {} at offset 0x{offset:x}: {e}",
+                            slot.slot
+                        )))
+                    },
+                )?;
 
-/// Wrapper over KVM system ioctls.
-pub struct KvmHypervisor {
-    kvm: Kvm,
-}
+                offset += size;
+                done += size;
+                if let Some(progress) = progress.as_mut() {
+                    progress(done, total);
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-impl KvmHypervisor {
-    #[cfg(target_arch = "x86_64")]
     ///
-    /// Retrieve the list of MSRs supported by the hypervisor.
+    /// Advises the kernel on the mappings of the memory slots, leaving the
+    /// device mappings aside.
     ///
-    fn get_msr_list(&self) -> hypervisor::Result<MsrList> {
-        self.kvm
-            .get_msr_index_list()
-            .map_err(|e| hypervisor::HypervisorError::GetMsrList(e.into()))
-    }
-}
+    fn set_memory_advice(&self, advice: vm::MemoryAdvice) -> vm::Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        if advice == vm::MemoryAdvice::Mergeable && self.confidential {
+            return Err(
+                self.labelled(vm::HypervisorVmError::SetMemoryAdvice(anyhow!(
+                    "KSM can't merge the memory of a confidential VM"
+                ))),
+            );
+        }
 
-/// Enum for KVM related error
-#[derive(Debug, Error)]
-pub enum KvmError {
-    #[error("Capability missing: {0:?}")]
-    CapabilityMissing(Cap),
-}
+        let device_slots = self.device_mmio_slots.lock().unwrap();
+        for slot in self.memory_slots.read().unwrap().values() {
+            if device_slots.contains(&slot.slot) {
+                continue;
+            }
 
-pub type KvmResult<T> = result::Result<T, KvmError>;
+            // SAFETY: FFI call, the range is the mapping backing the slot
+            let ret = unsafe {
+                libc::madvise(
+                    slot.userspace_addr as *mut libc::c_void,
+                    slot.memory_size as usize,
+                    advice.as_raw(),
+                )
+            };
+            if ret != 0 {
+                return Err(
+                    self.labelled(vm::HypervisorVmError::SetMemoryAdvice(anyhow!(
+                        "Failed to advise slot {}: {}",
+                        slot.slot,
+                        std::io::Error::last_os_error()
+                    ))),
+                );
+            }
+        }
 
-impl KvmHypervisor {
-    /// Create a hypervisor based on Kvm
-    #[allow(clippy::new_ret_no_self)]
-    pub fn new() -> hypervisor::Result<Arc<dyn hypervisor::Hypervisor>> {
-        let kvm_obj = Kvm::new().map_err(|e| hypervisor::HypervisorError::VmCreate(e.into()))?;
-        let api_version = kvm_obj.get_api_version();
+        Ok(())
+    }
 
-        if api_version != kvm_bindings::KVM_API_VERSION as i32 {
-            return Err(hypervisor::HypervisorError::IncompatibleApiVersion);
+    ///
+    /// Carves the page containing `gpa` out of its memory slot to trap the
+    /// guest accesses to it.
+    ///
+    /// Only one page per memory slot can be watched at a time.
+    ///
+    fn set_gpa_watchpoint(
+        &self,
+        gpa: u64,
+        len: u64,
+        access: vm::WatchpointAccess,
+    ) -> vm::Result<()> {
+        let error =
+            |msg: String| self.labelled(vm::HypervisorVmError::SetGpaWatchpoint(anyhow!(msg)));
+
+        // SAFETY: FFI call, trivially safe
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let page = gpa & !(page_size - 1);
+        if len == 0 || gpa + len > page + page_size {
+            return Err(error(format!(
+                "Watched range 0x{gpa:x}-0x{:x} must fit in a single page",
+                gpa + len
+            )));
         }
 
-        Ok(Arc::new(KvmHypervisor { kvm: kvm_obj }))
-    }
+        let mut watchpoints = self.watchpoints.write().unwrap();
+        if let Some(w) = watchpoints.iter().find(|w| {
+            (w.slot.guest_phys_addr..w.slot.guest_phys_addr + w.slot.memory_size).contains(&gpa)
+        }) {
+            return Err(error(format!(
+                "Memory slot {} already contains a watchpoint",
+                w.slot.slot
+            )));
+        }
+        let mut slots = self.memory_slots.write().unwrap();
+        let slot = *slots
+            .values()
+            .find(|s| (s.guest_phys_addr..s.guest_phys_addr + s.memory_size).contains(&gpa))
+            .ok_or_else(|| error(format!("No memory slot contains 0x{gpa:x}")))?;
+
+        // Split the slot around the watched page, which is either not mapped
+        // at all or read-only, so that the accesses exit as MMIO. The page
+        // writes are completed by the VMM, which reports them as dirty.
+        let end = slot.guest_phys_addr + slot.memory_size;
+        let mut ranges = Vec::new();
+        if page > slot.guest_phys_addr {
+            ranges.push((
+                slot.guest_phys_addr,
+                page - slot.guest_phys_addr,
+                slot.flags,
+            ));
+        }
+        if access == vm::WatchpointAccess::Write {
+            let flags = (slot.flags & !KVM_MEM_LOG_DIRTY_PAGES) | KVM_MEM_READONLY;
+            ranges.push((page, page_size, flags));
+        }
+        if page + page_size < end {
+            ranges.push((page + page_size, end - page - page_size, slot.flags));
+        }
 
-    /// Check if the hypervisor is available
-    pub fn is_available() -> hypervisor::Result<bool> {
-        match std::fs::metadata("/dev/kvm") {
-            Ok(_) => Ok(true),
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
-            Err(err) => Err(hypervisor::HypervisorError::HypervisorAvailableCheck(
-                err.into(),
-            )),
+        let max_slots = self.fd.check_extension_int(Cap::NrMemslots).max(0) as u32;
+        let mut free_slots = (0..max_slots).rev().filter(|s| !slots.contains_key(s));
+
+        let mut regions = Vec::new();
+        for (index, (guest_phys_addr, memory_size, flags)) in ranges.into_iter().enumerate() {
+            // The first range keeps the slot of the original region
+            let id = if index == 0 {
+                slot.slot
+            } else {
+                free_slots
+                    .next()
+                    .ok_or_else(|| error("No free memory slot".to_string()))?
+            };
+            regions.push(kvm_userspace_memory_region {
+                slot: id,
+                guest_phys_addr,
+                memory_size,
+                userspace_addr: slot.userspace_addr + (guest_phys_addr - slot.guest_phys_addr),
+                flags,
+            });
         }
+
+        let kvm_regions: Vec<kvm_userspace_memory_region> = regions
+            .iter()
+            .map(|r| kvm_userspace_memory_region {
+                flags: self.kvm_slot_flags(r.flags),
+                ..*r
+            })
+            .collect();
+        self.save_dirty_log(&slot, &watchpoints)
+            .and_then(|_| self.replace_memory_slots(&[slot.slot], &kvm_regions))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetGpaWatchpoint(e.into())))?;
+
+        slots.remove(&slot.slot);
+        slots.extend(regions.iter().map(|r| (r.slot, *r)));
+        watchpoints.push(KvmGpaWatchpoint {
+            gpa,
+            len,
+            access,
+            page,
+            page_size,
+            slot,
+            regions,
+        });
+
+        Ok(())
     }
-}
 
-/// Implementation of Hypervisor trait for KVM
-///
-/// # Examples
-///
-/// ```
-/// # use hypervisor::kvm::KvmHypervisor;
-/// # use hypervisor::HypervisorVmConfig;
-/// # use std::sync::Arc;
-/// let kvm = KvmHypervisor::new().unwrap();
-/// let hypervisor = Arc::new(kvm);
-/// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
-/// ```
-impl hypervisor::Hypervisor for KvmHypervisor {
     ///
-    /// Returns the type of the hypervisor
+    /// Maps the page watched by the watchpoint covering `gpa` back in its
+    /// original memory slot.
     ///
-    fn hypervisor_type(&self) -> HypervisorType {
-        HypervisorType::Kvm
+    fn clear_gpa_watchpoint(&self, gpa: u64) -> vm::Result<()> {
+        let mut watchpoints = self.watchpoints.write().unwrap();
+        let index = watchpoints
+            .iter()
+            .position(|w| w.contains(gpa, 1))
+            .ok_or_else(|| {
+                self.labelled(vm::HypervisorVmError::ClearGpaWatchpoint(anyhow!(
+                    "No watchpoint covers 0x{gpa:x}"
+                )))
+            })?;
+        let old_slots: Vec<u32> = watchpoints[index].regions.iter().map(|r| r.slot).collect();
+        let original = watchpoints[index].slot;
+        let kvm_original = kvm_userspace_memory_region {
+            flags: self.kvm_slot_flags(original.flags),
+            ..original
+        };
+
+        self.save_dirty_log(&original, &watchpoints)
+            .and_then(|_| self.replace_memory_slots(&old_slots, &[kvm_original]))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::ClearGpaWatchpoint(e.into())))?;
+
+        watchpoints.remove(index);
+        let mut slots = self.memory_slots.write().unwrap();
+        for slot in &old_slots {
+            slots.remove(slot);
+        }
+        slots.insert(original.slot, original);
+
+        Ok(())
     }
 
-    /// Create a KVM vm object of a specific VM type and return the object as Vm trait object
     ///
-    /// # Examples
+    /// Limits the MMIO exits to `region`, the exits are accounted for by the
+    /// vCPUs before being handled.
     ///
-    /// ```
-    /// # use hypervisor::kvm::KvmHypervisor;
-    /// # use hypervisor::kvm::KvmVm;
-    /// # use hypervisor::HypervisorVmConfig;
-    /// let hypervisor = KvmHypervisor::new().unwrap();
-    /// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).unwrap();
-    /// ```
-    fn create_vm(&self, _config: HypervisorVmConfig) -> hypervisor::Result<Arc<dyn vm::Vm>> {
-        let fd: VmFd;
-
-        #[allow(unused_mut)]
-        #[allow(unused_assignments)]
-        let mut vm_type: u64 = 0; // Create with default platform type
-
-        // When KVM supports Cap::ArmVmIPASize, it is better to get the IPA
-        // size from the host and use that when creating the VM, which may
-        // avoid unnecessary VM creation failures.
-        #[cfg(target_arch = "aarch64")]
-        if self.kvm.check_extension(Cap::ArmVmIPASize) {
-            vm_type = self.kvm.get_host_ipa_limit().try_into().unwrap();
+    fn set_mmio_rate_limit(&self, region: Range<u64>, max_exits_per_sec: u32) -> vm::Result<()> {
+        let mut limits = self.mmio_rate_limits.write().unwrap();
+        limits.retain(|l| l.region != region);
+        if max_exits_per_sec == 0 {
+            return Ok(());
         }
 
-        #[cfg(feature = "tdx")]
-        if _config.tdx_enabled {
-            vm_type = KVM_X86_SW_PROTECTED_VM.into();
-        } else {
-            vm_type = KVM_X86_DEFAULT_VM.into();
-        };
-
-        loop {
-            match self.kvm.create_vm_with_type(vm_type) {
-                Ok(res) => fd = res,
-                Err(e) => {
-                    if e.errno() == libc::EINTR {
-                        // If the error returned is EINTR, which means the
-                        // ioctl has been interrupted, we have to retry as
-                        // this can't be considered as a regular error.
-                        continue;
-                    } else {
-                        return Err(hypervisor::HypervisorError::VmCreate(e.into()));
-                    }
-                }
-            }
-            break;
+        if region.is_empty() {
+            return Err(
+                self.labelled(vm::HypervisorVmError::SetMmioRateLimit(anyhow!(
+                    "Empty region 0x{:x}-0x{:x}",
+                    region.start,
+                    region.end
+                ))),
+            );
         }
-
-        let vm_fd = Arc::new(fd);
-
-        #[cfg(target_arch = "x86_64")]
+        if let Some(l) = limits
+            .iter()
+            .find(|l| l.region.start < region.end && region.start < l.region.end)
         {
-            let msr_list = self.get_msr_list()?;
-            let num_msrs = msr_list.as_fam_struct_ref().nmsrs as usize;
-            let mut msrs: Vec<MsrEntry> = vec![
-                MsrEntry {
-                    ..Default::default()
-                };
-                num_msrs
-            ];
-            let indices = msr_list.as_slice();
-            for (pos, index) in indices.iter().enumerate() {
-                msrs[pos].index = *index;
-            }
-
-            Ok(Arc::new(KvmVm {
-                fd: vm_fd,
-                msrs,
-                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
-            }))
+            return Err(
+                self.labelled(vm::HypervisorVmError::SetMmioRateLimit(anyhow!(
+                    "Region 0x{:x}-0x{:x} overlaps with the limited region 0x{:x}-0x{:x}",
+                    region.start,
+                    region.end,
+                    l.region.start,
+                    l.region.end
+                ))),
+            );
         }
 
-        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
-        {
-            Ok(Arc::new(KvmVm {
-                fd: vm_fd,
-                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
-            }))
-        }
+        limits.push(KvmMmioRateLimit::new(region, max_exits_per_sec));
+        Ok(())
     }
 
-    fn check_required_extensions(&self) -> hypervisor::Result<()> {
-        check_required_kvm_extensions(&self.kvm)
-            .map_err(|e| hypervisor::HypervisorError::CheckExtensions(e.into()))
+    fn get_mmio_exit_stats(&self) -> vm::Result<Vec<vm::MmioExitStats>> {
+        Ok(self
+            .mmio_rate_limits
+            .read()
+            .unwrap()
+            .iter()
+            .map(KvmMmioRateLimit::stats)
+            .collect())
     }
 
-    #[cfg(target_arch = "x86_64")]
     ///
-    /// X86 specific call to get the system supported CPUID values.
+    /// Get the halt-polling window of the VM.
     ///
-    fn get_supported_cpuid(&self) -> hypervisor::Result<Vec<CpuIdEntry>> {
-        let kvm_cpuid = self
-            .kvm
-            .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
-            .map_err(|e| hypervisor::HypervisorError::GetCpuId(e.into()))?;
-
-        let v = kvm_cpuid.as_slice().iter().map(|e| (*e).into()).collect();
+    /// KVM does not report the per-VM value, so this is either the last value
+    /// set through `set_halt_poll_ns()` or the kvm module default.
+    ///
+    fn get_halt_poll_ns(&self) -> vm::Result<u32> {
+        if let Some(ns) = *self.halt_poll_ns.read().unwrap() {
+            return Ok(ns);
+        }
 
-        Ok(v)
+        let ns = std::fs::read_to_string("/sys/module/kvm/parameters/halt_poll_ns")
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetHaltPollNs(e.into())))?;
+        ns.trim()
+            .parse::<u32>()
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetHaltPollNs(e.into())))
     }
 
-    #[cfg(target_arch = "aarch64")]
     ///
-    /// Retrieve AArch64 host maximum IPA size supported by KVM.
+    /// Returns the MMU counters from the VM binary statistics.
     ///
-    fn get_host_ipa_limit(&self) -> i32 {
-        self.kvm.get_host_ipa_limit()
-    }
+    fn get_mmu_stats(&self) -> vm::Result<cpu::MmuStats> {
+        let stats = stats::open_stats_fd(&self.fd)
+            .and_then(|f| stats::read_stats(&f))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetStats(e.into())))?;
+        let value = |name: &str| stats::stat_value(&stats, name).unwrap_or(0);
 
-    ///
-    /// Retrieve TDX capabilities
-    ///
-    #[cfg(feature = "tdx")]
-    fn tdx_capabilities(&self) -> hypervisor::Result<TdxCapabilities> {
-        let data = TdxCapabilities {
-            nr_cpuid_configs: TDX_MAX_NR_CPUID_CONFIGS as u32,
+        Ok(cpu::MmuStats {
+            pte_writes: value("mmu_pte_write"),
+            shadow_zapped: value("mmu_shadow_zapped"),
+            nx_huge_page_splits: value("nx_lpage_splits"),
+            pages_4k: value("pages_4k"),
+            pages_2m: value("pages_2m"),
+            pages_1g: value("pages_1g"),
             ..Default::default()
-        };
+        })
+    }
 
-        tdx_command(
-            &self.kvm.as_raw_fd(),
-            TdxCommand::Capabilities,
-            0,
-            &data as *const _ as u64,
-        )
-        .map_err(|e| hypervisor::HypervisorError::TdxCapabilities(e.into()))?;
+    fn guest_fault_stats(&self) -> vm::Result<vm::FaultStats> {
+        let mut fault_stats = vm::FaultStats::default();
+        for file in self.vcpu_stats.lock().unwrap().iter() {
+            let stats = stats::read_stats(file)
+                .map_err(|e| self.labelled(vm::HypervisorVmError::GetStats(e.into())))?;
+            let value = |name: &str| stats::stat_value(&stats, name).unwrap_or(0);
 
-        Ok(data)
+            fault_stats.taken += value("pf_taken");
+            fault_stats.fixed += value("pf_fixed");
+            fault_stats.guest += value("pf_guest");
+        }
+
+        Ok(fault_stats)
     }
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
     ///
-    /// Get the number of supported hardware breakpoints
+    /// Returns a duplicate of the eventfd signaled by the vCPUs on shutdown.
     ///
-    fn get_guest_debug_hw_bps(&self) -> usize {
-        #[cfg(target_arch = "x86_64")]
-        {
-            4
-        }
-        #[cfg(target_arch = "aarch64")]
-        {
-            self.kvm.get_guest_debug_hw_bps() as usize
-        }
+    fn shutdown_eventfd(&self) -> vm::Result<EventFd> {
+        self.shutdown_evt
+            .try_clone()
+            .map_err(|e| self.labelled(vm::HypervisorVmError::ShutdownEventFd(e.into())))
     }
 
-    /// Get maximum number of vCPUs
-    fn get_max_vcpus(&self) -> u32 {
-        self.kvm.get_max_vcpus().min(u32::MAX as usize) as u32
+    #[cfg(target_arch = "x86_64")]
+    fn in_kernel_pio_ports(&self) -> Vec<RangeInclusive<u16>> {
+        if self.in_kernel_pic.load(Ordering::Acquire) {
+            KVM_PIC_PIO_PORTS.to_vec()
+        } else {
+            Vec::new()
+        }
     }
-}
 
-/// Vcpu struct for KVM
-pub struct KvmVcpu {
-    fd: VcpuFd,
-    #[cfg(target_arch = "x86_64")]
-    msrs: Vec<MsrEntry>,
-    vm_ops: Option<Arc<dyn vm::VmOps>>,
     #[cfg(target_arch = "x86_64")]
-    hyperv_synic: AtomicBool,
-}
-
-/// Implementation of Vcpu trait for KVM
-///
-/// # Examples
-///
-/// ```
-/// # use hypervisor::kvm::KvmHypervisor;
-/// # use hypervisor::HypervisorVmConfig;
-/// # use std::sync::Arc;
-/// let kvm = KvmHypervisor::new().unwrap();
-/// let hypervisor = Arc::new(kvm);
-/// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
-/// let vcpu = vm.create_vcpu(0, None).unwrap();
-/// ```
-impl cpu::Vcpu for KvmVcpu {
-    ///
-    /// Returns StandardRegisters with default value set
-    ///
-    fn create_standard_regs(&self) -> StandardRegisters {
-        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-        {
-            kvm_bindings::kvm_regs::default().into()
+    fn register_pio_handler(
+        &self,
+        range: RangeInclusive<u16>,
+        handler: Arc<dyn vm::PioHandler>,
+    ) -> vm::Result<()> {
+        if range.is_empty() {
+            return Err(
+                self.labelled(vm::HypervisorVmError::RegisterPioHandler(anyhow!(
+                    "Empty port range {range:x?}"
+                ))),
+            );
         }
-        #[cfg(target_arch = "riscv64")]
+
+        let mut handlers = self.pio_handlers.write().unwrap();
+        if let Some((existing, _)) = handlers
+            .iter()
+            .find(|(r, _)| r.start() <= range.end() && range.start() <= r.end())
         {
-            kvm_bindings::kvm_riscv_core::default().into()
+            return Err(
+                self.labelled(vm::HypervisorVmError::RegisterPioHandler(anyhow!(
+                    "Port range {range:x?} overlaps the registered range {existing:x?}"
+                ))),
+            );
         }
+        handlers.push((range, handler));
+
+        Ok(())
     }
+
     #[cfg(target_arch = "x86_64")]
-    ///
-    /// Returns the vCPU general purpose registers.
-    ///
-    fn get_regs(&self) -> cpu::Result<StandardRegisters> {
-        Ok(self
+    fn set_apic_bus_cycle_ns(&self, ns: u32) -> vm::Result<()> {
+        if self
             .fd
-            .get_regs()
-            .map_err(|e| cpu::HypervisorCpuError::GetStandardRegs(e.into()))?
-            .into())
-    }
-
-    ///
-    /// Returns the vCPU general purpose registers.
-    /// The `KVM_GET_REGS` ioctl is not available on AArch64, `KVM_GET_ONE_REG`
-    /// is used to get registers one by one.
-    ///
-    #[cfg(target_arch = "aarch64")]
-    fn get_regs(&self) -> cpu::Result<StandardRegisters> {
-        let mut state = kvm_regs::default();
-        let mut off = offset_of!(user_pt_regs, regs);
-        // There are 31 user_pt_regs:
-        // https://elixir.bootlin.com/linux/v4.14.174/source/arch/arm64/include/uapi/asm/ptrace.h#L72
-        // These actually are the general-purpose registers of the Armv8-a
-        // architecture (i.e x0-x30 if used as a 64bit register or w0-30 when used as a 32bit register).
-        for i in 0..31 {
-            let mut bytes = [0_u8; 8];
-            self.fd
-                .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
-                .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
-            state.regs.regs[i] = u64::from_le_bytes(bytes);
-            off += std::mem::size_of::<u64>();
+            .check_extension_raw(KVM_CAP_X86_APIC_BUS_CYCLES_NS.into())
+            <= 0
+        {
+            return Err(
+                self.labelled(vm::HypervisorVmError::SetApicBusCycle(anyhow!(
+                    "KVM_CAP_X86_APIC_BUS_CYCLES_NS not supported"
+                ))),
+            );
+        }
+        if ns == 0 {
+            return Err(
+                self.labelled(vm::HypervisorVmError::SetApicBusCycle(anyhow!(
+                    "The APIC bus cycle period can't be 0"
+                ))),
+            );
+        }
+        // KVM would fail with EINVAL, say why instead
+        if !self.vcpu_io_in_flight.lock().unwrap().is_empty() {
+            return Err(
+                self.labelled(vm::HypervisorVmError::SetApicBusCycle(anyhow!(
+                    "vCPUs have already been created"
+                ))),
+            );
         }
 
-        // We are now entering the "Other register" section of the ARMv8-a architecture.
-        // First one, stack pointer.
-        let off = offset_of!(user_pt_regs, sp);
-        let mut bytes = [0_u8; 8];
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_APIC_BUS_CYCLES_NS,
+            ..Default::default()
+        };
+        cap.args[0] = ns.into();
         self.fd
-            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
-            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
-        state.regs.sp = u64::from_le_bytes(bytes);
+            .enable_cap(&cap)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetApicBusCycle(e.into())))
+    }
 
-        // Second one, the program counter.
-        let off = offset_of!(user_pt_regs, pc);
-        let mut bytes = [0_u8; 8];
-        self.fd
-            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
-            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
-        state.regs.pc = u64::from_le_bytes(bytes);
+    fn signal_msi(&self, addr: u64, data: u32) -> vm::Result<()> {
+        if !self.check_extension(Cap::SignalMsi) {
+            return Err(self.labelled(vm::HypervisorVmError::SignalMsi(anyhow!(
+                "KVM_CAP_SIGNAL_MSI not supported"
+            ))));
+        }
 
-        // Next is the processor state.
-        let off = offset_of!(user_pt_regs, pstate);
-        let mut bytes = [0_u8; 8];
-        self.fd
-            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
-            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
-        state.regs.pstate = u64::from_le_bytes(bytes);
+        let (address_lo, address_hi) =
+            Self::translate_msi_ext_dest_id(addr as u32, (addr >> 32) as u32);
+        let msi = kvm_bindings::kvm_msi {
+            address_lo,
+            address_hi,
+            data,
+            ..Default::default()
+        };
 
-        // The stack pointer associated with EL1
-        let off = offset_of!(kvm_regs, sp_el1);
-        let mut bytes = [0_u8; 8];
-        self.fd
-            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
-            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
-        state.sp_el1 = u64::from_le_bytes(bytes);
+        // KVM returns the number of vCPUs the MSI got delivered to, 0 when
+        // the guest blocked it.
+        let delivered = self
+            .fd
+            .signal_msi(msi)
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SignalMsi(e.into())))?;
+        if delivered == 0 {
+            return Err(self.labelled(vm::HypervisorVmError::MsiNotDelivered(addr, data)));
+        }
 
-        // Exception Link Register for EL1, when taking an exception to EL1, this register
-        // holds the address to which to return afterwards.
-        let off = offset_of!(kvm_regs, elr_el1);
-        let mut bytes = [0_u8; 8];
-        self.fd
-            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
-            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
-        state.elr_el1 = u64::from_le_bytes(bytes);
+        Ok(())
+    }
 
-        // Saved Program Status Registers, there are 5 of them used in the kernel.
-        let mut off = offset_of!(kvm_regs, spsr);
+    fn quiesce(&self) -> vm::Result<()> {
+        let busy: Vec<u32> = self
+            .vcpu_io_in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, in_flight)| in_flight.load(Ordering::SeqCst))
+            .map(|(id, _)| *id)
+            .collect();
+
+        if !busy.is_empty() {
+            return Err(self.labelled(vm::HypervisorVmError::Quiesce(anyhow!(
+                "vCPUs {busy:?} have an IO operation in flight"
+            ))));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn mte_enabled(&self) -> bool {
+        self.mte
+    }
+
+    ///
+    /// Read the MTE tags through KVM_ARM_MTE_COPY_TAGS.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn get_mte_tags(&self, gpa: u64, tags: &mut [u8]) -> vm::Result<()> {
+        self.copy_mte_tags(
+            gpa,
+            tags.as_mut_ptr(),
+            tags.len(),
+            kvm_bindings::KVM_ARM_TAGS_FROM_GUEST as u64,
+        )
+    }
+
+    ///
+    /// Write the MTE tags through KVM_ARM_MTE_COPY_TAGS.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_mte_tags(&self, gpa: u64, tags: &[u8]) -> vm::Result<()> {
+        // The kernel only reads from the buffer when copying to the guest
+        self.copy_mte_tags(
+            gpa,
+            tags.as_ptr().cast_mut(),
+            tags.len(),
+            kvm_bindings::KVM_ARM_TAGS_TO_GUEST as u64,
+        )
+    }
+}
+
+#[cfg(feature = "tdx")]
+fn tdx_command(
+    fd: &RawFd,
+    command: TdxCommand,
+    flags: u32,
+    data: u64,
+) -> std::result::Result<(), std::io::Error> {
+    #[repr(C)]
+    struct TdxIoctlCmd {
+        command: TdxCommand,
+        flags: u32,
+        data: u64,
+        error: u64,
+        unused: u64,
+    }
+    let cmd = TdxIoctlCmd {
+        command,
+        flags,
+        data,
+        error: 0,
+        unused: 0,
+    };
+    // SAFETY: FFI call. All input parameters are valid.
+    let ret = unsafe {
+        ioctl_with_val(
+            fd,
+            KVM_MEMORY_ENCRYPT_OP(),
+            &cmd as *const TdxIoctlCmd as std::os::raw::c_ulong,
+        )
+    };
+
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wrapper over KVM system ioctls.
+pub struct KvmHypervisor {
+    kvm: Kvm,
+    /// Supported CPUID, computed on the first request when caching is enabled
+    #[cfg(target_arch = "x86_64")]
+    supported_cpuid: Mutex<Option<Vec<CpuIdEntry>>>,
+    #[cfg(target_arch = "x86_64")]
+    cache_cpuid: AtomicBool,
+}
+
+impl KvmHypervisor {
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Retrieve the list of MSRs supported by the hypervisor.
+    ///
+    fn get_msr_list(&self) -> hypervisor::Result<MsrList> {
+        self.kvm
+            .get_msr_index_list()
+            .map_err(|e| hypervisor::HypervisorError::GetMsrList(e.into()))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Retrieve the VM types reported by KVM_CAP_VM_TYPES, `None` on kernels
+    /// lacking the capability.
+    ///
+    fn reported_vm_types(&self) -> Option<Vec<hypervisor::VmType>> {
+        let types = self
+            .kvm
+            .check_extension_raw(kvm_bindings::KVM_CAP_VM_TYPES.into());
+        (types > 0).then(|| {
+            KVM_X86_VM_TYPES
+                .iter()
+                .filter(|(kvm_type, _)| types & (1 << kvm_type) != 0)
+                .map(|(_, vm_type)| *vm_type)
+                .collect()
+        })
+    }
+}
+
+/// Enum for KVM related error
+#[derive(Debug, Error)]
+pub enum KvmError {
+    #[error("Capability missing: {0:?}")]
+    CapabilityMissing(Cap),
+}
+
+pub type KvmResult<T> = result::Result<T, KvmError>;
+
+impl KvmHypervisor {
+    /// Create a hypervisor based on Kvm
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> hypervisor::Result<Arc<dyn hypervisor::Hypervisor>> {
+        let kvm_obj = Kvm::new().map_err(|e| hypervisor::HypervisorError::VmCreate {
+            context: hypervisor::VmCreateContext {
+                operation: "open(/dev/kvm)",
+                vm_type: 0,
+                errno: Some(e.errno()),
+            },
+            source: e.into(),
+        })?;
+
+        Self::from_kvm(kvm_obj)
+    }
+
+    /// Lists the KVM devices of the host, the character devices of `/dev`
+    /// named `kvm*`, sorted by path. Any of them can be given to
+    /// `new_with_path()`.
+    pub fn devices() -> std::io::Result<Vec<PathBuf>> {
+        let mut devices = Vec::new();
+        for entry in std::fs::read_dir("/dev")? {
+            let entry = entry?;
+            if entry.file_name().as_bytes().starts_with(b"kvm")
+                && entry.file_type()?.is_char_device()
+            {
+                devices.push(entry.path());
+            }
+        }
+        devices.sort();
+        Ok(devices)
+    }
+
+    /// Create a hypervisor based on Kvm, using the KVM device at `path`
+    /// instead of `/dev/kvm`.
+    pub fn new_with_path(path: &Path) -> hypervisor::Result<Arc<dyn hypervisor::Hypervisor>> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| hypervisor::HypervisorError::HypervisorCreate(e.into()))?;
+        let kvm_obj = Kvm::new_with_path(&c_path).map_err(|e| {
+            hypervisor::HypervisorError::HypervisorCreate(anyhow!(
+                "Failed to open {}: {e}",
+                path.display()
+            ))
+        })?;
+
+        Self::from_kvm(kvm_obj)
+    }
+
+    /// Create a hypervisor based on Kvm from an already opened KVM device,
+    /// taking ownership of `fd`.
+    ///
+    /// This allows running without access to `/dev/kvm`, e.g. from within
+    /// a sandbox.
+    pub fn from_fd(fd: RawFd) -> hypervisor::Result<Arc<dyn hypervisor::Hypervisor>> {
+        // SAFETY: the caller transfers the ownership of the file descriptor,
+        // which is closed on error when `kvm_obj` is dropped.
+        let kvm_obj = unsafe { Kvm::from_raw_fd(fd) };
+
+        // KVM_GET_API_VERSION fails on anything but a KVM device
+        if kvm_obj.get_api_version() < 0 {
+            return Err(hypervisor::HypervisorError::HypervisorCreate(anyhow!(
+                "fd {fd} is not a KVM device: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        Self::from_kvm(kvm_obj)
+    }
+
+    fn from_kvm(kvm_obj: Kvm) -> hypervisor::Result<Arc<dyn hypervisor::Hypervisor>> {
+        let api_version = kvm_obj.get_api_version();
+
+        if api_version != kvm_bindings::KVM_API_VERSION as i32 {
+            return Err(hypervisor::HypervisorError::IncompatibleApiVersion);
+        }
+
+        Ok(Arc::new(KvmHypervisor {
+            kvm: kvm_obj,
+            #[cfg(target_arch = "x86_64")]
+            supported_cpuid: Mutex::new(None),
+            #[cfg(target_arch = "x86_64")]
+            cache_cpuid: AtomicBool::new(true),
+        }))
+    }
+
+    fn vm_from_fd(
+        &self,
+        fd: VmFd,
+        vm_type: u64,
+        config: HypervisorVmConfig,
+    ) -> hypervisor::Result<Arc<dyn vm::Vm>> {
+        // MTE must be enabled before any vCPU is created
+        #[cfg(target_arch = "aarch64")]
+        if config.mte {
+            if fd.check_extension_raw(kvm_bindings::KVM_CAP_ARM_MTE.into()) <= 0 {
+                return Err(hypervisor::HypervisorError::MteUnsupported);
+            }
+            let cap = kvm_bindings::kvm_enable_cap {
+                cap: kvm_bindings::KVM_CAP_ARM_MTE,
+                ..Default::default()
+            };
+            fd.enable_cap(&cap)
+                .map_err(|e| hypervisor::HypervisorError::EnableMte(e.into()))?;
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if config.system_suspend {
+            if fd.check_extension_raw(kvm_bindings::KVM_CAP_ARM_SYSTEM_SUSPEND.into()) <= 0 {
+                return Err(hypervisor::HypervisorError::SystemSuspendUnsupported);
+            }
+            let cap = kvm_bindings::kvm_enable_cap {
+                cap: kvm_bindings::KVM_CAP_ARM_SYSTEM_SUSPEND,
+                ..Default::default()
+            };
+            fd.enable_cap(&cap)
+                .map_err(|e| hypervisor::HypervisorError::EnableSystemSuspend(e.into()))?;
+        }
+
+        // The MSR filter must be set before any vCPU runs
+        #[cfg(target_arch = "x86_64")]
+        if config.rapl.is_some() {
+            enable_rapl_msr_exits(&fd)?;
+        }
+
+        let vm_fd = Arc::new(fd);
+        debug!("Created KVM VM \"{}\" (type {vm_type})", config.label);
+
+        let shutdown_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+            hypervisor::HypervisorError::VmCreate {
+                context: hypervisor::VmCreateContext {
+                    operation: "eventfd",
+                    vm_type,
+                    errno: e.raw_os_error(),
+                },
+                source: e.into(),
+            }
+        })?);
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let indices = self.migration_msr_indices()?;
+            // KVM only lists the CET MSRs when it supports CET, a guest CET
+            // is exposed to needs all of them.
+            if config.cet
+                && let Some(index) = CET_MIGRATION_MSRS
+                    .iter()
+                    .find(|index| !indices.contains(index))
+            {
+                return Err(hypervisor::HypervisorError::GetMsrList(anyhow!(
+                    "CET MSR 0x{index:x} can't be saved"
+                )));
+            }
+            // Likewise for the Processor Trace MSRs
+            if config.processor_trace
+                && let Some(index) = PT_MIGRATION_MSRS
+                    .iter()
+                    .find(|index| !indices.contains(index))
+            {
+                return Err(hypervisor::HypervisorError::GetMsrList(anyhow!(
+                    "Processor Trace MSR 0x{index:x} can't be saved"
+                )));
+            }
+
+            let msrs: Vec<MsrEntry> = indices
+                .into_iter()
+                .filter(|index| !config.disabled_msrs.contains(index))
+                .map(|index| MsrEntry {
+                    index,
+                    ..Default::default()
+                })
+                .collect();
+
+            Ok(Arc::new(KvmVm {
+                fd: vm_fd,
+                label: config.label,
+                msrs,
+                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
+                dirty_log_enabled: AtomicBool::new(false),
+                dirty_log_pending: Mutex::new(HashMap::new()),
+                memory_slots: RwLock::new(HashMap::new()),
+                halt_poll_ns: RwLock::new(None),
+                watchpoints: Arc::new(RwLock::new(Vec::new())),
+                mmio_rate_limits: Arc::new(RwLock::new(Vec::new())),
+                vcpu_stats: Mutex::new(Vec::new()),
+                device_mmio_slots: Mutex::new(Vec::new()),
+                vcpu_io_in_flight: Mutex::new(Vec::new()),
+                pio_handlers: Arc::new(RwLock::new(Vec::new())),
+                ioapic_pins: AtomicU32::new(0),
+                in_kernel_pic: AtomicBool::new(false),
+                rapl: config.rapl.map(|mode| Arc::new(RaplMsrs::new(mode))),
+                topology: config.topology,
+                max_vcpus: config.max_vcpus.max(config.boot_vcpus),
+                confidential: KVM_X86_VM_TYPES.iter().any(|(kvm_type, kind)| {
+                    u64::from(*kvm_type) == vm_type && *kind != hypervisor::VmType::Default
+                }),
+                shutdown_evt,
+            }))
+        }
+
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        {
+            Ok(Arc::new(KvmVm {
+                fd: vm_fd,
+                label: config.label,
+                dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
+                dirty_log_enabled: AtomicBool::new(false),
+                dirty_log_pending: Mutex::new(HashMap::new()),
+                memory_slots: RwLock::new(HashMap::new()),
+                halt_poll_ns: RwLock::new(None),
+                watchpoints: Arc::new(RwLock::new(Vec::new())),
+                mmio_rate_limits: Arc::new(RwLock::new(Vec::new())),
+                vcpu_stats: Mutex::new(Vec::new()),
+                device_mmio_slots: Mutex::new(Vec::new()),
+                vcpu_io_in_flight: Mutex::new(Vec::new()),
+                shutdown_evt,
+                #[cfg(target_arch = "aarch64")]
+                mte: config.mte,
+            }))
+        }
+    }
+
+    /// Check if the hypervisor is available
+    pub fn is_available() -> hypervisor::Result<bool> {
+        match std::fs::metadata("/dev/kvm") {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(hypervisor::HypervisorError::HypervisorAvailableCheck(
+                err.into(),
+            )),
+        }
+    }
+}
+
+/// Implementation of Hypervisor trait for KVM
+///
+/// # Examples
+///
+/// ```
+/// # use hypervisor::kvm::KvmHypervisor;
+/// # use hypervisor::HypervisorVmConfig;
+/// # use std::sync::Arc;
+/// let kvm = KvmHypervisor::new().unwrap();
+/// let hypervisor = Arc::new(kvm);
+/// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
+/// ```
+impl hypervisor::Hypervisor for KvmHypervisor {
+    ///
+    /// Returns the type of the hypervisor
+    ///
+    fn hypervisor_type(&self) -> HypervisorType {
+        HypervisorType::Kvm
+    }
+
+    /// Create a KVM vm object of a specific VM type and return the object as Vm trait object
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use hypervisor::kvm::KvmHypervisor;
+    /// # use hypervisor::kvm::KvmVm;
+    /// # use hypervisor::HypervisorVmConfig;
+    /// let hypervisor = KvmHypervisor::new().unwrap();
+    /// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).unwrap();
+    /// ```
+    fn create_vm(&self, config: HypervisorVmConfig) -> hypervisor::Result<Arc<dyn vm::Vm>> {
+        config.check_vcpu_counts(self.get_max_vcpus())?;
+
+        let fd: VmFd;
+
+        #[allow(unused_mut)]
+        #[allow(unused_assignments)]
+        let mut vm_type: u64 = 0; // Create with default platform type
+
+        // When KVM supports Cap::ArmVmIPASize, it is better to get the IPA
+        // size from the host and use that when creating the VM, which may
+        // avoid unnecessary VM creation failures.
+        #[cfg(target_arch = "aarch64")]
+        if self.kvm.check_extension(Cap::ArmVmIPASize) {
+            vm_type = self.kvm.get_host_ipa_limit().try_into().unwrap();
+        }
+
+        #[cfg(feature = "tdx")]
+        if config.tdx_enabled {
+            vm_type = KVM_X86_SW_PROTECTED_VM.into();
+        } else {
+            vm_type = KVM_X86_DEFAULT_VM.into();
+        };
+
+        // Fail early rather than from within KVM_CREATE_VM, for which an
+        // unsupported type is only reported as EINVAL. Kernels lacking
+        // KVM_CAP_VM_TYPES are left to KVM_CREATE_VM.
+        #[cfg(target_arch = "x86_64")]
+        if let Some((_, requested)) = KVM_X86_VM_TYPES
+            .iter()
+            .find(|(kvm_type, _)| u64::from(*kvm_type) == vm_type)
+            && let Some(supported) = self.reported_vm_types()
+            && !supported.contains(requested)
+        {
+            return Err(hypervisor::HypervisorError::UnsupportedVmType());
+        }
+
+        loop {
+            match self.kvm.create_vm_with_type(vm_type) {
+                Ok(res) => fd = res,
+                Err(e) => {
+                    if e.errno() == libc::EINTR {
+                        // If the error returned is EINTR, which means the
+                        // ioctl has been interrupted, we have to retry as
+                        // this can't be considered as a regular error.
+                        continue;
+                    } else {
+                        return Err(hypervisor::HypervisorError::VmCreate {
+                            context: hypervisor::VmCreateContext {
+                                operation: "KVM_CREATE_VM",
+                                vm_type,
+                                errno: Some(e.errno()),
+                            },
+                            source: e.into(),
+                        });
+                    }
+                }
+            }
+            break;
+        }
+
+        self.vm_from_fd(fd, vm_type, config)
+    }
+
+    ///
+    /// Create a Vm from a VM file descriptor created outside of this process,
+    /// taking ownership of it.
+    ///
+    fn create_vm_from_fd(
+        &self,
+        fd: RawFd,
+        config: HypervisorVmConfig,
+    ) -> hypervisor::Result<Arc<dyn vm::Vm>> {
+        // SAFETY: the caller transfers the ownership of the file descriptor.
+        let vm_fd = unsafe { self.kvm.create_vmfd_from_rawfd(fd) }.map_err(|e| {
+            hypervisor::HypervisorError::VmCreate {
+                context: hypervisor::VmCreateContext {
+                    operation: "create_vmfd_from_rawfd",
+                    vm_type: 0,
+                    errno: Some(e.errno()),
+                },
+                source: e.into(),
+            }
+        })?;
+
+        // KVM_CHECK_EXTENSION fails on anything but a KVM file descriptor
+        if vm_fd.check_extension_int(Cap::UserMemory) <= 0 {
+            return Err(hypervisor::HypervisorError::VmCreate {
+                context: hypervisor::VmCreateContext {
+                    operation: "KVM_CHECK_EXTENSION",
+                    vm_type: 0,
+                    errno: None,
+                },
+                source: anyhow!("fd {fd} is not a KVM VM file descriptor"),
+            });
+        }
+
+        self.vm_from_fd(vm_fd, 0, config)
+    }
+
+    fn check_required_extensions(&self) -> hypervisor::Result<()> {
+        check_required_kvm_extensions(&self.kvm)
+            .map_err(|e| hypervisor::HypervisorError::CheckExtensions(e.into()))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// X86 specific call to get the system supported CPUID values.
+    ///
+    fn get_supported_cpuid(&self) -> hypervisor::Result<Vec<CpuIdEntry>> {
+        // The host CPUID doesn't change at runtime, so KVM_GET_SUPPORTED_CPUID
+        // is only issued once unless the cache is disabled.
+        let cache = self.cache_cpuid.load(Ordering::Acquire);
+        let mut supported_cpuid = self.supported_cpuid.lock().unwrap();
+        if cache && let Some(cpuid) = supported_cpuid.as_ref() {
+            return Ok(cpuid.clone());
+        }
+
+        let kvm_cpuid = self
+            .kvm
+            .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+            .map_err(|e| hypervisor::HypervisorError::GetCpuId(e.into()))?;
+
+        let v: Vec<CpuIdEntry> = kvm_cpuid.as_slice().iter().map(|e| (*e).into()).collect();
+        if cache {
+            *supported_cpuid = Some(v.clone());
+        }
+
+        Ok(v)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_cpuid_cache(&self, enabled: bool) {
+        self.cache_cpuid.store(enabled, Ordering::Release);
+        self.invalidate_cpuid_cache();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn invalidate_cpuid_cache(&self) {
+        *self.supported_cpuid.lock().unwrap() = None;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn hyperv_tlb_flush_supported(&self) -> bool {
+        self.kvm
+            .check_extension_raw(kvm_bindings::KVM_CAP_HYPERV_TLBFLUSH.into())
+            > 0
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the MSRs reported by KVM_GET_MSR_INDEX_LIST, checking that all the
+    /// MSRs required to migrate a vCPU are part of them.
+    ///
+    /// Feature MSRs (KVM_GET_MSR_FEATURE_INDEX_LIST) are only migrated when
+    /// KVM also reports them as saveable, e.g. IA32_ARCH_CAPABILITIES. The
+    /// others, such as the VMX capabilities, describe the host and can only
+    /// be set on a vCPU in some configurations.
+    ///
+    fn migration_msr_indices(&self) -> hypervisor::Result<Vec<u32>> {
+        let mut indices = self.get_msr_list()?.as_slice().to_vec();
+
+        if let Some(index) = REQUIRED_MIGRATION_MSRS
+            .iter()
+            .find(|index| !indices.contains(index))
+        {
+            return Err(hypervisor::HypervisorError::GetMsrList(anyhow!(
+                "Required MSR 0x{index:x} can't be saved"
+            )));
+        }
+
+        // IA32_TSC_AUX identifies the vCPU to a guest using RDTSCP or RDPID,
+        // possibly to index per CPU data, losing it would corrupt them.
+        let tsc_aux = self.get_supported_cpuid()?.iter().any(|e| {
+            (e.function == 0x8000_0001 && e.edx & (1 << RDTSCP_EDX_BIT) != 0)
+                || (e.function == 7 && e.index == 0 && e.ecx & (1 << RDPID_ECX_BIT) != 0)
+        });
+        if tsc_aux && !indices.contains(&msr_index::MSR_TSC_AUX) {
+            return Err(hypervisor::HypervisorError::GetMsrList(anyhow!(
+                "TSC_AUX MSR 0x{:x} can't be saved",
+                msr_index::MSR_TSC_AUX
+            )));
+        }
+
+        // SMBASE is only accessible from the host, and moves when the guest
+        // relocates its SMRAM. It must follow a guest taking SMIs.
+        if self
+            .kvm
+            .check_extension_raw(kvm_bindings::KVM_CAP_X86_SMM.into())
+            > 0
+        {
+            indices.push(msr_index::MSR_IA32_SMBASE);
+        }
+
+        // The steal time MSR holds the guest buffer address which must be
+        // carried over, otherwise the guest stops seeing its steal time.
+        // KVM lists it already when it supports steal time.
+        indices.push(MSR_KVM_STEAL_TIME);
+
+        indices.sort_unstable();
+        indices.dedup();
+
+        // KVM only accepts enabling interrupt based delivery through
+        // MSR_KVM_ASYNC_PF_EN once MSR_KVM_ASYNC_PF_INT holds the vector,
+        // so the latter must be restored first.
+        if let (Some(en), Some(int)) = (
+            indices.iter().position(|i| *i == MSR_KVM_ASYNC_PF_EN),
+            indices.iter().position(|i| *i == MSR_KVM_ASYNC_PF_INT),
+        ) {
+            let index = indices.remove(en);
+            indices.insert(int, index);
+        }
+
+        // KVM rejects writes to the other IA32_RTIT_* MSRs while tracing is
+        // enabled, so IA32_RTIT_CTL must be restored last.
+        if let Some(ctl) = indices
+            .iter()
+            .position(|i| *i == msr_index::MSR_IA32_RTIT_CTL)
+        {
+            let index = indices.remove(ctl);
+            indices.push(index);
+        }
+
+        Ok(indices)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get a feature MSR listed by KVM_GET_MSR_FEATURE_INDEX_LIST.
+    ///
+    fn get_feature_msr(&self, index: u32) -> hypervisor::Result<u64> {
+        let features = self
+            .kvm
+            .get_msr_feature_index_list()
+            .map_err(|e| hypervisor::HypervisorError::GetFeatureMsr(e.into()))?;
+        if !features.as_slice().contains(&index) {
+            return Err(hypervisor::HypervisorError::GetFeatureMsr(anyhow!(
+                "MSR 0x{index:x} is not a feature MSR"
+            )));
+        }
+
+        let mut msrs = MsrEntries::from_entries(&[kvm_msr_entry {
+            index,
+            ..Default::default()
+        }])
+        .unwrap();
+        let read = self
+            .kvm
+            .get_msrs(&mut msrs)
+            .map_err(|e| hypervisor::HypervisorError::GetFeatureMsr(e.into()))?;
+        if read != 1 {
+            return Err(hypervisor::HypervisorError::GetFeatureMsr(anyhow!(
+                "Failed to read MSR 0x{index:x}"
+            )));
+        }
+
+        Ok(msrs.as_slice()[0].data)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    ///
+    /// Retrieve AArch64 host maximum IPA size supported by KVM.
+    ///
+    fn get_host_ipa_limit(&self) -> i32 {
+        self.kvm.get_host_ipa_limit()
+    }
+
+    ///
+    /// Retrieve TDX capabilities
+    ///
+    #[cfg(feature = "tdx")]
+    fn tdx_capabilities(&self) -> hypervisor::Result<TdxCapabilities> {
+        let data = TdxCapabilities {
+            nr_cpuid_configs: TDX_MAX_NR_CPUID_CONFIGS as u32,
+            ..Default::default()
+        };
+
+        tdx_command(
+            &self.kvm.as_raw_fd(),
+            TdxCommand::Capabilities,
+            0,
+            &data as *const _ as u64,
+        )
+        .map_err(|e| hypervisor::HypervisorError::TdxCapabilities(e.into()))?;
+
+        Ok(data)
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    ///
+    /// Get the number of supported hardware breakpoints
+    ///
+    fn get_guest_debug_hw_bps(&self) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        {
+            4
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            self.kvm.get_guest_debug_hw_bps() as usize
+        }
+    }
+
+    /// Get maximum number of vCPUs
+    fn get_max_vcpus(&self) -> u32 {
+        self.kvm.get_max_vcpus().min(u32::MAX as usize) as u32
+    }
+
+    ///
+    /// Get the VM types reported by KVM_CAP_VM_TYPES, only the default type
+    /// is supported by kernels lacking it
+    ///
+    fn supported_vm_types(&self) -> Vec<hypervisor::VmType> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(types) = self.reported_vm_types() {
+            return types;
+        }
+
+        vec![hypervisor::VmType::Default]
+    }
+
+    ///
+    /// Read the parameters of the loaded kvm modules from sysfs
+    ///
+    fn kvm_module_params(&self) -> hypervisor::Result<hypervisor::KvmModuleParams> {
+        let mut raw = BTreeMap::new();
+
+        let modules = std::fs::read_dir("/sys/module")
+            .map_err(|e| hypervisor::HypervisorError::GetModuleParams(e.into()))?;
+        for module in modules.flatten() {
+            let module_name = module.file_name().to_string_lossy().into_owned();
+            if !module_name.starts_with("kvm") {
+                continue;
+            }
+
+            let Ok(params) = std::fs::read_dir(module.path().join("parameters")) else {
+                continue;
+            };
+            for param in params.flatten() {
+                // Some parameters can only be read by root, skip them.
+                if let Ok(value) = std::fs::read_to_string(param.path()) {
+                    raw.insert(
+                        format!("{module_name}.{}", param.file_name().to_string_lossy()),
+                        value.trim().to_string(),
+                    );
+                }
+            }
+        }
+
+        Ok(hypervisor::KvmModuleParams::from_raw(raw))
+    }
+
+    ///
+    /// Read the MMU in use from the kvm module parameters.
+    ///
+    /// `kvm.tdp_mmu` only tells whether the TDP MMU is allowed, KVM falls
+    /// back to the shadow MMU when EPT or NPT is disabled.
+    ///
+    fn mmu_mode(&self) -> hypervisor::MmuMode {
+        let param = |path: &str| {
+            std::fs::read_to_string(path)
+                .ok()
+                .map(|v| matches!(v.trim(), "Y" | "y" | "1"))
+        };
+
+        let Some(tdp_mmu) = param("/sys/module/kvm/parameters/tdp_mmu") else {
+            return hypervisor::MmuMode::Unknown;
+        };
+        let tdp = param("/sys/module/kvm_intel/parameters/ept")
+            .or_else(|| param("/sys/module/kvm_amd/parameters/npt"))
+            .unwrap_or(true);
+
+        if tdp_mmu && tdp {
+            hypervisor::MmuMode::Tdp
+        } else {
+            hypervisor::MmuMode::Shadow
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Retrieve the SEV ASIDs from CPUID, and the number of guests from the
+    /// SEV firmware through `/dev/sev`.
+    ///
+    fn sev_asid_info(&self) -> hypervisor::Result<hypervisor::SevAsidInfo> {
+        let enabled = std::fs::read_to_string("/sys/module/kvm_amd/parameters/sev")
+            .map(|v| matches!(v.trim(), "Y" | "y" | "1"))
+            .unwrap_or(false);
+        if !enabled {
+            return Err(hypervisor::HypervisorError::SevUnsupported);
+        }
+
+        // SAFETY: call cpuid with valid leaves
+        if unsafe { std::arch::x86_64::__cpuid(0x8000_0000) }.eax < 0x8000_001f {
+            return Err(hypervisor::HypervisorError::SevUnsupported);
+        }
+        // SAFETY: call cpuid with valid leaves
+        let leaf = unsafe { std::arch::x86_64::__cpuid(0x8000_001f) };
+
+        let sev = std::fs::File::open("/dev/sev")
+            .map_err(|e| hypervisor::HypervisorError::GetSevAsidInfo(e.into()))?;
+        let mut status = SevPlatformStatus::default();
+        let mut cmd = SevIssueCmd {
+            cmd: SEV_PLATFORM_STATUS,
+            data: &mut status as *mut SevPlatformStatus as u64,
+            ..Default::default()
+        };
+        // SAFETY: FFI call with a valid command, whose data points to a
+        // structure of the size the firmware writes for that command
+        let ret = unsafe { ioctl_with_mut_ref(&sev, SEV_ISSUE_CMD(), &mut cmd) };
+        if ret < 0 {
+            return Err(hypervisor::HypervisorError::GetSevAsidInfo(anyhow!(
+                "SEV_PLATFORM_STATUS failed: {}, firmware error 0x{:x}",
+                std::io::Error::last_os_error(),
+                { cmd.error }
+            )));
+        }
+
+        Ok(hypervisor::SevAsidInfo {
+            asids: leaf.ecx,
+            es_asids: leaf.edx.saturating_sub(1),
+            guests: status.guest_count,
+        })
+    }
+}
+
+/// Vcpu struct for KVM
+pub struct KvmVcpu {
+    fd: VcpuFd,
+    #[cfg(target_arch = "x86_64")]
+    msrs: Vec<MsrEntry>,
+    vm_ops: Option<Arc<dyn vm::VmOps>>,
+    #[cfg(target_arch = "x86_64")]
+    hyperv_synic: AtomicBool,
+    watchpoints: Arc<RwLock<Vec<KvmGpaWatchpoint>>>,
+    mmio_rate_limits: Arc<RwLock<Vec<KvmMmioRateLimit>>>,
+    #[cfg(target_arch = "aarch64")]
+    shutdown_evt: Arc<EventFd>,
+    // Set while an IO or MMIO exit waits for the next KVM_RUN to complete
+    io_in_flight: Arc<AtomicBool>,
+    #[cfg(target_arch = "x86_64")]
+    pio_handlers: Arc<RwLock<Vec<KvmPioHandler>>>,
+    #[cfg(target_arch = "x86_64")]
+    last_exit_insn: Mutex<LastExitInsn>,
+    #[cfg(target_arch = "x86_64")]
+    rapl: Option<Arc<RaplMsrs>>,
+    // Whether KVM_CAP_X86_SMM is supported
+    #[cfg(target_arch = "x86_64")]
+    smm: bool,
+    exit_observer: Mutex<Option<Arc<dyn cpu::VcpuExitObserver>>>,
+    // Last KVM_ARM_VCPU_INIT parameters, replayed on reset
+    #[cfg(target_arch = "aarch64")]
+    init: Mutex<Option<kvm_bindings::kvm_vcpu_init>>,
+}
+
+/// Implementation of Vcpu trait for KVM
+///
+/// # Examples
+///
+/// ```
+/// # use hypervisor::kvm::KvmHypervisor;
+/// # use hypervisor::HypervisorVmConfig;
+/// # use std::sync::Arc;
+/// let kvm = KvmHypervisor::new().unwrap();
+/// let hypervisor = Arc::new(kvm);
+/// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
+/// let vcpu = vm.create_vcpu(0, None).unwrap();
+/// ```
+impl cpu::Vcpu for KvmVcpu {
+    ///
+    /// Returns StandardRegisters with default value set
+    ///
+    fn create_standard_regs(&self) -> StandardRegisters {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            kvm_bindings::kvm_regs::default().into()
+        }
+        #[cfg(target_arch = "riscv64")]
+        {
+            kvm_bindings::kvm_riscv_core::default().into()
+        }
+    }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the vCPU general purpose registers.
+    ///
+    fn get_regs(&self) -> cpu::Result<StandardRegisters> {
+        Ok(self
+            .fd
+            .get_regs()
+            .map_err(|e| cpu::HypervisorCpuError::GetStandardRegs(e.into()))?
+            .into())
+    }
+
+    ///
+    /// Returns the vCPU general purpose registers.
+    /// The `KVM_GET_REGS` ioctl is not available on AArch64, `KVM_GET_ONE_REG`
+    /// is used to get registers one by one.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn get_regs(&self) -> cpu::Result<StandardRegisters> {
+        let mut state = kvm_regs::default();
+        let mut off = offset_of!(user_pt_regs, regs);
+        // There are 31 user_pt_regs:
+        // https://elixir.bootlin.com/linux/v4.14.174/source/arch/arm64/include/uapi/asm/ptrace.h#L72
+        // These actually are the general-purpose registers of the Armv8-a
+        // architecture (i.e x0-x30 if used as a 64bit register or w0-30 when used as a 32bit register).
+        for i in 0..31 {
+            let mut bytes = [0_u8; 8];
+            self.fd
+                .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
+                .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
+            state.regs.regs[i] = u64::from_le_bytes(bytes);
+            off += std::mem::size_of::<u64>();
+        }
+
+        // We are now entering the "Other register" section of the ARMv8-a architecture.
+        // First one, stack pointer.
+        let off = offset_of!(user_pt_regs, sp);
+        let mut bytes = [0_u8; 8];
+        self.fd
+            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
+            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
+        state.regs.sp = u64::from_le_bytes(bytes);
+
+        // Second one, the program counter.
+        let off = offset_of!(user_pt_regs, pc);
+        let mut bytes = [0_u8; 8];
+        self.fd
+            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
+            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
+        state.regs.pc = u64::from_le_bytes(bytes);
+
+        // Next is the processor state.
+        let off = offset_of!(user_pt_regs, pstate);
+        let mut bytes = [0_u8; 8];
+        self.fd
+            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
+            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
+        state.regs.pstate = u64::from_le_bytes(bytes);
+
+        // The stack pointer associated with EL1
+        let off = offset_of!(kvm_regs, sp_el1);
+        let mut bytes = [0_u8; 8];
+        self.fd
+            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
+            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
+        state.sp_el1 = u64::from_le_bytes(bytes);
+
+        // Exception Link Register for EL1, when taking an exception to EL1, this register
+        // holds the address to which to return afterwards.
+        let off = offset_of!(kvm_regs, elr_el1);
+        let mut bytes = [0_u8; 8];
+        self.fd
+            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
+            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
+        state.elr_el1 = u64::from_le_bytes(bytes);
+
+        // Saved Program Status Registers, there are 5 of them used in the kernel.
+        let mut off = offset_of!(kvm_regs, spsr);
         for i in 0..KVM_NR_SPSR as usize {
             let mut bytes = [0_u8; 8];
             self.fd
@@ -1816,6 +3879,117 @@ impl cpu::Vcpu for KvmVcpu {
             .map_err(|e| cpu::HypervisorCpuError::EnableHyperVSyncIc(e.into()))
     }
 
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Inject a #GP on guest accesses to the paravirtual MSRs not advertised
+    /// in CPUID.
+    ///
+    fn enforce_pv_cpuid(&self) -> cpu::Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_ENFORCE_PV_FEATURE_CPUID,
+            args: [1, 0, 0, 0],
+            ..Default::default()
+        };
+        self.fd
+            .enable_cap(&cap)
+            .map_err(|e| cpu::HypervisorCpuError::EnforcePvCpuid(e.into()))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Advertise or hide the asynchronous page faults in the KVM features
+    /// leaf of the vCPU CPUID.
+    ///
+    fn configure_async_pf(&self, enabled: bool) -> cpu::Result<()> {
+        let mut cpuid = self
+            .get_cpuid2(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+            .map_err(|e| cpu::HypervisorCpuError::ConfigureAsyncPf(e.into()))?;
+
+        // The KVM leaves are moved away when the Hyper-V ones are exposed
+        let kvm_leaves = cpuid.iter().any(|e| {
+            e.function == KVM_CPUID_SIGNATURE
+                && e.ebx == 0x4b4d_564b
+                && e.ecx == 0x564b_4d56
+                && e.edx == 0x4d
+        });
+        let features = cpuid
+            .iter_mut()
+            .find(|e| kvm_leaves && e.function == KVM_CPUID_FEATURES);
+
+        match features {
+            Some(entry) if !enabled => {
+                if entry.eax & KVM_ASYNC_PF_FEATURES == 0 {
+                    return Ok(());
+                }
+                entry.eax &= !KVM_ASYNC_PF_FEATURES;
+                self.set_cpuid2(&cpuid)
+                    .map_err(|e| cpu::HypervisorCpuError::ConfigureAsyncPf(e.into()))
+            }
+            Some(entry) if entry.eax & KVM_ASYNC_PF_FEATURES != 0 => Ok(()),
+            None if !enabled => Ok(()),
+            _ => Err(cpu::HypervisorCpuError::ConfigureAsyncPf(anyhow!(
+                "Asynchronous page faults are not advertised in the vCPU CPUID"
+            ))),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Relocate the LAPIC MMIO window through MSR_IA32_APIC_BASE.
+    ///
+    fn set_apic_base(&self, base: u64) -> cpu::Result<()> {
+        // Enable, BSP and x2APIC bits, the base fills the bits above
+        let flags_mask = 0xfff;
+        if base & flags_mask != 0 {
+            return Err(cpu::HypervisorCpuError::SetApicBase(anyhow!(
+                "APIC base 0x{base:x} is not 4KiB aligned"
+            )));
+        }
+        if let Some(vm_ops) = &self.vm_ops
+            && vm_ops.guest_mem_read(base, &mut [0u8]).is_ok()
+        {
+            return Err(cpu::HypervisorCpuError::SetApicBase(anyhow!(
+                "APIC base 0x{base:x} overlaps guest RAM"
+            )));
+        }
+
+        let (_, current) = self.read_msrs(&[msr_index::MSR_IA32_APICBASE])?[0];
+        let msr = MsrEntry {
+            index: msr_index::MSR_IA32_APICBASE,
+            data: base | (current & flags_mask),
+        };
+        if self.set_msrs(&[msr])? != 1 {
+            return Err(cpu::HypervisorCpuError::SetApicBase(anyhow!(
+                "KVM rejected APIC base 0x{:x}",
+                msr.data
+            )));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Advertise crash notification support to the guest.
+    ///
+    fn enable_hyperv_crash_msrs(&self) -> cpu::Result<()> {
+        // The guest reads HV_X64_MSR_CRASH_CTL to find out which crash
+        // actions are supported. This host write only sets the advertised
+        // value, KVM exits with KVM_SYSTEM_EVENT_CRASH once the guest itself
+        // writes HV_CRASH_CTL_CRASH_NOTIFY after filling P0..P4.
+        let msr = MsrEntry {
+            index: HV_X64_MSR_CRASH_CTL,
+            data: HV_CRASH_CTL_CRASH_NOTIFY,
+        };
+        if self.set_msrs(&[msr])? != 1 {
+            return Err(cpu::HypervisorCpuError::EnableHyperVCrashMsrs(anyhow!(
+                "HV_X64_MSR_CRASH_CTL not supported"
+            )));
+        }
+
+        Ok(())
+    }
+
     ///
     /// X86 specific call to retrieve the CPUID registers.
     ///
@@ -1889,6 +4063,45 @@ impl cpu::Vcpu for KvmVcpu {
             .map_err(|e| cpu::HypervisorCpuError::SetMsrEntries(e.into()))
     }
 
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Point MSR_KVM_STEAL_TIME at the guest buffer at `gpa`.
+    ///
+    fn enable_steal_time(&self, gpa: u64) -> cpu::Result<()> {
+        if gpa % KVM_STEAL_TIME_SIZE != 0 {
+            return Err(cpu::HypervisorCpuError::EnableStealTime(anyhow!(
+                "Steal time buffer 0x{gpa:x} is not {KVM_STEAL_TIME_SIZE} bytes aligned"
+            )));
+        }
+
+        // KVM silently stops updating the buffer when it isn't backed by
+        // guest RAM, so check it here.
+        let vm_ops = self.vm_ops.as_ref().ok_or_else(|| {
+            cpu::HypervisorCpuError::EnableStealTime(anyhow!("No VmOps to access guest memory"))
+        })?;
+        let mut buf = [0u8; KVM_STEAL_TIME_SIZE as usize];
+        match vm_ops.guest_mem_read(gpa, &mut buf) {
+            Ok(len) if len == buf.len() => {}
+            _ => {
+                return Err(cpu::HypervisorCpuError::EnableStealTime(anyhow!(
+                    "Steal time buffer 0x{gpa:x} is not in guest RAM"
+                )));
+            }
+        }
+
+        let msr = MsrEntry {
+            index: MSR_KVM_STEAL_TIME,
+            data: gpa | KVM_MSR_ENABLED,
+        };
+        if self.set_msrs(&[msr])? != 1 {
+            return Err(cpu::HypervisorCpuError::EnableStealTime(anyhow!(
+                "MSR_KVM_STEAL_TIME not supported"
+            )));
+        }
+
+        Ok(())
+    }
+
     ///
     /// Returns the vcpu's current "multiprocessing state".
     ///
@@ -1931,89 +4144,59 @@ impl cpu::Vcpu for KvmVcpu {
     /// Triggers the running of the current virtual CPU returning an exit reason.
     ///
     fn run(&mut self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
-        match self.fd.run() {
-            Ok(run) => match run {
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::IoIn(addr, data) => {
-                    if let Some(vm_ops) = &self.vm_ops {
-                        return vm_ops
-                            .pio_read(addr.into(), data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
-                    }
+        // run() gets exclusive access, no need to lock the observer.
+        let Some(observer) = self.exit_observer.get_mut().unwrap().clone() else {
+            return self.run_vcpu(None);
+        };
 
-                    Ok(cpu::VmExit::Ignore)
-                }
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::IoOut(addr, data) => {
-                    if let Some(vm_ops) = &self.vm_ops {
-                        return vm_ops
-                            .pio_write(addr.into(), data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
-                    }
+        let mut duration_in_guest = Duration::ZERO;
+        let exit = self.run_vcpu(Some(&mut duration_in_guest));
+        if exit.is_ok() {
+            // KVM sets the exit reason on signals as well.
+            let kind = exit_kind(self.fd.get_kvm_run().exit_reason);
+            observer.on_exit(kind, duration_in_guest);
+        }
 
-                    Ok(cpu::VmExit::Ignore)
-                }
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+        exit
+    }
 
-                #[cfg(target_arch = "aarch64")]
-                VcpuExit::SystemEvent(event_type, flags) => {
-                    use kvm_bindings::{KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN};
-                    // On Aarch64, when the VM is shutdown, run() returns
-                    // VcpuExit::SystemEvent with reason KVM_SYSTEM_EVENT_SHUTDOWN
-                    if event_type == KVM_SYSTEM_EVENT_RESET {
-                        Ok(cpu::VmExit::Reset)
-                    } else if event_type == KVM_SYSTEM_EVENT_SHUTDOWN {
-                        Ok(cpu::VmExit::Shutdown)
-                    } else {
-                        Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
-                            "Unexpected system event with type 0x{:x}, flags 0x{:x?}",
-                            event_type,
-                            flags
-                        )))
-                    }
-                }
+    fn set_exit_observer(&self, obs: Arc<dyn cpu::VcpuExitObserver>) -> cpu::Result<()> {
+        *self.exit_observer.lock().unwrap() = Some(obs);
+        Ok(())
+    }
 
-                VcpuExit::MmioRead(addr, data) => {
-                    if let Some(vm_ops) = &self.vm_ops {
-                        return vm_ops
-                            .mmio_read(addr, data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
-                    }
+    #[cfg(target_arch = "x86_64")]
+    fn get_smbase(&self) -> cpu::Result<u64> {
+        if !self.smm {
+            return Err(cpu::HypervisorCpuError::GetSmbase(anyhow!(
+                "KVM_CAP_X86_SMM not supported"
+            )));
+        }
 
-                    Ok(cpu::VmExit::Ignore)
-                }
-                VcpuExit::MmioWrite(addr, data) => {
-                    if let Some(vm_ops) = &self.vm_ops {
-                        return vm_ops
-                            .mmio_write(addr, data)
-                            .map(|_| cpu::VmExit::Ignore)
-                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
-                    }
+        self.read_msrs(&[msr_index::MSR_IA32_SMBASE])
+            .map(|msrs| msrs[0].1)
+            .map_err(|e| cpu::HypervisorCpuError::GetSmbase(e.into()))
+    }
 
-                    Ok(cpu::VmExit::Ignore)
-                }
-                VcpuExit::Hyperv => Ok(cpu::VmExit::Hyperv),
-                #[cfg(feature = "tdx")]
-                VcpuExit::Unsupported(KVM_EXIT_TDX) => Ok(cpu::VmExit::Tdx),
-                VcpuExit::Debug(_) => Ok(cpu::VmExit::Debug),
+    #[cfg(target_arch = "x86_64")]
+    fn set_smbase(&self, smbase: u64) -> cpu::Result<()> {
+        if !self.smm {
+            return Err(cpu::HypervisorCpuError::SetSmbase(anyhow!(
+                "KVM_CAP_X86_SMM not supported"
+            )));
+        }
 
-                r => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
-                    "Unexpected exit reason on vcpu run: {r:?}"
-                ))),
-            },
+        self.write_msrs(&[(msr_index::MSR_IA32_SMBASE, smbase)])
+            .map(|_| ())
+            .map_err(|e| cpu::HypervisorCpuError::SetSmbase(e.into()))
+    }
 
-            Err(ref e) => match e.errno() {
-                libc::EAGAIN | libc::EINTR => Ok(cpu::VmExit::Ignore),
-                _ => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
-                    "VCPU error {e:?}"
-                ))),
-            },
+    #[cfg(target_arch = "x86_64")]
+    fn last_exit_instruction(&self) -> Option<Vec<u8>> {
+        match &*self.last_exit_insn.lock().unwrap() {
+            LastExitInsn::Unknown => None,
+            LastExitInsn::AtRip => self.insn_at_rip(),
+            LastExitInsn::Bytes(bytes) => Some(bytes.clone()),
         }
     }
 
@@ -2153,7 +4336,9 @@ impl cpu::Vcpu for KvmVcpu {
         let kvm_kvi: kvm_bindings::kvm_vcpu_init = (*kvi).into();
         self.fd
             .vcpu_init(&kvm_kvi)
-            .map_err(|e| cpu::HypervisorCpuError::VcpuInit(e.into()))
+            .map_err(|e| cpu::HypervisorCpuError::VcpuInit(e.into()))?;
+        *self.init.lock().unwrap() = Some(kvm_kvi);
+        Ok(())
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -2235,13 +4420,7 @@ impl cpu::Vcpu for KvmVcpu {
         // Other vCPUs are powered off initially awaiting PSCI wakeup.
         if cpu_id == 0 {
             // Setting the PC (Processor Counter) to the current program address (kernel address).
-            let pc = offset_of!(kvm_regs, regs.pc);
-            self.fd
-                .set_one_reg(
-                    arm64_core_reg_id!(KVM_REG_SIZE_U64, pc),
-                    &boot_ip.to_le_bytes(),
-                )
-                .map_err(|e| cpu::HypervisorCpuError::SetAarchCoreRegister(e.into()))?;
+            self.set_reset_vector(boot_ip)?;
 
             // Last mandatory thing to set -> the address pointing to the FDT (also called DTB).
             // "The device tree blob (dtb) must be placed on an 8-byte boundary and must
@@ -2255,7 +4434,49 @@ impl cpu::Vcpu for KvmVcpu {
                 )
                 .map_err(|e| cpu::HypervisorCpuError::SetAarchCoreRegister(e.into()))?;
         }
-        Ok(())
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    ///
+    /// Set the PC the vCPU starts at.
+    ///
+    /// Secondary vCPUs are initialized with KVM_ARM_VCPU_POWER_OFF. When the
+    /// guest issues PSCI CPU_ON, KVM resets the target vCPU and loads the
+    /// entry address from the call into its PC, so the guest's choice always
+    /// wins over a vector configured here.
+    ///
+    fn set_reset_vector(&self, pc: u64) -> cpu::Result<()> {
+        self.fd
+            .set_one_reg(
+                arm64_core_reg_id!(KVM_REG_SIZE_U64, offset_of!(kvm_regs, regs.pc)),
+                &pc.to_le_bytes(),
+            )
+            .map_err(|e| cpu::HypervisorCpuError::SetAarchCoreRegister(e.into()))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    ///
+    /// Make a virtual SError pending through KVM_SET_VCPU_EVENTS.
+    ///
+    /// Providing a syndrome requires the RAS extension on the host, KVM
+    /// fails otherwise.
+    ///
+    fn inject_serror(&self, esr: u64) -> cpu::Result<()> {
+        // Only the ISS field can be set, ESR_ELx[24:0]
+        if esr & !0x1ff_ffff != 0 {
+            return Err(cpu::HypervisorCpuError::InjectSError(anyhow!(
+                "Invalid syndrome 0x{esr:x}"
+            )));
+        }
+
+        let mut events = VcpuEvents::default();
+        events.exception.serror_pending = 1;
+        events.exception.serror_has_esr = u8::from(esr != 0);
+        events.exception.serror_esr = esr;
+        self.fd
+            .set_vcpu_events(&events)
+            .map_err(|e| cpu::HypervisorCpuError::InjectSError(e.into()))
     }
 
     #[cfg(target_arch = "riscv64")]
@@ -2297,6 +4518,74 @@ impl cpu::Vcpu for KvmVcpu {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Updates IA32_MISC_ENABLE, checking XD Bit Disable against the NX bit
+    /// of the vCPU CPUID.
+    ///
+    fn set_misc_enable_bits(&self, set: u64, clear: u64) -> cpu::Result<()> {
+        use crate::arch::x86::msr_index::{MSR_IA32_MISC_ENABLE, MSR_IA32_MISC_ENABLE_XD_DISABLE};
+
+        if set & clear != 0 {
+            return Err(cpu::HypervisorCpuError::SetMiscEnable(anyhow!(
+                "Bits 0x{:x} both set and cleared",
+                set & clear
+            )));
+        }
+
+        if (set | clear) & MSR_IA32_MISC_ENABLE_XD_DISABLE != 0 {
+            let nx = self
+                .get_cpuid2(kvm_bindings::KVM_MAX_CPUID_ENTRIES)?
+                .iter()
+                .any(|e| e.function == 0x8000_0001 && e.edx & (1 << NX_EDX_BIT) != 0);
+            let xd_disable = set & MSR_IA32_MISC_ENABLE_XD_DISABLE != 0;
+            if xd_disable == nx {
+                return Err(cpu::HypervisorCpuError::SetMiscEnable(anyhow!(
+                    "XD Bit Disable must be {} as NX is {}advertised",
+                    if nx { "clear" } else { "set" },
+                    if nx { "" } else { "not " }
+                )));
+            }
+        }
+
+        let value = (self.misc_enable()? | set) & !clear;
+        self.write_msrs(&[(MSR_IA32_MISC_ENABLE, value)])
+            .map_err(|e| cpu::HypervisorCpuError::SetMiscEnable(e.into()))?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns XCR0 from the extended control registers.
+    ///
+    fn get_xcr0(&self) -> cpu::Result<u64> {
+        use crate::arch::x86::XCR0_INDEX;
+
+        let xcrs = self.get_xcrs()?;
+        xcrs.xcrs[..xcrs.nr_xcrs as usize]
+            .iter()
+            .find(|x| x.xcr == XCR0_INDEX)
+            .map(|x| x.value)
+            .ok_or_else(|| cpu::HypervisorCpuError::GetXcsr(anyhow!("XCR0 not reported")))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Sets XCR0 alone, the other extended control registers are left
+    /// untouched.
+    ///
+    fn set_xcr0(&self, xcr0: u64) -> cpu::Result<()> {
+        use crate::arch::x86::XCR0_INDEX;
+
+        let mut xcrs = ExtendedControlRegisters {
+            nr_xcrs: 1,
+            ..Default::default()
+        };
+        xcrs.xcrs[0].xcr = XCR0_INDEX;
+        xcrs.xcrs[0].value = xcr0;
+        self.set_xcrs(&xcrs)
+    }
+
     #[cfg(target_arch = "x86_64")]
     ///
     /// Get the current CPU state
@@ -2331,7 +4620,7 @@ impl cpu::Vcpu for KvmVcpu {
     /// let kvm = KvmHypervisor::new().unwrap();
     /// let hv = Arc::new(kvm);
     /// let vm = hv.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
-    /// vm.enable_split_irq().unwrap();
+    /// vm.enable_split_irq(24).unwrap();
     /// let vcpu = vm.create_vcpu(0, None).unwrap();
     /// let state = vcpu.state().unwrap();
     /// ```
@@ -2340,12 +4629,25 @@ impl cpu::Vcpu for KvmVcpu {
         let mp_state = self.get_mp_state()?.into();
         let regs = self.get_regs()?;
         let sregs = self.get_sregs()?;
-        let xsave = self.get_xsave()?;
-        let xcrs = self.get_xcrs()?;
         let lapic_state = self.get_lapic()?;
         let fpu = self.get_fpu()?;
         let nested_state = self.nested_state()?;
 
+        // Prefer XSAVE, which covers the legacy FPU state, for guests that
+        // can use it.
+        let fpu_format = if cpuid
+            .iter()
+            .any(|e| e.function == 1 && e.ecx & (1 << XSAVE_ECX_BIT) != 0)
+        {
+            FpuStateFormat::Xsave
+        } else {
+            FpuStateFormat::Legacy
+        };
+        let (xsave, xcrs) = match fpu_format {
+            FpuStateFormat::Xsave => (self.get_xsave()?, self.get_xcrs()?),
+            FpuStateFormat::Legacy => (XsaveState::default(), ExtendedControlRegisters::default()),
+        };
+
         // Try to get all MSRs based on the list previously retrieved from KVM.
         // If the number of MSRs obtained from GET_MSRS is different from the
         // expected amount, we fallback onto a slower method by getting MSRs
@@ -2356,6 +4658,26 @@ impl cpu::Vcpu for KvmVcpu {
         // Save extra MSRs if the Hyper-V synthetic interrupt controller is
         // emulated.
         if self.hyperv_synic.load(Ordering::Acquire) {
+            // The enlightenments the guest enabled, e.g. the hypercall page
+            // the TLB flush hypercalls go through. The guest OS ID must be
+            // restored first, KVM ignores enabling hypercalls without it.
+            let enlightenment_msrs = [
+                HV_X64_MSR_GUEST_OS_ID,
+                HV_X64_MSR_HYPERCALL,
+                HV_X64_MSR_VP_INDEX,
+                HV_X64_MSR_VP_ASSIST_PAGE,
+            ]
+            .into_iter()
+            .chain(HV_X64_MSR_CRASH_P0..=HV_X64_MSR_CRASH_CTL);
+            for index in enlightenment_msrs {
+                if !msr_entries.iter().any(|m| m.index == index) {
+                    msr_entries.push(MsrEntry {
+                        index,
+                        ..Default::default()
+                    });
+                }
+            }
+
             let hyperv_synic_msrs = vec![
                 0x40000020, 0x40000021, 0x40000080, 0x40000081, 0x40000082, 0x40000083, 0x40000084,
                 0x40000090, 0x40000091, 0x40000092, 0x40000093, 0x40000094, 0x40000095, 0x40000096,
@@ -2364,11 +4686,13 @@ impl cpu::Vcpu for KvmVcpu {
                 0x400000b5, 0x400000b6, 0x400000b7,
             ];
             for index in hyperv_synic_msrs {
-                let msr = kvm_msr_entry {
-                    index,
-                    ..Default::default()
-                };
-                msr_entries.push(msr.into());
+                if !msr_entries.iter().any(|m| m.index == index) {
+                    let msr = kvm_msr_entry {
+                        index,
+                        ..Default::default()
+                    };
+                    msr_entries.push(msr.into());
+                }
             }
         }
 
@@ -2420,6 +4744,7 @@ impl cpu::Vcpu for KvmVcpu {
             mp_state,
             tsc_khz,
             nested_state,
+            fpu_format,
         }
         .into())
     }
@@ -2524,322 +4849,781 @@ impl cpu::Vcpu for KvmVcpu {
             });
         }
 
-        state.non_core_regs = sys_regs;
+        state.non_core_regs = sys_regs;
+
+        Ok(state.into())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Restore the previously saved CPU state
+    ///
+    /// Ordering requirements:
+    ///
+    /// KVM_GET_VCPU_EVENTS/KVM_SET_VCPU_EVENTS is unsafe if other vCPUs are
+    /// still running.
+    ///
+    /// Some SET ioctls (like set_mp_state) depend on kvm_vcpu_is_bsp(), so
+    /// if we ever change the BSP, we have to do that before restoring anything.
+    /// The same seems to be true for CPUID stuff.
+    ///
+    /// SREGS saves/restores a pending interrupt, similar to what
+    /// VCPU_EVENTS also does.
+    ///
+    /// SET_REGS clears pending exceptions unconditionally, thus, it must be
+    /// done before SET_VCPU_EVENTS, which restores it.
+    ///
+    /// SET_LAPIC must come after SET_SREGS, because the latter restores
+    /// the apic base msr.
+    ///
+    /// SET_LAPIC must come before SET_MSRS, because the TSC deadline MSR
+    /// only restores successfully, when the LAPIC is correctly configured.
+    ///
+    /// Arguments: CpuState
+    /// # Example
+    ///
+    /// ```rust
+    /// # use hypervisor::kvm::KvmHypervisor;
+    /// # use hypervisor::HypervisorVmConfig;
+    /// # use std::sync::Arc;
+    /// let kvm = KvmHypervisor::new().unwrap();
+    /// let hv = Arc::new(kvm);
+    /// let vm = hv.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
+    /// vm.enable_split_irq(24).unwrap();
+    /// let vcpu = vm.create_vcpu(0, None).unwrap();
+    /// let state = vcpu.state().unwrap();
+    /// vcpu.set_state(&state).unwrap();
+    /// ```
+    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
+        let state: VcpuKvmState = state.clone().into();
+        self.set_cpuid2(&state.cpuid)?;
+        self.set_mp_state(state.mp_state.into())?;
+        self.set_regs(&state.regs.into())?;
+        self.set_sregs(&state.sregs.into())?;
+        match state.fpu_format {
+            // XCR0 must be restored first, KVM rejects the XSAVE components
+            // the guest hasn't enabled yet.
+            FpuStateFormat::Xsave => {
+                self.set_xcrs(&state.xcrs)?;
+                self.set_xsave(&state.xsave)?;
+            }
+            FpuStateFormat::Legacy => self.set_fpu(&state.fpu)?,
+        }
+        self.set_lapic(&state.lapic_state)?;
+        if let Some(nested_state) = state.nested_state {
+            self.set_nested_state(&nested_state)?;
+        }
+
+        if let Some(freq) = state.tsc_khz {
+            self.set_tsc_khz(freq)?;
+        }
+
+        // Try to set all MSRs previously stored with a single call. When an
+        // MSR is rejected, the ones before it have been applied already, so
+        // skip it and carry on with the remaining ones. This is the only way
+        // to make sure we try to set as many MSRs as possible, even if some
+        // MSRs are not supported. The saved order is preserved, which keeps
+        // MSRs depending on each other (EFER first) applied correctly.
+        let entries: Vec<(u32, u64)> = state.msrs.iter().map(|m| (m.index, m.data)).collect();
+        let mut start_pos = 0;
+        while start_pos < entries.len() {
+            match self.write_msrs(&entries[start_pos..]) {
+                Ok(_) => break,
+                Err(cpu::HypervisorCpuError::WriteMsr(index, written)) => {
+                    warn!("Detected faulty MSR 0x{index:x} while setting MSRs");
+                    // Skip the first bad MSR
+                    start_pos += written + 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.set_vcpu_events(&state.vcpu_events)?;
+
+        Ok(())
+    }
+
+    ///
+    /// Restore the previously saved AArch64 CPU state
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
+        let state: VcpuKvmState = state.clone().into();
+        // Set core registers
+        self.set_regs(&state.core_regs.into())?;
+        // Set system registers
+        for reg in &state.sys_regs {
+            self.fd
+                .set_one_reg(reg.id, &reg.addr.to_le_bytes())
+                .map_err(|e| cpu::HypervisorCpuError::SetSysRegister(e.into()))?;
+        }
+
+        self.set_mp_state(state.mp_state.into())?;
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    ///
+    /// Restore the previously saved RISC-V 64-bit CPU state
+    ///
+    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
+        let state: VcpuKvmState = state.clone().into();
+        // Set core registers
+        self.set_regs(&state.core_regs.into())?;
+        // Set system registers
+        for reg in &state.non_core_regs {
+            self.fd
+                .set_one_reg(reg.id, &reg.addr.to_le_bytes())
+                .map_err(|e| cpu::HypervisorCpuError::SetSysRegister(e.into()))?;
+        }
+
+        self.set_mp_state(state.mp_state.into())?;
+
+        Ok(())
+    }
+
+    ///
+    /// Initialize TDX for this CPU
+    ///
+    #[cfg(feature = "tdx")]
+    fn tdx_init(&self, hob_address: u64) -> cpu::Result<()> {
+        tdx_command(&self.fd.as_raw_fd(), TdxCommand::InitVcpu, 0, hob_address)
+            .map_err(cpu::HypervisorCpuError::InitializeTdx)
+    }
+
+    ///
+    /// Set the "immediate_exit" state
+    ///
+    fn set_immediate_exit(&mut self, exit: bool) {
+        self.fd.set_kvm_immediate_exit(exit.into());
+    }
+
+    ///
+    /// Returns the location of the "immediate_exit" state
+    ///
+    fn immediate_exit_ptr(&mut self) -> Option<NonNull<u8>> {
+        Some(NonNull::from(&mut self.fd.get_kvm_run().immediate_exit))
+    }
+
+    ///
+    /// Returns the details about TDX exit reason
+    ///
+    #[cfg(feature = "tdx")]
+    fn get_tdx_exit_details(&mut self) -> cpu::Result<TdxExitDetails> {
+        let kvm_run = self.fd.get_kvm_run();
+        // SAFETY: accessing a union field in a valid structure
+        let tdx_vmcall = unsafe {
+            &mut (*((&mut kvm_run.__bindgen_anon_1) as *mut kvm_run__bindgen_ty_1
+                as *mut KvmTdxExit))
+                .u
+                .vmcall
+        };
+
+        tdx_vmcall.status_code = TDG_VP_VMCALL_INVALID_OPERAND;
+
+        if tdx_vmcall.type_ != 0 {
+            return Err(cpu::HypervisorCpuError::UnknownTdxVmCall);
+        }
+
+        match tdx_vmcall.subfunction {
+            TDG_VP_VMCALL_GET_QUOTE => Ok(TdxExitDetails::GetQuote),
+            TDG_VP_VMCALL_SETUP_EVENT_NOTIFY_INTERRUPT => {
+                Ok(TdxExitDetails::SetupEventNotifyInterrupt)
+            }
+            _ => Err(cpu::HypervisorCpuError::UnknownTdxVmCall),
+        }
+    }
+
+    ///
+    /// Set the status code for TDX exit
+    ///
+    #[cfg(feature = "tdx")]
+    fn set_tdx_status(&mut self, status: TdxExitStatus) {
+        let kvm_run = self.fd.get_kvm_run();
+        // SAFETY: accessing a union field in a valid structure
+        let tdx_vmcall = unsafe {
+            &mut (*((&mut kvm_run.__bindgen_anon_1) as *mut kvm_run__bindgen_ty_1
+                as *mut KvmTdxExit))
+                .u
+                .vmcall
+        };
+
+        tdx_vmcall.status_code = match status {
+            TdxExitStatus::Success => TDG_VP_VMCALL_SUCCESS,
+            TdxExitStatus::InvalidOperand => TDG_VP_VMCALL_INVALID_OPERAND,
+        };
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Return the list of initial MSR entries for a VCPU
+    ///
+    fn boot_msr_entries(&self) -> Vec<MsrEntry> {
+        use crate::arch::x86::{MTRR_ENABLE, MTRR_MEM_TYPE_WB};
+
+        [
+            msr!(msr_index::MSR_IA32_SYSENTER_CS),
+            msr!(msr_index::MSR_IA32_SYSENTER_ESP),
+            msr!(msr_index::MSR_IA32_SYSENTER_EIP),
+            msr!(msr_index::MSR_STAR),
+            msr!(msr_index::MSR_CSTAR),
+            msr!(msr_index::MSR_LSTAR),
+            msr!(msr_index::MSR_KERNEL_GS_BASE),
+            msr!(msr_index::MSR_SYSCALL_MASK),
+            msr!(msr_index::MSR_IA32_TSC),
+            msr_data!(
+                msr_index::MSR_IA32_MISC_ENABLE,
+                msr_index::MSR_IA32_MISC_ENABLE_FAST_STRING as u64
+            ),
+            msr_data!(msr_index::MSR_MTRRdefType, MTRR_ENABLE | MTRR_MEM_TYPE_WB),
+        ]
+        .to_vec()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn has_pmu_support(&self) -> bool {
+        let cpu_attr = kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT),
+            addr: 0x0,
+            flags: 0,
+        };
+        self.fd.has_device_attr(&cpu_attr).is_ok()
+    }
 
-        Ok(state.into())
+    #[cfg(target_arch = "aarch64")]
+    fn init_pmu(&self, irq: u32) -> cpu::Result<()> {
+        let cpu_attr = kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT),
+            addr: 0x0,
+            flags: 0,
+        };
+        let cpu_attr_irq = kvm_bindings::kvm_device_attr {
+            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_IRQ),
+            addr: &irq as *const u32 as u64,
+            flags: 0,
+        };
+        self.fd
+            .set_device_attr(&cpu_attr_irq)
+            .map_err(|_| cpu::HypervisorCpuError::InitializePmu)?;
+        self.fd
+            .set_device_attr(&cpu_attr)
+            .map_err(|_| cpu::HypervisorCpuError::InitializePmu)
     }
 
     #[cfg(target_arch = "x86_64")]
     ///
-    /// Restore the previously saved CPU state
+    /// Get the frequency of the TSC if available
     ///
-    /// Ordering requirements:
+    fn tsc_khz(&self) -> cpu::Result<Option<u32>> {
+        match self.fd.get_tsc_khz() {
+            Err(e) => {
+                if e.errno() == libc::EIO {
+                    Ok(None)
+                } else {
+                    Err(cpu::HypervisorCpuError::GetTscKhz(e.into()))
+                }
+            }
+            Ok(v) => Ok(Some(v)),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
     ///
-    /// KVM_GET_VCPU_EVENTS/KVM_SET_VCPU_EVENTS is unsafe if other vCPUs are
-    /// still running.
+    /// Set the frequency of the TSC if available
     ///
-    /// Some SET ioctls (like set_mp_state) depend on kvm_vcpu_is_bsp(), so
-    /// if we ever change the BSP, we have to do that before restoring anything.
-    /// The same seems to be true for CPUID stuff.
+    fn set_tsc_khz(&self, freq: u32) -> cpu::Result<()> {
+        match self.fd.set_tsc_khz(freq) {
+            Err(e) => {
+                if e.errno() == libc::EIO {
+                    Ok(())
+                } else {
+                    Err(cpu::HypervisorCpuError::SetTscKhz(e.into()))
+                }
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
     ///
-    /// SREGS saves/restores a pending interrupt, similar to what
-    /// VCPU_EVENTS also does.
+    /// Trigger NMI interrupt
     ///
-    /// SET_REGS clears pending exceptions unconditionally, thus, it must be
-    /// done before SET_VCPU_EVENTS, which restores it.
+    fn nmi(&self) -> cpu::Result<()> {
+        match self.fd.nmi() {
+            Err(e) => {
+                if e.errno() == libc::EIO {
+                    Ok(())
+                } else {
+                    Err(cpu::HypervisorCpuError::Nmi(e.into()))
+                }
+            }
+            Ok(_) => Ok(()),
+        }
+    }
+
     ///
-    /// SET_LAPIC must come after SET_SREGS, because the latter restores
-    /// the apic base msr.
+    /// Returns the halt-polling statistics from the vCPU binary statistics.
     ///
-    /// SET_LAPIC must come before SET_MSRS, because the TSC deadline MSR
-    /// only restores successfully, when the LAPIC is correctly configured.
+    fn halt_poll_stats(&self) -> cpu::Result<cpu::HaltPollStats> {
+        let stats = stats::open_stats_fd(&self.fd)
+            .and_then(|f| stats::read_stats(&f))
+            .map_err(|e| cpu::HypervisorCpuError::GetStats(e.into()))?;
+        let value = |name: &str| stats::stat_value(&stats, name).unwrap_or(0);
+
+        Ok(cpu::HaltPollStats {
+            successful_polls: value("halt_successful_poll"),
+            attempted_polls: value("halt_attempted_poll"),
+            invalid_polls: value("halt_poll_invalid"),
+            wakeups: value("halt_wakeup"),
+            success_ns: value("halt_poll_success_ns"),
+            fail_ns: value("halt_poll_fail_ns"),
+        })
+    }
+
     ///
-    /// Arguments: CpuState
-    /// # Example
+    /// Returns the page fault counters from the vCPU binary statistics.
     ///
-    /// ```rust
-    /// # use hypervisor::kvm::KvmHypervisor;
-    /// # use hypervisor::HypervisorVmConfig;
-    /// # use std::sync::Arc;
-    /// let kvm = KvmHypervisor::new().unwrap();
-    /// let hv = Arc::new(kvm);
-    /// let vm = hv.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
-    /// vm.enable_split_irq().unwrap();
-    /// let vcpu = vm.create_vcpu(0, None).unwrap();
-    /// let state = vcpu.state().unwrap();
-    /// vcpu.set_state(&state).unwrap();
-    /// ```
-    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
-        let state: VcpuKvmState = state.clone().into();
-        self.set_cpuid2(&state.cpuid)?;
-        self.set_mp_state(state.mp_state.into())?;
-        self.set_regs(&state.regs.into())?;
-        self.set_sregs(&state.sregs.into())?;
-        self.set_xsave(&state.xsave)?;
-        self.set_xcrs(&state.xcrs)?;
-        self.set_lapic(&state.lapic_state)?;
-        self.set_fpu(&state.fpu)?;
-        if let Some(nested_state) = state.nested_state {
-            self.set_nested_state(&nested_state)?;
-        }
+    fn get_mmu_stats(&self) -> cpu::Result<cpu::MmuStats> {
+        let stats = stats::open_stats_fd(&self.fd)
+            .and_then(|f| stats::read_stats(&f))
+            .map_err(|e| cpu::HypervisorCpuError::GetStats(e.into()))?;
+        let value = |name: &str| stats::stat_value(&stats, name).unwrap_or(0);
 
-        if let Some(freq) = state.tsc_khz {
-            self.set_tsc_khz(freq)?;
-        }
+        Ok(cpu::MmuStats {
+            pf_taken: value("pf_taken"),
+            pf_fixed: value("pf_fixed"),
+            pf_emulate: value("pf_emulate"),
+            pf_spurious: value("pf_spurious"),
+            ..Default::default()
+        })
+    }
 
-        // Try to set all MSRs previously stored.
-        // If the number of MSRs set from SET_MSRS is different from the
-        // expected amount, we fallback onto a slower method by setting MSRs
-        // by chunks. This is the only way to make sure we try to set as many
-        // MSRs as possible, even if some MSRs are not supported.
-        let expected_num_msrs = state.msrs.len();
-        let num_msrs = self.set_msrs(&state.msrs)?;
-        if num_msrs != expected_num_msrs {
-            let mut faulty_msr_index = num_msrs;
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Loads the RESET values of the registers, see `reset_sregs()`. The
+    /// MSRs and the local APIC registers other than its base are left as is.
+    ///
+    fn reset(&self) -> cpu::Result<()> {
+        let sregs = reset_sregs(&self.get_sregs()?);
+        let bsp = sregs.apic_base & APIC_BASE_BSP != 0;
 
-            loop {
-                warn!(
-                    "Detected faulty MSR 0x{:x} while setting MSRs",
-                    state.msrs[faulty_msr_index].index
-                );
+        let mut regs = self.create_standard_regs();
+        regs.set_rip(RESET_RIP);
+        regs.set_rflags(RESET_RFLAGS);
 
-                // Skip the first bad MSR
-                let start_pos = faulty_msr_index + 1;
+        self.set_sregs(&sregs)
+            .and_then(|_| self.set_regs(&regs))
+            .and_then(|_| self.set_fpu(&reset_fpu()))
+            .and_then(|_| self.set_mp_state(reset_mp_state(bsp).into()))
+            .map_err(|e| cpu::HypervisorCpuError::ResetVcpu(e.into()))
+    }
 
-                let sub_msr_entries = state.msrs[start_pos..].to_vec();
+    #[cfg(target_arch = "aarch64")]
+    ///
+    /// Issues KVM_ARM_VCPU_INIT again, which resets the registers.
+    ///
+    fn reset(&self) -> cpu::Result<()> {
+        let kvi = self.init.lock().unwrap().ok_or_else(|| {
+            cpu::HypervisorCpuError::ResetVcpu(anyhow!("The vCPU was never initialized"))
+        })?;
+        self.fd
+            .vcpu_init(&kvi)
+            .map_err(|e| cpu::HypervisorCpuError::ResetVcpu(e.into()))
+    }
+}
 
-                let num_msrs = self.set_msrs(&sub_msr_entries)?;
+///
+/// Have the guest accesses to the RAPL MSRs exit to userspace, by enabling
+/// the MSR exits on filtered accesses and setting a filter denying them.
+///
+#[cfg(target_arch = "x86_64")]
+fn enable_rapl_msr_exits(fd: &VmFd) -> hypervisor::Result<()> {
+    if fd.check_extension_raw(kvm_bindings::KVM_CAP_X86_USER_SPACE_MSR.into()) <= 0
+        || fd.check_extension_raw(kvm_bindings::KVM_CAP_X86_MSR_FILTER.into()) <= 0
+    {
+        return Err(hypervisor::HypervisorError::RaplUnsupported);
+    }
 
-                if num_msrs == sub_msr_entries.len() {
-                    break;
-                }
+    let mut cap = kvm_enable_cap {
+        cap: kvm_bindings::KVM_CAP_X86_USER_SPACE_MSR,
+        ..Default::default()
+    };
+    cap.args[0] = kvm_bindings::KVM_MSR_EXIT_REASON_FILTER.into();
+    fd.enable_cap(&cap)
+        .map_err(|e| hypervisor::HypervisorError::EnableRapl(e.into()))?;
+
+    // One bit per MSR from the first RAPL MSR to the last, the accesses to
+    // the MSRs whose bit is cleared are denied
+    let base = RAPL_MSRS[0];
+    let nmsrs = RAPL_MSRS[RAPL_MSRS.len() - 1] - base + 1;
+    let mut bitmap = vec![0xffu8; nmsrs.div_ceil(8) as usize];
+    for index in RAPL_MSRS {
+        let bit = (index - base) as usize;
+        bitmap[bit / 8] &= !(1 << (bit % 8));
+    }
 
-                faulty_msr_index = start_pos + num_msrs;
-            }
-        }
+    let mut filter = kvm_bindings::kvm_msr_filter {
+        flags: kvm_bindings::KVM_MSR_FILTER_DEFAULT_ALLOW,
+        ..Default::default()
+    };
+    filter.ranges[0] = kvm_bindings::kvm_msr_filter_range {
+        flags: kvm_bindings::KVM_MSR_FILTER_READ | kvm_bindings::KVM_MSR_FILTER_WRITE,
+        nmsrs,
+        base,
+        bitmap: bitmap.as_mut_ptr(),
+    };
+    // SAFETY: the filter and its bitmap outlive the ioctl, which copies them
+    let ret = unsafe { ioctl_with_ref(fd, KVM_X86_SET_MSR_FILTER(), &filter) };
+    if ret < 0 {
+        return Err(hypervisor::HypervisorError::EnableRapl(
+            std::io::Error::last_os_error().into(),
+        ));
+    }
 
-        self.set_vcpu_events(&state.vcpu_events)?;
+    Ok(())
+}
 
-        Ok(())
+///
+/// MP state of a vCPU after RESET: the BSP runs from the reset vector while
+/// the APs wait for INIT/SIPI.
+///
+#[cfg(target_arch = "x86_64")]
+fn reset_mp_state(bsp: bool) -> kvm_mp_state {
+    kvm_mp_state {
+        mp_state: if bsp {
+            kvm_bindings::KVM_MP_STATE_RUNNABLE
+        } else {
+            kvm_bindings::KVM_MP_STATE_UNINITIALIZED
+        },
     }
+}
 
+impl KvmVcpu {
     ///
-    /// Restore the previously saved AArch64 CPU state
+    /// Notify the shutdown eventfd of the VM.
     ///
     #[cfg(target_arch = "aarch64")]
-    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
-        let state: VcpuKvmState = state.clone().into();
-        // Set core registers
-        self.set_regs(&state.core_regs.into())?;
-        // Set system registers
-        for reg in &state.sys_regs {
-            self.fd
-                .set_one_reg(reg.id, &reg.addr.to_le_bytes())
-                .map_err(|e| cpu::HypervisorCpuError::SetSysRegister(e.into()))?;
+    fn signal_shutdown(&self) {
+        if let Err(e) = self.shutdown_evt.write(1) {
+            warn!("Failed to signal the guest shutdown: {e}");
         }
+    }
 
-        self.set_mp_state(state.mp_state.into())?;
-
-        Ok(())
+    ///
+    /// Read the general purpose register `Xn`.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn core_reg(&self, n: usize) -> cpu::Result<u64> {
+        let off = offset_of!(user_pt_regs, regs) + n * std::mem::size_of::<u64>();
+        let mut bytes = [0_u8; 8];
+        self.fd
+            .get_one_reg(arm64_core_reg_id!(KVM_REG_SIZE_U64, off), &mut bytes)
+            .map_err(|e| cpu::HypervisorCpuError::GetAarchCoreRegister(e.into()))?;
+        Ok(u64::from_le_bytes(bytes))
     }
 
-    #[cfg(target_arch = "riscv64")]
     ///
-    /// Restore the previously saved RISC-V 64-bit CPU state
+    /// Run the vCPU once, `duration_in_guest` is only measured if provided.
     ///
-    fn set_state(&self, state: &CpuState) -> cpu::Result<()> {
-        let state: VcpuKvmState = state.clone().into();
-        // Set core registers
-        self.set_regs(&state.core_regs.into())?;
-        // Set system registers
-        for reg in &state.non_core_regs {
-            self.fd
-                .set_one_reg(reg.id, &reg.addr.to_le_bytes())
-                .map_err(|e| cpu::HypervisorCpuError::SetSysRegister(e.into()))?;
+    fn run_vcpu(
+        &mut self,
+        duration_in_guest: Option<&mut Duration>,
+    ) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
+        let entry = duration_in_guest.is_some().then(Instant::now);
+        let run = self.fd.run();
+        if let (Some(duration), Some(entry)) = (duration_in_guest, entry) {
+            *duration = entry.elapsed();
+        }
+        // Entering KVM_RUN completed the previous IO operation, if any
+        self.io_in_flight.store(
+            matches!(&run, Ok(exit) if exit_completes_on_next_run(exit)),
+            Ordering::SeqCst,
+        );
+        // RIP is only moved past IO instructions and MMIO reads once their
+        // operation completes, while MMIO writes are committed before exiting.
+        #[cfg(target_arch = "x86_64")]
+        {
+            *self.last_exit_insn.get_mut().unwrap() = match &run {
+                Ok(VcpuExit::IoIn(..) | VcpuExit::IoOut(..) | VcpuExit::MmioRead(..)) => {
+                    LastExitInsn::AtRip
+                }
+                _ => LastExitInsn::Unknown,
+            };
         }
 
-        self.set_mp_state(state.mp_state.into())?;
+        match run {
+            Ok(run) => match run {
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::IoIn(addr, data) => {
+                    if let Some((base, handler)) = find_pio_handler(&self.pio_handlers, addr) {
+                        handler.read(base, addr - base, data);
+                        return Ok(cpu::VmExit::Ignore);
+                    }
+
+                    if let Some(vm_ops) = &self.vm_ops {
+                        return vm_ops
+                            .pio_read(addr.into(), data)
+                            .map(|_| cpu::VmExit::Ignore)
+                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                    }
+
+                    Ok(cpu::VmExit::Ignore)
+                }
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::IoOut(addr, data) => {
+                    if let Some((base, handler)) = find_pio_handler(&self.pio_handlers, addr) {
+                        handler.write(base, addr - base, data);
+                        return Ok(cpu::VmExit::Ignore);
+                    }
+
+                    if let Some(vm_ops) = &self.vm_ops {
+                        return vm_ops
+                            .pio_write(addr.into(), data)
+                            .map(|_| cpu::VmExit::Ignore)
+                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                    }
+
+                    Ok(cpu::VmExit::Ignore)
+                }
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
+                // Only the RAPL MSRs are filtered out, a failed access
+                // injects a #GP
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::X86Rdmsr(exit) => {
+                    match self.rapl.as_ref().and_then(|rapl| rapl.read(exit.index)) {
+                        Some(value) => *exit.data = value,
+                        None => *exit.error = 1,
+                    }
+                    Ok(cpu::VmExit::Ignore)
+                }
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::X86Wrmsr(exit) => {
+                    // The RAPL MSRs are read-only
+                    *exit.error = 1;
+                    Ok(cpu::VmExit::Ignore)
+                }
+                // A triple fault resets the VM, the guest didn't power off.
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::SystemEvent(event_type, flags) => {
+                    if event_type == kvm_bindings::KVM_SYSTEM_EVENT_CRASH {
+                        // Raised on a guest write to HV_X64_MSR_CRASH_CTL
+                        self.hyperv_crash_exit()
+                    } else {
+                        Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                            "Unexpected system event with type 0x{:x}, flags 0x{:x?}",
+                            event_type,
+                            flags
+                        )))
+                    }
+                }
+
+                #[cfg(target_arch = "aarch64")]
+                VcpuExit::SystemEvent(event_type, flags) => {
+                    use kvm_bindings::{
+                        KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_RESET_FLAG_PSCI_RESET2,
+                        KVM_SYSTEM_EVENT_SHUTDOWN,
+                    };
+                    // On Aarch64, when the VM is shutdown, run() returns
+                    // VcpuExit::SystemEvent with reason KVM_SYSTEM_EVENT_SHUTDOWN
+                    if event_type == KVM_SYSTEM_EVENT_RESET {
+                        let reset2 = flags.first().is_some_and(|f| {
+                            f & u64::from(KVM_SYSTEM_EVENT_RESET_FLAG_PSCI_RESET2) != 0
+                        });
+                        if !reset2 {
+                            return Ok(cpu::VmExit::SystemReset(cpu::ResetReason::Cold));
+                        }
+                        // The SYSTEM_RESET2 arguments are left in X1 and X2
+                        let reset_type = self.core_reg(1)? as u32;
+                        let cookie = self.core_reg(2)?;
+                        cpu::ResetReason::from_reset2(reset_type, cookie)
+                            .map(cpu::VmExit::SystemReset)
+                            .ok_or_else(|| {
+                                cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                                    "Unexpected SYSTEM_RESET2 reset type 0x{reset_type:x}"
+                                ))
+                            })
+                    } else if event_type == KVM_SYSTEM_EVENT_SHUTDOWN {
+                        self.signal_shutdown();
+                        Ok(cpu::VmExit::SystemOff)
+                    } else if event_type == kvm_bindings::KVM_SYSTEM_EVENT_SUSPEND {
+                        // The PSCI call arguments are left in X1 and X2
+                        Ok(cpu::VmExit::Suspend {
+                            entry_point: self.core_reg(1)?,
+                            context_id: self.core_reg(2)?,
+                        })
+                    } else {
+                        Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                            "Unexpected system event with type 0x{:x}, flags 0x{:x?}",
+                            event_type,
+                            flags
+                        )))
+                    }
+                }
 
-        Ok(())
-    }
+                VcpuExit::MmioRead(addr, data) => {
+                    throttle_mmio_exit(&self.mmio_rate_limits, addr);
+                    if let Some(exit) = watched_mmio_read(&self.watchpoints, addr, data) {
+                        return Ok(exit);
+                    }
 
-    ///
-    /// Initialize TDX for this CPU
-    ///
-    #[cfg(feature = "tdx")]
-    fn tdx_init(&self, hob_address: u64) -> cpu::Result<()> {
-        tdx_command(&self.fd.as_raw_fd(), TdxCommand::InitVcpu, 0, hob_address)
-            .map_err(cpu::HypervisorCpuError::InitializeTdx)
-    }
+                    if let Some(vm_ops) = &self.vm_ops {
+                        return vm_ops
+                            .mmio_read(addr, data)
+                            .map(|_| cpu::VmExit::Ignore)
+                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                    }
 
-    ///
-    /// Set the "immediate_exit" state
-    ///
-    fn set_immediate_exit(&mut self, exit: bool) {
-        self.fd.set_kvm_immediate_exit(exit.into());
-    }
+                    Ok(cpu::VmExit::Ignore)
+                }
+                VcpuExit::MmioWrite(addr, data) => {
+                    throttle_mmio_exit(&self.mmio_rate_limits, addr);
+                    if let Some(exit) = watched_mmio_write(&self.watchpoints, addr, data) {
+                        return Ok(exit);
+                    }
 
-    ///
-    /// Returns the details about TDX exit reason
-    ///
-    #[cfg(feature = "tdx")]
-    fn get_tdx_exit_details(&mut self) -> cpu::Result<TdxExitDetails> {
-        let kvm_run = self.fd.get_kvm_run();
-        // SAFETY: accessing a union field in a valid structure
-        let tdx_vmcall = unsafe {
-            &mut (*((&mut kvm_run.__bindgen_anon_1) as *mut kvm_run__bindgen_ty_1
-                as *mut KvmTdxExit))
-                .u
-                .vmcall
-        };
+                    if let Some(vm_ops) = &self.vm_ops {
+                        return vm_ops
+                            .mmio_write(addr, data)
+                            .map(|_| cpu::VmExit::Ignore)
+                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()));
+                    }
 
-        tdx_vmcall.status_code = TDG_VP_VMCALL_INVALID_OPERAND;
+                    Ok(cpu::VmExit::Ignore)
+                }
+                VcpuExit::Hyperv => Ok(cpu::VmExit::Hyperv),
+                #[cfg(feature = "tdx")]
+                VcpuExit::Unsupported(KVM_EXIT_TDX) => Ok(cpu::VmExit::Tdx),
+                VcpuExit::Debug(_) => Ok(cpu::VmExit::Debug),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::InternalError => Ok(self.internal_error_exit()),
 
-        if tdx_vmcall.type_ != 0 {
-            return Err(cpu::HypervisorCpuError::UnknownTdxVmCall);
-        }
+                r => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                    "Unexpected exit reason on vcpu run: {r:?}"
+                ))),
+            },
 
-        match tdx_vmcall.subfunction {
-            TDG_VP_VMCALL_GET_QUOTE => Ok(TdxExitDetails::GetQuote),
-            TDG_VP_VMCALL_SETUP_EVENT_NOTIFY_INTERRUPT => {
-                Ok(TdxExitDetails::SetupEventNotifyInterrupt)
-            }
-            _ => Err(cpu::HypervisorCpuError::UnknownTdxVmCall),
+            Err(ref e) => match e.errno() {
+                libc::EAGAIN | libc::EINTR => Ok(cpu::VmExit::Ignore),
+                _ => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                    "VCPU error {e:?}"
+                ))),
+            },
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
     ///
-    /// Set the status code for TDX exit
+    /// Decode an internal error, keeping the instruction bytes KVM reports
+    /// on emulation failures.
     ///
-    #[cfg(feature = "tdx")]
-    fn set_tdx_status(&mut self, status: TdxExitStatus) {
+    fn internal_error_exit(&mut self) -> cpu::VmExit {
         let kvm_run = self.fd.get_kvm_run();
         // SAFETY: accessing a union field in a valid structure
-        let tdx_vmcall = unsafe {
-            &mut (*((&mut kvm_run.__bindgen_anon_1) as *mut kvm_run__bindgen_ty_1
-                as *mut KvmTdxExit))
-                .u
-                .vmcall
-        };
-
-        tdx_vmcall.status_code = match status {
-            TdxExitStatus::Success => TDG_VP_VMCALL_SUCCESS,
-            TdxExitStatus::InvalidOperand => TDG_VP_VMCALL_INVALID_OPERAND,
+        let internal = unsafe { &kvm_run.__bindgen_anon_1.internal };
+        let ndata = std::cmp::min(internal.ndata as usize, internal.data.len());
+        let data = internal.data[..ndata].to_vec();
+        let suberror = match internal.suberror {
+            kvm_bindings::KVM_INTERNAL_ERROR_EMULATION => cpu::InternalErrorKind::Emulation,
+            kvm_bindings::KVM_INTERNAL_ERROR_SIMUL_EX => {
+                cpu::InternalErrorKind::SimultaneousException
+            }
+            kvm_bindings::KVM_INTERNAL_ERROR_DELIVERY_EV => cpu::InternalErrorKind::EventDelivery,
+            kvm_bindings::KVM_INTERNAL_ERROR_UNEXPECTED_EXIT_REASON => {
+                cpu::InternalErrorKind::UnexpectedExitReason
+            }
+            other => cpu::InternalErrorKind::Other(other),
         };
-    }
-
-    #[cfg(target_arch = "x86_64")]
-    ///
-    /// Return the list of initial MSR entries for a VCPU
-    ///
-    fn boot_msr_entries(&self) -> Vec<MsrEntry> {
-        use crate::arch::x86::{MTRR_ENABLE, MTRR_MEM_TYPE_WB, msr_index};
-
-        [
-            msr!(msr_index::MSR_IA32_SYSENTER_CS),
-            msr!(msr_index::MSR_IA32_SYSENTER_ESP),
-            msr!(msr_index::MSR_IA32_SYSENTER_EIP),
-            msr!(msr_index::MSR_STAR),
-            msr!(msr_index::MSR_CSTAR),
-            msr!(msr_index::MSR_LSTAR),
-            msr!(msr_index::MSR_KERNEL_GS_BASE),
-            msr!(msr_index::MSR_SYSCALL_MASK),
-            msr!(msr_index::MSR_IA32_TSC),
-            msr_data!(
-                msr_index::MSR_IA32_MISC_ENABLE,
-                msr_index::MSR_IA32_MISC_ENABLE_FAST_STRING as u64
-            ),
-            msr_data!(msr_index::MSR_MTRRdefType, MTRR_ENABLE | MTRR_MEM_TYPE_WB),
-        ]
-        .to_vec()
-    }
 
-    #[cfg(target_arch = "aarch64")]
-    fn has_pmu_support(&self) -> bool {
-        let cpu_attr = kvm_bindings::kvm_device_attr {
-            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
-            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT),
-            addr: 0x0,
-            flags: 0,
-        };
-        self.fd.has_device_attr(&cpu_attr).is_ok()
-    }
+        // SAFETY: accessing a union field in a valid structure, the
+        // emulation_failure layout extends the internal one
+        let failure = unsafe { &kvm_run.__bindgen_anon_1.emulation_failure };
+        let insn = (suberror == cpu::InternalErrorKind::Emulation
+            && failure.flags
+                & kvm_bindings::KVM_INTERNAL_ERROR_EMULATION_FLAG_INSTRUCTION_BYTES as u64
+                != 0)
+            .then(|| {
+                // SAFETY: the instruction bytes are valid as per the flag above
+                let insn = unsafe { &failure.__bindgen_anon_1.__bindgen_anon_1 };
+                let len = std::cmp::min(insn.insn_size as usize, insn.insn_bytes.len());
+                insn.insn_bytes[..len].to_vec()
+            });
 
-    #[cfg(target_arch = "aarch64")]
-    fn init_pmu(&self, irq: u32) -> cpu::Result<()> {
-        let cpu_attr = kvm_bindings::kvm_device_attr {
-            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
-            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_INIT),
-            addr: 0x0,
-            flags: 0,
-        };
-        let cpu_attr_irq = kvm_bindings::kvm_device_attr {
-            group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
-            attr: u64::from(kvm_bindings::KVM_ARM_VCPU_PMU_V3_IRQ),
-            addr: &irq as *const u32 as u64,
-            flags: 0,
-        };
-        self.fd
-            .set_device_attr(&cpu_attr_irq)
-            .map_err(|_| cpu::HypervisorCpuError::InitializePmu)?;
-        self.fd
-            .set_device_attr(&cpu_attr)
-            .map_err(|_| cpu::HypervisorCpuError::InitializePmu)
-    }
+        if let Some(bytes) = &insn {
+            *self.last_exit_insn.get_mut().unwrap() = LastExitInsn::Bytes(bytes.clone());
+        }
 
-    #[cfg(target_arch = "x86_64")]
-    ///
-    /// Get the frequency of the TSC if available
-    ///
-    fn tsc_khz(&self) -> cpu::Result<Option<u32>> {
-        match self.fd.get_tsc_khz() {
-            Err(e) => {
-                if e.errno() == libc::EIO {
-                    Ok(None)
-                } else {
-                    Err(cpu::HypervisorCpuError::GetTscKhz(e.into()))
-                }
-            }
-            Ok(v) => Ok(Some(v)),
+        cpu::VmExit::InternalError {
+            suberror,
+            data,
+            insn,
         }
     }
 
     #[cfg(target_arch = "x86_64")]
     ///
-    /// Set the frequency of the TSC if available
+    /// Read the bytes at RIP, up to the maximum instruction length, stopping
+    /// at the first page not backed by guest memory.
     ///
-    fn set_tsc_khz(&self, freq: u32) -> cpu::Result<()> {
-        match self.fd.set_tsc_khz(freq) {
-            Err(e) => {
-                if e.errno() == libc::EIO {
-                    Ok(())
-                } else {
-                    Err(cpu::HypervisorCpuError::SetTscKhz(e.into()))
-                }
+    fn insn_at_rip(&self) -> Option<Vec<u8>> {
+        // Architectural maximum length of an x86 instruction
+        const MAX_INSN_LEN: u64 = 15;
+
+        let vm_ops = self.vm_ops.as_ref()?;
+        let linear = self
+            .get_sregs()
+            .ok()?
+            .cs
+            .base
+            .wrapping_add(self.get_regs().ok()?.get_rip());
+
+        let mut bytes = Vec::new();
+        while (bytes.len() as u64) < MAX_INSN_LEN {
+            let gva = linear.wrapping_add(bytes.len() as u64);
+            let Ok((gpa, _)) = self.translate_gva(gva, 0) else {
+                break;
+            };
+            let len = std::cmp::min(MAX_INSN_LEN - bytes.len() as u64, 0x1000 - (gva & 0xfff));
+            let mut chunk = vec![0u8; len as usize];
+            if vm_ops.guest_mem_read(gpa, &mut chunk).is_err() {
+                break;
             }
-            Ok(_) => Ok(()),
+            bytes.extend_from_slice(&chunk);
         }
+
+        (!bytes.is_empty()).then_some(bytes)
     }
 
     #[cfg(target_arch = "x86_64")]
     ///
-    /// Trigger NMI interrupt
+    /// Collect the crash parameters the guest wrote before notifying the
+    /// crash through HV_X64_MSR_CRASH_CTL.
     ///
-    fn nmi(&self) -> cpu::Result<()> {
-        match self.fd.nmi() {
-            Err(e) => {
-                if e.errno() == libc::EIO {
-                    Ok(())
-                } else {
-                    Err(cpu::HypervisorCpuError::Nmi(e.into()))
-                }
-            }
-            Ok(_) => Ok(()),
+    fn hyperv_crash_exit(&self) -> cpu::Result<cpu::VmExit> {
+        let mut msrs: Vec<MsrEntry> = (0..5)
+            .map(|i| MsrEntry {
+                index: HV_X64_MSR_CRASH_P0 + i,
+                ..Default::default()
+            })
+            .collect();
+        if self.get_msrs(&mut msrs)? != msrs.len() {
+            return Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
+                "Failed to read the Hyper-V crash parameters"
+            )));
         }
+
+        Ok(cpu::VmExit::GuestCrash {
+            code: msrs[0].data,
+            params: [msrs[1].data, msrs[2].data, msrs[3].data, msrs[4].data],
+        })
     }
-}
 
-impl KvmVcpu {
     #[cfg(target_arch = "x86_64")]
     ///
     /// X86 specific call that returns the vcpu's current "xsave struct".
@@ -2938,6 +5722,126 @@ impl KvmVcpu {
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_mmio_rate_limit() {
+        use super::*;
+
+        let limit = KvmMmioRateLimit::new(0x1000..0x2000, 100);
+        let start = limit.counters.lock().unwrap().next;
+        // A one second burst goes through
+        for _ in 0..100 {
+            assert_eq!(limit.account(start), None);
+        }
+        // Then each exit waits for its 10 ms slot
+        assert_eq!(limit.account(start), Some(Duration::from_millis(10)));
+        assert_eq!(limit.account(start), Some(Duration::from_millis(20)));
+        // Exits at the limit rate are let through
+        let now = start + Duration::from_millis(20);
+        assert_eq!(limit.account(now), Some(Duration::from_millis(10)));
+        // As is a new burst once the region was left alone
+        let now = start + Duration::from_secs(3);
+        for _ in 0..100 {
+            assert_eq!(limit.account(now), None);
+        }
+
+        let stats = limit.stats();
+        assert_eq!(stats.region, 0x1000..0x2000);
+        assert_eq!((stats.exits, stats.throttled), (203, 3));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_get_and_set_fpu() {
+        use super::*;
+
+        let kvm = KvmHypervisor::new().unwrap();
+        let hypervisor = Arc::new(kvm);
+        let vm = hypervisor
+            .create_vm(HypervisorVmConfig::default())
+            .expect("new VM fd creation failed");
+        let vcpu0 = vm.create_vcpu(0, None).unwrap();
+
+        let mut fpu = vcpu0.get_fpu().unwrap();
+        // Round to nearest, 64-bit precision, all exceptions masked but the
+        // invalid operation one.
+        fpu.fcw = 0x37e;
+        // MMX registers alias the mantissa of the x87 registers.
+        for (i, fpr) in fpu.fpr.iter_mut().enumerate() {
+            fpr[..8].copy_from_slice(&(0x0101_0101_0101_0101u64 * i as u64).to_le_bytes());
+        }
+        vcpu0.set_fpu(&fpu).unwrap();
+
+        let actual = vcpu0.get_fpu().unwrap();
+        assert_eq!(actual.fcw, fpu.fcw);
+        for (actual, expected) in actual.fpr.iter().zip(fpu.fpr.iter()) {
+            assert_eq!(actual[..8], expected[..8]);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_reset_vcpu() {
+        use super::*;
+        use crate::arch::x86::regs::{CR0_PE, RESET_CR0, RESET_CS_SELECTOR};
+
+        let kvm = KvmHypervisor::new().unwrap();
+        let hypervisor = Arc::new(kvm);
+        let vm = hypervisor
+            .create_vm(HypervisorVmConfig::default())
+            .expect("new VM fd creation failed");
+        vm.create_irq_chip().unwrap();
+        // KVM makes vCPU 0 the BSP
+        let bsp = vm.create_vcpu(0, None).unwrap();
+        let ap = vm.create_vcpu(1, None).unwrap();
+
+        for vcpu in [&bsp, &ap] {
+            let mut sregs = vcpu.get_sregs().unwrap();
+            sregs.cr0 |= CR0_PE;
+            vcpu.set_sregs(&sregs).unwrap();
+            let mut regs = vcpu.get_regs().unwrap();
+            regs.set_rip(0x10_0000);
+            vcpu.set_regs(&regs).unwrap();
+
+            vcpu.reset().unwrap();
+
+            let sregs = vcpu.get_sregs().unwrap();
+            assert_eq!(sregs.cr0, RESET_CR0);
+            assert_eq!(sregs.cs.selector, RESET_CS_SELECTOR);
+            assert_eq!(vcpu.get_regs().unwrap().get_rip(), RESET_RIP);
+        }
+
+        let mp_state =
+            |vcpu: &dyn cpu::Vcpu| kvm_mp_state::from(vcpu.get_mp_state().unwrap()).mp_state;
+        assert_eq!(mp_state(bsp.as_ref()), kvm_bindings::KVM_MP_STATE_RUNNABLE);
+        assert_eq!(
+            mp_state(ap.as_ref()),
+            kvm_bindings::KVM_MP_STATE_UNINITIALIZED
+        );
+        assert_ne!(bsp.get_sregs().unwrap().apic_base & APIC_BASE_BSP, 0);
+        assert_eq!(ap.get_sregs().unwrap().apic_base & APIC_BASE_BSP, 0);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_apic_base() {
+        use super::*;
+
+        let kvm = KvmHypervisor::new().unwrap();
+        let hypervisor = Arc::new(kvm);
+        let vm = hypervisor
+            .create_vm(HypervisorVmConfig::default())
+            .expect("new VM fd creation failed");
+        vm.create_irq_chip().unwrap();
+        let vcpu = vm.create_vcpu(0, None).unwrap();
+        let flags = vcpu.get_sregs().unwrap().apic_base & 0xfff;
+
+        vcpu.set_apic_base(0xfed0_0000).unwrap();
+        assert_eq!(vcpu.get_sregs().unwrap().apic_base, 0xfed0_0000 | flags);
+
+        vcpu.set_apic_base(0xfed0_0800).unwrap_err();
+        assert_eq!(vcpu.get_sregs().unwrap().apic_base, 0xfed0_0000 | flags);
+    }
+
     #[test]
     #[cfg(target_arch = "riscv64")]
     fn test_get_and_set_regs() {
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Access to the KVM binary statistics.
+//!
+//! See the documentation for `KVM_GET_STATS_FD` in the kernel's KVM API
+//! documentation for the layout of the data parsed here.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use vmm_sys_util::ioctl::ioctl;
+use vmm_sys_util::ioctl_io_nr;
+
+ioctl_io_nr!(KVM_GET_STATS_FD, kvm_bindings::KVMIO, 0xce);
+
+// Size of `struct kvm_stats_header`.
+const STATS_HEADER_SIZE: usize = 24;
+// Size of `struct kvm_stats_desc`, not including the trailing name.
+const STATS_DESC_SIZE: usize = 16;
+
+/// Statistics values indexed by their name.
+///
+/// Each statistic may hold several values (e.g. histograms), hence the
+/// `Vec<u64>`.
+pub type KvmStats = HashMap<String, Vec<u64>>;
+
+/// Opens the binary statistics file descriptor of a VM or a vCPU.
+pub fn open_stats_fd<F: AsRawFd>(fd: &F) -> io::Result<File> {
+    // SAFETY: KVM_GET_STATS_FD takes no argument and we check the result.
+    let ret = unsafe { ioctl(fd, KVM_GET_STATS_FD()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: the file descriptor was just returned by the kernel and
+    // nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(ret) })
+}
+
+/// Reads all the statistics exposed through a binary statistics file.
+pub fn read_stats(file: &File) -> io::Result<KvmStats> {
+    let mut header = [0u8; STATS_HEADER_SIZE];
+    file.read_exact_at(&mut header, 0)?;
+
+    let name_size = read_u32(&header, 4) as usize;
+    let num_desc = read_u32(&header, 8) as usize;
+    let desc_offset = read_u32(&header, 16) as u64;
+    let data_offset = read_u32(&header, 20) as u64;
+
+    let mut descs = vec![0u8; num_desc * (STATS_DESC_SIZE + name_size)];
+    file.read_exact_at(&mut descs, desc_offset)?;
+
+    let data_size = descs
+        .chunks_exact(STATS_DESC_SIZE + name_size)
+        .map(|desc| read_u32(desc, 8) as usize + read_u16(desc, 6) as usize * size_of::<u64>())
+        .max()
+        .unwrap_or(0);
+    let mut data = vec![0u8; data_size];
+    file.read_exact_at(&mut data, data_offset)?;
+
+    parse_stats(name_size, &descs, &data)
+}
+
+/// Returns the first value of the statistic `name`, if it exists.
+pub fn stat_value(stats: &KvmStats, name: &str) -> Option<u64> {
+    stats.get(name).and_then(|v| v.first().copied())
+}
+
+fn parse_stats(name_size: usize, descs: &[u8], data: &[u8]) -> io::Result<KvmStats> {
+    let mut stats = KvmStats::new();
+
+    for desc in descs.chunks_exact(STATS_DESC_SIZE + name_size) {
+        let size = read_u16(desc, 6) as usize;
+        let offset = read_u32(desc, 8) as usize;
+        let name = &desc[STATS_DESC_SIZE..];
+        let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        let name = String::from_utf8_lossy(&name[..name_len]).into_owned();
+
+        let end = offset + size * size_of::<u64>();
+        if end > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("statistic {name} out of data block bounds"),
+            ));
+        }
+
+        let values = data[offset..end]
+            .chunks_exact(size_of::<u64>())
+            .map(|v| u64::from_ne_bytes(v.try_into().unwrap()))
+            .collect();
+        stats.insert(name, values);
+    }
+
+    Ok(stats)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME_SIZE: usize = 16;
+
+    fn desc(name: &str, size: u16, offset: u32) -> Vec<u8> {
+        let mut d = vec![0u8; STATS_DESC_SIZE + NAME_SIZE];
+        d[6..8].copy_from_slice(&size.to_ne_bytes());
+        d[8..12].copy_from_slice(&offset.to_ne_bytes());
+        d[STATS_DESC_SIZE..STATS_DESC_SIZE + name.len()].copy_from_slice(name.as_bytes());
+        d
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let mut descs = desc("halt_wakeup", 1, 0);
+        descs.extend(desc("histogram", 2, 8));
+
+        let mut data = Vec::new();
+        for v in [42u64, 1, 2] {
+            data.extend(v.to_ne_bytes());
+        }
+
+        let stats = parse_stats(NAME_SIZE, &descs, &data).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stat_value(&stats, "halt_wakeup"), Some(42));
+        assert_eq!(stats["histogram"], vec![1, 2]);
+        assert_eq!(stat_value(&stats, "missing"), None);
+    }
+
+    #[test]
+    fn test_parse_stats_out_of_bounds() {
+        let descs = desc("halt_wakeup", 2, 0);
+        let data = 42u64.to_ne_bytes();
+
+        parse_stats(NAME_SIZE, &descs, &data).unwrap_err();
+    }
+}
@@ -63,6 +63,16 @@ pub fn check_required_kvm_extensions(kvm: &Kvm) -> KvmResult<()> {
     Ok(())
 }
 
+/// Which of the saved FPU states must be restored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FpuStateFormat {
+    /// `xsave` holds the complete state, `fpu` is only a subset of it.
+    #[default]
+    Xsave,
+    /// The guest doesn't use XSAVE, only `fpu` holds a meaningful state.
+    Legacy,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct VcpuKvmState {
     pub cpuid: Vec<CpuIdEntry>,
@@ -79,6 +89,9 @@ pub struct VcpuKvmState {
     // Option to prevent useless 8K (de)serialization when no nested
     // state exists.
     pub nested_state: Option<KvmNestedStateBuffer>,
+    // Snapshots predating this field always restored xsave.
+    #[serde(default)]
+    pub fpu_format: FpuStateFormat,
 }
 
 impl From<SegmentRegister> for kvm_segment {
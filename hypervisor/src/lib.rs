@@ -51,23 +51,42 @@ mod cpu;
 /// Device related module
 mod device;
 
+#[cfg(feature = "kvm")]
+use std::os::unix::io::RawFd;
+#[cfg(feature = "kvm")]
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use concat_idents::concat_idents;
 #[cfg(target_arch = "x86_64")]
 pub use cpu::CpuVendor;
-pub use cpu::{HypervisorCpuError, Vcpu, VmExit};
+#[cfg(target_arch = "x86_64")]
+pub use cpu::InternalErrorKind;
+#[cfg(target_arch = "aarch64")]
+pub use cpu::ResetReason;
+pub use cpu::{
+    HaltPollStats, HypervisorCpuError, MmuStats, Vcpu, VcpuExitKind, VcpuExitObserver, VmExit,
+    register_run_timeout_handler,
+};
 pub use device::HypervisorDeviceError;
 #[cfg(all(feature = "kvm", target_arch = "aarch64"))]
 pub use kvm::aarch64;
 #[cfg(all(feature = "kvm", target_arch = "riscv64"))]
 pub use kvm::{AiaState, riscv64};
+#[cfg(target_arch = "x86_64")]
+pub use vm::PioHandler;
 pub use vm::{
-    DataMatch, HypervisorVmError, InterruptSourceConfig, LegacyIrqSourceConfig, MsiIrqSourceConfig,
-    Vm, VmOps,
+    DataMatch, FaultStats, HypervisorVmError, InterruptSourceConfig, LegacyIrqSourceConfig,
+    MemoryAdvice, MmioExitStats, MsiIrqSourceConfig, Vm, VmOps, WatchpointAccess,
 };
 
-pub use crate::hypervisor::{Hypervisor, HypervisorError};
+#[cfg(target_arch = "x86_64")]
+pub use crate::arch::x86::rapl::RaplMode;
+pub use crate::hypervisor::{
+    Hypervisor, HypervisorError, KvmModuleParams, MmuMode, SnapshotHeader, VmCreateContext, VmType,
+};
+#[cfg(target_arch = "x86_64")]
+pub use crate::hypervisor::{PleConfig, SNAPSHOT_FEATURES, SevAsidInfo, host_hypervisor_signature};
 
 #[derive(Debug, Copy, Clone)]
 pub enum HypervisorType {
@@ -93,6 +112,27 @@ pub fn new() -> std::result::Result<Arc<dyn Hypervisor>, HypervisorError> {
     )))
 }
 
+/// List the KVM devices of the host, to select one for
+/// `kvm_new_with_path()`.
+#[cfg(feature = "kvm")]
+pub fn kvm_devices() -> std::io::Result<Vec<PathBuf>> {
+    kvm::KvmHypervisor::devices()
+}
+
+/// Create a KVM hypervisor from the KVM device at `path`, e.g. when the
+/// device isn't exposed as `/dev/kvm`.
+#[cfg(feature = "kvm")]
+pub fn kvm_new_with_path(path: &Path) -> std::result::Result<Arc<dyn Hypervisor>, HypervisorError> {
+    kvm::KvmHypervisor::new_with_path(path)
+}
+
+/// Create a KVM hypervisor from an already opened KVM device, taking
+/// ownership of `fd`.
+#[cfg(feature = "kvm")]
+pub fn kvm_from_fd(fd: RawFd) -> std::result::Result<Arc<dyn Hypervisor>, HypervisorError> {
+    kvm::KvmHypervisor::from_fd(fd)
+}
+
 // Returns a `Vec<T>` with a size in bytes at least as large as `size_in_bytes`.
 fn vec_with_size_in_bytes<T: Default>(size_in_bytes: usize) -> Vec<T> {
     let rounded_size = size_in_bytes.div_ceil(size_of::<T>());
@@ -188,14 +228,74 @@ impl ClockData {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct HypervisorVmConfig {
+    /// Free-form name used to identify the VM in logs and error messages.
+    pub label: String,
+    /// Enable the Memory Tagging Extension, guest memory must then be mapped
+    /// with `PROT_MTE`.
+    #[cfg(target_arch = "aarch64")]
+    pub mte: bool,
+    /// Report the guest PSCI SYSTEM_SUSPEND calls as `VmExit::Suspend`,
+    /// instead of having them fail with NOT_SUPPORTED.
+    #[cfg(target_arch = "aarch64")]
+    pub system_suspend: bool,
     #[cfg(feature = "tdx")]
     pub tdx_enabled: bool,
     #[cfg(feature = "sev_snp")]
     pub sev_snp_enabled: bool,
     #[cfg(feature = "sev_snp")]
     pub mem_size: u64,
+    /// Number of vCPUs created when the VM boots, `0` if not specified.
+    pub boot_vcpus: u32,
+    /// Number of vCPUs the VM can grow to through hotplug, `0` if not
+    /// specified. APIC IDs are derived from the vCPU index so the IDs of the
+    /// CPUs above `boot_vcpus` are reserved from the start.
+    pub max_vcpus: u32,
+    /// Threads per core, cores per die, dies per package and packages the
+    /// APIC IDs of the vCPUs are derived from, the IDs are the vCPU indexes
+    /// when `None`.
+    #[cfg(target_arch = "x86_64")]
+    pub topology: Option<(u16, u16, u16, u16)>,
+    /// MSRs hidden from the guest, left out of the MSRs saved and restored
+    /// along with the vCPU state.
+    #[cfg(target_arch = "x86_64")]
+    pub disabled_msrs: Vec<u32>,
+    /// Handle the guest accesses to the RAPL energy MSRs, which KVM always
+    /// reads as 0 otherwise.
+    #[cfg(target_arch = "x86_64")]
+    pub rapl: Option<RaplMode>,
+    /// CET is exposed to the guest, its MSRs must then all be saved and
+    /// restored along with the vCPU state.
+    #[cfg(target_arch = "x86_64")]
+    pub cet: bool,
+    /// Intel Processor Trace is exposed to the guest, its configuration
+    /// MSRs must then all be saved and restored along with the vCPU state.
+    #[cfg(target_arch = "x86_64")]
+    pub processor_trace: bool,
+}
+
+impl HypervisorVmConfig {
+    /// Check `boot_vcpus <= max_vcpus <= hardware_max`, the counts left at
+    /// `0` are not checked.
+    pub(crate) fn check_vcpu_counts(&self, hardware_max: u32) -> Result<(), HypervisorError> {
+        if self.boot_vcpus != 0 && self.max_vcpus != 0 && self.boot_vcpus > self.max_vcpus {
+            return Err(HypervisorError::InvalidVcpuCount(anyhow!(
+                "boot_vcpus ({}) is greater than max_vcpus ({})",
+                self.boot_vcpus,
+                self.max_vcpus
+            )));
+        }
+
+        let max_vcpus = self.max_vcpus.max(self.boot_vcpus);
+        if max_vcpus > hardware_max {
+            return Err(HypervisorError::InvalidVcpuCount(anyhow!(
+                "{max_vcpus} vCPUs requested while the hypervisor supports {hardware_max}"
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone)]
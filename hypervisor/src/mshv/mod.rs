@@ -73,7 +73,7 @@ use crate::arch::aarch64::gic::{Vgic, VgicConfig};
 #[cfg(target_arch = "aarch64")]
 use crate::arch::aarch64::regs;
 #[cfg(target_arch = "x86_64")]
-use crate::arch::x86::{CpuIdEntry, FpuState, MsrEntry};
+use crate::arch::x86::{CpuIdEntry, FpuState, MsrEntry, x2apic_id};
 use crate::{
     CpuState, IoEventAddress, IrqRoutingEntry, MpState, USER_MEMORY_REGION_ADJUSTABLE,
     USER_MEMORY_REGION_EXECUTE, USER_MEMORY_REGION_READ, USER_MEMORY_REGION_WRITE,
@@ -82,6 +82,16 @@ use crate::{
 
 pub const PAGE_SHIFT: usize = 12;
 
+// Invalid opcode exception vector
+#[cfg(target_arch = "x86_64")]
+const UD_VECTOR: u16 = 6;
+// HV_REGISTER_PENDING_EVENT0 bits of a pending exception: event_pending (bit
+// 0), event_type (bits 3:1, 0 for an exception) and vector (bits 31:16)
+#[cfg(target_arch = "x86_64")]
+const PENDING_EVENT_PENDING: u64 = 1;
+#[cfg(target_arch = "x86_64")]
+const PENDING_EVENT_VECTOR_SHIFT: u64 = 16;
+
 impl From<mshv_user_mem_region> for UserMemoryRegion {
     fn from(region: mshv_user_mem_region) -> Self {
         let mut flags: u32 = USER_MEMORY_REGION_READ | USER_MEMORY_REGION_ADJUSTABLE;
@@ -324,13 +334,30 @@ impl hypervisor::Hypervisor for MshvHypervisor {
     /// let hypervisor = MshvHypervisor::new().unwrap();
     /// let vm = hypervisor.create_vm(config).unwrap();
     /// ```
-    fn create_vm(&self, _config: HypervisorVmConfig) -> hypervisor::Result<Arc<dyn vm::Vm>> {
+    fn create_vm(&self, config: HypervisorVmConfig) -> hypervisor::Result<Arc<dyn vm::Vm>> {
+        config.check_vcpu_counts(self.get_max_vcpus())?;
+
+        #[cfg(target_arch = "aarch64")]
+        if config.mte {
+            return Err(hypervisor::HypervisorError::MteUnsupported);
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if config.system_suspend {
+            return Err(hypervisor::HypervisorError::SystemSuspendUnsupported);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if config.rapl.is_some() {
+            return Err(hypervisor::HypervisorError::RaplUnsupported);
+        }
+
         #[allow(unused_mut)]
         #[allow(unused_assignments)]
         let mut mshv_vm_type = VmType::Normal; // Create with default platform type
         #[cfg(feature = "sev_snp")]
         {
-            mshv_vm_type = if _config.sev_snp_enabled {
+            mshv_vm_type = if config.sev_snp_enabled {
                 VmType::Snp
             } else {
                 VmType::Normal
@@ -348,7 +375,14 @@ impl hypervisor::Hypervisor for MshvHypervisor {
                         // this can't be considered as a regular error.
                         continue;
                     } else {
-                        return Err(hypervisor::HypervisorError::VmCreate(e.into()));
+                        return Err(hypervisor::HypervisorError::VmCreate {
+                            context: hypervisor::VmCreateContext {
+                                operation: "MSHV_CREATE_PARTITION",
+                                vm_type: mshv_vm_type as u64,
+                                errno: Some(e.errno()),
+                            },
+                            source: e.into(),
+                        });
                     }
                 }
             }
@@ -372,14 +406,17 @@ impl hypervisor::Hypervisor for MshvHypervisor {
 
             Ok(Arc::new(MshvVm {
                 fd: vm_fd,
+                label: config.label,
                 msrs,
+                topology: config.topology,
+                max_vcpus: config.max_vcpus.max(config.boot_vcpus),
                 dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
                 #[cfg(feature = "sev_snp")]
                 sev_snp_enabled: mshv_vm_type == VmType::Snp,
                 #[cfg(feature = "sev_snp")]
                 host_access_pages: ArcSwap::new(
                     AtomicBitmap::new(
-                        _config.mem_size as usize,
+                        config.mem_size as usize,
                         NonZeroUsize::new(HV_PAGE_SIZE).unwrap(),
                     )
                     .into(),
@@ -391,10 +428,19 @@ impl hypervisor::Hypervisor for MshvHypervisor {
         {
             Ok(Arc::new(MshvVm {
                 fd: vm_fd,
+                label: config.label,
                 dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
             }))
         }
     }
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Get the MSRs to migrate, i.e. all the ones supported by MSHV.
+    ///
+    fn migration_msr_indices(&self) -> hypervisor::Result<Vec<u32>> {
+        self.get_msr_list()
+    }
+
     #[cfg(target_arch = "x86_64")]
     ///
     /// Get the supported CpuID
@@ -603,6 +649,12 @@ impl cpu::Vcpu for MshvVcpu {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn enable_hyperv_crash_msrs(&self) -> cpu::Result<()> {
+        /* The crash MSRs are handled by the hypervisor on MSHV */
+        Ok(())
+    }
+
     #[allow(non_upper_case_globals)]
     fn run(&mut self) -> std::result::Result<cpu::VmExit, cpu::HypervisorCpuError> {
         match self.fd.run() {
@@ -616,9 +668,11 @@ impl cpu::Vcpu for MshvVcpu {
                     let reset_msg = x.to_reset_intercept_msg().unwrap();
 
                     match reset_msg.reset_type {
-                        hv_arm64_reset_type_HV_ARM64_RESET_TYPE_REBOOT => Ok(cpu::VmExit::Reset),
+                        hv_arm64_reset_type_HV_ARM64_RESET_TYPE_REBOOT => {
+                            Ok(cpu::VmExit::SystemReset(cpu::ResetReason::Cold))
+                        }
                         hv_arm64_reset_type_HV_ARM64_RESET_TYPE_POWER_OFF => {
-                            Ok(cpu::VmExit::Shutdown)
+                            Ok(cpu::VmExit::SystemOff)
                         }
                         _ => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
                             "Unhandled VCPU exit (RESET_INTERCEPT): reset type: {:?}",
@@ -860,7 +914,11 @@ impl cpu::Vcpu for MshvVcpu {
                 hv_message_type_HVMSG_X64_EXCEPTION_INTERCEPT => {
                     //TODO: Handler for VMCALL here.
                     let info = x.to_exception_info().unwrap();
-                    debug!("Exception Info {:?}", { info.exception_vector });
+                    if { info.exception_vector } == UD_VECTOR {
+                        self.report_invalid_opcode(&info)?;
+                    } else {
+                        debug!("Exception Info {:?}", { info.exception_vector });
+                    }
                     Ok(cpu::VmExit::Ignore)
                 }
                 #[cfg(target_arch = "x86_64")]
@@ -1266,6 +1324,13 @@ impl cpu::Vcpu for MshvVcpu {
         Ok(())
     }
 
+    #[cfg(target_arch = "aarch64")]
+    fn set_reset_vector(&self, pc: u64) -> cpu::Result<()> {
+        let arr_reg_name_value = [(hv_register_name_HV_ARM64_REGISTER_PC, pc)];
+        set_registers_64!(self.fd, arr_reg_name_value)
+            .map_err(|e| cpu::HypervisorCpuError::SetRegister(e.into()))
+    }
+
     #[cfg(target_arch = "aarch64")]
     fn get_sys_reg(&self, sys_reg: u32) -> cpu::Result<u64> {
         let mshv_reg = self.sys_reg_to_mshv_reg(sys_reg)?;
@@ -1678,6 +1743,36 @@ impl MshvVcpu {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Log the instruction which raised the intercepted #UD, then deliver
+    /// the exception to the guest as if it hadn't been intercepted.
+    ///
+    fn report_invalid_opcode(&self, info: &hv_x64_exception_intercept_message) -> cpu::Result<()> {
+        let len = usize::from(info.instruction_byte_count).min(info.instruction_bytes.len());
+        warn!(
+            "vCPU {}: invalid opcode at rip {:#x}: {:02x?}",
+            self.vp_index,
+            { info.header.rip },
+            &{ info.instruction_bytes }[..len]
+        );
+
+        let reg_assocs = [hv_register_assoc {
+            name: hv_register_name_HV_REGISTER_PENDING_EVENT0,
+            value: hv_register_value {
+                reg128: hv_u128 {
+                    low_part: PENDING_EVENT_PENDING
+                        | (u64::from(UD_VECTOR) << PENDING_EVENT_VECTOR_SHIFT),
+                    high_part: 0,
+                },
+            },
+            ..Default::default()
+        }];
+        self.fd
+            .set_reg(&reg_assocs)
+            .map_err(|e| cpu::HypervisorCpuError::SetRegister(e.into()))
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn advance_rip_update_rax(
         &self,
@@ -1727,8 +1822,14 @@ impl MshvVcpu {
 /// Wrapper over Mshv VM ioctls.
 pub struct MshvVm {
     fd: Arc<VmFd>,
+    label: String,
     #[cfg(target_arch = "x86_64")]
     msrs: Vec<MsrEntry>,
+    // Topology and vCPU count the APIC IDs are derived from
+    #[cfg(target_arch = "x86_64")]
+    topology: Option<(u16, u16, u16, u16)>,
+    #[cfg(target_arch = "x86_64")]
+    max_vcpus: u32,
     dirty_log_slots: Arc<RwLock<HashMap<u64, MshvDirtyLogSlot>>>,
     #[cfg(feature = "sev_snp")]
     sev_snp_enabled: bool,
@@ -1737,6 +1838,13 @@ pub struct MshvVm {
 }
 
 impl MshvVm {
+    ///
+    /// Attaches the VM label to an error.
+    ///
+    fn labelled(&self, e: vm::HypervisorVmError) -> vm::HypervisorVmError {
+        e.with_label(&self.label)
+    }
+
     ///
     /// Creates an in-kernel device.
     ///
@@ -1765,6 +1873,28 @@ impl MshvVm {
 /// let vm = hypervisor.create_vm(HypervisorVmConfig::default()).expect("new VM fd creation failed");
 /// ```
 impl vm::Vm for MshvVm {
+    ///
+    /// Returns the label the VM has been created with.
+    ///
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    ///
+    /// Returns the APIC ID the guest sees for the vCPU `index`.
+    ///
+    fn apic_id_for_vcpu(&self, index: u32) -> vm::Result<u32> {
+        if self.max_vcpus != 0 && index >= self.max_vcpus {
+            return Err(self.labelled(vm::HypervisorVmError::GetApicId(anyhow!(
+                "vCPU {index} is beyond the {} vCPUs of the VM",
+                self.max_vcpus
+            ))));
+        }
+
+        Ok(x2apic_id(index, self.topology))
+    }
+
     #[cfg(target_arch = "x86_64")]
     ///
     /// Sets the address of the one-page region in the VM's address space.
@@ -1796,7 +1926,7 @@ impl vm::Vm for MshvVm {
 
         self.fd
             .register_irqfd(fd, gsi)
-            .map_err(|e| vm::HypervisorVmError::RegisterIrqFd(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIrqFd(e.into())))?;
 
         Ok(())
     }
@@ -1809,7 +1939,7 @@ impl vm::Vm for MshvVm {
 
         self.fd
             .unregister_irqfd(fd, gsi)
-            .map_err(|e| vm::HypervisorVmError::UnregisterIrqFd(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::UnregisterIrqFd(e.into())))?;
 
         Ok(())
     }
@@ -1826,7 +1956,7 @@ impl vm::Vm for MshvVm {
         let vcpu_fd = self
             .fd
             .create_vcpu(id)
-            .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CreateVcpu(e.into())))?;
 
         /* Map the GHCB page to the VMM(root) address space
          * The map is available after the vcpu creation. This address is mapped
@@ -1849,7 +1979,7 @@ impl vm::Vm for MshvVm {
             if std::ptr::eq(addr, libc::MAP_FAILED) {
                 // No point of continuing, without this mmap VMGEXIT will fail anyway
                 // Return error
-                return Err(vm::HypervisorVmError::MmapToRoot);
+                return Err(self.labelled(vm::HypervisorVmError::MmapToRoot));
             }
             Some(Ghcb(addr as *mut svm_ghcb_base))
         } else {
@@ -1873,10 +2003,23 @@ impl vm::Vm for MshvVm {
     }
 
     #[cfg(target_arch = "x86_64")]
-    fn enable_split_irq(&self) -> vm::Result<()> {
+    fn enable_split_irq(&self, _ioapic_pins: u32) -> vm::Result<()> {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn enable_invalid_opcode_trap(&self) -> vm::Result<()> {
+        self.fd
+            .install_intercept(mshv_install_intercept {
+                access_type_mask: HV_INTERCEPT_ACCESS_MASK_EXECUTE,
+                intercept_type: hv_intercept_type_HV_INTERCEPT_TYPE_EXCEPTION,
+                intercept_parameter: hv_intercept_parameters {
+                    exception_vector: UD_VECTOR,
+                },
+            })
+            .map_err(|e| self.labelled(vm::HypervisorVmError::EnableInvalidOpcodeTrap(e.into())))
+    }
+
     fn register_ioevent(
         &self,
         fd: &EventFd,
@@ -1900,16 +2043,16 @@ impl vm::Vm for MshvVm {
                 vm::DataMatch::DataMatch32(mshv_dm32) => self
                     .fd
                     .register_ioevent(fd, addr, mshv_dm32)
-                    .map_err(|e| vm::HypervisorVmError::RegisterIoEvent(e.into())),
+                    .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIoEvent(e.into()))),
                 vm::DataMatch::DataMatch64(mshv_dm64) => self
                     .fd
                     .register_ioevent(fd, addr, mshv_dm64)
-                    .map_err(|e| vm::HypervisorVmError::RegisterIoEvent(e.into())),
+                    .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIoEvent(e.into()))),
             }
         } else {
             self.fd
                 .register_ioevent(fd, addr, NoDatamatch)
-                .map_err(|e| vm::HypervisorVmError::RegisterIoEvent(e.into()))
+                .map_err(|e| self.labelled(vm::HypervisorVmError::RegisterIoEvent(e.into())))
         }
     }
 
@@ -1925,7 +2068,7 @@ impl vm::Vm for MshvVm {
 
         self.fd
             .unregister_ioevent(fd, addr, NoDatamatch)
-            .map_err(|e| vm::HypervisorVmError::UnregisterIoEvent(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::UnregisterIoEvent(e.into())))
     }
 
     /// Creates a guest physical memory region.
@@ -1944,7 +2087,7 @@ impl vm::Vm for MshvVm {
 
         self.fd
             .map_user_memory(user_memory_region)
-            .map_err(|e| vm::HypervisorVmError::CreateUserMemory(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CreateUserMemory(e.into())))?;
         Ok(())
     }
 
@@ -1959,7 +2102,7 @@ impl vm::Vm for MshvVm {
 
         self.fd
             .unmap_user_memory(user_memory_region)
-            .map_err(|e| vm::HypervisorVmError::RemoveUserMemory(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::RemoveUserMemory(e.into())))?;
         Ok(())
     }
 
@@ -1995,7 +2138,7 @@ impl vm::Vm for MshvVm {
         };
 
         self.create_device(&mut vfio_dev)
-            .map_err(|e| vm::HypervisorVmError::CreatePassthroughDevice(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CreatePassthroughDevice(e.into())))
     }
 
     ///
@@ -2052,7 +2195,7 @@ impl vm::Vm for MshvVm {
 
         self.fd
             .set_msi_routing(&msi_routing[0])
-            .map_err(|e| vm::HypervisorVmError::SetGsiRouting(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetGsiRouting(e.into())))
     }
 
     ///
@@ -2061,7 +2204,7 @@ impl vm::Vm for MshvVm {
     fn start_dirty_log(&self) -> vm::Result<()> {
         self.fd
             .enable_dirty_page_tracking()
-            .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::StartDirtyLog(e.into())))
     }
 
     ///
@@ -2079,11 +2222,11 @@ impl vm::Vm for MshvVm {
                     s.memory_size as usize,
                     MSHV_GPAP_ACCESS_OP_SET as u8,
                 )
-                .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
+                .map_err(|e| self.labelled(vm::HypervisorVmError::StartDirtyLog(e.into())))?;
         }
         self.fd
             .disable_dirty_page_tracking()
-            .map_err(|e| vm::HypervisorVmError::StartDirtyLog(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::StartDirtyLog(e.into())))?;
         Ok(())
     }
 
@@ -2097,7 +2240,7 @@ impl vm::Vm for MshvVm {
                 memory_size as usize,
                 MSHV_GPAP_ACCESS_OP_CLEAR as u8,
             )
-            .map_err(|e| vm::HypervisorVmError::GetDirtyLog(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetDirtyLog(e.into())))
     }
 
     /// Retrieve guest clock.
@@ -2106,7 +2249,7 @@ impl vm::Vm for MshvVm {
         let val = self
             .fd
             .get_partition_property(hv_partition_property_code_HV_PARTITION_PROPERTY_REFERENCE_TIME)
-            .map_err(|e| vm::HypervisorVmError::GetClock(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::GetClock(e.into())))?;
         Ok(MshvClockData { ref_time: val }.into())
     }
 
@@ -2119,7 +2262,7 @@ impl vm::Vm for MshvVm {
                 hv_partition_property_code_HV_PARTITION_PROPERTY_REFERENCE_TIME,
                 data.ref_time,
             )
-            .map_err(|e| vm::HypervisorVmError::SetClock(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::SetClock(e.into())))
     }
 
     /// Downcast to the underlying MshvVm type
@@ -2135,7 +2278,7 @@ impl vm::Vm for MshvVm {
                 hv_partition_property_code_HV_PARTITION_PROPERTY_ISOLATION_STATE,
                 hv_partition_isolation_state_HV_PARTITION_ISOLATION_SECURE as u64,
             )
-            .map_err(|e| vm::HypervisorVmError::InitializeSevSnp(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::InitializeSevSnp(e.into())))
     }
 
     ///
@@ -2166,7 +2309,7 @@ impl vm::Vm for MshvVm {
         }
         self.fd
             .import_isolated_pages(&isolated_pages[0])
-            .map_err(|e| vm::HypervisorVmError::ImportIsolatedPages(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::ImportIsolatedPages(e.into())))
     }
 
     ///
@@ -2218,13 +2361,17 @@ impl vm::Vm for MshvVm {
         };
         self.fd
             .complete_isolated_import(&data)
-            .map_err(|e| vm::HypervisorVmError::CompleteIsolatedImport(e.into()))
+            .map_err(|e| self.labelled(vm::HypervisorVmError::CompleteIsolatedImport(e.into())))
     }
 
     #[cfg(target_arch = "aarch64")]
     fn create_vgic(&self, config: VgicConfig) -> vm::Result<Arc<Mutex<dyn Vgic>>> {
-        let gic_device = MshvGicV2M::new(self, config)
-            .map_err(|e| vm::HypervisorVmError::CreateVgic(anyhow!("Vgic error {:?}", e)))?;
+        let gic_device = MshvGicV2M::new(self, config).map_err(|e| {
+            self.labelled(vm::HypervisorVmError::CreateVgic(anyhow!(
+                "Vgic error {:?}",
+                e
+            )))
+        })?;
 
         // Register GICD address with the hypervisor
         self.fd
@@ -2233,7 +2380,10 @@ impl vm::Vm for MshvVm {
                 gic_device.dist_addr,
             )
             .map_err(|e| {
-                vm::HypervisorVmError::CreateVgic(anyhow!("Failed to set GICD address: {}", e))
+                self.labelled(vm::HypervisorVmError::CreateVgic(anyhow!(
+                    "Failed to set GICD address: {}",
+                    e
+                )))
             })?;
 
         // Register GITS address with the hypervisor
@@ -2244,7 +2394,10 @@ impl vm::Vm for MshvVm {
                 gic_device.gits_addr,
             )
             .map_err(|e| {
-                vm::HypervisorVmError::CreateVgic(anyhow!("Failed to set GITS address: {}", e))
+                self.labelled(vm::HypervisorVmError::CreateVgic(anyhow!(
+                    "Failed to set GITS address: {}",
+                    e
+                )))
             })?;
 
         Ok(Arc::new(Mutex::new(gic_device)))
@@ -2264,10 +2417,10 @@ impl vm::Vm for MshvVm {
                 1u64,
             )
             .map_err(|e| {
-                vm::HypervisorVmError::SetVmProperty(anyhow!(
+                self.labelled(vm::HypervisorVmError::SetVmProperty(anyhow!(
                     "Failed to set partition property: {}",
                     e
-                ))
+                )))
             })
     }
 
@@ -2280,10 +2433,10 @@ impl vm::Vm for MshvVm {
                 0u64,
             )
             .map_err(|e| {
-                vm::HypervisorVmError::SetVmProperty(anyhow!(
+                self.labelled(vm::HypervisorVmError::SetVmProperty(anyhow!(
                     "Failed to set partition property: {}",
                     e
-                ))
+                )))
             })
     }
 
@@ -2339,7 +2492,7 @@ impl vm::Vm for MshvVm {
 
             self.fd
                 .modify_gpa_host_access(&gpa_list[0])
-                .map_err(|e| vm::HypervisorVmError::ModifyGpaHostAccess(e.into()))?;
+                .map_err(|e| self.labelled(vm::HypervisorVmError::ModifyGpaHostAccess(e.into())))?;
 
             for acquired_gpa in gpas {
                 self.host_access_pages.rcu(|bitmap| {
@@ -2362,10 +2515,10 @@ impl vm::Vm for MshvVm {
                     0,
                 )
                 .map_err(|e| {
-                    vm::HypervisorVmError::InitializeVm(anyhow!(
+                    self.labelled(vm::HypervisorVmError::InitializeVm(anyhow!(
                         "Failed to set GIC LPI support: {}",
                         e
-                    ))
+                    )))
                 })?;
 
             self.fd
@@ -2374,10 +2527,10 @@ impl vm::Vm for MshvVm {
                     (AARCH64_ARCH_TIMER_VIRT_IRQ + AARCH64_MIN_PPI_IRQ) as u64,
                 )
                 .map_err(|e| {
-                    vm::HypervisorVmError::InitializeVm(anyhow!(
+                    self.labelled(vm::HypervisorVmError::InitializeVm(anyhow!(
                         "Failed to set arch timer interrupt ID: {}",
                         e
-                    ))
+                    )))
                 })?;
 
             self.fd
@@ -2386,16 +2539,16 @@ impl vm::Vm for MshvVm {
                     (AARCH64_PMU_IRQ + AARCH64_MIN_PPI_IRQ) as u64,
                 )
                 .map_err(|e| {
-                    vm::HypervisorVmError::InitializeVm(anyhow!(
+                    self.labelled(vm::HypervisorVmError::InitializeVm(anyhow!(
                         "Failed to set PMU interrupt ID: {}",
                         e
-                    ))
+                    )))
                 })?;
         }
 
         self.fd
             .initialize()
-            .map_err(|e| vm::HypervisorVmError::InitializeVm(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::InitializeVm(e.into())))?;
 
         // Set additional partition property for SEV-SNP partition.
         #[cfg(feature = "sev_snp")]
@@ -2413,7 +2566,7 @@ impl vm::Vm for MshvVm {
                         hv_partition_property_code_HV_PARTITION_PROPERTY_ISOLATION_POLICY,
                         snp_policy.as_uint64,
                     )
-                    .map_err(|e| vm::HypervisorVmError::InitializeVm(e.into()))?;
+                    .map_err(|e| self.labelled(vm::HypervisorVmError::InitializeVm(e.into())))?;
                 debug!(
                     "Setting the partition property to enable VMGEXIT offloads as : 0x{:x}",
                     vmgexit_offloads.as_uint64
@@ -2423,7 +2576,7 @@ impl vm::Vm for MshvVm {
                         hv_partition_property_code_HV_PARTITION_PROPERTY_SEV_VMGEXIT_OFFLOADS,
                         vmgexit_offloads.as_uint64,
                     )
-                    .map_err(|e| vm::HypervisorVmError::InitializeVm(e.into()))?;
+                    .map_err(|e| self.labelled(vm::HypervisorVmError::InitializeVm(e.into())))?;
             }
         }
         // Default Microsoft Hypervisor behavior for unimplemented MSR is to
@@ -2438,7 +2591,7 @@ impl vm::Vm for MshvVm {
                 hv_unimplemented_msr_action_HV_UNIMPLEMENTED_MSR_ACTION_IGNORE_WRITE_READ_ZERO
                     as u64,
             )
-            .map_err(|e| vm::HypervisorVmError::InitializeVm(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::InitializeVm(e.into())))?;
 
         // Always create a frozen partition
         self.fd
@@ -2446,7 +2599,7 @@ impl vm::Vm for MshvVm {
                 hv_partition_property_code_HV_PARTITION_PROPERTY_TIME_FREEZE,
                 1u64,
             )
-            .map_err(|e| vm::HypervisorVmError::InitializeVm(e.into()))?;
+            .map_err(|e| self.labelled(vm::HypervisorVmError::InitializeVm(e.into())))?;
 
         Ok(())
     }
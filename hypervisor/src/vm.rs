@@ -11,6 +11,10 @@
 //
 
 use std::any::Any;
+use std::fs::File;
+#[cfg(target_arch = "x86_64")]
+use std::ops::{Range, RangeInclusive};
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 use std::sync::Mutex;
@@ -18,6 +22,7 @@ use std::sync::Mutex;
 #[cfg(feature = "sev_snp")]
 use igvm_defs::IGVM_VHS_SNP_ID_BLOCK;
 use thiserror::Error;
+use vm_memory::GuestAddress;
 use vmm_sys_util::eventfd::EventFd;
 
 #[cfg(target_arch = "x86_64")]
@@ -28,7 +33,13 @@ use crate::arch::aarch64::gic::{Vgic, VgicConfig};
 use crate::arch::riscv64::aia::{Vaia, VaiaConfig};
 #[cfg(feature = "tdx")]
 use crate::arch::x86::CpuIdEntry;
-use crate::cpu::Vcpu;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::MsrEntry;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::msr_index::MSR_IA32_TSC;
+#[cfg(target_arch = "x86_64")]
+use crate::cpu::HypervisorCpuError;
+use crate::cpu::{MmuStats, Vcpu};
 use crate::{IoEventAddress, IrqRoutingEntry, UserMemoryRegion};
 
 ///
@@ -109,6 +120,26 @@ pub enum HypervisorVmError {
     #[error("Failed to remove user memory")]
     RemoveUserMemory(#[source] anyhow::Error),
     ///
+    /// Create guest_memfd error
+    ///
+    #[error("Failed to create guest_memfd")]
+    CreateGuestMemfd(#[source] anyhow::Error),
+    ///
+    /// Set memory attributes error
+    ///
+    #[error("Failed to set memory attributes")]
+    SetMemoryAttributes(#[source] anyhow::Error),
+    ///
+    /// Map device MMIO error
+    ///
+    #[error("Failed to map device MMIO")]
+    MapDeviceMmio(#[source] anyhow::Error),
+    ///
+    /// Unmap device MMIO error
+    ///
+    #[error("Failed to unmap device MMIO")]
+    UnmapDeviceMmio(#[source] anyhow::Error),
+    ///
     /// Create device error
     ///
     #[error("Failed to set GSI routing")]
@@ -138,6 +169,16 @@ pub enum HypervisorVmError {
     #[error("Failed to set clock")]
     SetClock(#[source] anyhow::Error),
     ///
+    /// Set RTC time error
+    ///
+    #[error("Failed to set RTC time")]
+    SetRtcTime(#[source] anyhow::Error),
+    ///
+    /// Synchronize TSC error
+    ///
+    #[error("Failed to synchronize the TSC of the vCPUs")]
+    SynchronizeTsc(#[source] anyhow::Error),
+    ///
     /// Create passthrough device
     ///
     #[error("Failed to create passthrough device")]
@@ -257,6 +298,117 @@ pub enum HypervisorVmError {
     ///
     #[error("Failed to initialize VM")]
     InitializeVm(#[source] anyhow::Error),
+    ///
+    /// Set halt-polling window error
+    ///
+    #[error("Failed to set halt-polling window")]
+    SetHaltPollNs(#[source] anyhow::Error),
+    ///
+    /// Get halt-polling window error
+    ///
+    #[error("Failed to get halt-polling window")]
+    GetHaltPollNs(#[source] anyhow::Error),
+    ///
+    /// Prefault guest memory error
+    ///
+    #[error("Failed to prefault guest memory")]
+    PrefaultMemory(#[source] anyhow::Error),
+    ///
+    /// Set memory advice error
+    ///
+    #[error("Failed to set memory advice")]
+    SetMemoryAdvice(#[source] anyhow::Error),
+    ///
+    /// Set GPA watchpoint error
+    ///
+    #[error("Failed to set GPA watchpoint")]
+    SetGpaWatchpoint(#[source] anyhow::Error),
+    ///
+    /// Clear GPA watchpoint error
+    ///
+    #[error("Failed to clear GPA watchpoint")]
+    ClearGpaWatchpoint(#[source] anyhow::Error),
+    ///
+    /// Set MMIO rate limit error
+    ///
+    #[error("Failed to set MMIO rate limit")]
+    SetMmioRateLimit(#[source] anyhow::Error),
+    ///
+    /// Set APIC bus cycle period error
+    ///
+    #[cfg(target_arch = "x86_64")]
+    #[error("Failed to set the APIC bus cycle period")]
+    SetApicBusCycle(#[source] anyhow::Error),
+    ///
+    /// Enable invalid opcode trap error
+    ///
+    #[cfg(target_arch = "x86_64")]
+    #[error("Failed to enable the invalid opcode trap")]
+    EnableInvalidOpcodeTrap(#[source] anyhow::Error),
+    ///
+    /// Copy MTE tags error
+    ///
+    #[cfg(target_arch = "aarch64")]
+    #[error("Failed to copy MTE tags")]
+    CopyMteTags(#[source] anyhow::Error),
+    ///
+    /// Getting VM statistics error
+    ///
+    #[error("Failed to get VM statistics")]
+    GetStats(#[source] anyhow::Error),
+    ///
+    /// Getting the shutdown eventfd error
+    ///
+    #[error("Failed to get the shutdown eventfd")]
+    ShutdownEventFd(#[source] anyhow::Error),
+    ///
+    /// Quiescing the vCPUs error
+    ///
+    #[error("Failed to quiesce the vCPUs")]
+    Quiesce(#[source] anyhow::Error),
+    ///
+    /// Register PIO handler error
+    ///
+    #[error("Failed to register PIO handler")]
+    RegisterPioHandler(#[source] anyhow::Error),
+    ///
+    /// Signal MSI error
+    ///
+    #[error("Failed to signal MSI")]
+    SignalMsi(#[source] anyhow::Error),
+    ///
+    /// MSI not delivered error
+    ///
+    #[error("MSI to 0x{0:x} with data 0x{1:x} not accepted by any vCPU")]
+    MsiNotDelivered(u64 /* address */, u32 /* data */),
+    ///
+    /// Get APIC ID error
+    ///
+    #[error("Failed to get the APIC ID of a vCPU")]
+    GetApicId(#[source] anyhow::Error),
+    ///
+    /// Error reported by a VM created with a label
+    ///
+    #[error("Error on VM \"{label}\"")]
+    Labelled {
+        label: String,
+        #[source]
+        source: Box<HypervisorVmError>,
+    },
+}
+
+impl HypervisorVmError {
+    /// Attach the VM label to the error, unless the label is empty.
+    pub fn with_label(self, label: &str) -> Self {
+        if label.is_empty() || matches!(self, HypervisorVmError::Labelled { .. }) {
+            return self;
+        }
+
+        HypervisorVmError::Labelled {
+            label: label.to_string(),
+            source: Box::new(self),
+        }
+    }
 }
 ///
 /// Result type for returning from a function
@@ -287,6 +439,84 @@ pub struct MsiIrqSourceConfig {
     pub devid: u32,
 }
 
+/// Guest accesses triggering a guest physical address watchpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchpointAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// MMIO exits to a guest physical region rate limited with
+/// `Vm::set_mmio_rate_limit()`, summed over all the vCPUs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MmioExitStats {
+    pub region: Range<u64>,
+    pub max_exits_per_sec: u32,
+    /// Exits to the region since its limit was set
+    pub exits: u64,
+    /// Exits which exceeded the limit and paused their vCPU
+    pub throttled: u64,
+}
+
+/// Page faults taken by the hypervisor while running the guest, summed
+/// over all the vCPUs.
+///
+/// The counters are cumulative since the vCPUs were created, a rate is
+/// obtained by sampling them periodically and diffing consecutive samples.
+/// Reading them only costs one read of the statistics per vCPU, which is
+/// fine for polling every second.
+///
+/// The hypervisor doesn't tell minor and major faults apart: both end up in
+/// `fixed`, a major fault on guest memory also being accounted as a major
+/// fault of the VMM process by the host kernel. Put together with the
+/// resident size of the guest memory, a quickly growing `fixed` indicates
+/// the guest is touching memory it didn't use so far, or memory that was
+/// reclaimed from it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Faults on guest memory handled by the hypervisor, i.e. EPT/NPT
+    /// violations, or guest page faults with shadow paging
+    pub taken: u64,
+    /// Faults resolved by mapping host memory in the guest
+    pub fixed: u64,
+    /// Faults that were the guest's own and got injected back into it,
+    /// only counted with shadow paging
+    pub guest: u64,
+}
+
+/// Advice on the use of the host memory backing the guest RAM, passed to
+/// madvise(2).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryAdvice {
+    /// Let KSM merge identical pages, `MADV_MERGEABLE`
+    Mergeable,
+    /// Unmerge the pages merged by KSM, `MADV_UNMERGEABLE`
+    Unmergeable,
+    /// Back the memory with transparent huge pages, `MADV_HUGEPAGE`
+    HugePage,
+    /// Don't use transparent huge pages, `MADV_NOHUGEPAGE`
+    NoHugePage,
+    /// Exclude the memory from core dumps, `MADV_DONTDUMP`
+    DontDump,
+    /// Include the memory in core dumps, `MADV_DODUMP`
+    DoDump,
+}
+
+impl MemoryAdvice {
+    /// Returns the madvise(2) advice value.
+    pub fn as_raw(&self) -> libc::c_int {
+        match self {
+            MemoryAdvice::Mergeable => libc::MADV_MERGEABLE,
+            MemoryAdvice::Unmergeable => libc::MADV_UNMERGEABLE,
+            MemoryAdvice::HugePage => libc::MADV_HUGEPAGE,
+            MemoryAdvice::NoHugePage => libc::MADV_NOHUGEPAGE,
+            MemoryAdvice::DontDump => libc::MADV_DONTDUMP,
+            MemoryAdvice::DoDump => libc::MADV_DODUMP,
+        }
+    }
+}
+
 /// Configuration data for an interrupt source.
 #[derive(Copy, Clone, Debug)]
 pub enum InterruptSourceConfig {
@@ -302,6 +532,17 @@ pub enum InterruptSourceConfig {
 /// This crate provides a hypervisor-agnostic interfaces for Vm
 ///
 pub trait Vm: Send + Sync + Any {
+    /// Returns the label the VM has been created with.
+    fn label(&self) -> &str {
+        ""
+    }
+    /// Returns the APIC ID the guest sees for the vCPU `index`, derived from
+    /// the topology the VM has been created with. MSI routes must target
+    /// this ID rather than the vCPU index.
+    #[cfg(target_arch = "x86_64")]
+    fn apic_id_for_vcpu(&self, _index: u32) -> Result<u32> {
+        Err(HypervisorVmError::GetApicId(anyhow!("unsupported")))
+    }
     #[cfg(target_arch = "x86_64")]
     /// Sets the address of the one-page region in the VM's address space.
     fn set_identity_map_address(&self, address: u64) -> Result<()>;
@@ -349,18 +590,126 @@ pub trait Vm: Send + Sync + Any {
     fn create_user_memory_region(&self, user_memory_region: UserMemoryRegion) -> Result<()>;
     /// Removes a guest physical memory slot.
     fn remove_user_memory_region(&self, user_memory_region: UserMemoryRegion) -> Result<()>;
+    /// Create a guest_memfd of `size` bytes, holding the private memory of
+    /// a confidential VM.
+    fn create_guest_memfd(&self, _size: u64, _flags: u64) -> Result<File> {
+        Err(HypervisorVmError::CreateGuestMemfd(anyhow!("unsupported")))
+    }
+    /// Creates a guest physical memory slot backed by two memories: the
+    /// shared one at `userspace_addr`, and the private one in `guest_memfd`
+    /// starting at `guest_memfd_offset`.
+    ///
+    /// Which of the two backs a given page is decided by its private
+    /// attribute, see `set_memory_private()`. All the pages start shared.
+    /// Such slots can't be used for dirty pages logging.
+    fn create_user_memory_region_with_guest_memfd(
+        &self,
+        _user_memory_region: UserMemoryRegion,
+        _guest_memfd: &File,
+        _guest_memfd_offset: u64,
+    ) -> Result<()> {
+        Err(HypervisorVmError::CreateUserMemory(anyhow!("unsupported")))
+    }
+    /// Switch the pages of a range of guest memory between the shared and
+    /// private backings of their slot.
+    fn set_memory_private(&self, _guest_phys_addr: u64, _size: u64, _private: bool) -> Result<()> {
+        Err(HypervisorVmError::SetMemoryAttributes(anyhow!(
+            "unsupported"
+        )))
+    }
+    /// Map `size` bytes of a device, e.g. a VFIO BAR, at `offset` in
+    /// `host_fd` directly into the guest at `gpa` through the memory `slot`,
+    /// so that guest accesses reach the device without exiting.
+    ///
+    /// The slot is allocated by the caller, along with the ones of the guest
+    /// RAM, and must be passed to `unmap_device_mmio()`. `gpa`, `offset` and
+    /// `size` must be page aligned. The device is mapped `MAP_SHARED`, a
+    /// private mapping would have the guest access copy-on-write pages
+    /// instead of the device.
+    fn map_device_mmio(
+        &self,
+        _slot: u32,
+        _gpa: GuestAddress,
+        _host_fd: RawFd,
+        _offset: u64,
+        _size: u64,
+    ) -> Result<()> {
+        Err(HypervisorVmError::MapDeviceMmio(anyhow!("unsupported")))
+    }
+    /// Remove a mapping created with `map_device_mmio()` from the guest, the
+    /// caller can then reuse the slot.
+    fn unmap_device_mmio(&self, _slot: u32) -> Result<()> {
+        Err(HypervisorVmError::UnmapDeviceMmio(anyhow!("unsupported")))
+    }
     /// Returns the preferred CPU target type which can be emulated by KVM on underlying host.
     #[cfg(target_arch = "aarch64")]
     fn get_preferred_target(&self, kvi: &mut crate::VcpuInit) -> Result<()>;
-    /// Enable split Irq capability
+    /// Enable split Irq capability, reserving the GSIs below `ioapic_pins`
+    /// for the pins of the userspace IOAPIC. MSIs use the GSIs above them.
     #[cfg(target_arch = "x86_64")]
-    fn enable_split_irq(&self) -> Result<()>;
+    fn enable_split_irq(&self, ioapic_pins: u32) -> Result<()>;
     /// Retrieve guest clock.
     #[cfg(target_arch = "x86_64")]
     fn get_clock(&self) -> Result<ClockData>;
     /// Set guest clock.
     #[cfg(target_arch = "x86_64")]
     fn set_clock(&self, data: &ClockData) -> Result<()>;
+    /// Set the time read by the guest from the in-kernel RTC, as seconds
+    /// since the Unix epoch.
+    ///
+    /// This is unrelated to `set_clock()`: KVM_SET_CLOCK only sets the
+    /// kvmclock (and through it the guest TSC based time), the guest keeps
+    /// reading the wall clock from the RTC at boot. When an in-kernel RTC
+    /// exists, its state must be saved along with the VM state so that the
+    /// time carries over on migration.
+    ///
+    /// Backends without an in-kernel RTC, which includes KVM, return an
+    /// error and the RTC is then emulated by the userspace CMOS device.
+    fn set_rtc_time(&self, _unix_secs: u64) -> Result<()> {
+        Err(HypervisorVmError::SetRtcTime(anyhow!("unsupported")))
+    }
+    /// Make all the vCPUs start from the same TSC value, must be called
+    /// before any of them runs.
+    ///
+    /// The TSC frequency of the first vCPU is applied to the others, then
+    /// the TSC of the first vCPU is written to all of them in a tight loop.
+    /// KVM considers TSC writes happening close in time at the same
+    /// frequency as an attempt to synchronize them, and matches their
+    /// offsets exactly.
+    ///
+    /// This is best effort, the vCPUs may still be slightly skewed if the
+    /// writes are delayed. Guests relying on a synchronized TSC should also
+    /// be given the kvmclock.
+    #[cfg(target_arch = "x86_64")]
+    fn synchronize_tsc(&self, vcpus: &[&dyn Vcpu]) -> Result<()> {
+        let Some((first, others)) = vcpus.split_first() else {
+            return Ok(());
+        };
+        let map_err = |e: HypervisorCpuError| HypervisorVmError::SynchronizeTsc(e.into());
+
+        if let Some(freq) = first.tsc_khz().map_err(map_err)? {
+            for vcpu in others {
+                if vcpu.tsc_khz().map_err(map_err)? != Some(freq) {
+                    vcpu.set_tsc_khz(freq).map_err(map_err)?;
+                }
+            }
+        }
+
+        let (_, tsc) = first.read_msrs(&[MSR_IA32_TSC]).map_err(map_err)?[0];
+        let entries = [MsrEntry {
+            index: MSR_IA32_TSC,
+            data: tsc,
+        }];
+        for vcpu in vcpus {
+            if vcpu.set_msrs(&entries).map_err(map_err)? != entries.len() {
+                return Err(HypervisorVmError::SynchronizeTsc(anyhow!(
+                    "Failed to write the TSC"
+                )));
+            }
+        }
+
+        Ok(())
+    }
     /// Create a device that is used for passthrough
     fn create_passthrough_device(&self) -> Result<vfio_ioctls::VfioDeviceFd>;
     /// Start logging dirty pages
@@ -440,6 +789,210 @@ pub trait Vm: Send + Sync + Any {
     fn enable_x2apic_api(&self) -> Result<()> {
         unimplemented!("x2Apic is only supported on KVM/Linux hosts")
     }
+
+    /// Set the maximum time, in nanoseconds, a halted vCPU polls for a
+    /// wakeup before being scheduled out.
+    ///
+    /// This is a hint: the kernel may cap or ignore the value. On KVM the
+    /// window applies to all the vCPUs of the VM.
+    fn set_halt_poll_ns(&self, _ns: u32) -> Result<()> {
+        Err(HypervisorVmError::SetHaltPollNs(anyhow!("unsupported")))
+    }
+
+    /// Get the halt-polling window, in nanoseconds.
+    fn get_halt_poll_ns(&self) -> Result<u32> {
+        Err(HypervisorVmError::GetHaltPollNs(anyhow!("unsupported")))
+    }
+
+    /// Populate all the guest RAM regions, so that the guest does not pay
+    /// for the first access to each page. Device memory regions are skipped.
+    ///
+    /// `progress` is called after each populated chunk with the number of
+    /// bytes populated so far and the total number of bytes.
+    fn prefault_memory(&self, _progress: Option<&mut dyn FnMut(u64, u64)>) -> Result<()> {
+        Err(HypervisorVmError::PrefaultMemory(anyhow!("unsupported")))
+    }
+
+    /// Apply `advice` to the host memory backing all the guest RAM slots,
+    /// to tune how the host manages it, e.g. when overcommitting memory.
+    ///
+    /// `MemoryAdvice::Mergeable` is rejected for confidential VMs, the host
+    /// can't share their encrypted memory between VMs.
+    fn set_memory_advice(&self, _advice: MemoryAdvice) -> Result<()> {
+        Err(HypervisorVmError::SetMemoryAdvice(anyhow!("unsupported")))
+    }
+
+    /// Set a watchpoint on the guest physical range `[gpa, gpa + len)`,
+    /// independently of the guest virtual mappings. Hits are reported by
+    /// `Vcpu::run()` as `VmExit::Watchpoint`, after the access completed.
+    ///
+    /// The watched range must fit in a single page, which is unmapped (or
+    /// made read-only for `WatchpointAccess::Write`) from the guest. Every
+    /// access to that page, including accesses outside the watched range,
+    /// then traps to userspace and is emulated there, which is several orders
+    /// of magnitude slower than a regular memory access. Watching a frequently
+    /// accessed page (e.g. a stack or a lock) considerably slows the guest
+    /// down. The guest cannot execute code from a page watched for reads.
+    ///
+    /// The vCPUs must not be running while watchpoints are set or cleared.
+    fn set_gpa_watchpoint(&self, _gpa: u64, _len: u64, _access: WatchpointAccess) -> Result<()> {
+        Err(HypervisorVmError::SetGpaWatchpoint(anyhow!("unsupported")))
+    }
+
+    /// Clear the watchpoint covering `gpa`.
+    fn clear_gpa_watchpoint(&self, _gpa: u64) -> Result<()> {
+        Err(HypervisorVmError::ClearGpaWatchpoint(anyhow!(
+            "unsupported"
+        )))
+    }
+
+    /// Limit the MMIO exits of the vCPUs to the guest physical `region` to
+    /// `max_exits_per_sec`, 0 removing the limit. Regions are unlimited by
+    /// default, and the limited ones can't overlap.
+    ///
+    /// Bursts of up to one second worth of exits are let through, after
+    /// which every exit pauses its vCPU for the time needed to get back
+    /// under the limit, i.e. about `1 / max_exits_per_sec` second. This
+    /// keeps a guest hammering a device from starving the other work of
+    /// the host, at the expense of the guest throughput: a guest driving
+    /// the device legitimately faster is slowed down all the same.
+    fn set_mmio_rate_limit(&self, _region: Range<u64>, _max_exits_per_sec: u32) -> Result<()> {
+        Err(HypervisorVmError::SetMmioRateLimit(anyhow!("unsupported")))
+    }
+
+    /// Returns the exit counters of the regions limited with
+    /// `set_mmio_rate_limit()`.
+    fn get_mmio_exit_stats(&self) -> Result<Vec<MmioExitStats>> {
+        Err(HypervisorVmError::GetStats(anyhow!("unsupported")))
+    }
+
+    /// Returns the MMU statistics accounted for the whole VM, the per-vCPU
+    /// page fault counters are available from `Vcpu::get_mmu_stats()`.
+    fn get_mmu_stats(&self) -> Result<MmuStats> {
+        Err(HypervisorVmError::GetStats(anyhow!("unsupported")))
+    }
+
+    /// Returns the page fault counters of all the vCPUs of the VM, see
+    /// `FaultStats` for their meaning.
+    fn guest_fault_stats(&self) -> Result<FaultStats> {
+        Err(HypervisorVmError::GetStats(anyhow!("unsupported")))
+    }
+
+    /// Returns an eventfd signaled each time the guest powers off: from
+    /// `Vcpu::run()` on a PSCI SYSTEM_OFF on aarch64, and by the VMM when the
+    /// power off goes through emulated devices (ACPI on x86_64).
+    ///
+    /// The eventfd is not one-shot: a counter is incremented on every
+    /// shutdown, and reading it returns and clears the number of shutdowns
+    /// since the previous read. It is only a notification, the exit is still
+    /// returned from `Vcpu::run()` and the VMM applies its policy. Resets
+    /// don't signal it, including an x86_64 triple fault (KVM_EXIT_SHUTDOWN)
+    /// which is reported as `VmExit::Reset`.
+    fn shutdown_eventfd(&self) -> Result<EventFd> {
+        Err(HypervisorVmError::ShutdownEventFd(anyhow!("unsupported")))
+    }
+
+    /// Checks that no vCPU is in the middle of an IO operation, so that the
+    /// state captured next is crash-consistent.
+    ///
+    /// An IO or MMIO exit handled by the VMM is only complete once the vCPU
+    /// re-entered the hypervisor, and a snapshot taken before would replay
+    /// it on restore. The `Vcpu::run()` loop tracks these exits, and the
+    /// vCPU threads must complete them before pausing, by running the vCPU
+    /// once more with `Vcpu::set_immediate_exit()` set so that it stops at
+    /// the next instruction boundary. This fails if any vCPU didn't.
+    fn quiesce(&self) -> Result<()> {
+        Err(HypervisorVmError::Quiesce(anyhow!("unsupported")))
+    }
+
+    /// Returns the IO port ranges emulated by the hypervisor itself, whose
+    /// accesses never exit: a userspace device claiming them wouldn't see
+    /// any access.
+    ///
+    /// This reflects the in-kernel devices created so far, e.g. the PICs
+    /// once `create_irq_chip()` was called, and is empty with a split
+    /// irqchip. Ports bound to an ioeventfd are not included, as only
+    /// writes are handled in kernel.
+    #[cfg(target_arch = "x86_64")]
+    fn in_kernel_pio_ports(&self) -> Vec<RangeInclusive<u16>> {
+        Vec::new()
+    }
+
+    /// Route the guest accesses to the IO ports of `range` to `handler`,
+    /// from `Vcpu::run()`. The exit is then reported as `VmExit::Ignore`,
+    /// while the accesses to the other ports still go to `VmOps`.
+    ///
+    /// Fails if `range` overlaps a range already registered.
+    #[cfg(target_arch = "x86_64")]
+    fn register_pio_handler(
+        &self,
+        _range: RangeInclusive<u16>,
+        _handler: Arc<dyn PioHandler>,
+    ) -> Result<()> {
+        Err(HypervisorVmError::RegisterPioHandler(anyhow!(
+            "unsupported"
+        )))
+    }
+
+    /// Set the period of the APIC bus cycles, which clock the LAPIC timer,
+    /// to `ns` instead of the 1 ns default. The guest must be told about
+    /// it, e.g. through the KVM timing leaf, not to miscalibrate its timer.
+    ///
+    /// Requires the in-kernel irqchip, and must be called before any vCPU
+    /// is created.
+    #[cfg(target_arch = "x86_64")]
+    fn set_apic_bus_cycle_ns(&self, _ns: u32) -> Result<()> {
+        Err(HypervisorVmError::SetApicBusCycle(anyhow!("unsupported")))
+    }
+
+    /// Intercept the invalid opcode exceptions (#UD) raised on all the
+    /// vCPUs. The faulting instruction is logged along with its address,
+    /// then the exception is delivered to the guest, which handles it as
+    /// usual.
+    ///
+    /// This audits the instructions a guest relies on that the CPU doesn't
+    /// implement or has disabled. The instructions implemented by the CPU
+    /// but hidden from the guest CPUID usually don't fault, and can't be
+    /// caught this way.
+    ///
+    /// Unsupported by KVM, which handles the #UD exits in kernel.
+    #[cfg(target_arch = "x86_64")]
+    fn enable_invalid_opcode_trap(&self) -> Result<()> {
+        Err(HypervisorVmError::EnableInvalidOpcodeTrap(anyhow!(
+            "unsupported"
+        )))
+    }
+
+    /// Inject the MSI writing `data` to `addr`, without going through a GSI
+    /// route. This is the one-shot equivalent of an irqfd.
+    ///
+    /// Fails with `HypervisorVmError::MsiNotDelivered` when no vCPU accepted
+    /// it, e.g. because the guest masked it or the destination didn't match.
+    fn signal_msi(&self, _addr: u64, _data: u32) -> Result<()> {
+        Err(HypervisorVmError::SignalMsi(anyhow!("unsupported")))
+    }
+
+    /// Whether the Memory Tagging Extension is enabled for the guest.
+    #[cfg(target_arch = "aarch64")]
+    fn mte_enabled(&self) -> bool {
+        false
+    }
+
+    /// Read the MTE tags of the guest memory starting at `gpa`, one tag per
+    /// byte of `tags` for each `arch::aarch64::MTE_GRANULE_SIZE` bytes of memory.
+    ///
+    /// `gpa` and the covered length must be page aligned.
+    #[cfg(target_arch = "aarch64")]
+    fn get_mte_tags(&self, _gpa: u64, _tags: &mut [u8]) -> Result<()> {
+        Err(HypervisorVmError::CopyMteTags(anyhow!("unsupported")))
+    }
+
+    /// Write the MTE tags of the guest memory starting at `gpa`, see
+    /// `get_mte_tags()` for the layout of `tags`.
+    #[cfg(target_arch = "aarch64")]
+    fn set_mte_tags(&self, _gpa: u64, _tags: &[u8]) -> Result<()> {
+        Err(HypervisorVmError::CopyMteTags(anyhow!("unsupported")))
+    }
 }
 
 pub trait VmOps: Send + Sync {
@@ -452,3 +1005,16 @@ pub trait VmOps: Send + Sync {
     #[cfg(target_arch = "x86_64")]
     fn pio_write(&self, port: u64, data: &[u8]) -> Result<()>;
 }
+
+/// Handler of the guest accesses to a range of IO ports, see
+/// `Vm::register_pio_handler()`.
+///
+/// `base` is the first port of the registered range and `offset` the
+/// position of the accessed port in it. `data` holds the 1, 2 or 4 bytes
+/// of the access, in little endian order, and the registered range only
+/// has to contain its first port.
+#[cfg(target_arch = "x86_64")]
+pub trait PioHandler: Send + Sync {
+    fn read(&self, base: u16, offset: u16, data: &mut [u8]);
+    fn write(&self, base: u16, offset: u16, data: &[u8]);
+}
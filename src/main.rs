@@ -213,7 +213,16 @@ fn get_cli_options_sorted(
                     topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
                     kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>,\
                     affinity=<list_of_vcpus_with_their_associated_cpuset>,\
-                    features=<list_of_features_to_enable>",
+                    features=<list_of_features_to_enable>,apic_mode=xapic|x2apic|auto,\
+                    boot_mode=real|protected|long,invariant_tsc=on|off,\
+                    vendor=<cpu_vendor_string>,core_crystal_hz=<core_crystal_clock_frequency>,\
+                    disabled_pv_msrs=<list_of_paravirtual_msrs_to_disable>,arat=on|off,\
+                    async_pf=on|off,arch_capabilities=<ia32_arch_capabilities_value>,\
+                    disabled_pmu_events=<list_of_pmu_events_to_disable>,\
+                    linear_bits=<number_of_linear_address_bits>,tsc_khz=<tsc_frequency_in_khz>,\
+                    rapl=emulated|passthrough,apic_bus_cycle_ns=<apic_bus_cycle_period_in_ns>,\
+                    trap_ud=on|off,leaf_7_overrides=<list_of_leaf_7_feature_overrides>,\
+                    mwait=<min_monitor_line_size>:<max_monitor_line_size>[:<mwait_sub_cstates>]",
             )
             .default_value(default_vcpus)
             .group("vm-config"),
@@ -338,7 +347,7 @@ fn get_cli_options_sorted(
                      hotplug_method=acpi|virtio-mem,\
                      hotplug_size=<hotpluggable_memory_size>,\
                      hotplugged_size=<hotplugged_memory_size>,\
-                     prefault=on|off,thp=on|off\"",
+                     prefault=on|off,thp=on|off,thp_collapse=on|off\"",
             )
             .default_value(default_memory)
             .group("vm-config"),
@@ -374,7 +383,7 @@ fn get_cli_options_sorted(
         Arg::new("platform")
             .long("platform")
             .help(
-                "num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,iommu_address_width=<bits>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>"
+                "num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,iommu_address_width=<bits>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>,label=<vm_label>,ioapic_pins=<ioapic_pins>,ioapics=<list_of_ioapics_with_their_gsi_base_and_pins>,rtc_time=<unix_seconds>"
             )
             .num_args(1)
             .group("vm-config"),
@@ -910,8 +919,8 @@ mod unit_tests {
     #[cfg(target_arch = "x86_64")]
     use vmm::vm_config::DebugConsoleConfig;
     use vmm::vm_config::{
-        ConsoleConfig, ConsoleOutputMode, CpuFeatures, CpusConfig, HotplugMethod, MemoryConfig,
-        PayloadConfig, RngConfig, VmConfig,
+        ApicMode, ConsoleConfig, ConsoleOutputMode, CpuFeatures, CpusConfig, HotplugMethod,
+        MemoryConfig, PayloadConfig, RngConfig, VmConfig,
     };
 
     use crate::test_util::assert_args_sorted;
@@ -961,6 +970,39 @@ mod unit_tests {
                 max_phys_bits: 46,
                 affinity: None,
                 features: CpuFeatures::default(),
+                apic_mode: ApicMode::Auto,
+                #[cfg(target_arch = "x86_64")]
+                boot_mode: Default::default(),
+                #[cfg(target_arch = "x86_64")]
+                invariant_tsc: None,
+                #[cfg(target_arch = "x86_64")]
+                cpu_vendor_override: None,
+                #[cfg(target_arch = "x86_64")]
+                core_crystal_hz: None,
+                #[cfg(target_arch = "x86_64")]
+                disabled_pv_msrs: Vec::new(),
+                #[cfg(target_arch = "x86_64")]
+                arat: true,
+                #[cfg(target_arch = "x86_64")]
+                async_pf: false,
+                #[cfg(target_arch = "x86_64")]
+                arch_capabilities: None,
+                #[cfg(target_arch = "x86_64")]
+                disabled_pmu_events: Vec::new(),
+                #[cfg(target_arch = "x86_64")]
+                linear_bits: None,
+                #[cfg(target_arch = "x86_64")]
+                tsc_khz: None,
+                #[cfg(target_arch = "x86_64")]
+                rapl: None,
+                #[cfg(target_arch = "x86_64")]
+                apic_bus_cycle_ns: None,
+                #[cfg(target_arch = "x86_64")]
+                trap_ud: false,
+                #[cfg(target_arch = "x86_64")]
+                leaf_7_overrides: Vec::new(),
+                #[cfg(target_arch = "x86_64")]
+                mwait: None,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -974,6 +1016,7 @@ mod unit_tests {
                 prefault: false,
                 zones: None,
                 thp: true,
+                thp_collapse: true,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
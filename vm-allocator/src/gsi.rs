@@ -81,18 +81,16 @@ impl GsiAllocator {
     #[cfg(target_arch = "x86_64")]
     /// Allocate an IRQ
     pub fn allocate_irq(&mut self) -> Result<u32> {
-        let mut irq: u32 = 0;
-        for (base, irqs) in self.apics.iter() {
-            // HACKHACK - This only works with 1 single IOAPIC...
-            if self.next_irq >= *base && self.next_irq < *base + *irqs {
-                irq = self.next_irq;
-                self.next_irq += 1;
-            }
-        }
+        // Move on to the next IOAPIC once all the pins of the current one
+        // are allocated.
+        let (base, _) = self
+            .apics
+            .iter()
+            .find(|(base, irqs)| self.next_irq < *base + *irqs)
+            .ok_or(Error::Overflow)?;
 
-        if irq == 0 {
-            return Err(Error::Overflow);
-        }
+        let irq = self.next_irq.max(*base);
+        self.next_irq = irq + 1;
 
         Ok(irq)
     }
@@ -112,3 +110,24 @@ impl Default for GsiAllocator {
         GsiAllocator::new()
     }
 }
+
+#[cfg(test)]
+#[cfg(target_arch = "x86_64")]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_irq_multiple_apics() {
+        // Two IOAPICs, with a gap between their GSI ranges
+        let mut allocator = GsiAllocator::new(vec![GsiApic::new(5, 19), GsiApic::new(32, 2)]);
+
+        let irqs: Vec<u32> = (0..21).map(|_| allocator.allocate_irq().unwrap()).collect();
+        assert_eq!(irqs[0], 5);
+        assert_eq!(irqs[18], 23);
+        assert_eq!(irqs[19..], [32, 33]);
+        allocator.allocate_irq().unwrap_err();
+
+        // MSIs use the GSIs above the ones of the IOAPICs
+        assert_eq!(allocator.allocate_gsi().unwrap(), 34);
+    }
+}
@@ -320,12 +320,12 @@ fn create_srat_table(
             ))
         }
 
-        for cpu in &node.cpus {
-            #[cfg(target_arch = "x86_64")]
-            let x2apic_id = arch::x86_64::get_x2apic_id(*cpu, topology);
-            #[cfg(target_arch = "aarch64")]
-            let x2apic_id = *cpu;
+        #[cfg(target_arch = "x86_64")]
+        let x2apic_ids = arch::x86_64::numa_node_x2apic_ids(&node.cpus, topology);
+        #[cfg(target_arch = "aarch64")]
+        let x2apic_ids = node.cpus.clone();
 
+        for x2apic_id in x2apic_ids {
             // Flags
             // - Enabled = 1 (bit 0)
             // - Reserved bits 1-31
@@ -665,7 +665,11 @@ fn create_acpi_tables_internal(
         .unwrap()
         .get_vgic()
         .unwrap();
+    #[cfg(target_arch = "x86_64")]
+    let ioapics = device_manager.lock().unwrap().ioapics();
     let madt = cpu_manager.lock().unwrap().create_madt(
+        #[cfg(target_arch = "x86_64")]
+        &ioapics,
         #[cfg(target_arch = "aarch64")]
         vgic,
     );
@@ -935,7 +939,8 @@ pub fn create_acpi_tables_tdx(
     tables.push(create_facp_table(GuestAddress(0), device_manager));
 
     // MADT
-    tables.push(cpu_manager.lock().unwrap().create_madt());
+    let ioapics = device_manager.lock().unwrap().ioapics();
+    tables.push(cpu_manager.lock().unwrap().create_madt(&ioapics));
 
     // MCFG
     tables.push(create_mcfg_table(
@@ -10,6 +10,8 @@ use std::path::PathBuf;
 use std::result;
 use std::str::FromStr;
 
+#[cfg(target_arch = "x86_64")]
+use arch::x86_64::{MAX_APIC_BUS_CYCLE_NS, MAX_SUPPORTED_CPUS_LEGACY};
 use clap::ArgMatches;
 use option_parser::{
     ByteSized, IntegerList, OptionParser, OptionParserError, StringList, Toggle, Tuple,
@@ -26,6 +28,13 @@ use crate::vm_config::*;
 
 const MAX_NUM_PCI_SEGMENTS: u16 = 96;
 const MAX_IOMMU_ADDRESS_WIDTH_BITS: u8 = 64;
+#[cfg(target_arch = "x86_64")]
+const MAX_IOAPIC_PINS: u32 = devices::ioapic::MAX_IOAPIC_PINS as u32;
+#[cfg(target_arch = "x86_64")]
+const MAX_IOAPICS: usize = arch::layout::MAX_IOAPICS;
+// The IOAPIC ID register holds 4 bits
+#[cfg(target_arch = "x86_64")]
+const MAX_IOAPIC_ID: u8 = 0xf;
 
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 const MAX_SUPPORTED_CPUS: u32 = 8192;
@@ -62,6 +71,25 @@ pub enum Error {
     /// Invalid CPU features
     #[error("Invalid feature in --cpus features list: {0}")]
     InvalidCpuFeatures(String),
+    /// Invalid CPU vendor
+    #[error("Invalid --cpus vendor, 12 ASCII characters are expected: {0}")]
+    InvalidCpuVendor(String),
+    /// Invalid paravirtual MSR
+    #[cfg(target_arch = "x86_64")]
+    #[error("Invalid --cpus disabled_pv_msrs")]
+    InvalidPvMsr(#[source] arch::x86_64::ParsePvMsrError),
+    /// Invalid IA32_ARCH_CAPABILITIES value
+    #[cfg(target_arch = "x86_64")]
+    #[error("Invalid --cpus arch_capabilities, a hex value with a `0x` prefix is expected: {0}")]
+    InvalidArchCapabilities(String),
+    /// Invalid PMU event
+    #[cfg(target_arch = "x86_64")]
+    #[error("Invalid --cpus disabled_pmu_events")]
+    InvalidPmuEvent(#[source] arch::x86_64::ParsePmuEventError),
+    /// Invalid leaf 7 override
+    #[cfg(target_arch = "x86_64")]
+    #[error("Invalid --cpus leaf_7_overrides")]
+    InvalidLeaf7Override(#[source] arch::x86_64::ParseLeaf7OverrideError),
     /// Error parsing memory options
     #[error("Error parsing --memory")]
     ParseMemory(#[source] OptionParserError),
@@ -190,6 +218,30 @@ pub enum ValidationError {
     /// Too many CPUs.
     #[error("Too many CPUs: specified {0} but {MAX_SUPPORTED_CPUS} is the limit")]
     TooManyCpus(u32 /* specified CPUs */),
+    /// Null TSC frequency
+    #[cfg(target_arch = "x86_64")]
+    #[error("The TSC frequency can't be 0")]
+    InvalidTscFrequency,
+    /// APIC bus cycle period out of range
+    #[cfg(target_arch = "x86_64")]
+    #[error("The APIC bus cycle period must be between 1 and {MAX_APIC_BUS_CYCLE_NS} ns: {0}")]
+    InvalidApicBusCycle(u32),
+    /// Invalid MONITOR/MWAIT parameters
+    #[cfg(target_arch = "x86_64")]
+    #[error("Invalid MONITOR/MWAIT parameters: {0:?}")]
+    InvalidMwaitConfig(arch::MwaitConfig),
+    /// Booting in real mode without a firmware
+    #[cfg(target_arch = "x86_64")]
+    #[error("Booting in real mode requires a firmware")]
+    RealModeWithoutFirmware,
+    /// Asynchronous page faults enabled while their MSRs are disabled
+    #[cfg(target_arch = "x86_64")]
+    #[error("Asynchronous page faults can't be enabled when disabled_pv_msrs contains async_pf")]
+    AsyncPfWithDisabledMsrs,
+    /// Asynchronous page faults enabled along with the Hyper-V enlightenments
+    #[cfg(target_arch = "x86_64")]
+    #[error("Asynchronous page faults can't be enabled along with kvm_hyperv")]
+    AsyncPfWithKvmHyperv,
     /// Missing file value for debug-console
     #[cfg(target_arch = "x86_64")]
     #[error("Path missing when using file mode for debug console")]
@@ -215,6 +267,10 @@ pub enum ValidationError {
     /// One part of the CPU topology was zero
     #[error("No part of the CPU topology can be zero")]
     CpuTopologyZeroPart,
+    /// Some APIC IDs don't fit in xAPIC
+    #[cfg(target_arch = "x86_64")]
+    #[error("xAPIC mode requires APIC IDs up to {MAX_SUPPORTED_CPUS_LEGACY}, got {0}")]
+    XApicTooManyCpus(u32 /* max APIC ID */),
     #[cfg(target_arch = "aarch64")]
     /// Dies per package must be 1
     #[error("Dies per package must be 1")]
@@ -237,6 +293,10 @@ pub enum ValidationError {
     /// Huge page size is not power of 2
     #[error("Huge page size is not power of 2: {0}")]
     InvalidHugePageSize(u64),
+    /// MTE requires anonymous memory
+    #[cfg(target_arch = "aarch64")]
+    #[error("MTE requires anonymous memory without huge pages nor hotplug")]
+    MteIncompatibleMemory,
     /// CPU Hotplug is not permitted with TDX
     #[cfg(feature = "tdx")]
     #[error("CPU hotplug is not permitted with TDX")]
@@ -260,6 +320,10 @@ pub enum ValidationError {
     /// Memory zone is reused across NUMA nodes
     #[error("Memory zone: {0} belongs to multiple NUMA nodes: {1} and {2}")]
     MemoryZoneReused(String, u32, u32),
+    /// Threads of a core assigned to different NUMA nodes
+    #[cfg(target_arch = "x86_64")]
+    #[error("CPUs {0} and {1} are threads of the same core but belong to different NUMA nodes")]
+    NumaNodeSplitsCore(u32, u32),
     /// Invalid number of PCI segments
     #[error("Number of PCI segments ({0}) not in range of 1 to {MAX_NUM_PCI_SEGMENTS}")]
     InvalidNumPciSegments(u16),
@@ -274,6 +338,43 @@ pub enum ValidationError {
         "IOMMU address width in bits ({0}) should be less than or equal to {MAX_IOMMU_ADDRESS_WIDTH_BITS}"
     )]
     InvalidIommuAddressWidthBits(u8),
+    /// Label which can't be used to name the vCPU threads
+    #[error("Invalid VM label {0:?}: it must not contain null bytes")]
+    InvalidLabel(String),
+    /// Invalid number of IOAPIC pins
+    #[cfg(target_arch = "x86_64")]
+    #[error(
+        "Number of IOAPIC pins ({0}) not in range of {DEFAULT_IOAPIC_PINS} to {MAX_IOAPIC_PINS}"
+    )]
+    InvalidIoapicPins(u32),
+    /// Invalid number of IOAPICs
+    #[cfg(target_arch = "x86_64")]
+    #[error("Number of IOAPICs ({0}) not in range of 1 to {MAX_IOAPICS}")]
+    InvalidIoapicCount(usize),
+    /// IOAPIC ID not fitting the 4 bits of the ID register
+    #[cfg(target_arch = "x86_64")]
+    #[error("IOAPIC ID {0} greater than {MAX_IOAPIC_ID}")]
+    InvalidIoapicId(u8),
+    /// IOAPIC ID used twice
+    #[cfg(target_arch = "x86_64")]
+    #[error("IOAPIC ID {0} used more than once")]
+    DuplicateIoapicId(u8),
+    /// Invalid GSI range of one of the IOAPICs
+    #[cfg(target_arch = "x86_64")]
+    #[error("Invalid GSI range of IOAPIC {0}: {1} pins, 1 to {MAX_IOAPIC_PINS} are expected")]
+    InvalidIoapicGsiRange(u8, u32),
+    /// IOAPICs handling the same GSIs
+    #[cfg(target_arch = "x86_64")]
+    #[error("GSI ranges of IOAPICs {0} and {1} overlap")]
+    OverlappingIoapics(u8, u8),
+    /// No IOAPIC for the legacy IRQs
+    #[cfg(target_arch = "x86_64")]
+    #[error("An IOAPIC with GSI base 0 and at least {DEFAULT_IOAPIC_PINS} pins is required")]
+    MissingLegacyIoapic,
+    /// Both the number of pins and the IOAPICs are given
+    #[cfg(target_arch = "x86_64")]
+    #[error("ioapic_pins can't be used along with ioapics")]
+    IoapicPinsWithIoapics,
     /// Balloon too big
     #[error("Ballon size ({0}) greater than RAM ({1})")]
     BalloonLargerThanRam(u64, u64),
@@ -554,6 +655,24 @@ impl FromStr for HotplugMethod {
     }
 }
 
+#[derive(Debug)]
+pub enum ParseApicModeError {
+    InvalidValue(String),
+}
+
+impl FromStr for ApicMode {
+    type Err = ParseApicModeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "xapic" => Ok(ApicMode::XApic),
+            "x2apic" => Ok(ApicMode::X2Apic),
+            "auto" => Ok(ApicMode::Auto),
+            _ => Err(ParseApicModeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 pub enum CpuTopologyParseError {
     InvalidValue(String),
 }
@@ -597,7 +716,24 @@ impl CpusConfig {
             .add("kvm_hyperv")
             .add("max_phys_bits")
             .add("affinity")
-            .add("features");
+            .add("features")
+            .add("apic_mode")
+            .add("boot_mode")
+            .add("invariant_tsc")
+            .add("vendor")
+            .add("core_crystal_hz")
+            .add("disabled_pv_msrs")
+            .add("arat")
+            .add("async_pf")
+            .add("arch_capabilities")
+            .add("disabled_pmu_events")
+            .add("linear_bits")
+            .add("tsc_khz")
+            .add("rapl")
+            .add("apic_bus_cycle_ns")
+            .add("trap_ud")
+            .add("leaf_7_overrides")
+            .add("mwait");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
         let boot_vcpus: u32 = parser
@@ -648,9 +784,125 @@ impl CpusConfig {
                     features.amx = true;
                     Ok(())
                 }
+                #[cfg(target_arch = "x86_64")]
+                "cet" => {
+                    features.cet = true;
+                    Ok(())
+                }
+                #[cfg(target_arch = "x86_64")]
+                "processor_trace" => {
+                    features.processor_trace = true;
+                    Ok(())
+                }
+                #[cfg(target_arch = "aarch64")]
+                "mte" => {
+                    features.mte = true;
+                    Ok(())
+                }
+                #[cfg(target_arch = "aarch64")]
+                "system_suspend" => {
+                    features.system_suspend = true;
+                    Ok(())
+                }
                 _ => Err(Error::InvalidCpuFeatures(s)),
             }?;
         }
+        let apic_mode = parser
+            .convert("apic_mode")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or_default();
+        #[cfg(target_arch = "x86_64")]
+        let boot_mode = parser
+            .convert("boot_mode")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or_default();
+        #[cfg(target_arch = "x86_64")]
+        let invariant_tsc = parser
+            .convert::<Toggle>("invariant_tsc")
+            .map_err(Error::ParseCpus)?
+            .map(|t| t.0);
+        #[cfg(target_arch = "x86_64")]
+        let cpu_vendor_override = parser
+            .get("vendor")
+            .map(|v| match <[u8; 12]>::try_from(v.as_bytes()) {
+                Ok(vendor) if v.is_ascii() => Ok(vendor),
+                _ => Err(Error::InvalidCpuVendor(v)),
+            })
+            .transpose()?;
+        #[cfg(target_arch = "x86_64")]
+        let core_crystal_hz = parser
+            .convert::<u32>("core_crystal_hz")
+            .map_err(Error::ParseCpus)?;
+        #[cfg(target_arch = "x86_64")]
+        let disabled_pv_msrs = parser
+            .convert::<StringList>("disabled_pv_msrs")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or_default()
+            .0
+            .iter()
+            .map(|s| s.parse().map_err(Error::InvalidPvMsr))
+            .collect::<Result<Vec<arch::PvMsr>>>()?;
+        #[cfg(target_arch = "x86_64")]
+        let arat = parser
+            .convert::<Toggle>("arat")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(true))
+            .0;
+        #[cfg(target_arch = "x86_64")]
+        let async_pf = parser
+            .convert::<Toggle>("async_pf")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        #[cfg(target_arch = "x86_64")]
+        let arch_capabilities = parser
+            .get("arch_capabilities")
+            .map(|v| {
+                v.strip_prefix("0x")
+                    .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| Error::InvalidArchCapabilities(v.clone()))
+            })
+            .transpose()?;
+        #[cfg(target_arch = "x86_64")]
+        let disabled_pmu_events = parser
+            .convert::<StringList>("disabled_pmu_events")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or_default()
+            .0
+            .iter()
+            .map(|s| s.parse().map_err(Error::InvalidPmuEvent))
+            .collect::<Result<Vec<arch::PmuEvent>>>()?;
+        #[cfg(target_arch = "x86_64")]
+        let linear_bits = parser
+            .convert::<u8>("linear_bits")
+            .map_err(Error::ParseCpus)?;
+        #[cfg(target_arch = "x86_64")]
+        let tsc_khz = parser.convert::<u32>("tsc_khz").map_err(Error::ParseCpus)?;
+        #[cfg(target_arch = "x86_64")]
+        let rapl = parser.convert("rapl").map_err(Error::ParseCpus)?;
+        #[cfg(target_arch = "x86_64")]
+        let apic_bus_cycle_ns = parser
+            .convert::<u32>("apic_bus_cycle_ns")
+            .map_err(Error::ParseCpus)?;
+        #[cfg(target_arch = "x86_64")]
+        let trap_ud = parser
+            .convert::<Toggle>("trap_ud")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        #[cfg(target_arch = "x86_64")]
+        let leaf_7_overrides = parser
+            .convert::<StringList>("leaf_7_overrides")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or_default()
+            .0
+            .iter()
+            .map(|s| s.parse().map_err(Error::InvalidLeaf7Override))
+            .collect::<Result<Vec<arch::Leaf7Override>>>()?;
+        #[cfg(target_arch = "x86_64")]
+        let mwait = parser
+            .convert::<arch::MwaitConfig>("mwait")
+            .map_err(Error::ParseCpus)?;
 
         Ok(CpusConfig {
             boot_vcpus,
@@ -660,6 +912,39 @@ impl CpusConfig {
             max_phys_bits,
             affinity,
             features,
+            apic_mode,
+            #[cfg(target_arch = "x86_64")]
+            boot_mode,
+            #[cfg(target_arch = "x86_64")]
+            invariant_tsc,
+            #[cfg(target_arch = "x86_64")]
+            cpu_vendor_override,
+            #[cfg(target_arch = "x86_64")]
+            core_crystal_hz,
+            #[cfg(target_arch = "x86_64")]
+            disabled_pv_msrs,
+            #[cfg(target_arch = "x86_64")]
+            arat,
+            #[cfg(target_arch = "x86_64")]
+            async_pf,
+            #[cfg(target_arch = "x86_64")]
+            arch_capabilities,
+            #[cfg(target_arch = "x86_64")]
+            disabled_pmu_events,
+            #[cfg(target_arch = "x86_64")]
+            linear_bits,
+            #[cfg(target_arch = "x86_64")]
+            tsc_khz,
+            #[cfg(target_arch = "x86_64")]
+            rapl,
+            #[cfg(target_arch = "x86_64")]
+            apic_bus_cycle_ns,
+            #[cfg(target_arch = "x86_64")]
+            trap_ud,
+            #[cfg(target_arch = "x86_64")]
+            leaf_7_overrides,
+            #[cfg(target_arch = "x86_64")]
+            mwait,
         })
     }
 }
@@ -731,7 +1016,10 @@ impl PlatformConfig {
             .add("iommu_address_width")
             .add("serial_number")
             .add("uuid")
-            .add("oem_strings");
+            .add("oem_strings")
+            .add("label");
+        #[cfg(target_arch = "x86_64")]
+        parser.add("ioapic_pins").add("ioapics").add("rtc_time");
         #[cfg(feature = "tdx")]
         parser.add("tdx");
         #[cfg(feature = "sev_snp")]
@@ -758,6 +1046,33 @@ impl PlatformConfig {
             .convert::<StringList>("oem_strings")
             .map_err(Error::ParsePlatform)?
             .map(|v| v.0);
+        let label = parser.convert("label").map_err(Error::ParsePlatform)?;
+        #[cfg(target_arch = "x86_64")]
+        let ioapic_pins: u32 = parser
+            .convert("ioapic_pins")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(DEFAULT_IOAPIC_PINS);
+        #[cfg(target_arch = "x86_64")]
+        let ioapics = parser
+            .convert::<Tuple<u8, Vec<u64>>>("ioapics")
+            .map_err(Error::ParsePlatform)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|(id, range)| match range[..] {
+                        [gsi_base, pins] => Ok(IoApicConfig {
+                            id: *id,
+                            gsi_base: gsi_base as u32,
+                            pins: pins as u32,
+                        }),
+                        _ => Err(Error::ParsePlatform(OptionParserError::InvalidValue(
+                            format!("{id}@{range:?}"),
+                        ))),
+                    })
+                    .collect::<Result<Vec<IoApicConfig>>>()
+            })
+            .transpose()?;
+        #[cfg(target_arch = "x86_64")]
+        let rtc_time = parser.convert("rtc_time").map_err(Error::ParsePlatform)?;
         #[cfg(feature = "tdx")]
         let tdx = parser
             .convert::<Toggle>("tdx")
@@ -777,6 +1092,13 @@ impl PlatformConfig {
             serial_number,
             uuid,
             oem_strings,
+            label,
+            #[cfg(target_arch = "x86_64")]
+            ioapic_pins,
+            #[cfg(target_arch = "x86_64")]
+            ioapics,
+            #[cfg(target_arch = "x86_64")]
+            rtc_time,
             #[cfg(feature = "tdx")]
             tdx,
             #[cfg(feature = "sev_snp")]
@@ -805,6 +1127,70 @@ impl PlatformConfig {
             ));
         }
 
+        // The label is part of the vCPU thread names
+        if let Some(label) = &self.label
+            && label.contains('\0')
+        {
+            return Err(ValidationError::InvalidLabel(label.clone()));
+        }
+
+        // The legacy IRQs assume at least the default number of pins
+        #[cfg(target_arch = "x86_64")]
+        if !(DEFAULT_IOAPIC_PINS..=MAX_IOAPIC_PINS).contains(&self.ioapic_pins) {
+            return Err(ValidationError::InvalidIoapicPins(self.ioapic_pins));
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(ioapics) = &self.ioapics {
+            // The pins are given for each of the IOAPICs instead
+            if self.ioapic_pins != DEFAULT_IOAPIC_PINS {
+                return Err(ValidationError::IoapicPinsWithIoapics);
+            }
+            Self::validate_ioapics(ioapics)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn validate_ioapics(ioapics: &[IoApicConfig]) -> ValidationResult<()> {
+        if ioapics.is_empty() || ioapics.len() > MAX_IOAPICS {
+            return Err(ValidationError::InvalidIoapicCount(ioapics.len()));
+        }
+
+        for (i, ioapic) in ioapics.iter().enumerate() {
+            if ioapic.id > MAX_IOAPIC_ID {
+                return Err(ValidationError::InvalidIoapicId(ioapic.id));
+            }
+            if !(1..=MAX_IOAPIC_PINS).contains(&ioapic.pins)
+                || ioapic.gsi_base.checked_add(ioapic.pins).is_none()
+            {
+                return Err(ValidationError::InvalidIoapicGsiRange(
+                    ioapic.id,
+                    ioapic.pins,
+                ));
+            }
+
+            for other in &ioapics[..i] {
+                if other.id == ioapic.id {
+                    return Err(ValidationError::DuplicateIoapicId(ioapic.id));
+                }
+                if ioapic.gsi_base < other.gsi_base + other.pins
+                    && other.gsi_base < ioapic.gsi_base + ioapic.pins
+                {
+                    return Err(ValidationError::OverlappingIoapics(other.id, ioapic.id));
+                }
+            }
+        }
+
+        // The legacy IRQs are allocated from the first GSIs
+        if !ioapics
+            .iter()
+            .any(|ioapic| ioapic.gsi_base == 0 && ioapic.pins >= DEFAULT_IOAPIC_PINS)
+        {
+            return Err(ValidationError::MissingLegacyIoapic);
+        }
+
         Ok(())
     }
 }
@@ -823,7 +1209,8 @@ impl MemoryConfig {
             .add("hugepages")
             .add("hugepage_size")
             .add("prefault")
-            .add("thp");
+            .add("thp")
+            .add("thp_collapse");
         parser.parse(memory).map_err(Error::ParseMemory)?;
 
         let size = parser
@@ -872,6 +1259,11 @@ impl MemoryConfig {
             .map_err(Error::ParseMemory)?
             .unwrap_or(Toggle(true))
             .0;
+        let thp_collapse = parser
+            .convert::<Toggle>("thp_collapse")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(true))
+            .0;
 
         let zones: Option<Vec<MemoryZoneConfig>> = if let Some(memory_zones) = &memory_zones {
             let mut zones = Vec::new();
@@ -959,6 +1351,7 @@ impl MemoryConfig {
             prefault,
             zones,
             thp,
+            thp_collapse,
         })
     }
 
@@ -2547,6 +2940,46 @@ impl VmConfig {
             return Err(ValidationError::TooManyCpus(self.cpus.max_vcpus));
         }
 
+        #[cfg(target_arch = "x86_64")]
+        if self.cpus.tsc_khz == Some(0) {
+            return Err(ValidationError::InvalidTscFrequency);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(ns) = self.cpus.apic_bus_cycle_ns
+            && !(1..=MAX_APIC_BUS_CYCLE_NS).contains(&ns)
+        {
+            return Err(ValidationError::InvalidApicBusCycle(ns));
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(mwait) = self.cpus.mwait
+            && !mwait.is_valid()
+        {
+            return Err(ValidationError::InvalidMwaitConfig(mwait));
+        }
+
+        // A kernel isn't started in real mode, its entry point would be
+        // ignored. At this point we know payload isn't None.
+        #[cfg(target_arch = "x86_64")]
+        if self.cpus.boot_mode == arch::BootMode::RealMode
+            && self.payload.as_ref().unwrap().firmware.is_none()
+        {
+            return Err(ValidationError::RealModeWithoutFirmware);
+        }
+
+        // Hiding the MSRs also hides the feature, and the KVM features leaf
+        // async_pf relies on is moved away when the Hyper-V leaves are exposed
+        #[cfg(target_arch = "x86_64")]
+        if self.cpus.async_pf {
+            if self.cpus.disabled_pv_msrs.contains(&arch::PvMsr::AsyncPf) {
+                return Err(ValidationError::AsyncPfWithDisabledMsrs);
+            }
+            if self.cpus.kvm_hyperv {
+                return Err(ValidationError::AsyncPfWithKvmHyperv);
+            }
+        }
+
         if let Some(rate_limit_groups) = &self.rate_limit_groups {
             for rate_limit_group in rate_limit_groups {
                 rate_limit_group.validate(self)?;
@@ -2647,6 +3080,25 @@ impl VmConfig {
             }
         }
 
+        #[cfg(target_arch = "x86_64")]
+        if self.cpus.apic_mode == ApicMode::XApic {
+            let max_apic_id =
+                self.cpus
+                    .topology
+                    .as_ref()
+                    .map_or(self.cpus.max_vcpus.saturating_sub(1), |t| {
+                        arch::x86_64::get_max_x2apic_id((
+                            t.threads_per_core,
+                            t.cores_per_die,
+                            t.dies_per_package,
+                            t.packages,
+                        ))
+                    });
+            if max_apic_id > MAX_SUPPORTED_CPUS_LEGACY {
+                return Err(ValidationError::XApicTooManyCpus(max_apic_id));
+            }
+        }
+
         if let Some(hugepage_size) = &self.memory.hugepage_size {
             if !self.memory.hugepages {
                 return Err(ValidationError::HugePageSizeWithoutHugePages);
@@ -2656,6 +3108,19 @@ impl VmConfig {
             }
         }
 
+        #[cfg(target_arch = "aarch64")]
+        if self.cpus.features.mte {
+            let zones = self.memory.zones.as_deref().unwrap_or_default();
+            if self.memory.hugepages
+                || self.memory.hotplug_size.is_some()
+                || zones
+                    .iter()
+                    .any(|z| z.file.is_some() || z.hugepages || z.hotplug_size.is_some())
+            {
+                return Err(ValidationError::MteIncompatibleMemory);
+            }
+        }
+
         if let Some(user_devices) = &self.user_devices {
             if !user_devices.is_empty() && !self.backed_by_shared_memory() {
                 return Err(ValidationError::UserDevicesRequireSharedMemory);
@@ -2768,6 +3233,28 @@ impl VmConfig {
                     }
                 }
             }
+
+            #[cfg(target_arch = "x86_64")]
+            if let Some(t) = &self.cpus.topology {
+                let cpu_nodes = numa
+                    .iter()
+                    .flat_map(|node| {
+                        node.cpus
+                            .iter()
+                            .flatten()
+                            .map(|cpu| (*cpu, node.guest_numa_id))
+                    })
+                    .collect();
+                let topology = (
+                    t.threads_per_core,
+                    t.cores_per_die,
+                    t.dies_per_package,
+                    t.packages,
+                );
+                if let Some((cpu, sibling)) = arch::x86_64::numa_split_core(topology, &cpu_nodes) {
+                    return Err(ValidationError::NumaNodeSplitsCore(cpu, sibling));
+                }
+            }
         }
 
         if let Some(zones) = &self.memory.zones {
@@ -3242,6 +3729,128 @@ mod tests {
                 ..Default::default()
             },
         );
+        assert_eq!(
+            CpusConfig::parse("boot=2,apic_mode=x2apic")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                apic_mode: ApicMode::X2Apic,
+                ..Default::default()
+            },
+        );
+        CpusConfig::parse("boot=2,apic_mode=foo").unwrap_err();
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(
+                CpusConfig::parse("boot=1,boot_mode=real")?,
+                CpusConfig {
+                    boot_mode: arch::BootMode::RealMode,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(
+                CpusConfig::parse("boot=1,boot_mode=long")?,
+                CpusConfig {
+                    boot_mode: arch::BootMode::LongMode,
+                    ..Default::default()
+                },
+            );
+            CpusConfig::parse("boot=1,boot_mode=foo").unwrap_err();
+            assert_eq!(
+                CpusConfig::parse("boot=1,invariant_tsc=on")?,
+                CpusConfig {
+                    invariant_tsc: Some(true),
+                    ..Default::default()
+                },
+            );
+            assert_eq!(CpusConfig::parse("boot=1")?.invariant_tsc, None);
+            assert_eq!(
+                CpusConfig::parse("boot=1,vendor=AuthenticAMD")?,
+                CpusConfig {
+                    cpu_vendor_override: Some(*b"AuthenticAMD"),
+                    ..Default::default()
+                },
+            );
+            CpusConfig::parse("boot=1,vendor=Intel").unwrap_err();
+            assert!(CpusConfig::parse("boot=1,features=cet")?.features.cet);
+            assert!(
+                CpusConfig::parse("boot=1,features=processor_trace")?
+                    .features
+                    .processor_trace
+            );
+            assert_eq!(
+                CpusConfig::parse("boot=1,core_crystal_hz=24000000")?.core_crystal_hz,
+                Some(24_000_000)
+            );
+            assert_eq!(
+                CpusConfig::parse("boot=1,disabled_pv_msrs=[async_pf,steal_time]")?
+                    .disabled_pv_msrs,
+                vec![arch::PvMsr::AsyncPf, arch::PvMsr::StealTime]
+            );
+            CpusConfig::parse("boot=1,disabled_pv_msrs=[hyperv]").unwrap_err();
+            assert!(CpusConfig::parse("boot=1")?.arat);
+            assert!(!CpusConfig::parse("boot=1,arat=off")?.arat);
+            assert!(!CpusConfig::parse("boot=1")?.async_pf);
+            assert!(CpusConfig::parse("boot=1,async_pf=on")?.async_pf);
+            assert_eq!(
+                CpusConfig::parse("boot=1,arch_capabilities=0x2000023")?.arch_capabilities,
+                Some(0x200_0023)
+            );
+            CpusConfig::parse("boot=1,arch_capabilities=23").unwrap_err();
+            assert_eq!(
+                CpusConfig::parse("boot=1,disabled_pmu_events=[llc_misses,topdown_slots]")?
+                    .disabled_pmu_events,
+                vec![arch::PmuEvent::LlcMisses, arch::PmuEvent::TopdownSlots]
+            );
+            CpusConfig::parse("boot=1,disabled_pmu_events=[cache_misses]").unwrap_err();
+            assert_eq!(
+                CpusConfig::parse("boot=1,linear_bits=48")?.linear_bits,
+                Some(48)
+            );
+            assert_eq!(
+                CpusConfig::parse("boot=1,tsc_khz=2400000")?.tsc_khz,
+                Some(2_400_000)
+            );
+            assert_eq!(
+                CpusConfig::parse("boot=1,rapl=passthrough")?.rapl,
+                Some(hypervisor::RaplMode::Passthrough)
+            );
+            CpusConfig::parse("boot=1,rapl=on").unwrap_err();
+            assert_eq!(
+                CpusConfig::parse("boot=1,apic_bus_cycle_ns=10")?.apic_bus_cycle_ns,
+                Some(10)
+            );
+            assert!(!CpusConfig::parse("boot=1")?.trap_ud);
+            assert!(CpusConfig::parse("boot=1,trap_ud=on")?.trap_ud);
+            assert_eq!(
+                CpusConfig::parse("boot=1,leaf_7_overrides=[1.eax+0x10,2.edx-0x1]")?
+                    .leaf_7_overrides,
+                vec![
+                    arch::Leaf7Override {
+                        subleaf: 1,
+                        register: arch::x86_64::CpuidReg::EAX,
+                        bits: 0x10,
+                        expose: true,
+                    },
+                    arch::Leaf7Override {
+                        subleaf: 2,
+                        register: arch::x86_64::CpuidReg::EDX,
+                        bits: 0x1,
+                        expose: false,
+                    },
+                ]
+            );
+            CpusConfig::parse("boot=1,leaf_7_overrides=[0.eax+0x1]").unwrap_err();
+            assert_eq!(
+                CpusConfig::parse("boot=1,mwait=64:64:0/2/1")?.mwait,
+                Some(arch::MwaitConfig {
+                    min_monitor_line_size: 64,
+                    max_monitor_line_size: 64,
+                    sub_cstates: arch::MwaitSubstates([0, 2, 1, 0, 0, 0, 0, 0]),
+                })
+            );
+            CpusConfig::parse("boot=1,mwait=on").unwrap_err();
+        }
 
         Ok(())
     }
@@ -3307,6 +3916,15 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,prefault=on,thp_collapse=off", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                prefault: true,
+                thp_collapse: false,
+                ..Default::default()
+            }
+        );
         Ok(())
     }
 
@@ -4064,6 +4682,13 @@ mod tests {
             serial_number: None,
             uuid: None,
             oem_strings: None,
+            label: None,
+            #[cfg(target_arch = "x86_64")]
+            ioapic_pins: DEFAULT_IOAPIC_PINS,
+            #[cfg(target_arch = "x86_64")]
+            ioapics: None,
+            #[cfg(target_arch = "x86_64")]
+            rtc_time: None,
             #[cfg(feature = "tdx")]
             tdx: false,
             #[cfg(feature = "sev_snp")]
@@ -4101,6 +4726,7 @@ mod tests {
                 prefault: false,
                 zones: None,
                 thp: true,
+                thp_collapse: true,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -4194,6 +4820,55 @@ mod tests {
             Err(ValidationError::CpusMaxLowerThanBoot)
         );
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.tsc_khz = Some(0);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidTscFrequency)
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.apic_bus_cycle_ns = Some(0);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidApicBusCycle(0))
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.mwait = Some(arch::MwaitConfig::default());
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidMwaitConfig(
+                    arch::MwaitConfig::default()
+                ))
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.boot_mode = arch::BootMode::RealMode;
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::RealModeWithoutFirmware)
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.async_pf = true;
+            invalid_config.cpus.disabled_pv_msrs = vec![arch::PvMsr::AsyncPf];
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::AsyncPfWithDisabledMsrs)
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.async_pf = true;
+            invalid_config.cpus.kvm_hyperv = true;
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::AsyncPfWithKvmHyperv)
+            );
+        }
+
         let mut invalid_config = valid_config.clone();
         invalid_config.cpus.max_vcpus = 16;
         invalid_config.cpus.boot_vcpus = 16;
@@ -4208,6 +4883,22 @@ mod tests {
             Err(ValidationError::CpuTopologyCount)
         );
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut config = valid_config.clone();
+            config.cpus.max_vcpus = 255;
+            config.cpus.apic_mode = ApicMode::XApic;
+            config.validate().unwrap();
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.max_vcpus = 256;
+            invalid_config.cpus.apic_mode = ApicMode::XApic;
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::XApicTooManyCpus(255))
+            );
+        }
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
             vhost_socket: Some("/path/to/sock".to_owned()),
@@ -4395,6 +5086,36 @@ mod tests {
             ))
         );
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.platform = Some(PlatformConfig {
+                ioapic_pins: MAX_IOAPIC_PINS,
+                ..platform_fixture()
+            });
+            still_valid_config.validate().unwrap();
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.platform = Some(PlatformConfig {
+                ioapic_pins: MAX_IOAPIC_PINS + 1,
+                ..platform_fixture()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidIoapicPins(MAX_IOAPIC_PINS + 1))
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.platform = Some(PlatformConfig {
+                ioapic_pins: DEFAULT_IOAPIC_PINS - 1,
+                ..platform_fixture()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidIoapicPins(DEFAULT_IOAPIC_PINS - 1))
+            );
+        }
+
         let mut still_valid_config = valid_config.clone();
         still_valid_config.platform = Some(PlatformConfig {
             iommu_segments: Some(vec![1, 2, 3]),
@@ -4660,6 +5381,35 @@ mod tests {
             Err(ValidationError::InvalidPciSegment(1))
         );
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.boot_vcpus = 4;
+            invalid_config.cpus.max_vcpus = 4;
+            invalid_config.cpus.topology = Some(CpuTopology {
+                threads_per_core: 2,
+                cores_per_die: 2,
+                dies_per_package: 1,
+                packages: 1,
+            });
+            invalid_config.numa = Some(vec![
+                NumaConfig {
+                    guest_numa_id: 0,
+                    cpus: Some(vec![0]),
+                    ..numa_fixture()
+                },
+                NumaConfig {
+                    guest_numa_id: 1,
+                    cpus: Some(vec![1, 2, 3]),
+                    ..numa_fixture()
+                },
+            ]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::NumaNodeSplitsCore(0, 1))
+            );
+        }
+
         let mut invalid_config = valid_config.clone();
         invalid_config.disks = Some(vec![DiskConfig {
             rate_limit_group: Some("foo".into()),
@@ -4807,6 +5557,91 @@ mod tests {
         }
         let _still_valid_config = still_valid_config.clone();
     }
+
+    #[test]
+    fn test_platform_label() -> Result<()> {
+        let platform = PlatformConfig::parse("label=tenant-a")?;
+        assert_eq!(platform.label.as_deref(), Some("tenant-a"));
+        assert_eq!(PlatformConfig::parse("num_pci_segments=1")?.label, None);
+        platform.validate().unwrap();
+        let platform = PlatformConfig {
+            label: Some("tenant\0a".to_string()),
+            ..platform
+        };
+        assert_eq!(
+            platform.validate(),
+            Err(ValidationError::InvalidLabel("tenant\0a".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_platform_rtc_time() -> Result<()> {
+        let platform = PlatformConfig::parse("rtc_time=1700000000")?;
+        assert_eq!(platform.rtc_time, Some(1700000000));
+        assert_eq!(PlatformConfig::parse("num_pci_segments=1")?.rtc_time, None);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_platform_ioapics() -> Result<()> {
+        let platform = PlatformConfig::parse("ioapics=[0@[0,24],1@[24,32]]")?;
+        assert_eq!(
+            platform.ioapics,
+            Some(vec![
+                IoApicConfig {
+                    id: 0,
+                    gsi_base: 0,
+                    pins: 24,
+                },
+                IoApicConfig {
+                    id: 1,
+                    gsi_base: 24,
+                    pins: 32,
+                },
+            ])
+        );
+        platform.validate().unwrap();
+        PlatformConfig::parse("ioapics=[0@[0,24,8]]").unwrap_err();
+
+        let validate = |ioapics: &str| {
+            PlatformConfig::parse(&format!("ioapics=[{ioapics}]"))
+                .unwrap()
+                .validate()
+        };
+        assert_eq!(
+            validate("0@[0,24],0@[24,24]"),
+            Err(ValidationError::DuplicateIoapicId(0))
+        );
+        assert_eq!(
+            validate("0@[0,24],1@[16,24]"),
+            Err(ValidationError::OverlappingIoapics(0, 1))
+        );
+        assert_eq!(
+            validate("0@[0,24],1@[24,0]"),
+            Err(ValidationError::InvalidIoapicGsiRange(1, 0))
+        );
+        assert_eq!(
+            validate("0@[0,24],16@[24,24]"),
+            Err(ValidationError::InvalidIoapicId(16))
+        );
+        assert_eq!(
+            validate("0@[24,24]"),
+            Err(ValidationError::MissingLegacyIoapic)
+        );
+        // Gaps between the GSI ranges are allowed
+        validate("1@[64,24],0@[0,24]").unwrap();
+
+        assert_eq!(
+            PlatformConfig::parse("ioapic_pins=32,ioapics=[0@[0,24]]")?.validate(),
+            Err(ValidationError::IoapicPinsWithIoapics)
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_landlock_parsing() -> Result<()> {
         // should not be empty
@@ -87,6 +87,8 @@ use crate::seccomp_filters::{Thread, get_seccomp_filter};
 #[cfg(target_arch = "x86_64")]
 use crate::vm::physical_bits;
 use crate::vm_config::CpusConfig;
+#[cfg(target_arch = "x86_64")]
+use crate::vm_config::IoApicConfig;
 use crate::{CPU_MANAGER_SNAPSHOT_ID, GuestMemoryMmap};
 
 #[cfg(all(target_arch = "aarch64", feature = "guest_debug"))]
@@ -127,6 +129,10 @@ pub enum Error {
     #[error("Error configuring vCPU")]
     VcpuConfiguration(#[source] arch::Error),
 
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error enforcing the paravirtual CPUID features")]
+    EnforcePvCpuid(#[source] hypervisor::HypervisorCpuError),
+
     #[error("Still pending removed vCPU")]
     VcpuPendingRemovedVcpu,
 
@@ -142,6 +148,10 @@ pub enum Error {
     #[error("Error initialising vCPU")]
     VcpuArmInit(#[source] hypervisor::HypervisorCpuError),
 
+    #[cfg(target_arch = "aarch64")]
+    #[error("Error resuming vCPU from system suspend")]
+    VcpuArmResume(#[source] hypervisor::HypervisorCpuError),
+
     #[cfg(target_arch = "aarch64")]
     #[error("Error finalising vCPU")]
     VcpuArmFinalize(#[source] hypervisor::HypervisorCpuError),
@@ -203,6 +213,10 @@ pub enum Error {
     #[error("Maximum number of vCPUs {0} exceeds host limit {1}")]
     MaximumVcpusExceeded(u32, u32),
 
+    #[cfg(target_arch = "x86_64")]
+    #[error("Booting in real mode requires unrestricted guest support")]
+    RealModeUnsupported,
+
     #[cfg(feature = "sev_snp")]
     #[error("Failed to set sev control register")]
     SetSevControlRegister(#[source] hypervisor::HypervisorCpuError),
@@ -210,6 +224,13 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     #[error("Failed to inject NMI")]
     NmiError(#[source] hypervisor::HypervisorCpuError),
+
+    #[error("Error getting vCPU state")]
+    VcpuGetState(#[source] hypervisor::HypervisorCpuError),
+
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error configuring the asynchronous page faults")]
+    ConfigureAsyncPf(#[source] hypervisor::HypervisorCpuError),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -449,6 +470,12 @@ impl Vcpu {
         #[cfg(target_arch = "x86_64")] cpuid: Vec<CpuIdEntry>,
         #[cfg(target_arch = "x86_64")] kvm_hyperv: bool,
         #[cfg(target_arch = "x86_64")] topology: (u16, u16, u16, u16),
+        #[cfg(target_arch = "x86_64")] x2apic: Option<bool>,
+        #[cfg(target_arch = "x86_64")] boot_mode: arch::BootMode,
+        #[cfg(target_arch = "x86_64")] core_crystal_hz: Option<u32>,
+        #[cfg(target_arch = "x86_64")] arch_capabilities: Option<u64>,
+        #[cfg(target_arch = "x86_64")] tsc_khz: Option<u32>,
+        #[cfg(target_arch = "x86_64")] apic_bus_cycle_ns: Option<u32>,
     ) -> Result<()> {
         #[cfg(target_arch = "aarch64")]
         {
@@ -469,6 +496,12 @@ impl Vcpu {
             kvm_hyperv,
             self.vendor,
             topology,
+            x2apic,
+            boot_mode,
+            core_crystal_hz,
+            arch_capabilities,
+            tsc_khz,
+            apic_bus_cycle_ns,
         )
         .map_err(Error::VcpuConfiguration)?;
 
@@ -515,6 +548,30 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Wakes an aarch64 vcpu up from PSCI SYSTEM_SUSPEND.
+    ///
+    /// The vcpu is reset powered on, as the features are initialized for the
+    /// boot vcpu, and resumes at `entry_point` with `context_id` in X0. The
+    /// vcpu was already finalized, which survives the reset.
+    #[cfg(target_arch = "aarch64")]
+    pub fn resume_from_suspend(
+        &self,
+        vm: &dyn hypervisor::Vm,
+        entry_point: u64,
+        context_id: u64,
+    ) -> Result<()> {
+        let mut kvi = self.vcpu.create_vcpu_init();
+        vm.get_preferred_target(&mut kvi)
+            .map_err(Error::VcpuArmPreferredTarget)?;
+        self.vcpu
+            .vcpu_set_processor_features(vm, &mut kvi, 0)
+            .map_err(Error::VcpuSetProcessorFeatures)?;
+        self.vcpu.vcpu_init(&kvi).map_err(Error::VcpuArmInit)?;
+        self.vcpu
+            .setup_regs(0, entry_point, context_id)
+            .map_err(Error::VcpuArmResume)
+    }
+
     /// Runs the VCPU until it exits, returning the reason.
     ///
     /// Note that the state of the VCPU and associated VM must be setup first for this to do
@@ -607,6 +664,28 @@ const CPU_EJECT_FLAG: usize = 3;
 const CPU_STATUS_OFFSET: u64 = 4;
 const CPU_SELECTION_OFFSET: u64 = 0;
 
+// Longest thread name the kernel keeps, without the trailing NUL
+const MAX_THREAD_NAME_LEN: usize = 15;
+
+/// Name of the thread running the vCPU `vcpu_id` of the VM labelled
+/// `label`, `vcpu<label>/<vcpu_id>`, or `vcpu<vcpu_id>` for an unlabelled
+/// VM. The label is truncated for the name to fit in the kernel limit, the
+/// index always being kept whole to tell the vCPUs apart.
+fn vcpu_thread_name(label: &str, vcpu_id: u32) -> String {
+    if label.is_empty() {
+        return format!("vcpu{vcpu_id}");
+    }
+
+    let suffix = format!("/{vcpu_id}");
+    let mut end = MAX_THREAD_NAME_LEN
+        .saturating_sub("vcpu".len() + suffix.len())
+        .min(label.len());
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("vcpu{}{suffix}", &label[..end])
+}
+
 impl BusDevice for CpuManager {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         // The Linux kernel, quite reasonably, doesn't zero the memory it gives us.
@@ -782,6 +861,40 @@ impl CpuManager {
         #[cfg(target_arch = "x86_64")]
         let cpu_vendor = hypervisor.get_cpu_vendor();
 
+        // Without unrestricted guest, VMX can't run the vCPU in real mode
+        // unless paging and protected mode are enabled, which KVM can only
+        // emulate with a vm86 task. That covers a limited subset of real
+        // mode code, so refuse to start rather than failing at the first
+        // unsupported instruction.
+        #[cfg(target_arch = "x86_64")]
+        if config.boot_mode == arch::BootMode::RealMode
+            && let Ok(params) = hypervisor.kvm_module_params()
+            && params
+                .raw
+                .get("kvm_intel.unrestricted_guest")
+                .is_some_and(|v| v.trim() == "N")
+        {
+            return Err(Error::RealModeUnsupported);
+        }
+
+        // The guest would skip the mitigations for vulnerabilities the host
+        // is actually affected by
+        #[cfg(target_arch = "x86_64")]
+        if let Some(arch_capabilities) = config.arch_capabilities {
+            match hypervisor
+                .get_feature_msr(hypervisor::arch::x86::features::MSR_IA32_ARCH_CAPABILITIES)
+            {
+                Ok(supported) if arch_capabilities & !supported != 0 => warn!(
+                    "IA32_ARCH_CAPABILITIES bits 0x{:x} are advertised to the guest while the host doesn't provide them, the guest may not mitigate the corresponding vulnerabilities",
+                    arch_capabilities & !supported
+                ),
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Could not check IA32_ARCH_CAPABILITIES 0x{arch_capabilities:x} against the host: {e}"
+                ),
+            }
+        }
+
         #[cfg(target_arch = "x86_64")]
         if config.features.amx {
             const ARCH_GET_XCOMP_GUEST_PERM: usize = 0x1024;
@@ -817,6 +930,16 @@ impl CpuManager {
                     return Err(Error::AmxEnable(anyhow!("Guest AMX usage not supported")));
                 }
             }
+
+            // The vCPU XSAVE state is saved into a fixed size buffer, which
+            // the AMX tile data may not fit in
+            let xsave_size = hypervisor.xsave_layout().standard_size() as usize;
+            let saved_size = std::mem::size_of::<hypervisor::arch::x86::XsaveState>();
+            if xsave_size > saved_size {
+                warn!(
+                    "The {xsave_size} bytes XSAVE area exceeds the {saved_size} bytes saved by snapshots, the AMX state won't be preserved across snapshot and migration"
+                );
+            }
         }
 
         let proximity_domain_per_cpu: BTreeMap<u32, u32> = {
@@ -889,6 +1012,18 @@ impl CpuManager {
                     #[cfg(feature = "tdx")]
                     tdx,
                     amx: self.config.features.amx,
+                    cet: self.config.features.cet,
+                    processor_trace: self.config.features.processor_trace,
+                    x2apic: self.config.apic_mode.x2apic(),
+                    invariant_tsc: self.config.invariant_tsc,
+                    cpu_vendor_override: self.config.cpu_vendor_override,
+                    disabled_pv_msrs: self.config.disabled_pv_msrs.clone(),
+                    arat: self.config.arat,
+                    disabled_pmu_events: self.config.disabled_pmu_events.clone(),
+                    linear_bits: self.config.linear_bits,
+                    leaf_7_overrides: self.config.leaf_7_overrides.clone(),
+                    mwait: self.config.mwait,
+                    ..Default::default()
                 },
             )
             .map_err(Error::CommonCpuId)?
@@ -901,9 +1036,7 @@ impl CpuManager {
         info!("Creating vCPU: cpu_id = {cpu_id}");
 
         #[cfg(target_arch = "x86_64")]
-        let topology = self.get_vcpu_topology();
-        #[cfg(target_arch = "x86_64")]
-        let x2apic_id = arch::x86_64::get_x2apic_id(cpu_id, topology);
+        let x2apic_id = self.apic_id_for_vcpu(cpu_id);
         #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
         let x2apic_id = cpu_id;
 
@@ -916,6 +1049,14 @@ impl CpuManager {
             self.hypervisor.get_cpu_vendor(),
         )?;
 
+        // Hidden paravirtual MSRs must not be reachable by the guest anyway
+        #[cfg(target_arch = "x86_64")]
+        if !self.config.disabled_pv_msrs.is_empty() {
+            vcpu.vcpu
+                .enforce_pv_cpuid()
+                .map_err(Error::EnforcePvCpuid)?;
+        }
+
         if let Some(snapshot) = snapshot {
             // AArch64 vCPUs should be initialized after created.
             #[cfg(target_arch = "aarch64")]
@@ -987,8 +1128,25 @@ impl CpuManager {
             self.cpuid.clone(),
             self.config.kvm_hyperv,
             topology,
+            self.config.apic_mode.x2apic(),
+            self.config.boot_mode,
+            self.config.core_crystal_hz,
+            self.config.arch_capabilities,
+            self.config.tsc_khz,
+            self.config.apic_bus_cycle_ns,
         )?;
 
+        // Off by default, as it hurts the latency of real-time guests
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if matches!(
+            self.hypervisor.hypervisor_type(),
+            hypervisor::HypervisorType::Kvm
+        ) {
+            vcpu.vcpu
+                .configure_async_pf(self.config.async_pf)
+                .map_err(Error::ConfigureAsyncPf)?;
+        }
+
         #[cfg(target_arch = "aarch64")]
         vcpu.configure(self.vm.as_ref(), boot_setup)?;
 
@@ -1083,17 +1241,7 @@ impl CpuManager {
             .clone();
 
         // Prepare the CPU set the current vCPU is expected to run onto.
-        let cpuset = self.affinity.get(&vcpu_id).map(|host_cpus| {
-            // SAFETY: all zeros is a valid pattern
-            let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
-            // SAFETY: FFI call, trivially safe
-            unsafe { libc::CPU_ZERO(&mut cpuset) };
-            for host_cpu in host_cpus {
-                // SAFETY: FFI call, trivially safe
-                unsafe { libc::CPU_SET(*host_cpu, &mut cpuset) };
-            }
-            cpuset
-        });
+        let host_cpus = self.affinity.get(&vcpu_id).cloned();
 
         // Retrieve seccomp filter for vcpu thread
         let vcpu_seccomp_filter = get_seccomp_filter(
@@ -1105,32 +1253,24 @@ impl CpuManager {
 
         #[cfg(target_arch = "x86_64")]
         let interrupt_controller_clone = self.interrupt_controller.as_ref().cloned();
+        #[cfg(target_arch = "aarch64")]
+        let vm = self.vm.clone();
 
         info!("Starting vCPU: cpu_id = {vcpu_id}");
 
+        let thread_name = vcpu_thread_name(self.vm.label(), vcpu_id);
         let handle = Some(
             thread::Builder::new()
-                .name(format!("vcpu{vcpu_id}"))
+                .name(thread_name)
                 .spawn(move || {
                     // Schedule the thread to run on the expected CPU set
-                    if let Some(cpuset) = cpuset.as_ref() {
-                        // SAFETY: FFI call with correct arguments
-                        let ret = unsafe {
-                            libc::sched_setaffinity(
-                                0,
-                                std::mem::size_of::<libc::cpu_set_t>(),
-                                cpuset as *const libc::cpu_set_t,
-                            )
-                        };
-
-                        if ret != 0 {
-                            error!(
-                                "Failed scheduling the vCPU {} on the expected CPU set: {}",
-                                vcpu_id,
-                                io::Error::last_os_error()
-                            );
-                            return;
-                        }
+                    if let Some(host_cpus) = host_cpus.as_ref()
+                        && let Err(e) = vcpu.lock().unwrap().vcpu.set_vcpu_affinity(host_cpus)
+                    {
+                        error!(
+                            "Failed scheduling the vCPU {vcpu_id} on the expected CPU set: {e}"
+                        );
+                        return;
                     }
 
                     // Apply seccomp filter for vcpu thread.
@@ -1243,8 +1383,29 @@ impl CpuManager {
                                                 .end_of_interrupt(vector);
                                         }
                                     }
-                                    VmExit::Ignore => {}
+                                    VmExit::Ignore | VmExit::Timeout => {}
+                                    VmExit::Watchpoint { gpa, size, write } => {
+                                        debug!(
+                                            "VmExit::Watchpoint: gpa 0x{gpa:x}, size {size}, write {write}"
+                                        );
+                                    }
                                     VmExit::Hyperv => {}
+                                    #[cfg(target_arch = "x86_64")]
+                                    VmExit::GuestCrash { code, params } => {
+                                        error!(
+                                            "vCPU {vcpu_id} reported a guest crash: code 0x{code:x}, parameters 0x{:x} 0x{:x} 0x{:x} 0x{:x}",
+                                            params[0], params[1], params[2], params[3]
+                                        );
+                                    }
+                                    #[cfg(target_arch = "x86_64")]
+                                    VmExit::InternalError { suberror, data, insn } => {
+                                        error!(
+                                            "vCPU {vcpu_id} internal error: {suberror:?}, data {data:x?}, instruction {insn:x?}"
+                                        );
+                                        vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                        exit_evt.write(1).unwrap();
+                                        break;
+                                    }
                                     VmExit::Reset => {
                                         info!("VmExit::Reset");
                                         vcpu_run_interrupted.store(true, Ordering::SeqCst);
@@ -1257,6 +1418,47 @@ impl CpuManager {
                                         exit_evt.write(1).unwrap();
                                         break;
                                     }
+                                    #[cfg(target_arch = "aarch64")]
+                                    VmExit::SystemReset(reason) => {
+                                        info!("VmExit::SystemReset: {reason}");
+                                        event!(
+                                            "vcpu",
+                                            "system-reset",
+                                            "id",
+                                            vcpu_id.to_string(),
+                                            "reason",
+                                            reason.to_string()
+                                        );
+                                        vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                        reset_evt.write(1).unwrap();
+                                        break;
+                                    }
+                                    #[cfg(target_arch = "aarch64")]
+                                    VmExit::SystemOff => {
+                                        info!("VmExit::SystemOff");
+                                        event!("vcpu", "system-off", "id", vcpu_id.to_string());
+                                        vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                        exit_evt.write(1).unwrap();
+                                        break;
+                                    }
+                                    // Suspending isn't supported, the
+                                    // guest is woken up right away
+                                    #[cfg(target_arch = "aarch64")]
+                                    VmExit::Suspend {
+                                        entry_point,
+                                        context_id,
+                                    } => {
+                                        if let Err(e) = vcpu.resume_from_suspend(
+                                            vm.as_ref(),
+                                            entry_point,
+                                            context_id,
+                                        ) {
+                                            error!("Failed to resume vCPU {vcpu_id}: {e:?}");
+                                            vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                            exit_evt.write(1).unwrap();
+                                            break;
+                                        }
+                                    }
                                     #[cfg(feature = "tdx")]
                                     VmExit::Tdx => {
                                             match vcpu.vcpu.get_tdx_exit_details() {
@@ -1524,18 +1726,58 @@ impl CpuManager {
             .collect()
     }
 
+    /// Builds the CpuManager snapshot from the `states` of all the vCPUs, as
+    /// returned by `vcpus_state()`.
+    pub fn snapshot_states(
+        &mut self,
+        states: Vec<CpuState>,
+    ) -> std::result::Result<Snapshot, MigratableError> {
+        let mut cpu_manager_snapshot = Snapshot::default();
+
+        // The CpuManager snapshot is a collection of all vCPUs snapshots.
+        for (vcpu, state) in self.vcpus.iter().zip(states) {
+            let mut vcpu = vcpu.lock().unwrap();
+            let snapshot = Snapshot::from_data(SnapshotData::new_from_state(&state)?);
+            vcpu.saved_state = Some(state);
+            cpu_manager_snapshot.add_snapshot(vcpu.id(), snapshot);
+        }
+
+        Ok(cpu_manager_snapshot)
+    }
+
+    /// Reads the current state of all the vCPUs, which must be paused.
+    pub fn vcpus_state(&self) -> Result<Vec<CpuState>> {
+        self.vcpus
+            .iter()
+            .map(|cpu| {
+                cpu.lock()
+                    .unwrap()
+                    .vcpu
+                    .state()
+                    .map_err(Error::VcpuGetState)
+            })
+            .collect()
+    }
+
     pub fn get_vcpu_topology(&self) -> Option<(u16, u16, u16, u16)> {
-        self.config.topology.clone().map(|t| {
-            (
-                t.threads_per_core,
-                t.cores_per_die,
-                t.dies_per_package,
-                t.packages,
-            )
-        })
+        self.config.vcpu_topology()
     }
 
-    pub fn create_madt(&self, #[cfg(target_arch = "aarch64")] vgic: Arc<Mutex<dyn Vgic>>) -> Sdt {
+    /// Returns the APIC ID the guest sees for the vCPU `index`.
+    ///
+    /// IDs are derived from the topology and are not contiguous when a
+    /// topology level isn't a power of two, MSI routes must target this ID
+    /// rather than the vCPU index.
+    #[cfg(target_arch = "x86_64")]
+    pub fn apic_id_for_vcpu(&self, index: u32) -> u32 {
+        get_x2apic_id(index, self.get_vcpu_topology())
+    }
+
+    pub fn create_madt(
+        &self,
+        #[cfg(target_arch = "x86_64")] ioapics: &[IoApicConfig],
+        #[cfg(target_arch = "aarch64")] vgic: Arc<Mutex<dyn Vgic>>,
+    ) -> Sdt {
         use crate::acpi;
         // This is also checked in the commandline parsing.
         assert!(self.config.boot_vcpus <= self.config.max_vcpus);
@@ -1546,7 +1788,7 @@ impl CpuManager {
             madt.write(36, arch::layout::APIC_START.0);
 
             for cpu in 0..self.config.max_vcpus {
-                let x2apic_id = get_x2apic_id(cpu, self.get_vcpu_topology());
+                let x2apic_id = self.apic_id_for_vcpu(cpu);
 
                 let lapic = LocalX2Apic {
                     r#type: acpi::ACPI_X2APIC_PROCESSOR,
@@ -1563,14 +1805,18 @@ impl CpuManager {
                 madt.append(lapic);
             }
 
-            madt.append(Ioapic {
-                r#type: acpi::ACPI_APIC_IO,
-                length: 12,
-                ioapic_id: 0,
-                apic_address: arch::layout::IOAPIC_START.0 as u32,
-                gsi_base: 0,
-                ..Default::default()
-            });
+            for (i, ioapic) in ioapics.iter().enumerate() {
+                madt.append(Ioapic {
+                    r#type: acpi::ACPI_APIC_IO,
+                    length: 12,
+                    ioapic_id: ioapic.id,
+                    apic_address: (arch::layout::IOAPIC_START.0
+                        + i as u64 * arch::layout::IOAPIC_STRIDE)
+                        as u32,
+                    gsi_base: ioapic.gsi_base,
+                    ..Default::default()
+                });
+            }
 
             madt.append(InterruptSourceOverride {
                 r#type: acpi::ACPI_APIC_XRUPT_OVERRIDE,
@@ -2506,15 +2752,11 @@ impl Snapshottable for CpuManager {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        let mut cpu_manager_snapshot = Snapshot::default();
-
-        // The CpuManager snapshot is a collection of all vCPUs snapshots.
-        for vcpu in &self.vcpus {
-            let mut vcpu = vcpu.lock().unwrap();
-            cpu_manager_snapshot.add_snapshot(vcpu.id(), vcpu.snapshot()?);
-        }
+        let states = self
+            .vcpus_state()
+            .map_err(|e| MigratableError::Snapshot(anyhow!("Could not get vCPU state {e:?}")))?;
 
-        Ok(cpu_manager_snapshot)
+        self.snapshot_states(states)
     }
 }
 
@@ -3101,6 +3343,7 @@ mod tests {
                 entry_addr: vm_memory::GuestAddress(expected_regs.get_rip()),
                 setup_header: None,
             },
+            BootMode::ProtectedMode,
         )
         .unwrap();
 
@@ -3130,12 +3373,30 @@ mod tests {
                     ..Default::default()
                 }),
             },
+            BootMode::ProtectedMode,
         )
         .unwrap();
 
         let actual_regs: StandardRegisters = vcpu.get_regs().unwrap();
         assert_eq!(actual_regs, expected_regs);
     }
+
+    #[test]
+    fn test_vcpu_thread_name() {
+        assert_eq!(super::vcpu_thread_name("", 3), "vcpu3");
+        assert_eq!(super::vcpu_thread_name("web", 3), "vcpuweb/3");
+        // The label is truncated, the index is kept
+        assert_eq!(
+            super::vcpu_thread_name("production-db", 7),
+            "vcpuproductio/7"
+        );
+        assert_eq!(
+            super::vcpu_thread_name("production-db", 123),
+            "vcpuproduct/123"
+        );
+        // Without splitting a character
+        assert_eq!(super::vcpu_thread_name("äääää", 1), "vcpuääää/1");
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
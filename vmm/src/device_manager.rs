@@ -17,6 +17,8 @@ use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::PathBuf;
 use std::result;
+#[cfg(target_arch = "x86_64")]
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 #[cfg(not(target_arch = "riscv64"))]
 use std::time::Instant;
@@ -26,7 +28,7 @@ use acpi_tables::{Aml, aml};
 #[cfg(not(target_arch = "riscv64"))]
 use anyhow::anyhow;
 #[cfg(target_arch = "x86_64")]
-use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
+use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START, IOAPIC_STRIDE};
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 use arch::{DeviceType, MmioDeviceInfo};
 use arch::{NumaNodes, layout};
@@ -116,6 +118,8 @@ use crate::interrupt::{LegacyUserspaceInterruptManager, MsiInterruptManager};
 use crate::memory_manager::{Error as MemoryManagerError, MEMORY_MANAGER_ACPI_SIZE, MemoryManager};
 use crate::pci_segment::PciSegment;
 use crate::serial_manager::{Error as SerialManagerError, SerialManager};
+#[cfg(target_arch = "x86_64")]
+use crate::vm_config::IoApicConfig;
 #[cfg(feature = "ivshmem")]
 use crate::vm_config::IvshmemConfig;
 use crate::vm_config::{
@@ -128,12 +132,18 @@ use crate::{DEVICE_MANAGER_SNAPSHOT_ID, GuestRegionMmap, PciDeviceInfo, device_n
 #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
 const MMIO_LEN: u64 = 0x1000;
 
+// Number of POST codes kept by the 0x80 debug port for late subscribers
+#[cfg(target_arch = "x86_64")]
+const DEBUG_PORT_HISTORY_SIZE: usize = 256;
+
 // Singleton devices / devices the user cannot name
 #[cfg(target_arch = "x86_64")]
 const IOAPIC_DEVICE_NAME: &str = "__ioapic";
 const SERIAL_DEVICE_NAME: &str = "__serial";
 #[cfg(target_arch = "x86_64")]
 const DEBUGCON_DEVICE_NAME: &str = "__debug_console";
+#[cfg(target_arch = "x86_64")]
+const CMOS_DEVICE_NAME: &str = "__cmos";
 #[cfg(target_arch = "aarch64")]
 const GPIO_DEVICE_NAME: &str = "__gpio";
 const RNG_DEVICE_NAME: &str = "__rng";
@@ -953,7 +963,7 @@ pub struct DeviceManager {
 
     // Interrupt controller
     #[cfg(target_arch = "x86_64")]
-    interrupt_controller: Option<Arc<Mutex<ioapic::Ioapic>>>,
+    interrupt_controller: Option<Arc<Mutex<ioapic::IoapicSet>>>,
     #[cfg(target_arch = "aarch64")]
     interrupt_controller: Option<Arc<Mutex<gic::Gic>>>,
     #[cfg(target_arch = "riscv64")]
@@ -1093,6 +1103,14 @@ pub struct DeviceManager {
     #[cfg(feature = "fw_cfg")]
     fw_cfg: Option<Arc<Mutex<FwCfg>>>,
 
+    #[cfg(target_arch = "x86_64")]
+    // CMOS/RTC device
+    cmos: Option<Arc<Mutex<devices::legacy::Cmos>>>,
+
+    #[cfg(target_arch = "x86_64")]
+    // 0x80 debug port
+    debug_port: Option<Arc<Mutex<devices::legacy::DebugPort>>>,
+
     #[cfg(feature = "ivshmem")]
     // ivshmem device
     ivshmem_device: Option<Arc<Mutex<devices::IvshmemDevice>>>,
@@ -1360,6 +1378,10 @@ impl DeviceManager {
             mmio_regions: Arc::new(Mutex::new(Vec::new())),
             #[cfg(feature = "fw_cfg")]
             fw_cfg: None,
+            #[cfg(target_arch = "x86_64")]
+            cmos: None,
+            #[cfg(target_arch = "x86_64")]
+            debug_port: None,
             #[cfg(feature = "ivshmem")]
             ivshmem_device: None,
         };
@@ -1776,37 +1798,54 @@ impl DeviceManager {
     fn add_interrupt_controller(
         &mut self,
     ) -> DeviceManagerResult<Arc<Mutex<dyn InterruptController>>> {
-        let id = String::from(IOAPIC_DEVICE_NAME);
+        let ioapics_config = self.config.lock().unwrap().ioapics();
+        let mut ioapics = Vec::new();
+
+        for (i, ioapic_config) in ioapics_config.iter().enumerate() {
+            // The first IOAPIC keeps the historical name for snapshots taken
+            // before several IOAPICs were supported.
+            let id = if i == 0 {
+                String::from(IOAPIC_DEVICE_NAME)
+            } else {
+                format!("{IOAPIC_DEVICE_NAME}{i}")
+            };
+            let address = IOAPIC_START.0 + i as u64 * IOAPIC_STRIDE;
 
-        // Create IOAPIC
-        let interrupt_controller = Arc::new(Mutex::new(
-            ioapic::Ioapic::new(
-                id.clone(),
-                APIC_START,
-                Arc::clone(&self.msi_interrupt_manager),
-                state_from_id(self.snapshot.as_ref(), id.as_str())
-                    .map_err(DeviceManagerError::RestoreGetState)?,
-            )
-            .map_err(DeviceManagerError::CreateInterruptController)?,
-        ));
+            // Create IOAPIC
+            let ioapic = Arc::new(Mutex::new(
+                ioapic::Ioapic::new(
+                    id.clone(),
+                    APIC_START,
+                    ioapic_config.id,
+                    ioapic_config.pins as usize,
+                    Arc::clone(&self.msi_interrupt_manager),
+                    state_from_id(self.snapshot.as_ref(), id.as_str())
+                        .map_err(DeviceManagerError::RestoreGetState)?,
+                )
+                .map_err(DeviceManagerError::CreateInterruptController)?,
+            ));
 
-        self.interrupt_controller = Some(interrupt_controller.clone());
+            self.address_manager
+                .mmio_bus
+                .insert(ioapic.clone(), address, IOAPIC_SIZE)
+                .map_err(DeviceManagerError::BusError)?;
 
-        self.address_manager
-            .mmio_bus
-            .insert(interrupt_controller.clone(), IOAPIC_START.0, IOAPIC_SIZE)
-            .map_err(DeviceManagerError::BusError)?;
+            self.bus_devices
+                .push(Arc::clone(&ioapic) as Arc<dyn BusDeviceSync>);
 
-        self.bus_devices
-            .push(Arc::clone(&interrupt_controller) as Arc<dyn BusDeviceSync>);
+            // Fill the device tree with a new node. In case of restore, we
+            // know there is nothing to do, so we can simply override the
+            // existing entry.
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, ioapic));
 
-        // Fill the device tree with a new node. In case of restore, we
-        // know there is nothing to do, so we can simply override the
-        // existing entry.
-        self.device_tree
-            .lock()
-            .unwrap()
-            .insert(id.clone(), device_node!(id, interrupt_controller));
+            ioapics.push((ioapic_config.gsi_base as usize, ioapic));
+        }
+
+        let interrupt_controller = Arc::new(Mutex::new(ioapic::IoapicSet::new(ioapics)));
+        self.interrupt_controller = Some(interrupt_controller.clone());
 
         Ok(interrupt_controller)
     }
@@ -1826,6 +1865,7 @@ impl DeviceManager {
         let shutdown_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
             exit_evt,
             reset_evt,
+            self.address_manager.vm.shutdown_eventfd().ok(),
             vcpus_kill_signalled,
         )));
 
@@ -1958,11 +1998,15 @@ impl DeviceManager {
             let mem_below_4g = std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
             let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
 
+            let id = String::from(CMOS_DEVICE_NAME);
             let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
+                id.clone(),
                 mem_below_4g,
                 mem_above_4g,
                 reset_evt,
                 Some(vcpus_kill_signalled),
+                state_from_id(self.snapshot.as_ref(), id.as_str())
+                    .map_err(DeviceManagerError::RestoreGetState)?,
             )));
 
             self.bus_devices
@@ -1970,9 +2014,15 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(cmos, 0x70, 0x2)
+                .insert(cmos.clone(), 0x70, 0x2)
                 .map_err(DeviceManagerError::BusError)?;
 
+            self.device_tree
+                .lock()
+                .unwrap()
+                .insert(id.clone(), device_node!(id, cmos));
+            self.cmos = Some(cmos);
+
             let fwdebug = Arc::new(Mutex::new(devices::legacy::FwDebugDevice::new()));
 
             self.bus_devices
@@ -1985,13 +2035,17 @@ impl DeviceManager {
         }
 
         // 0x80 debug port
-        let debug_port = Arc::new(Mutex::new(devices::legacy::DebugPort::new(self.timestamp)));
+        let debug_port = Arc::new(Mutex::new(devices::legacy::DebugPort::with_history(
+            self.timestamp,
+            DEBUG_PORT_HISTORY_SIZE,
+        )));
         self.bus_devices
             .push(Arc::clone(&debug_port) as Arc<dyn BusDeviceSync>);
         self.address_manager
             .io_bus
-            .insert(debug_port, 0x80, 0x1)
+            .insert(debug_port.clone(), 0x80, 0x1)
             .map_err(DeviceManagerError::BusError)?;
+        self.debug_port = Some(debug_port);
 
         Ok(())
     }
@@ -4316,10 +4370,34 @@ impl DeviceManager {
         self.fw_cfg.as_ref()
     }
 
+    /// Set the time read by the guest from the userspace RTC, as seconds
+    /// since the Unix epoch.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_rtc_time(&self, unix_secs: u64) {
+        if let Some(cmos) = self.cmos.as_ref() {
+            cmos.lock().unwrap().set_time(unix_secs);
+        }
+    }
+
+    /// Stream of the POST codes written by the guest to port 0x80, starting
+    /// with the last `DEBUG_PORT_HISTORY_SIZE` ones.
+    #[cfg(target_arch = "x86_64")]
+    pub fn subscribe_post_codes(&self) -> Option<Receiver<devices::legacy::PostCode>> {
+        self.debug_port
+            .as_ref()
+            .map(|debug_port| debug_port.lock().unwrap().subscribe())
+    }
+
     pub fn allocator(&self) -> &Arc<Mutex<SystemAllocator>> {
         &self.address_manager.allocator
     }
 
+    /// Userspace IOAPICs, each one mapped `IOAPIC_STRIDE` after the previous.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn ioapics(&self) -> Vec<IoApicConfig> {
+        self.config.lock().unwrap().ioapics()
+    }
+
     pub fn interrupt_controller(&self) -> Option<Arc<Mutex<dyn InterruptController>>> {
         self.interrupt_controller
             .as_ref()
@@ -57,9 +57,7 @@ use crate::config::{RestoreConfig, add_to_config};
 use crate::coredump::GuestDebuggable;
 use crate::landlock::Landlock;
 use crate::memory_manager::MemoryManager;
-#[cfg(all(feature = "kvm", target_arch = "x86_64"))]
-use crate::migration::get_vm_snapshot;
-use crate::migration::{recv_vm_config, recv_vm_state};
+use crate::migration::{get_vm_snapshot, recv_vm_config, recv_vm_state};
 use crate::seccomp_filters::{Thread, get_seccomp_filter};
 use crate::vm::{Error as VmError, Vm, VmState};
 use crate::vm_config::{
@@ -872,15 +870,41 @@ impl Vmm {
             })?;
         }
 
-        let vm = Vm::create_hypervisor_vm(
-            self.hypervisor.as_ref(),
-            #[cfg(feature = "tdx")]
-            false,
-            #[cfg(feature = "sev_snp")]
-            false,
-            #[cfg(feature = "sev_snp")]
-            config.lock().unwrap().memory.total_size(),
-        )
+        let vm = {
+            let config = config.lock().unwrap();
+            Vm::create_hypervisor_vm(
+                self.hypervisor.as_ref(),
+                config.label(),
+                config.cpus.boot_vcpus,
+                config.cpus.max_vcpus,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.vcpu_topology(),
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.disabled_msrs(),
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.rapl,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.features.cet,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.features.processor_trace,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.apic_bus_cycle_ns,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.trap_ud,
+                #[cfg(target_arch = "x86_64")]
+                config.ioapic_pins(),
+                #[cfg(target_arch = "aarch64")]
+                config.cpus.features.mte,
+                #[cfg(target_arch = "aarch64")]
+                config.cpus.features.system_suspend,
+                #[cfg(feature = "tdx")]
+                false,
+                #[cfg(feature = "sev_snp")]
+                false,
+                #[cfg(feature = "sev_snp")]
+                config.memory.total_size(),
+            )
+        }
         .map_err(|e| {
             MigratableError::MigrateReceive(anyhow!(
                 "Error creating hypervisor VM from snapshot: {e:?}"
@@ -897,11 +921,16 @@ impl Vmm {
             config.lock().unwrap().cpus.max_phys_bits,
         );
 
+        let memory_config = config.lock().unwrap().memory.clone();
+        #[cfg(target_arch = "x86_64")]
+        let ioapics = config.lock().unwrap().ioapics();
         let memory_manager = MemoryManager::new(
             vm,
-            &config.lock().unwrap().memory.clone(),
+            &memory_config,
             None,
             phys_bits,
+            #[cfg(target_arch = "x86_64")]
+            &ioapics,
             #[cfg(feature = "tdx")]
             false,
             Some(&vm_migration_config.memory_manager_data),
@@ -1130,19 +1159,28 @@ impl Vmm {
                 )));
             };
 
-            let amx = vm_config.lock().unwrap().cpus.features.amx;
-            let phys_bits = vm::physical_bits(
-                hypervisor.as_ref(),
-                vm_config.lock().unwrap().cpus.max_phys_bits,
-            );
+            let vm_config = &vm_config.lock().unwrap();
+            let phys_bits = vm::physical_bits(hypervisor.as_ref(), vm_config.cpus.max_phys_bits);
             arch::generate_common_cpuid(
                 hypervisor.as_ref(),
                 &arch::CpuidConfig {
                     phys_bits,
-                    kvm_hyperv: vm_config.lock().unwrap().cpus.kvm_hyperv,
+                    kvm_hyperv: vm_config.cpus.kvm_hyperv,
                     #[cfg(feature = "tdx")]
                     tdx: false,
-                    amx,
+                    amx: vm_config.cpus.features.amx,
+                    cet: vm_config.cpus.features.cet,
+                    processor_trace: vm_config.cpus.features.processor_trace,
+                    x2apic: vm_config.cpus.apic_mode.x2apic(),
+                    invariant_tsc: vm_config.cpus.invariant_tsc,
+                    cpu_vendor_override: vm_config.cpus.cpu_vendor_override,
+                    disabled_pv_msrs: vm_config.cpus.disabled_pv_msrs.clone(),
+                    arat: vm_config.cpus.arat,
+                    disabled_pmu_events: vm_config.cpus.disabled_pmu_events.clone(),
+                    linear_bits: vm_config.cpus.linear_bits,
+                    leaf_7_overrides: vm_config.cpus.leaf_7_overrides.clone(),
+                    mwait: vm_config.cpus.mwait,
+                    ..Default::default()
                 },
             )
             .map_err(|e| {
@@ -1282,17 +1320,44 @@ impl Vmm {
                     #[cfg(feature = "tdx")]
                     tdx: false,
                     amx: vm_config.cpus.features.amx,
+                    cet: vm_config.cpus.features.cet,
+                    processor_trace: vm_config.cpus.features.processor_trace,
+                    x2apic: vm_config.cpus.apic_mode.x2apic(),
+                    invariant_tsc: vm_config.cpus.invariant_tsc,
+                    cpu_vendor_override: vm_config.cpus.cpu_vendor_override,
+                    disabled_pv_msrs: vm_config.cpus.disabled_pv_msrs.clone(),
+                    arat: vm_config.cpus.arat,
+                    disabled_pmu_events: vm_config.cpus.disabled_pmu_events.clone(),
+                    linear_bits: vm_config.cpus.linear_bits,
+                    leaf_7_overrides: vm_config.cpus.leaf_7_overrides.clone(),
+                    mwait: vm_config.cpus.mwait,
+                    ..Default::default()
                 },
             )
             .map_err(|e| {
                 MigratableError::MigrateReceive(anyhow!("Error generating common cpuid: {e:?}"))
             })?
         };
-        arch::CpuidFeatureEntry::check_cpuid_compatibility(src_vm_cpuid, dest_cpuid).map_err(|e| {
-            MigratableError::MigrateReceive(anyhow!(
-                "Error checking cpu feature compatibility': {e:?}"
-            ))
-        })
+        arch::CpuidFeatureEntry::check_cpuid_compatibility(src_vm_cpuid, dest_cpuid).map_err(
+            |e| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "Error checking cpu feature compatibility': {e:?}"
+                ))
+            },
+        )?;
+
+        // The XSAVE state of the vCPUs is transferred in the standard format,
+        // whose layout must be the same on the destination.
+        let src_xsave_layout = hypervisor::arch::x86::xsave::XsaveLayout::from_cpuid(src_vm_cpuid);
+        if let Some(index) =
+            src_xsave_layout.incompatible_component(&self.hypervisor.xsave_layout())
+        {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "XSAVE component {index} is missing or laid out differently on the destination"
+            )));
+        }
+
+        Ok(())
     }
 
     fn vm_restore(
@@ -1302,9 +1367,15 @@ impl Vmm {
         prefault: bool,
     ) -> std::result::Result<(), VmError> {
         let snapshot = recv_vm_state(source_url).map_err(VmError::Restore)?;
-        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let vm_snapshot = get_vm_snapshot(&snapshot).map_err(VmError::Restore)?;
 
+        // Snapshots taken before the header was introduced can't be checked
+        if let Some(header) = &vm_snapshot.header {
+            self.hypervisor
+                .validate_snapshot_header(header)
+                .map_err(|e| VmError::Restore(MigratableError::Restore(e.into())))?;
+        }
+
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         self.vm_check_cpuid_compatibility(&vm_config, &vm_snapshot.common_cpuid)
             .map_err(VmError::Restore)?;
@@ -2337,8 +2408,8 @@ mod unit_tests {
     #[cfg(target_arch = "x86_64")]
     use crate::vm_config::DebugConsoleConfig;
     use crate::vm_config::{
-        ConsoleConfig, ConsoleOutputMode, CpuFeatures, CpusConfig, HotplugMethod, MemoryConfig,
-        PayloadConfig, RngConfig,
+        ApicMode, ConsoleConfig, ConsoleOutputMode, CpuFeatures, CpusConfig, HotplugMethod,
+        MemoryConfig, PayloadConfig, RngConfig,
     };
 
     fn create_dummy_vmm() -> Vmm {
@@ -2366,6 +2437,39 @@ mod unit_tests {
                 max_phys_bits: 46,
                 affinity: None,
                 features: CpuFeatures::default(),
+                apic_mode: ApicMode::Auto,
+                #[cfg(target_arch = "x86_64")]
+                boot_mode: arch::BootMode::ProtectedMode,
+                #[cfg(target_arch = "x86_64")]
+                invariant_tsc: None,
+                #[cfg(target_arch = "x86_64")]
+                cpu_vendor_override: None,
+                #[cfg(target_arch = "x86_64")]
+                core_crystal_hz: None,
+                #[cfg(target_arch = "x86_64")]
+                disabled_pv_msrs: Vec::new(),
+                #[cfg(target_arch = "x86_64")]
+                arat: true,
+                #[cfg(target_arch = "x86_64")]
+                async_pf: false,
+                #[cfg(target_arch = "x86_64")]
+                arch_capabilities: None,
+                #[cfg(target_arch = "x86_64")]
+                disabled_pmu_events: Vec::new(),
+                #[cfg(target_arch = "x86_64")]
+                linear_bits: None,
+                #[cfg(target_arch = "x86_64")]
+                tsc_khz: None,
+                #[cfg(target_arch = "x86_64")]
+                rapl: None,
+                #[cfg(target_arch = "x86_64")]
+                apic_bus_cycle_ns: None,
+                #[cfg(target_arch = "x86_64")]
+                trap_ud: false,
+                #[cfg(target_arch = "x86_64")]
+                leaf_7_overrides: Vec::new(),
+                #[cfg(target_arch = "x86_64")]
+                mwait: None,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -2379,6 +2483,7 @@ mod unit_tests {
                 prefault: false,
                 zones: None,
                 thp: true,
+                thp_collapse: true,
             },
             payload: Some(PayloadConfig {
                 kernel: Some(PathBuf::from("/path/to/kernel")),
@@ -8,7 +8,7 @@ use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{self};
-use std::ops::{BitAnd, Deref, Not, Sub};
+use std::ops::{BitAnd, Deref, Not, Range, Sub};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::os::fd::AsFd;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
@@ -20,8 +20,6 @@ use std::{ffi, result, thread};
 use acpi_tables::{Aml, aml};
 use anyhow::anyhow;
 use arch::RegionType;
-#[cfg(target_arch = "x86_64")]
-use devices::ioapic;
 #[cfg(target_arch = "aarch64")]
 use hypervisor::HypervisorVmError;
 use libc::_SC_NPROCESSORS_ONLN;
@@ -50,6 +48,8 @@ use crate::coredump::{
     CoredumpMemoryRegion, CoredumpMemoryRegions, DumpState, GuestDebuggableError,
 };
 use crate::migration::url_to_path;
+#[cfg(target_arch = "x86_64")]
+use crate::vm_config::IoApicConfig;
 use crate::vm_config::{HotplugMethod, MemoryConfig, MemoryZoneConfig};
 use crate::{GuestMemoryMmap, GuestRegionMmap, MEMORY_MANAGER_SNAPSHOT_ID};
 
@@ -58,6 +58,12 @@ pub const MEMORY_MANAGER_ACPI_SIZE: usize = 0x18;
 const DEFAULT_MEMORY_ZONE: &str = "mem0";
 
 const SNAPSHOT_FILENAME: &str = "memory-ranges";
+#[cfg(target_arch = "aarch64")]
+const MTE_TAGS_SNAPSHOT_FILENAME: &str = "memory-tags";
+
+// Not exposed by the libc crate
+#[cfg(target_arch = "aarch64")]
+const PROT_MTE: i32 = 0x20;
 
 #[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
@@ -74,6 +80,16 @@ const PLATFORM_DEVICE_AREA_SIZE: u64 = 1 << 20;
 
 const MAX_PREFAULT_THREAD_COUNT: usize = 16;
 
+const PAGE_IDLE_BITMAP: &str = "/sys/kernel/mm/page_idle/bitmap";
+// Entries of /proc/self/pagemap
+const PAGEMAP_ENTRY_SIZE: u64 = 8;
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+const PAGEMAP_PFN_MASK: u64 = (1 << 55) - 1;
+// Pages whose pagemap entries are read at once when scanning idle pages
+const IDLE_SCAN_CHUNK_PAGES: u64 = 512;
+
+const PROC_SELF_SMAPS: &str = "/proc/self/smaps";
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 struct HotPlugState {
     base: u64,
@@ -145,6 +161,77 @@ struct GuestRamMapping {
     file_offset: u64,
 }
 
+/// Guest physical frame number, in units of the host page size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GuestPageFrame(pub u64);
+
+/// Transparent huge page state of a guest RAM region, as reported by
+/// `/proc/self/smaps` for the host mappings backing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThpState {
+    pub start: GuestAddress,
+    pub size: u64,
+    /// Whether the region may be backed by THP (`THPeligible`)
+    pub eligible: bool,
+    /// Whether the region is labelled `MADV_NOHUGEPAGE`, which keeps
+    /// khugepaged from collapsing it
+    pub collapse_disabled: bool,
+    /// Bytes currently backed by THP (`AnonHugePages` and `ShmemPmdMapped`)
+    pub huge_bytes: u64,
+}
+
+// Returns the THPeligible and MADV_NOHUGEPAGE flags, and the bytes backed
+// by THP, of the /proc/self/smaps entries overlapping [start, end).
+fn parse_smaps_thp(smaps: &str, start: u64, end: u64) -> (bool, bool, u64) {
+    let (mut eligible, mut collapse_disabled, mut huge_kib) = (false, false, 0);
+    let mut overlaps = false;
+
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or_default();
+
+        // Each entry starts with the address range of the mapping
+        if let Some((lo, hi)) = first.split_once('-')
+            && let (Ok(lo), Ok(hi)) = (u64::from_str_radix(lo, 16), u64::from_str_radix(hi, 16))
+        {
+            overlaps = lo < end && start < hi;
+            continue;
+        }
+        if !overlaps {
+            continue;
+        }
+
+        match first {
+            "AnonHugePages:" | "ShmemPmdMapped:" => {
+                huge_kib += fields
+                    .next()
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .unwrap_or(0);
+            }
+            "THPeligible:" => eligible |= fields.next() == Some("1"),
+            "VmFlags:" => collapse_disabled |= fields.any(|flag| flag == "nh"),
+            _ => {}
+        }
+    }
+
+    (eligible, collapse_disabled, huge_kib << 10)
+}
+
+// Splits the sorted host frame numbers `pfns` into runs whose words of the
+// idle page bitmap, one bit per frame, are contiguous and can be accessed
+// at once.
+fn idle_bitmap_runs(pfns: &[u64]) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for end in 1..=pfns.len() {
+        if end == pfns.len() || pfns[end] / 64 > pfns[end - 1] / 64 + 1 {
+            runs.push(start..end);
+            start = end;
+        }
+    }
+    runs
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 struct ArchMemRegion {
     base: u64,
@@ -174,6 +261,7 @@ pub struct MemoryManager {
     hugepage_size: Option<u64>,
     prefault: bool,
     thp: bool,
+    thp_collapse: bool,
     user_provided_zones: bool,
     snapshot_memory_ranges: MemoryRangeTable,
     memory_zones: MemoryZones,
@@ -190,6 +278,10 @@ pub struct MemoryManager {
     pub acpi_address: Option<GuestAddress>,
     #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
     uefi_flash: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+
+    // MTE tags from a snapshot, restored once the memory slots exist.
+    #[cfg(target_arch = "aarch64")]
+    pending_mte_tags: Option<(PathBuf, MemoryRangeTable)>,
 }
 
 #[derive(Error, Debug)]
@@ -347,6 +439,43 @@ pub enum Error {
     /// Memory size is misaligned with default page size or its hugepage size
     #[error("Memory size is misaligned with default page size or its hugepage size")]
     MisalignedMemorySize,
+
+    /// Reserved region is misaligned with the page size backing the memory
+    #[error(
+        "Reserved region {0:#x}-{1:#x} is misaligned with the {2:#x} page size backing the memory"
+    )]
+    MisalignedReservedRegion(u64, u64, u64),
+
+    /// Memory backing not compatible with MTE
+    #[cfg(target_arch = "aarch64")]
+    #[error("MTE requires anonymous memory without huge pages nor hotplug")]
+    MteIncompatibleMemory,
+
+    /// Failed to map guest memory with PROT_MTE
+    #[cfg(target_arch = "aarch64")]
+    #[error("Failed to map guest memory with PROT_MTE")]
+    EnableMte(#[source] io::Error),
+
+    /// Error copying MTE tags
+    #[cfg(target_arch = "aarch64")]
+    #[error("Error copying MTE tags")]
+    MteTagsCopy(#[source] anyhow::Error),
+
+    /// Guest RAM is not held by a single memfd
+    #[error("Guest RAM can't be exported: {0}")]
+    ExportMemoryUnsupported(String),
+
+    /// Failed to duplicate the memfd holding guest RAM
+    #[error("Failed to duplicate the memfd holding guest RAM")]
+    ExportMemoryFd(#[source] io::Error),
+
+    /// Failed to scan the idle guest pages
+    #[error("Failed to scan the idle guest pages")]
+    ScanIdlePages(#[source] io::Error),
+
+    /// Failed to read the transparent huge page state of guest RAM
+    #[error("Failed to read the transparent huge page state of guest RAM")]
+    ReadThpState(#[source] io::Error),
 }
 
 const ENABLE_FLAG: usize = 0;
@@ -441,6 +570,126 @@ where
     (val & (align - 1u8.into())) == 0u8.into()
 }
 
+#[inline]
+fn align_up(val: u64, align: u64) -> Option<u64> {
+    val.checked_add(align - 1).map(|v| align_down(v, align))
+}
+
+/// Builder laying out guest RAM into slots whose start address and size are
+/// multiples of the page size backing the memory.
+///
+/// The slots are carved out of the RAM ranges in order, and split around the
+/// reserved holes. The RAM ranges are shrunk to the closest page boundaries,
+/// while a reserved hole not aligned on the page size is an error, as the
+/// slots next to it would have to overlap the hole.
+pub struct RamSlotsBuilder {
+    ram_ranges: Vec<(GuestAddress, u64)>,
+    reserved: Vec<(GuestAddress, u64)>,
+    page_size: u64,
+}
+
+impl RamSlotsBuilder {
+    /// Creates a builder for `ram_ranges`, backed by normal pages.
+    pub fn new(ram_ranges: &[(GuestAddress, usize)]) -> Self {
+        // SAFETY: FFI call. Trivially safe.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+
+        RamSlotsBuilder {
+            ram_ranges: ram_ranges
+                .iter()
+                .map(|(start, size)| (*start, *size as u64))
+                .collect(),
+            reserved: Vec::new(),
+            page_size,
+        }
+    }
+
+    /// Uses the page size backing `zone`, the hugepage size if any.
+    pub fn backing(mut self, zone: &MemoryZoneConfig) -> Result<Self, Error> {
+        self.page_size = memory_zone_get_align_size(zone)?;
+        Ok(self)
+    }
+
+    /// Uses `page_size`, which must be a power of two.
+    pub fn page_size(mut self, page_size: u64) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Excludes `size` bytes starting at `start` from the slots.
+    pub fn reserved(mut self, start: GuestAddress, size: u64) -> Self {
+        self.reserved.push((start, size));
+        self
+    }
+
+    /// Returns the slots holding `size` bytes, rounded up to the page size.
+    pub fn build(&self, size: u64) -> Result<Vec<(GuestAddress, u64)>, Error> {
+        let page_size = self.page_size;
+        if !page_size.is_power_of_two() {
+            return Err(Error::InvalidMemoryParameters);
+        }
+
+        let mut reserved: Vec<(u64, u64)> = self
+            .reserved
+            .iter()
+            .map(|(start, size)| (start.raw_value(), start.raw_value().saturating_add(*size)))
+            .collect();
+        reserved.sort_unstable();
+
+        let mut remaining = align_up(size, page_size).ok_or(Error::GuestAddressOverFlow)?;
+        let mut slots = Vec::new();
+
+        for (start, size) in self.ram_ranges.iter() {
+            let range_start = start.raw_value();
+            let range_end = range_start.saturating_add(*size);
+
+            // A misaligned hole overlapping the range would be overlapped by
+            // the slot reaching the page it starts or ends in.
+            for (hole_start, hole_end) in reserved.iter() {
+                if *hole_start < range_end
+                    && *hole_end > range_start
+                    && (!is_aligned(*hole_start, page_size) || !is_aligned(*hole_end, page_size))
+                {
+                    return Err(Error::MisalignedReservedRegion(
+                        *hole_start,
+                        *hole_end,
+                        page_size,
+                    ));
+                }
+            }
+
+            let Some(mut cursor) = align_up(range_start, page_size) else {
+                continue;
+            };
+            let range_end = align_down(range_end, page_size);
+
+            while remaining > 0 && cursor < range_end {
+                let hole = reserved
+                    .iter()
+                    .find(|(hole_start, hole_end)| *hole_end > cursor && *hole_start < range_end);
+                let slot_end = hole.map_or(range_end, |(hole_start, _)| *hole_start);
+
+                if slot_end > cursor {
+                    let slot_size = std::cmp::min(slot_end - cursor, remaining);
+                    slots.push((GuestAddress(cursor), slot_size));
+                    remaining -= slot_size;
+                }
+
+                match hole {
+                    Some((_, hole_end)) => cursor = *hole_end,
+                    None => break,
+                }
+            }
+        }
+
+        if remaining > 0 {
+            return Err(Error::GuestAddressOverFlow);
+        }
+
+        Ok(slots)
+    }
+}
+
 impl BusDevice for MemoryManager {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         if self.selected_slot < self.hotplug_slots.len() {
@@ -527,65 +776,47 @@ impl MemoryManager {
     /// - Second one mapping partially the second memory zone on 1G-3G range
     /// - Third one mapping partially the second memory zone on 4G-6G range
     ///
-    /// Also, all memory regions are page-size aligned (e.g. their sizes must
-    /// be multiple of page-size), which may leave an additional hole in the
-    /// address space when hugepage is used.
+    /// Also, all memory regions are aligned on the page size backing their
+    /// zone (e.g. their start and size must be multiple of the hugepage
+    /// size), see `RamSlotsBuilder`, which may leave an additional hole in
+    /// the address space when hugepage is used.
     fn create_memory_regions_from_zones(
         ram_regions: &[(GuestAddress, usize)],
         zones: &[MemoryZoneConfig],
         prefault: Option<bool>,
         thp: bool,
     ) -> Result<(Vec<Arc<GuestRegionMmap>>, MemoryZones), Error> {
-        let mut zone_iter = zones.iter();
-        let mut mem_regions = Vec::new();
-        let mut zone = zone_iter.next().ok_or(Error::MissingMemoryZones)?;
-        let mut zone_align_size = memory_zone_get_align_size(zone)?;
-        let mut zone_offset = 0u64;
-        let mut memory_zones = HashMap::new();
-
-        if !is_aligned(zone.size, zone_align_size) {
-            return Err(Error::MisalignedMemorySize);
+        if zones.is_empty() {
+            return Err(Error::MissingMemoryZones);
         }
 
-        // Add zone id to the list of memory zones.
-        memory_zones.insert(zone.id.clone(), MemoryZone::default());
-
-        for ram_region in ram_regions.iter() {
-            let mut ram_region_offset = 0;
-            let mut exit = false;
-
-            loop {
-                let mut ram_region_consumed = false;
-                let mut pull_next_zone = false;
-
-                let ram_region_available_size =
-                    align_down(ram_region.1 as u64 - ram_region_offset, zone_align_size);
-                if ram_region_available_size == 0 {
-                    break;
-                }
-                let zone_sub_size = zone.size - zone_offset;
-
-                let file_offset = zone_offset;
-                let region_start = ram_region
-                    .0
-                    .checked_add(ram_region_offset)
-                    .ok_or(Error::GuestAddressOverFlow)?;
-                let region_size = if zone_sub_size <= ram_region_available_size {
-                    if zone_sub_size == ram_region_available_size {
-                        ram_region_consumed = true;
-                    }
+        let mut ram_regions = ram_regions.to_vec();
+        let mut mem_regions = Vec::new();
+        let mut memory_zones = HashMap::new();
 
-                    ram_region_offset += zone_sub_size;
-                    pull_next_zone = true;
+        for zone in zones.iter() {
+            if !is_aligned(zone.size, memory_zone_get_align_size(zone)?) {
+                return Err(Error::MisalignedMemorySize);
+            }
 
-                    zone_sub_size
-                } else {
-                    zone_offset += ram_region_available_size;
-                    ram_region_consumed = true;
+            // Check if zone id already exist. In case it does, throw an error
+            // as we need unique identifiers.
+            if memory_zones.contains_key(&zone.id) {
+                error!(
+                    "Memory zone identifier '{}' found more than once. \
+                    It must be unique",
+                    zone.id,
+                );
+                return Err(Error::DuplicateZoneId);
+            }
 
-                    ram_region_available_size
-                };
+            let slots = RamSlotsBuilder::new(&ram_regions)
+                .backing(zone)?
+                .build(zone.size)?;
 
+            let mut memory_zone = MemoryZone::default();
+            let mut file_offset = 0u64;
+            for (region_start, region_size) in slots.iter() {
                 info!(
                     "create ram region for zone {}, region_start: {:#x}, region_size: {:#x}",
                     zone.id,
@@ -595,8 +826,8 @@ impl MemoryManager {
                 let region = MemoryManager::create_ram_region(
                     &zone.file,
                     file_offset,
-                    region_start,
-                    region_size as usize,
+                    *region_start,
+                    *region_size as usize,
                     prefault.unwrap_or(zone.prefault),
                     zone.shared,
                     zone.hugepages,
@@ -606,49 +837,28 @@ impl MemoryManager {
                     thp,
                 )?;
 
-                // Add region to the list of regions associated with the
-                // current memory zone.
-                if let Some(memory_zone) = memory_zones.get_mut(&zone.id) {
-                    memory_zone.regions.push(region.clone());
-                }
-
+                memory_zone.regions.push(region.clone());
                 mem_regions.push(region);
-
-                if pull_next_zone {
-                    // Get the next zone and reset the offset.
-                    zone_offset = 0;
-                    if let Some(z) = zone_iter.next() {
-                        zone = z;
-                    } else {
-                        exit = true;
-                        break;
-                    }
-                    zone_align_size = memory_zone_get_align_size(zone)?;
-                    if !is_aligned(zone.size, zone_align_size) {
-                        return Err(Error::MisalignedMemorySize);
-                    }
-
-                    // Check if zone id already exist. In case it does, throw
-                    // an error as we need unique identifiers. Otherwise, add
-                    // the new zone id to the list of memory zones.
-                    if memory_zones.contains_key(&zone.id) {
-                        error!(
-                            "Memory zone identifier '{}' found more than once. \
-                            It must be unique",
-                            zone.id,
-                        );
-                        return Err(Error::DuplicateZoneId);
-                    }
-                    memory_zones.insert(zone.id.clone(), MemoryZone::default());
-                }
-
-                if ram_region_consumed {
-                    break;
-                }
+                file_offset += region_size;
             }
-
-            if exit {
-                break;
+            memory_zones.insert(zone.id.clone(), memory_zone);
+
+            // The next zone is laid out after the last slot of this one.
+            if let Some((start, size)) = slots.last() {
+                let zone_end = start.raw_value() + size;
+                ram_regions = ram_regions
+                    .into_iter()
+                    .filter_map(|(start, size)| {
+                        let end = start.raw_value().saturating_add(size as u64);
+                        if end <= zone_end {
+                            None
+                        } else if start.raw_value() >= zone_end {
+                            Some((start, size))
+                        } else {
+                            Some((GuestAddress(zone_end), (end - zone_end) as usize))
+                        }
+                    })
+                    .collect();
             }
         }
 
@@ -900,6 +1110,7 @@ impl MemoryManager {
 
         for (zone_id, regions) in list {
             for (region, virtio_mem) in regions {
+                self.disable_thp_collapse(&region);
                 let slot = self.create_userspace_mapping(
                     region.start_addr().raw_value(),
                     region.len(),
@@ -945,6 +1156,68 @@ impl MemoryManager {
                 .ok_or(Error::MemoryRangeAllocation)?;
         }
 
+        // The tags can only be copied through the memory slots.
+        #[cfg(target_arch = "aarch64")]
+        if let Some((file_path, ranges)) = self.pending_mte_tags.take() {
+            self.restore_mte_tags(file_path, ranges)?;
+        }
+
+        Ok(())
+    }
+
+    // MTE can only be enabled on anonymous mappings, some kernels don't
+    // support it on hugetlbfs. Memory hotplug isn't supported either as the
+    // hotplugged regions wouldn't be mapped with PROT_MTE.
+    #[cfg(target_arch = "aarch64")]
+    fn mte_compatible(config: &MemoryConfig, zones: &[MemoryZoneConfig]) -> bool {
+        !config.hugepages
+            && config.hotplug_size.is_none()
+            && zones
+                .iter()
+                .all(|z| z.file.is_none() && !z.hugepages && z.hotplug_size.is_none())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn enable_mte(region: &GuestRegionMmap) -> Result<(), Error> {
+        // SAFETY: FFI call on a mapping owned by the region, only adding
+        // PROT_MTE to the existing protection.
+        let ret = unsafe {
+            libc::mprotect(
+                region.as_ptr() as *mut libc::c_void,
+                region.len() as usize,
+                libc::PROT_READ | libc::PROT_WRITE | PROT_MTE,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::EnableMte(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    // The tags file holds one byte per MTE granule of each saved range, in
+    // the order of the ranges.
+    #[cfg(target_arch = "aarch64")]
+    fn restore_mte_tags(&self, file_path: PathBuf, ranges: MemoryRangeTable) -> Result<(), Error> {
+        use std::io::Read;
+
+        use hypervisor::arch::aarch64::MTE_GRANULE_SIZE;
+
+        let mut tags_file = OpenOptions::new()
+            .read(true)
+            .open(file_path)
+            .map_err(Error::SnapshotOpen)?;
+
+        for range in ranges.regions() {
+            let mut tags = vec![0u8; (range.length / MTE_GRANULE_SIZE) as usize];
+            tags_file
+                .read_exact(&mut tags)
+                .map_err(|e| Error::MteTagsCopy(e.into()))?;
+            self.vm
+                .set_mte_tags(range.gpa, &tags)
+                .map_err(|e| Error::MteTagsCopy(e.into()))?;
+        }
+
         Ok(())
     }
 
@@ -984,6 +1257,7 @@ impl MemoryManager {
         config: &MemoryConfig,
         prefault: Option<bool>,
         phys_bits: u8,
+        #[cfg(target_arch = "x86_64")] ioapics: &[IoApicConfig],
         #[cfg(feature = "tdx")] tdx_enabled: bool,
         restore_data: Option<&MemoryManagerSnapshotData>,
         existing_memory_files: Option<HashMap<u32, File>>,
@@ -1004,6 +1278,11 @@ impl MemoryManager {
         let (ram_size, zones, allow_mem_hotplug) =
             Self::validate_memory_config(config, user_provided_zones)?;
 
+        #[cfg(target_arch = "aarch64")]
+        if vm.mte_enabled() && !Self::mte_compatible(config, &zones) {
+            return Err(Error::MteIncompatibleMemory);
+        }
+
         let (
             start_of_device_area,
             boot_ram,
@@ -1151,6 +1430,13 @@ impl MemoryManager {
             )
         };
 
+        #[cfg(target_arch = "aarch64")]
+        if vm.mte_enabled() {
+            for region in guest_memory.iter() {
+                Self::enable_mte(region)?;
+            }
+        }
+
         let guest_memory = GuestMemoryAtomic::new(guest_memory);
 
         let allocator = Arc::new(Mutex::new(
@@ -1160,10 +1446,15 @@ impl MemoryManager {
                 start_of_platform_device_area,
                 PLATFORM_DEVICE_AREA_SIZE,
                 #[cfg(target_arch = "x86_64")]
-                vec![GsiApic::new(
-                    X86_64_IRQ_BASE,
-                    ioapic::NUM_IOAPIC_PINS as u32 - X86_64_IRQ_BASE,
-                )],
+                // Legacy IRQs are allocated from the pins of the IOAPICs, and
+                // MSI GSIs above them.
+                ioapics
+                    .iter()
+                    .map(|ioapic| {
+                        let base = ioapic.gsi_base.max(X86_64_IRQ_BASE);
+                        GsiApic::new(base, ioapic.gsi_base + ioapic.pins - base)
+                    })
+                    .collect(),
             )
             .ok_or(Error::CreateSystemAllocator)?,
         ));
@@ -1226,6 +1517,9 @@ impl MemoryManager {
             #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
             uefi_flash: None,
             thp: config.thp,
+            thp_collapse: config.thp_collapse,
+            #[cfg(target_arch = "aarch64")]
+            pending_mte_tags: None,
         };
 
         Ok(Arc::new(Mutex::new(memory_manager)))
@@ -1238,6 +1532,7 @@ impl MemoryManager {
         source_url: Option<&str>,
         prefault: bool,
         phys_bits: u8,
+        #[cfg(target_arch = "x86_64")] ioapics: &[IoApicConfig],
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
         if let Some(source_url) = source_url {
             let mut memory_file_path = url_to_path(source_url).map_err(Error::Restore)?;
@@ -1251,12 +1546,22 @@ impl MemoryManager {
                 config,
                 Some(prefault),
                 phys_bits,
+                #[cfg(target_arch = "x86_64")]
+                ioapics,
                 #[cfg(feature = "tdx")]
                 false,
                 Some(&mem_snapshot),
                 None,
             )?;
 
+            #[cfg(target_arch = "aarch64")]
+            if mm.lock().unwrap().vm.mte_enabled() {
+                let mut tags_file_path = url_to_path(source_url).map_err(Error::Restore)?;
+                tags_file_path.push(String::from(MTE_TAGS_SNAPSHOT_FILENAME));
+                mm.lock().unwrap().pending_mte_tags =
+                    Some((tags_file_path, mem_snapshot.memory_ranges.clone()));
+            }
+
             mm.lock()
                 .unwrap()
                 .fill_saved_regions(memory_file_path, mem_snapshot.memory_ranges)?;
@@ -1497,6 +1802,28 @@ impl MemoryManager {
         Ok(Arc::new(region))
     }
 
+    // Label the anonymous memory of `region` MADV_NOHUGEPAGE if THP collapse
+    // is disabled. The huge pages already faulted in, e.g. on prefault, are
+    // kept while khugepaged leaves the range alone.
+    fn disable_thp_collapse(&self, region: &GuestRegionMmap) {
+        if self.thp_collapse || region.file_offset().is_some() {
+            return;
+        }
+
+        // SAFETY: FFI call with correct arguments
+        let ret = unsafe {
+            libc::madvise(
+                region.as_ptr() as _,
+                region.len() as usize,
+                libc::MADV_NOHUGEPAGE,
+            )
+        };
+        if ret != 0 {
+            let e = io::Error::last_os_error();
+            warn!("Failed to disable THP collapse: {e}");
+        }
+    }
+
     // Duplicate of `memory_zone_get_align_size` that does not require a `zone`
     fn get_prefault_align_size(
         backing_file: &Option<PathBuf>,
@@ -1606,6 +1933,7 @@ impl MemoryManager {
             None,
             self.thp,
         )?;
+        self.disable_thp_collapse(&region);
 
         // Map it into the guest
         let slot = self.create_userspace_mapping(
@@ -2003,6 +2331,171 @@ impl MemoryManager {
         memory_slot_fds
     }
 
+    /// Returns a duplicate of the memfd holding the guest RAM, so that
+    /// another process can map it, e.g. to introspect the guest memory.
+    /// Offset 0 of the file is the start of the guest RAM.
+    ///
+    /// The RAM must be held by a single memfd, hence be made of a single
+    /// region backed with `shared=on` or `hugepages=on`. With a RAM size
+    /// crossing the MMIO hole, or with hotplugged memory, it is split over
+    /// several files instead.
+    ///
+    /// The mapping is read-write. Writes from another process to a running
+    /// guest memory race with the guest and the device emulation, their
+    /// effect is undefined.
+    pub fn export_memory_fd(&self) -> Result<File, Error> {
+        let [guest_ram_mapping] = self.guest_ram_mappings.as_slice() else {
+            return Err(Error::ExportMemoryUnsupported(format!(
+                "RAM is made of {} regions",
+                self.guest_ram_mappings.len()
+            )));
+        };
+
+        let guest_memory = self.guest_memory.memory();
+        let file = guest_memory
+            .find_region(GuestAddress(guest_ram_mapping.gpa))
+            .and_then(|region| region.file_offset())
+            .map(|file_offset| file_offset.file())
+            .ok_or_else(|| Error::ExportMemoryUnsupported("RAM is anonymous memory".to_string()))?;
+
+        let path = std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            .map_err(Error::ExportMemoryFd)?;
+        if !path.to_string_lossy().starts_with("/memfd:") {
+            return Err(Error::ExportMemoryUnsupported(format!(
+                "RAM is backed by {}",
+                path.display()
+            )));
+        }
+
+        file.try_clone().map_err(Error::ExportMemoryFd)
+    }
+
+    /// Returns the guest pages which haven't been accessed since the
+    /// previous call, based on the host idle page tracking. Unlike dirty
+    /// page tracking, reads count as accesses too.
+    ///
+    /// Each call goes through the two phases of the protocol:
+    /// * check: the pages whose host frame is still flagged idle in
+    ///   `/sys/kernel/mm/page_idle/bitmap` haven't been accessed since they
+    ///   were flagged, and are reported;
+    /// * set: every resident guest page is flagged idle again, the host
+    ///   kernel clearing the flag on its next access.
+    ///
+    /// The first call only arms the tracking, the pages it reports may have
+    /// been flagged by another process. Pages which are not resident are
+    /// skipped, as well as the ones the host doesn't track on its LRU lists,
+    /// such as hugetlbfs pages. Host frame numbers are read from
+    /// `/proc/self/pagemap`, which requires CAP_SYS_ADMIN.
+    pub fn scan_idle_pages(&self) -> Result<Vec<GuestPageFrame>, Error> {
+        use std::os::unix::fs::FileExt;
+
+        // SAFETY: FFI call. Trivially safe.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 };
+
+        let pagemap = File::open("/proc/self/pagemap").map_err(Error::ScanIdlePages)?;
+        let bitmap = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(PAGE_IDLE_BITMAP)
+            .map_err(Error::ScanIdlePages)?;
+
+        let mut idle_pages = Vec::new();
+        let mut entries = vec![0u8; (IDLE_SCAN_CHUNK_PAGES * PAGEMAP_ENTRY_SIZE) as usize];
+        for region in self.guest_memory.memory().iter() {
+            let first_frame = region.start_addr().raw_value() / page_size;
+            let first_host_page = region.as_ptr() as u64 / page_size;
+            let pages = region.len() / page_size;
+
+            for chunk in (0..pages).step_by(IDLE_SCAN_CHUNK_PAGES as usize) {
+                let count = IDLE_SCAN_CHUNK_PAGES.min(pages - chunk);
+                let entries = &mut entries[..(count * PAGEMAP_ENTRY_SIZE) as usize];
+                pagemap
+                    .read_exact_at(entries, (first_host_page + chunk) * PAGEMAP_ENTRY_SIZE)
+                    .map_err(Error::ScanIdlePages)?;
+
+                // (host frame, guest frame) of the resident pages
+                let mut frames = Vec::with_capacity(count as usize);
+                for (i, entry) in entries
+                    .chunks_exact(PAGEMAP_ENTRY_SIZE as usize)
+                    .enumerate()
+                {
+                    let entry = u64::from_le_bytes(entry.try_into().unwrap());
+                    if entry & PAGEMAP_PRESENT == 0 {
+                        continue;
+                    }
+
+                    let pfn = entry & PAGEMAP_PFN_MASK;
+                    if pfn == 0 {
+                        return Err(Error::ScanIdlePages(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "host frame numbers are hidden without CAP_SYS_ADMIN",
+                        )));
+                    }
+                    frames.push((pfn, first_frame + chunk + i as u64));
+                }
+                frames.sort_unstable();
+
+                let pfns: Vec<u64> = frames.iter().map(|(pfn, _)| *pfn).collect();
+                for run in idle_bitmap_runs(&pfns) {
+                    let frames = &frames[run];
+                    // The bitmap is accessed by 64 bits words, one bit per frame
+                    let first_word = frames[0].0 / 64;
+                    let words = (frames[frames.len() - 1].0 / 64 - first_word + 1) as usize;
+                    let mut bytes = vec![0u8; words * 8];
+                    bitmap
+                        .read_exact_at(&mut bytes, first_word * 8)
+                        .map_err(Error::ScanIdlePages)?;
+
+                    let mut bits = vec![0u64; words];
+                    for (pfn, frame) in frames {
+                        let word = (pfn / 64 - first_word) as usize;
+                        let bit = 1u64 << (pfn % 64);
+                        let idle = u64::from_le_bytes(bytes[word * 8..][..8].try_into().unwrap());
+                        if idle & bit != 0 {
+                            idle_pages.push(GuestPageFrame(*frame));
+                        }
+                        bits[word] |= bit;
+                    }
+
+                    // Only the bits set are written, the other frames are untouched
+                    let bytes: Vec<u8> = bits.iter().flat_map(|w| w.to_le_bytes()).collect();
+                    bitmap
+                        .write_all_at(&bytes, first_word * 8)
+                        .map_err(Error::ScanIdlePages)?;
+                }
+            }
+        }
+        idle_pages.sort_unstable();
+
+        Ok(idle_pages)
+    }
+
+    /// Returns the transparent huge page state of every guest RAM region.
+    ///
+    /// THP only applies to anonymous and shmem memory, the regions backed by
+    /// hugetlbfs (`hugepages=on`) are reported as not eligible.
+    pub fn thp_state(&self) -> Result<Vec<ThpState>, Error> {
+        let smaps = std::fs::read_to_string(PROC_SELF_SMAPS).map_err(Error::ReadThpState)?;
+
+        Ok(self
+            .guest_memory
+            .memory()
+            .iter()
+            .map(|region| {
+                let start = region.as_ptr() as u64;
+                let (eligible, collapse_disabled, huge_bytes) =
+                    parse_smaps_thp(&smaps, start, start + region.len());
+                ThpState {
+                    start: region.start_addr(),
+                    size: region.len(),
+                    eligible,
+                    collapse_disabled,
+                    huge_bytes,
+                }
+            })
+            .collect())
+    }
+
     pub fn acpi_address(&self) -> Option<GuestAddress> {
         self.acpi_address
     }
@@ -2548,6 +3041,32 @@ impl Transportable for MemoryManager {
                 }
             }
         }
+
+        #[cfg(target_arch = "aarch64")]
+        if self.vm.mte_enabled() {
+            use std::io::Write;
+
+            use hypervisor::arch::aarch64::MTE_GRANULE_SIZE;
+
+            let mut tags_file_path = url_to_path(destination_url)?;
+            tags_file_path.push(String::from(MTE_TAGS_SNAPSHOT_FILENAME));
+            let mut tags_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(tags_file_path)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+            for range in self.snapshot_memory_ranges.regions() {
+                let mut tags = vec![0u8; (range.length / MTE_GRANULE_SIZE) as usize];
+                self.vm
+                    .get_mte_tags(range.gpa, &mut tags)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                tags_file
+                    .write_all(&tags)
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -2622,3 +3141,101 @@ impl Migratable for MemoryManager {
         Ok(table)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn test_parse_smaps_thp() {
+        let smaps = "\
+7f0000000000-7f0040000000 rw-p 00000000 00:00 0
+Size:            1048576 kB
+AnonHugePages:    524288 kB
+ShmemPmdMapped:        0 kB
+THPeligible:    1
+VmFlags: rd wr mr mw me ac sd nh
+7f0040000000-7f0080000000 rw-s 00000000 00:0f 1234                       /memfd:ch_ram (deleted)
+AnonHugePages:         0 kB
+ShmemPmdMapped:    16384 kB
+THPeligible:    1
+VmFlags: rd wr sh mr mw me ms sd hg
+7f0080000000-7f00c0000000 rw-s 00000000 00:10 5678                       /dev/hugepages/ram
+AnonHugePages:         0 kB
+THPeligible:    0
+VmFlags: rd wr sh mr mw me ms de ht sd
+";
+
+        assert_eq!(
+            parse_smaps_thp(smaps, 0x7f00_0000_0000, 0x7f00_4000_0000),
+            (true, true, 512 << 20)
+        );
+        assert_eq!(
+            parse_smaps_thp(smaps, 0x7f00_4000_0000, 0x7f00_8000_0000),
+            (true, false, 16 << 20)
+        );
+        // A region spanning several mappings
+        assert_eq!(
+            parse_smaps_thp(smaps, 0x7f00_0000_0000, 0x7f00_8000_0000),
+            (true, true, (512 + 16) << 20)
+        );
+        assert_eq!(
+            parse_smaps_thp(smaps, 0x7f00_8000_0000, 0x7f00_c000_0000),
+            (false, false, 0)
+        );
+        assert_eq!(parse_smaps_thp(smaps, 0x1000, 0x2000), (false, false, 0));
+    }
+
+    #[test]
+    fn test_idle_bitmap_runs() {
+        assert!(idle_bitmap_runs(&[]).is_empty());
+        // Frames 0-127 are in the first two words, 256 starts a new run
+        assert_eq!(
+            idle_bitmap_runs(&[0, 1, 63, 64, 127, 256, 257, 1000]),
+            vec![0..5, 5..7, 7..8]
+        );
+        assert_eq!(idle_bitmap_runs(&[5, 5]), vec![0..2]);
+    }
+
+    #[test]
+    fn test_ram_slots_builder() {
+        // 0 ~ 3GiB and 4GiB ~ inf, with a 2MiB hole at 1GiB.
+        let builder = RamSlotsBuilder::new(&[
+            (GuestAddress(0), (3 * GIB) as usize),
+            (GuestAddress(4 * GIB), usize::MAX),
+        ])
+        .reserved(GuestAddress(GIB), 2 << 20);
+
+        // Normal pages only skip the hole, and the size is rounded up.
+        assert_eq!(
+            builder.build(4 * GIB - 1).unwrap(),
+            vec![
+                (GuestAddress(0), GIB),
+                (GuestAddress(GIB + (2 << 20)), 2 * GIB - (2 << 20)),
+                (GuestAddress(4 * GIB), GIB + (2 << 20)),
+            ]
+        );
+
+        // 1GiB pages cannot sit next to a hole smaller than a page.
+        assert!(matches!(
+            builder.page_size(GIB).build(4 * GIB),
+            Err(Error::MisalignedReservedRegion(..))
+        ));
+
+        // Misaligned RAM boundaries are rounded inwards.
+        let slots = RamSlotsBuilder::new(&[
+            (GuestAddress(GIB / 2), (3 * GIB) as usize),
+            (GuestAddress(4 * GIB), usize::MAX),
+        ])
+        .reserved(GuestAddress(2 * GIB), GIB)
+        .page_size(GIB)
+        .build(2 * GIB + 1)
+        .unwrap();
+        assert_eq!(
+            slots,
+            vec![(GuestAddress(GIB), GIB), (GuestAddress(4 * GIB), 2 * GIB),]
+        );
+    }
+}
@@ -391,6 +391,7 @@ fn create_vmm_ioctl_seccomp_rule_kvm() -> Result<Vec<SeccompRule>, BackendError>
     const KVM_SET_XSAVE: u64 = 0x5000_aea5;
     const KVM_SET_GUEST_DEBUG: u64 = 0x4048_ae9b;
     const KVM_TRANSLATE: u64 = 0xc018_ae85;
+    const KVM_X86_SET_MSR_FILTER: u64 = 0x4188_aec6;
 
     let common_rules = create_vmm_ioctl_seccomp_rule_common(HypervisorType::Kvm)?;
     let mut arch_rules = or![
@@ -419,6 +420,7 @@ fn create_vmm_ioctl_seccomp_rule_kvm() -> Result<Vec<SeccompRule>, BackendError>
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_XSAVE,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_GUEST_DEBUG,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_TRANSLATE,)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_X86_SET_MSR_FILTER)?],
     ];
     arch_rules.extend(common_rules);
 
@@ -45,7 +45,7 @@ use gdbstub_arch::aarch64::reg::AArch64CoreRegs as CoreRegs;
 use gdbstub_arch::x86::reg::X86_64CoreRegs as CoreRegs;
 #[cfg(target_arch = "aarch64")]
 use hypervisor::arch::aarch64::regs::AARCH64_PMU_IRQ;
-use hypervisor::{HypervisorVmConfig, HypervisorVmError, VmOps};
+use hypervisor::{CpuState, HypervisorVmConfig, HypervisorVmError, VmOps};
 use libc::{SIGWINCH, termios};
 use linux_loader::cmdline::Cmdline;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
@@ -157,6 +157,21 @@ pub enum Error {
     #[error("Error initializing VM")]
     InitializeVm(#[source] hypervisor::HypervisorVmError),
 
+    #[error("Error creating the hypervisor VM")]
+    CreateHypervisorVm(#[source] hypervisor::HypervisorError),
+
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error enabling the split irqchip")]
+    EnableSplitIrq(#[source] hypervisor::HypervisorVmError),
+
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error setting the APIC bus cycle period")]
+    SetApicBusCycle(#[source] hypervisor::HypervisorVmError),
+
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error enabling the invalid opcode trap")]
+    EnableInvalidOpcodeTrap(#[source] hypervisor::HypervisorVmError),
+
     #[error("No device with id {0:?} to remove")]
     NoDeviceToRemove(String),
 
@@ -178,6 +193,9 @@ pub enum Error {
     #[error("VM is not running")]
     VmNotRunning,
 
+    #[error("VM is not paused")]
+    VmNotPaused,
+
     #[error("Cannot clone EventFd")]
     EventFdClone(#[source] io::Error),
 
@@ -793,6 +811,23 @@ impl Vm {
             .transpose()
             .map_err(Error::InitramfsFile)?;
 
+        // On restore the RTC time comes with the CMOS device state.
+        #[cfg(target_arch = "x86_64")]
+        if snapshot.is_none()
+            && let Some(rtc_time) = config
+                .lock()
+                .unwrap()
+                .platform
+                .as_ref()
+                .and_then(|p| p.rtc_time)
+        {
+            // Without an in-kernel RTC the guest reads the time from the
+            // userspace CMOS device.
+            if vm.set_rtc_time(rtc_time).is_err() {
+                device_manager.lock().unwrap().set_rtc_time(rtc_time);
+            }
+        }
+
         #[cfg(target_arch = "x86_64")]
         let saved_clock = if let Some(snapshot) = snapshot.as_ref() {
             let vm_snapshot = get_vm_snapshot(snapshot).map_err(Error::Restore)?;
@@ -1014,15 +1049,41 @@ impl Vm {
             vm_config.lock().unwrap().is_sev_snp_enabled()
         };
 
-        let vm = Self::create_hypervisor_vm(
-            hypervisor.as_ref(),
-            #[cfg(feature = "tdx")]
-            tdx_enabled,
-            #[cfg(feature = "sev_snp")]
-            sev_snp_enabled,
-            #[cfg(feature = "sev_snp")]
-            vm_config.lock().unwrap().memory.total_size(),
-        )?;
+        let vm = {
+            let config = vm_config.lock().unwrap();
+            Self::create_hypervisor_vm(
+                hypervisor.as_ref(),
+                config.label(),
+                config.cpus.boot_vcpus,
+                config.cpus.max_vcpus,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.vcpu_topology(),
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.disabled_msrs(),
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.rapl,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.features.cet,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.features.processor_trace,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.apic_bus_cycle_ns,
+                #[cfg(target_arch = "x86_64")]
+                config.cpus.trap_ud,
+                #[cfg(target_arch = "x86_64")]
+                config.ioapic_pins(),
+                #[cfg(target_arch = "aarch64")]
+                config.cpus.features.mte,
+                #[cfg(target_arch = "aarch64")]
+                config.cpus.features.system_suspend,
+                #[cfg(feature = "tdx")]
+                tdx_enabled,
+                #[cfg(feature = "sev_snp")]
+                sev_snp_enabled,
+                #[cfg(feature = "sev_snp")]
+                config.memory.total_size(),
+            )?
+        };
 
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         if vm_config.lock().unwrap().max_apic_id() > MAX_SUPPORTED_CPUS_LEGACY {
@@ -1034,24 +1095,31 @@ impl Vm {
             vm_config.lock().unwrap().cpus.max_phys_bits,
         );
 
+        let memory_config = vm_config.lock().unwrap().memory.clone();
+        #[cfg(target_arch = "x86_64")]
+        let ioapics = vm_config.lock().unwrap().ioapics();
         let memory_manager = if let Some(snapshot) =
             snapshot_from_id(snapshot.as_ref(), MEMORY_MANAGER_SNAPSHOT_ID)
         {
             MemoryManager::new_from_snapshot(
                 &snapshot,
                 vm.clone(),
-                &vm_config.lock().unwrap().memory.clone(),
+                &memory_config,
                 source_url,
                 prefault.unwrap(),
                 phys_bits,
+                #[cfg(target_arch = "x86_64")]
+                &ioapics,
             )
             .map_err(Error::MemoryManager)?
         } else {
             MemoryManager::new(
                 vm.clone(),
-                &vm_config.lock().unwrap().memory.clone(),
+                &memory_config,
                 None,
                 phys_bits,
+                #[cfg(target_arch = "x86_64")]
+                &ioapics,
                 #[cfg(feature = "tdx")]
                 tdx_enabled,
                 None,
@@ -1080,30 +1148,74 @@ impl Vm {
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_hypervisor_vm(
         hypervisor: &dyn hypervisor::Hypervisor,
+        label: &str,
+        boot_vcpus: u32,
+        max_vcpus: u32,
+        #[cfg(target_arch = "x86_64")] topology: Option<(u16, u16, u16, u16)>,
+        #[cfg(target_arch = "x86_64")] disabled_msrs: Vec<u32>,
+        #[cfg(target_arch = "x86_64")] rapl: Option<hypervisor::RaplMode>,
+        #[cfg(target_arch = "x86_64")] cet: bool,
+        #[cfg(target_arch = "x86_64")] processor_trace: bool,
+        #[cfg(target_arch = "x86_64")] apic_bus_cycle_ns: Option<u32>,
+        #[cfg(target_arch = "x86_64")] trap_ud: bool,
+        #[cfg(target_arch = "x86_64")] ioapic_pins: u32,
+        #[cfg(target_arch = "aarch64")] mte: bool,
+        #[cfg(target_arch = "aarch64")] system_suspend: bool,
         #[cfg(feature = "tdx")] tdx_enabled: bool,
         #[cfg(feature = "sev_snp")] sev_snp_enabled: bool,
         #[cfg(feature = "sev_snp")] mem_size: u64,
     ) -> Result<Arc<dyn hypervisor::Vm>> {
         hypervisor.check_required_extensions().unwrap();
         let config = HypervisorVmConfig {
+            #[cfg(target_arch = "aarch64")]
+            mte,
+            #[cfg(target_arch = "aarch64")]
+            system_suspend,
             #[cfg(feature = "tdx")]
             tdx_enabled,
             #[cfg(feature = "sev_snp")]
             sev_snp_enabled,
             #[cfg(feature = "sev_snp")]
             mem_size,
+            label: label.to_string(),
+            boot_vcpus,
+            max_vcpus,
+            #[cfg(target_arch = "x86_64")]
+            topology,
+            #[cfg(target_arch = "x86_64")]
+            disabled_msrs,
+            #[cfg(target_arch = "x86_64")]
+            rapl,
+            #[cfg(target_arch = "x86_64")]
+            cet,
+            #[cfg(target_arch = "x86_64")]
+            processor_trace,
+            ..Default::default()
         };
 
-        let vm = hypervisor.create_vm(config).unwrap();
+        let vm = hypervisor
+            .create_vm(config)
+            .map_err(Error::CreateHypervisorVm)?;
 
         #[cfg(target_arch = "x86_64")]
         {
             vm.set_identity_map_address(KVM_IDENTITY_MAP_START.0)
                 .unwrap();
             vm.set_tss_address(KVM_TSS_START.0 as usize).unwrap();
-            vm.enable_split_irq().unwrap();
+            vm.enable_split_irq(ioapic_pins)
+                .map_err(Error::EnableSplitIrq)?;
+            // Needs the irqchip, and must precede the vCPUs creation
+            if let Some(ns) = apic_bus_cycle_ns {
+                vm.set_apic_bus_cycle_ns(ns)
+                    .map_err(Error::SetApicBusCycle)?;
+            }
+            if trap_ud {
+                vm.enable_invalid_opcode_trap()
+                    .map_err(Error::EnableInvalidOpcodeTrap)?;
+            }
         }
 
         Ok(vm)
@@ -1970,7 +2082,43 @@ impl Vm {
     }
 
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
-        Ok(self.device_manager.lock().unwrap().counters())
+        let mut counters = self.device_manager.lock().unwrap().counters();
+
+        // Transparent huge page usage of the guest RAM, summed over the regions
+        let thp_state = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .thp_state()
+            .map_err(Error::MemoryManager)?;
+        let mut memory_counters = HashMap::new();
+        memory_counters.insert(
+            "thp_eligible_bytes",
+            Wrapping(
+                thp_state
+                    .iter()
+                    .filter(|r| r.eligible)
+                    .map(|r| r.size)
+                    .sum(),
+            ),
+        );
+        memory_counters.insert(
+            "thp_collapse_disabled_bytes",
+            Wrapping(
+                thp_state
+                    .iter()
+                    .filter(|r| r.collapse_disabled)
+                    .map(|r| r.size)
+                    .sum(),
+            ),
+        );
+        memory_counters.insert(
+            "thp_huge_bytes",
+            Wrapping(thp_state.iter().map(|r| r.huge_bytes).sum()),
+        );
+        counters.insert(MEMORY_MANAGER_SNAPSHOT_ID.to_string(), memory_counters);
+
+        Ok(counters)
     }
 
     #[cfg(feature = "tdx")]
@@ -2516,6 +2664,28 @@ impl Vm {
             .map(|state| *state)
     }
 
+    /// Returns the state of all the vCPUs, as a consistent cut of the guest
+    /// execution.
+    ///
+    /// The VM must be paused, so every vCPU was stopped before the first one
+    /// is read, no vCPU runs between two reads and the states all describe
+    /// the same point in time: a guest synchronizing across vCPUs (e.g. one
+    /// spinning on a lock another holds) is seen in a state it could
+    /// actually be in. The devices are paused as well, so the cut also
+    /// covers the guest memory and the device state. This is how the vCPU
+    /// states of a snapshot are read.
+    pub fn snapshot_all_vcpus(&self) -> Result<Vec<CpuState>> {
+        if self.get_state()? != VmState::Paused {
+            return Err(Error::VmNotPaused);
+        }
+
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .vcpus_state()
+            .map_err(Error::CpuManager)
+    }
+
     /// Gets the actual size of the balloon.
     pub fn balloon_size(&self) -> u64 {
         self.device_manager.lock().unwrap().balloon_size()
@@ -2604,6 +2774,14 @@ impl Vm {
         self.device_manager.lock().unwrap().device_tree()
     }
 
+    /// Stream of the POST codes written by the guest to port 0x80.
+    #[cfg(target_arch = "x86_64")]
+    pub fn subscribe_post_codes(
+        &self,
+    ) -> Option<std::sync::mpsc::Receiver<devices::legacy::PostCode>> {
+        self.device_manager.lock().unwrap().subscribe_post_codes()
+    }
+
     /// Release all advisory locks held for the disk images.
     ///
     /// This should only be called when the VM is stopped and the VMM supposed
@@ -2844,6 +3022,8 @@ impl Pausable for Vm {
 
 #[derive(Serialize, Deserialize)]
 pub struct VmSnapshot {
+    #[serde(default)]
+    pub header: Option<hypervisor::SnapshotHeader>,
     #[cfg(target_arch = "x86_64")]
     pub clock: Option<hypervisor::ClockData>,
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
@@ -2875,21 +3055,42 @@ impl Snapshottable for Vm {
             )));
         }
 
+        // The vCPUs completed their IO operations when pausing, make sure
+        // none would be replayed on restore.
+        #[cfg(feature = "kvm")]
+        if matches!(
+            self.hypervisor.hypervisor_type(),
+            hypervisor::HypervisorType::Kvm
+        ) {
+            self.vm.quiesce().map_err(|e| {
+                MigratableError::Snapshot(anyhow!("Could not quiesce the vCPUs: {e}"))
+            })?;
+        }
+
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let common_cpuid = {
-            let amx = self.config.lock().unwrap().cpus.features.amx;
-            let phys_bits = physical_bits(
-                self.hypervisor.as_ref(),
-                self.config.lock().unwrap().cpus.max_phys_bits,
-            );
+            let config = &self.config.lock().unwrap();
+            let phys_bits = physical_bits(self.hypervisor.as_ref(), config.cpus.max_phys_bits);
             arch::generate_common_cpuid(
                 self.hypervisor.as_ref(),
                 &arch::CpuidConfig {
                     phys_bits,
-                    kvm_hyperv: self.config.lock().unwrap().cpus.kvm_hyperv,
+                    kvm_hyperv: config.cpus.kvm_hyperv,
                     #[cfg(feature = "tdx")]
                     tdx: false,
-                    amx,
+                    amx: config.cpus.features.amx,
+                    cet: config.cpus.features.cet,
+                    processor_trace: config.cpus.features.processor_trace,
+                    x2apic: config.cpus.apic_mode.x2apic(),
+                    invariant_tsc: config.cpus.invariant_tsc,
+                    cpu_vendor_override: config.cpus.cpu_vendor_override,
+                    disabled_pv_msrs: config.cpus.disabled_pv_msrs.clone(),
+                    arat: config.cpus.arat,
+                    disabled_pmu_events: config.cpus.disabled_pmu_events.clone(),
+                    linear_bits: config.cpus.linear_bits,
+                    leaf_7_overrides: config.cpus.leaf_7_overrides.clone(),
+                    mwait: config.cpus.mwait,
+                    ..Default::default()
                 },
             )
             .map_err(|e| {
@@ -2897,7 +3098,16 @@ impl Snapshottable for Vm {
             })?
         };
 
+        let header = self
+            .hypervisor
+            .snapshot_header(
+                #[cfg(target_arch = "x86_64")]
+                &self.cpu_manager.lock().unwrap().common_cpuid(),
+            )
+            .map_err(|e| MigratableError::Snapshot(e.into()))?;
+
         let vm_snapshot_state = VmSnapshot {
+            header: Some(header),
             #[cfg(target_arch = "x86_64")]
             clock: self.saved_clock,
             #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
@@ -2906,9 +3116,12 @@ impl Snapshottable for Vm {
 
         let mut vm_snapshot = Snapshot::new_from_state(&vm_snapshot_state)?;
 
+        let vcpu_states = self
+            .snapshot_all_vcpus()
+            .map_err(|e| MigratableError::Snapshot(e.into()))?;
         let (id, snapshot) = {
             let mut cpu_manager = self.cpu_manager.lock().unwrap();
-            (cpu_manager.id(), cpu_manager.snapshot()?)
+            (cpu_manager.id(), cpu_manager.snapshot_states(vcpu_states)?)
         };
         vm_snapshot.add_snapshot(id, snapshot);
         let (id, snapshot) = {
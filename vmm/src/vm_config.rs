@@ -35,6 +35,18 @@ pub struct CpuFeatures {
     #[cfg(target_arch = "x86_64")]
     #[serde(default)]
     pub amx: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub cet: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub processor_trace: bool,
+    #[cfg(target_arch = "aarch64")]
+    #[serde(default)]
+    pub mte: bool,
+    #[cfg(target_arch = "aarch64")]
+    #[serde(default)]
+    pub system_suspend: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -54,6 +66,34 @@ pub fn default_cpuconfig_max_phys_bits() -> u8 {
     DEFAULT_MAX_PHYS_BITS
 }
 
+pub fn default_cpuconfig_arat() -> bool {
+    true
+}
+
+/// Local APIC mode exposed to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub enum ApicMode {
+    /// x2APIC is hidden from the guest.
+    XApic,
+    /// x2APIC is exposed and enabled when the vCPUs boot.
+    X2Apic,
+    /// x2APIC is exposed if supported, and only enabled when the vCPUs boot
+    /// if some APIC IDs don't fit in xAPIC.
+    #[default]
+    Auto,
+}
+
+impl ApicMode {
+    /// Whether x2APIC is forced on or off.
+    pub fn x2apic(&self) -> Option<bool> {
+        match self {
+            ApicMode::XApic => Some(false),
+            ApicMode::X2Apic => Some(true),
+            ApicMode::Auto => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u32,
@@ -68,6 +108,56 @@ pub struct CpusConfig {
     pub affinity: Option<Vec<CpuAffinity>>,
     #[serde(default)]
     pub features: CpuFeatures,
+    #[serde(default)]
+    pub apic_mode: ApicMode,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub boot_mode: arch::BootMode,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub invariant_tsc: Option<bool>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub cpu_vendor_override: Option<[u8; 12]>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub core_crystal_hz: Option<u32>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub disabled_pv_msrs: Vec<arch::PvMsr>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default = "default_cpuconfig_arat")]
+    pub arat: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub async_pf: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub arch_capabilities: Option<u64>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub disabled_pmu_events: Vec<arch::PmuEvent>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub linear_bits: Option<u8>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub tsc_khz: Option<u32>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub rapl: Option<hypervisor::RaplMode>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub apic_bus_cycle_ns: Option<u32>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub trap_ud: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub leaf_7_overrides: Vec<arch::Leaf7Override>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub mwait: Option<arch::MwaitConfig>,
 }
 
 pub const DEFAULT_VCPUS: u32 = 1;
@@ -82,10 +172,67 @@ impl Default for CpusConfig {
             max_phys_bits: DEFAULT_MAX_PHYS_BITS,
             affinity: None,
             features: CpuFeatures::default(),
+            apic_mode: ApicMode::default(),
+            #[cfg(target_arch = "x86_64")]
+            boot_mode: arch::BootMode::default(),
+            #[cfg(target_arch = "x86_64")]
+            invariant_tsc: None,
+            #[cfg(target_arch = "x86_64")]
+            cpu_vendor_override: None,
+            #[cfg(target_arch = "x86_64")]
+            core_crystal_hz: None,
+            #[cfg(target_arch = "x86_64")]
+            disabled_pv_msrs: Vec::new(),
+            #[cfg(target_arch = "x86_64")]
+            arat: true,
+            #[cfg(target_arch = "x86_64")]
+            async_pf: false,
+            #[cfg(target_arch = "x86_64")]
+            arch_capabilities: None,
+            #[cfg(target_arch = "x86_64")]
+            disabled_pmu_events: Vec::new(),
+            #[cfg(target_arch = "x86_64")]
+            linear_bits: None,
+            #[cfg(target_arch = "x86_64")]
+            tsc_khz: None,
+            #[cfg(target_arch = "x86_64")]
+            rapl: None,
+            #[cfg(target_arch = "x86_64")]
+            apic_bus_cycle_ns: None,
+            #[cfg(target_arch = "x86_64")]
+            trap_ud: false,
+            #[cfg(target_arch = "x86_64")]
+            leaf_7_overrides: Vec::new(),
+            #[cfg(target_arch = "x86_64")]
+            mwait: None,
         }
     }
 }
 
+impl CpusConfig {
+    /// Threads per core, cores per die, dies per package and packages, if
+    /// a topology is given.
+    pub fn vcpu_topology(&self) -> Option<(u16, u16, u16, u16)> {
+        self.topology.as_ref().map(|t| {
+            (
+                t.threads_per_core,
+                t.cores_per_die,
+                t.dies_per_package,
+                t.packages,
+            )
+        })
+    }
+
+    /// Indices of the MSRs hidden from the guest.
+    #[cfg(target_arch = "x86_64")]
+    pub fn disabled_msrs(&self) -> Vec<u32> {
+        self.disabled_pv_msrs
+            .iter()
+            .flat_map(|msr| msr.msr_indices().iter().copied())
+            .collect()
+    }
+}
+
 pub const DEFAULT_NUM_PCI_SEGMENTS: u16 = 1;
 pub fn default_platformconfig_num_pci_segments() -> u16 {
     DEFAULT_NUM_PCI_SEGMENTS
@@ -96,6 +243,22 @@ pub fn default_platformconfig_iommu_address_width_bits() -> u8 {
     DEFAULT_IOMMU_ADDRESS_WIDTH_BITS
 }
 
+#[cfg(target_arch = "x86_64")]
+pub const DEFAULT_IOAPIC_PINS: u32 = devices::ioapic::NUM_IOAPIC_PINS as u32;
+#[cfg(target_arch = "x86_64")]
+pub fn default_platformconfig_ioapic_pins() -> u32 {
+    DEFAULT_IOAPIC_PINS
+}
+
+/// Userspace IOAPIC handling the GSIs from `gsi_base` to `gsi_base + pins`.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IoApicConfig {
+    pub id: u8,
+    pub gsi_base: u32,
+    pub pins: u32,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct PlatformConfig {
     #[serde(default = "default_platformconfig_num_pci_segments")]
@@ -110,6 +273,17 @@ pub struct PlatformConfig {
     pub uuid: Option<String>,
     #[serde(default)]
     pub oem_strings: Option<Vec<String>>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default = "default_platformconfig_ioapic_pins")]
+    pub ioapic_pins: u32,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub ioapics: Option<Vec<IoApicConfig>>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub rtc_time: Option<u64>,
     #[cfg(feature = "tdx")]
     #[serde(default)]
     pub tdx: bool,
@@ -199,6 +373,8 @@ pub struct MemoryConfig {
     pub zones: Option<Vec<MemoryZoneConfig>>,
     #[serde(default = "default_memoryconfig_thp")]
     pub thp: bool,
+    #[serde(default = "default_memoryconfig_thp")]
+    pub thp_collapse: bool,
 }
 
 pub const DEFAULT_MEMORY_MB: u64 = 512;
@@ -217,6 +393,7 @@ impl Default for MemoryConfig {
             prefault: false,
             zones: None,
             thp: true,
+            thp_collapse: true,
         }
     }
 }
@@ -1056,4 +1233,43 @@ impl VmConfig {
             self.cpus.max_vcpus
         }
     }
+
+    /// Label identifying the VM in the hypervisor errors and the names of
+    /// its vCPU threads, empty unless provided.
+    pub(crate) fn label(&self) -> &str {
+        self.platform
+            .as_ref()
+            .and_then(|p| p.label.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// Userspace IOAPICs, a single one with `ioapic_pins` pins unless a list
+    /// is provided.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn ioapics(&self) -> Vec<IoApicConfig> {
+        match &self.platform {
+            Some(PlatformConfig {
+                ioapics: Some(ioapics),
+                ..
+            }) => ioapics.clone(),
+            platform => vec![IoApicConfig {
+                id: 0,
+                gsi_base: 0,
+                pins: platform
+                    .as_ref()
+                    .map(|p| p.ioapic_pins)
+                    .unwrap_or(DEFAULT_IOAPIC_PINS),
+            }],
+        }
+    }
+
+    /// Number of GSIs reserved for the pins of the userspace IOAPICs.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn ioapic_pins(&self) -> u32 {
+        self.ioapics()
+            .iter()
+            .map(|ioapic| ioapic.gsi_base + ioapic.pins)
+            .max()
+            .unwrap_or(DEFAULT_IOAPIC_PINS)
+    }
 }